@@ -0,0 +1,367 @@
+//! The `icedrop` command line tool: sends files to a receiver, receives files into a directory and
+//! lists the receivers on the local network.
+
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::Duration;
+
+use clap::{ArgAction, Parser, Subcommand};
+use icedrop_core::{
+    Client, DiscoveryAnnouncer, DiscoveryBrowser, PeerCapabilities, ReceiveEvent, Server,
+    TransferConfig, DEFAULT_PORT, DISCOVERY_TIMEOUT,
+};
+use icedrop_proto::transfer::MAX_SEGMENT_SIZE;
+use log::LevelFilter;
+use tokio::fs::File;
+
+/// Sends files to and receives files from devices on the network.
+#[derive(Parser)]
+#[command(name = "icedrop", version)]
+struct Cli {
+    /// Logs more, up to three times.
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    verbose: u8,
+    /// Only logs errors.
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Sends files to a receiver.
+    Send {
+        /// A receiver's name as listed by `icedrop discover`, or its host with an optional port.
+        peer: String,
+        /// The files to send, which the receiver stores under their own names.
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+        /// The port of the receiver, unless the peer gives one.
+        #[arg(short, long, default_value_t = DEFAULT_PORT)]
+        port: u16,
+        /// The size of the segments files are sent in, e.g. `64k` or `1M`.
+        #[arg(long, value_parser = parse_chunk_size)]
+        chunk_size: Option<usize>,
+    },
+    /// Receives files until interrupted.
+    Receive {
+        /// Where received files are stored, created if needed.
+        #[arg(short, long, default_value = ".")]
+        dir: PathBuf,
+        /// The port connections are accepted on.
+        #[arg(short, long, default_value_t = DEFAULT_PORT)]
+        port: u16,
+        /// Announces the receiver on the local network under this name.
+        #[arg(short, long)]
+        name: Option<String>,
+        /// The largest segments senders may send, e.g. `64k` or `1M`.
+        #[arg(long, value_parser = parse_chunk_size)]
+        chunk_size: Option<usize>,
+        /// Serves metrics for Prometheus on this port, at `/metrics`.
+        #[arg(long)]
+        metrics_port: Option<u16>,
+    },
+    /// Lists the receivers announcing themselves on the local network.
+    Discover {
+        /// How long to listen for announcements, in seconds.
+        #[arg(short, long, default_value_t = DISCOVERY_TIMEOUT.as_secs())]
+        timeout: u64,
+    },
+}
+
+/// Reads a size in bytes with an optional `k` or `M` suffix, e.g. `64k`.
+fn parse_chunk_size(input: &str) -> Result<usize, String> {
+    let input = input.trim();
+    let (digits, multiplier) = match input.char_indices().last() {
+        Some((idx, 'k')) | Some((idx, 'K')) => (&input[..idx], 1024),
+        Some((idx, 'm')) | Some((idx, 'M')) => (&input[..idx], 1024 * 1024),
+        _ => (input, 1),
+    };
+    let size = digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|size| size.checked_mul(multiplier))
+        .ok_or_else(|| format!("{:?} is not a size", input))?;
+    if size == 0 || size > MAX_SEGMENT_SIZE {
+        return Err(format!(
+            "has to be between 1 and {} bytes",
+            MAX_SEGMENT_SIZE
+        ));
+    }
+    Ok(size)
+}
+
+/// The address to connect to for `peer` if it's given as a host, `None` if it's a name.
+fn peer_addr(peer: &str, port: u16) -> Option<String> {
+    if peer.parse::<SocketAddr>().is_ok() {
+        Some(peer.to_owned())
+    } else if let Ok(ip) = peer.parse::<IpAddr>() {
+        Some(SocketAddr::new(ip, port).to_string())
+    } else if peer.contains(':') || peer.contains('.') {
+        let has_port = peer
+            .rsplit_once(':')
+            .is_some_and(|(_, port)| port.parse::<u16>().is_ok());
+        Some(if has_port {
+            peer.to_owned()
+        } else {
+            format!("{}:{}", peer, port)
+        })
+    } else {
+        None
+    }
+}
+
+/// Looks for a receiver announced as `name`, for [`DISCOVERY_TIMEOUT`].
+async fn find_peer(name: &str) -> Option<SocketAddr> {
+    let mut browser = DiscoveryBrowser::bind().await.ok()?;
+    let search = async {
+        loop {
+            let peer = browser.next().await.ok()?;
+            if peer.name == name {
+                return Some(peer.addr);
+            }
+        }
+    };
+    tokio::time::timeout(DISCOVERY_TIMEOUT, search)
+        .await
+        .ok()
+        .flatten()
+}
+
+async fn send(peer: &str, files: &[PathBuf], port: u16, chunk_size: Option<usize>) -> ExitCode {
+    let addr = match peer_addr(peer, port) {
+        Some(addr) => addr,
+        // A bare word is a receiver's name, or else a host on the local network.
+        None => match find_peer(peer).await {
+            Some(addr) => addr.to_string(),
+            None => format!("{}:{}", peer, port),
+        },
+    };
+    let mut client = match Client::connect(&addr).await {
+        Ok(client) => client,
+        Err(err) => {
+            eprintln!("{}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+    if let Some(segment_size) = chunk_size {
+        client.set_transfer_config(TransferConfig {
+            segment_size,
+            ..TransferConfig::default()
+        });
+    }
+    for path in files {
+        let file = match File::open(path).await {
+            Ok(file) => file,
+            Err(err) => {
+                eprintln!("could not open {}: {}", path.display(), err);
+                return ExitCode::FAILURE;
+            }
+        };
+        client.queue_file(&file_name(path), file);
+    }
+    client.set_file_completed_callback(|_, file_name| println!("sent {}", file_name));
+
+    let summary = client.run().await;
+    match summary.error {
+        None => {
+            println!(
+                "sent {} files, {} bytes in {:.1?}",
+                summary.files.len(),
+                summary.bytes,
+                summary.duration
+            );
+            ExitCode::SUCCESS
+        }
+        Some(err) => {
+            eprintln!("sending failed after {} bytes: {}", summary.bytes, err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// The name a receiver stores the file at `path` as.
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.display().to_string())
+}
+
+async fn receive(
+    dir: &Path,
+    port: u16,
+    name: Option<String>,
+    chunk_size: Option<usize>,
+    metrics_port: Option<u16>,
+) -> ExitCode {
+    if let Err(err) = tokio::fs::create_dir_all(dir).await {
+        eprintln!("could not create {}: {}", dir.display(), err);
+        return ExitCode::FAILURE;
+    }
+    let mut builder = Server::builder().dest_dir(dir).event_callback(|event| {
+        if let ReceiveEvent::FileReceived { path, bytes } = event {
+            println!("received {} ({} bytes)", path.display(), bytes);
+        }
+    });
+    if let Some(segment_size) = chunk_size {
+        builder = builder.transfer_config(TransferConfig {
+            segment_size,
+            ..TransferConfig::default()
+        });
+    }
+    if let Some(metrics_port) = metrics_port {
+        builder = builder.metrics_addr(SocketAddr::from((Ipv4Addr::UNSPECIFIED, metrics_port)));
+    }
+    let bind_addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, port));
+    let mut server = match builder.bind(bind_addr).await {
+        Ok(server) => server,
+        Err(err) => {
+            eprintln!("could not listen on port {}: {}", port, err);
+            return ExitCode::FAILURE;
+        }
+    };
+    println!("receiving into {} on port {}", dir.display(), port);
+    let shutdown = server.shutdown_handle();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            println!("finishing the transfers in progress, interrupt again to quit right away");
+            shutdown.shutdown();
+        }
+        if tokio::signal::ctrl_c().await.is_ok() {
+            std::process::exit(130);
+        }
+    });
+
+    let name = match name {
+        Some(name) => name,
+        None => {
+            server.run().await;
+            return ExitCode::SUCCESS;
+        }
+    };
+    let announcer = match DiscoveryAnnouncer::bind(&name, port, PeerCapabilities::default()).await {
+        Ok(announcer) => announcer,
+        Err(err) => {
+            eprintln!("could not announce the receiver: {}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+    println!("announced as {:?}", name);
+    tokio::select! {
+        _ = server.run() => ExitCode::SUCCESS,
+        result = announcer.run() => {
+            if let Err(err) = result {
+                eprintln!("announcing the receiver failed: {}", err);
+            }
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn discover(timeout: Duration) -> ExitCode {
+    let mut browser = match DiscoveryBrowser::bind().await {
+        Ok(browser) => browser,
+        Err(err) => {
+            eprintln!("could not look for receivers: {}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut seen = HashSet::new();
+    let listen = async {
+        loop {
+            match browser.next().await {
+                // Only list each receiver once, even if it changes its name.
+                Ok(peer) if seen.insert(peer.addr) => {
+                    println!(
+                        "{}\t{}\tprotocol {}",
+                        peer.name, peer.addr, peer.capabilities.protocol_version
+                    );
+                }
+                Ok(_) => {}
+                Err(err) => return err,
+            }
+        }
+    };
+    if let Ok(err) = tokio::time::timeout(timeout, listen).await {
+        eprintln!("looking for receivers failed: {}", err);
+        return ExitCode::FAILURE;
+    }
+    if seen.is_empty() {
+        eprintln!("no receivers found");
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let level = match (cli.quiet, cli.verbose) {
+        (true, _) => LevelFilter::Error,
+        (false, 0) => LevelFilter::Warn,
+        (false, 1) => LevelFilter::Info,
+        (false, 2) => LevelFilter::Debug,
+        (false, _) => LevelFilter::Trace,
+    };
+    env_logger::Builder::new()
+        .filter_level(level)
+        .parse_default_env()
+        .init();
+
+    match cli.command {
+        Command::Send {
+            peer,
+            files,
+            port,
+            chunk_size,
+        } => send(&peer, &files, port, chunk_size).await,
+        Command::Receive {
+            dir,
+            port,
+            name,
+            chunk_size,
+            metrics_port,
+        } => receive(&dir, port, name, chunk_size, metrics_port).await,
+        Command::Discover { timeout } => discover(Duration::from_secs(timeout)).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_chunk_size, peer_addr, Cli};
+
+    use clap::CommandFactory;
+
+    #[test]
+    fn cli_is_well_formed() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn chunk_sizes_take_suffixes() {
+        assert_eq!(parse_chunk_size("4096"), Ok(4096));
+        assert_eq!(parse_chunk_size("64k"), Ok(64 * 1024));
+        assert_eq!(parse_chunk_size("1M"), Ok(1024 * 1024));
+        assert!(parse_chunk_size("0").is_err());
+        assert!(parse_chunk_size("1G").is_err());
+        assert!(parse_chunk_size("64M").is_err());
+    }
+
+    #[test]
+    fn peers_are_hosts_or_names() {
+        assert_eq!(
+            peer_addr("192.168.1.20", 7369).unwrap(),
+            "192.168.1.20:7369"
+        );
+        assert_eq!(
+            peer_addr("192.168.1.20:80", 7369).unwrap(),
+            "192.168.1.20:80"
+        );
+        assert_eq!(peer_addr("desk.local", 7369).unwrap(), "desk.local:7369");
+        assert_eq!(peer_addr("desk.local:80", 7369).unwrap(), "desk.local:80");
+        assert_eq!(peer_addr("Living room", 7369), None);
+    }
+}
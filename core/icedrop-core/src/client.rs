@@ -1,37 +1,361 @@
+use std::collections::VecDeque;
+#[cfg(feature = "tls")]
+use std::convert::TryFrom;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use icedrop_proto::compression::CompressionMode;
+use icedrop_proto::handshake::{
+    validate_display_name, Capabilities, DisplayNameError, HandshakeRequestFrame,
+};
+use icedrop_proto::relay::{RelayRole, RelayToken};
+use icedrop_proto::text::MAX_TEXT_LEN;
+use icedrop_proto::transfer::{TransferConfig, VerificationMode};
 use tokio::fs::File;
-use tokio::io::Result;
-use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio::net::ToSocketAddrs;
 use tokio::runtime::Handle;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+#[cfg(feature = "tls")]
+use tokio_rustls::rustls::pki_types::ServerName;
+#[cfg(feature = "tls")]
+use tokio_rustls::rustls::ClientConfig;
+#[cfg(feature = "tls")]
+use tokio_rustls::TlsConnector;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::Stream;
+
+use crate::connect::{connect, connect_relay, ConnectError};
+use crate::control::TransferControl;
+#[cfg(any(feature = "tls", feature = "noise"))]
+use crate::endpoint::peer_name;
+use crate::endpoint::{Endpoint, EndpointMiddleware};
+use crate::error::Error;
+use crate::handlers::benchmark::{BenchmarkError, BenchmarkHandler, BenchmarkReport};
+use crate::handlers::file_transfer::{
+    FileTransferEvent, FileTransferNextHandler, MetricsSnapshot, SenderPairing, Stripe,
+    TransferProgress, TransferSummary, DEFAULT_FILE_NAME,
+};
+use crate::handlers::text::TextSendingHandler;
+use crate::history::{HistoryEntry, HistoryStore};
+#[cfg(feature = "noise")]
+use crate::identity::IdentityStore;
+#[cfg(feature = "noise")]
+use crate::noise::{self, ShortAuthString};
+use crate::pairing::PairingStore;
+use crate::proto::PROTOCOL_VERSION;
+#[cfg(feature = "quic")]
+use crate::quic::QuicStream;
+use crate::rate_limit::RateLimiter;
+use crate::share::{self, RemoteEntry};
+#[cfg(feature = "websocket")]
+use crate::websocket;
+
+/// The name a client introduces itself with unless told otherwise.
+const DEFAULT_DISPLAY_NAME: &str = "icedrop";
+
+/// What happens while a client sends files, see [`Client::events`].
+#[derive(Debug, Clone)]
+pub enum TransferEvent {
+    /// The receiver answered the handshake, sending starts.
+    Started,
+    Progress(TransferProgress),
+    /// The receiver confirmed to have written this many segments and bytes of the current file.
+    SegmentAcked {
+        segments_acked: u32,
+        bytes_acked: u64,
+    },
+    /// Every file has been received, always the last event.
+    Completed(TransferSummary),
+    /// The session failed, always the last event.
+    Failed {
+        error: Error,
+        summary: TransferSummary,
+    },
+}
 
-use crate::endpoint::Endpoint;
-use crate::handlers::file_transfer::{FileTransferEvent, FileTransferNextHandler};
-use crate::handlers::handshake::HandshakeRequestFrame;
-use crate::handlers::session::EndSessionHandler;
+/// Where the events of a running session go, taken once it's over so the stream ends even if
+/// tasks of the session are still winding down.
+type EventSender = Arc<Mutex<Option<UnboundedSender<TransferEvent>>>>;
+
+fn send_event(events: &EventSender, event: TransferEvent) {
+    if let Some(events_tx) = &*events.lock().unwrap() {
+        events_tx.send(event).ok();
+    }
+}
+
+/// Completes the summary of a session that failed without the handler knowing why, with what
+/// stopped the endpoint.
+fn summarize_failure(mut summary: TransferSummary, result: Result<(), Error>) -> TransferSummary {
+    if !summary.success && summary.error.is_none() {
+        summary.error = Some(result.err().unwrap_or(Error::Cancelled));
+    }
+    summary
+}
+
+/// What it takes to open another connection to the receiver for a stripe of a file, see
+/// [`TransferConfig::parallel_streams`].
+#[derive(Clone)]
+struct StripeConnection {
+    addr: SocketAddr,
+    display_name: String,
+    verification: VerificationMode,
+    transfer_config: TransferConfig,
+    compression: CompressionMode,
+    stall_timeout: Option<Duration>,
+    abort_on_stall: bool,
+    rate_limiter: RateLimiter,
+    pairing: Option<SenderPairing>,
+    control: TransferControl,
+}
+
+impl StripeConnection {
+    /// Sends `stripe` over a connection of its own, and returns once the receiver got it.
+    async fn send(self, file_name: String, stripe: Stripe) -> Result<(), Error> {
+        let stream = match connect(self.addr).await {
+            Ok(stream) => stream,
+            Err(err) => {
+                println!("could not open another stream: {}", err);
+                return Err(err.into());
+            }
+        };
+        let mut endpoint = Endpoint::new(stream);
+        endpoint.share_rate_limit(self.rate_limiter);
+
+        let mut handler = FileTransferNextHandler::new(endpoint.handle(), VecDeque::new());
+        handler.queue_stripe(&file_name, stripe);
+        handler.set_stall_timeout(self.stall_timeout, self.abort_on_stall);
+        handler.set_verification(self.verification);
+        handler.set_transfer_config(self.transfer_config);
+        handler.set_compression(self.compression);
+        if let Some(pairing) = self.pairing {
+            handler.set_pairing(pairing);
+        }
+        handler.set_control(self.control.watch());
+        let summarize = handler.summarize();
+        endpoint.add_handler(handler);
+        let frame = handshake_request(self.display_name, self.transfer_config, self.compression);
+        send_handshake(&endpoint, frame);
+
+        let result = endpoint.run().await;
+        if let Err(err) = &result {
+            println!("error happened while sending a stripe: {:?}", err);
+        }
+        summarize_failure(summarize(), result)
+            .error
+            .map_or(Ok(()), Err)
+    }
+}
 
 pub struct Client {
-    stream: Option<TcpStream>,
-    file: Option<File>,
+    endpoint: Option<Endpoint>,
+    /// Where further connections for stripes of large files go, unset for TLS, QUIC, WebSocket,
+    /// relayed and end-to-end encrypted connections.
+    peer_addr: Option<SocketAddr>,
+    display_name: String,
+    files: VecDeque<(String, File)>,
     segment_sent_callback: Option<Box<dyn Fn(u32, usize) + Send>>,
+    progress_callback: Option<Box<dyn Fn(TransferProgress) + Send>>,
+    stalled_callback: Option<Box<dyn Fn(Duration) + Send>>,
+    receiver_disk_slow_callback: Option<Box<dyn Fn(Duration) + Send>>,
+    receiver_disk_error_callback: Option<Box<dyn Fn(bool, String) + Send>>,
+    metrics_callback: Option<Box<dyn Fn(MetricsSnapshot) + Send>>,
+    file_complete_callback: Option<Box<dyn Fn(u32, String) + Send>>,
     complete_callback: Option<Box<dyn Fn() + Send>>,
+    events_tx: Option<UnboundedSender<TransferEvent>>,
+    stall_timeout: Option<Duration>,
+    abort_on_stall: bool,
+    metrics_interval: Duration,
+    verification: VerificationMode,
+    transfer_config: TransferConfig,
+    compression: CompressionMode,
+    pairing: Option<SenderPairing>,
+    history: Option<Arc<HistoryStore>>,
+    middlewares: Vec<Box<dyn EndpointMiddleware>>,
+    control: TransferControl,
 }
 
 impl Client {
-    pub async fn connect<A>(addr: A) -> Result<Self>
+    /// Connects to a receiver. On failure the error describes every address that was tried and
+    /// why it failed.
+    pub async fn connect<A>(addr: A) -> Result<Self, ConnectError>
+    where
+        A: ToSocketAddrs,
+    {
+        let stream = connect(addr).await?;
+        let peer_addr = stream.peer_addr().ok();
+        Ok(Self::with_endpoint(Endpoint::new(stream), peer_addr))
+    }
+
+    /// Connects to a receiver through the relay at `addr`, for when they can't connect to each
+    /// other directly. The receiver has to join the session with the same `token`, see
+    /// [`Server::accept_relayed`](crate::Server::accept_relayed).
+    pub async fn connect_relay<A>(addr: A, token: RelayToken) -> io::Result<Self>
+    where
+        A: ToSocketAddrs,
+    {
+        let stream = connect_relay(addr, RelayRole::Sender, token).await?;
+        Ok(Self::with_endpoint(Endpoint::new(stream), None))
+    }
+
+    /// Connects to a receiver and secures the connection with TLS, checking the receiver's
+    /// certificate against `config` and `server_name`.
+    #[cfg(feature = "tls")]
+    pub async fn connect_tls<A>(
+        addr: A,
+        server_name: &str,
+        config: Arc<ClientConfig>,
+    ) -> io::Result<Self>
+    where
+        A: ToSocketAddrs,
+    {
+        let server_name = ServerName::try_from(server_name.to_owned())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        let stream = connect(addr).await?;
+        let peer = peer_name(&stream);
+        let stream = TlsConnector::from(config)
+            .connect(server_name, stream)
+            .await?;
+        Ok(Self::with_endpoint(
+            Endpoint::with_stream(stream, peer),
+            None,
+        ))
+    }
+
+    /// Connects to a receiver and encrypts the connection end to end, see
+    /// [`ServerBuilder::end_to_end_encryption`](crate::ServerBuilder::end_to_end_encryption).
+    /// `on_short_auth` is given the code to show the user, who compares it with the one the
+    /// receiver shows before trusting the connection.
+    #[cfg(feature = "noise")]
+    pub async fn connect_encrypted<A, F>(addr: A, on_short_auth: F) -> io::Result<Self>
+    where
+        A: ToSocketAddrs,
+        F: FnOnce(ShortAuthString),
+    {
+        Self::connect_noise(addr, None, on_short_auth).await
+    }
+
+    /// Connects to a receiver like [`Client::connect_encrypted`], using the key of `identity` and
+    /// checking the receiver's key against the one it used before, see [`IdentityStore`].
+    #[cfg(feature = "noise")]
+    pub async fn connect_encrypted_as<A, F>(
+        addr: A,
+        identity: &IdentityStore,
+        on_short_auth: F,
+    ) -> io::Result<Self>
+    where
+        A: ToSocketAddrs,
+        F: FnOnce(ShortAuthString),
+    {
+        Self::connect_noise(addr, Some(identity), on_short_auth).await
+    }
+
+    #[cfg(feature = "noise")]
+    async fn connect_noise<A, F>(
+        addr: A,
+        identity: Option<&IdentityStore>,
+        on_short_auth: F,
+    ) -> io::Result<Self>
     where
         A: ToSocketAddrs,
+        F: FnOnce(ShortAuthString),
     {
-        let stream = TcpStream::connect(addr).await?;
+        let stream = connect(addr).await?;
+        let peer = peer_name(&stream);
+        let peer_ip = stream.peer_addr()?.ip();
+        let (stream, short_auth_string, peer_key, peer_device_id) =
+            noise::connect(stream, identity).await?;
+        if let Some(identity) = identity {
+            identity.verify_peer(peer_device_id.as_ref(), &peer_key, peer_ip)?;
+        }
+        on_short_auth(short_auth_string);
+        Ok(Self::with_endpoint(
+            Endpoint::with_stream(stream, peer),
+            None,
+        ))
+    }
 
-        Ok(Self {
-            stream: Some(stream),
-            file: None,
+    /// Connects to a receiver over QUIC, checking the receiver's certificate against `config` and
+    /// `server_name`, see [`Server::bind_quic`](crate::Server::bind_quic).
+    #[cfg(feature = "quic")]
+    pub async fn connect_quic<A>(
+        addr: A,
+        server_name: &str,
+        config: quinn::ClientConfig,
+    ) -> io::Result<Self>
+    where
+        A: ToSocketAddrs,
+    {
+        let addr = tokio::net::lookup_host(addr)
+            .await?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address to connect to"))?;
+        let stream = QuicStream::connect(addr, server_name, config).await?;
+        let peer = stream.remote_address().to_string();
+        Ok(Self::with_endpoint(
+            Endpoint::with_stream(stream, peer),
+            None,
+        ))
+    }
+
+    /// Connects to a receiver at a WebSocket `url`, e.g. `ws://192.168.1.20:7370/`, see
+    /// [`Server::bind_ws`](crate::Server::bind_ws).
+    #[cfg(feature = "websocket")]
+    pub async fn connect_ws(url: &str) -> io::Result<Self> {
+        let stream = websocket::connect(url).await?;
+        Ok(Self::with_endpoint(
+            Endpoint::with_stream(stream, url.to_owned()),
+            None,
+        ))
+    }
+
+    pub(crate) fn with_endpoint(endpoint: Endpoint, peer_addr: Option<SocketAddr>) -> Self {
+        Self {
+            endpoint: Some(endpoint),
+            peer_addr,
+            display_name: DEFAULT_DISPLAY_NAME.to_owned(),
+            files: VecDeque::new(),
             segment_sent_callback: None,
+            progress_callback: None,
+            stalled_callback: None,
+            receiver_disk_slow_callback: None,
+            receiver_disk_error_callback: None,
+            metrics_callback: None,
+            file_complete_callback: None,
             complete_callback: None,
-        })
+            events_tx: None,
+            stall_timeout: Some(Duration::from_secs(30)),
+            abort_on_stall: false,
+            metrics_interval: Duration::from_secs(1),
+            verification: VerificationMode::default(),
+            transfer_config: TransferConfig::default(),
+            compression: CompressionMode::default(),
+            pairing: None,
+            history: None,
+            middlewares: Vec::new(),
+            control: TransferControl::new(),
+        }
     }
 
+    /// Sets the name shown to the receiver. Surrounding whitespace is removed, and names that
+    /// are empty, too long or contain control characters are rejected.
+    pub fn set_display_name(&mut self, name: &str) -> Result<(), DisplayNameError> {
+        self.display_name = validate_display_name(name)?;
+        Ok(())
+    }
+
+    /// Sets the only file to send, replacing any queued ones.
     pub fn set_file(&mut self, file: File) {
-        self.file = Some(file);
+        self.files.clear();
+        self.queue_file(DEFAULT_FILE_NAME, file);
+    }
+
+    /// Adds a file to send after the ones queued before, the receiver stores it as `file_name`.
+    /// Receivers speaking a protocol version older than 4 only take the first file.
+    pub fn queue_file(&mut self, file_name: &str, file: File) {
+        self.files.push_back((file_name.to_owned(), file));
     }
 
     pub fn set_segment_sent_callback<F>(&mut self, f: F)
@@ -41,6 +365,152 @@ impl Client {
         self.segment_sent_callback = Some(Box::new(f));
     }
 
+    /// Sets the callback receiving the progress of the file being sent whenever it changes.
+    /// Progress shown to users should be based on the bytes the receiver confirmed.
+    pub fn set_progress_callback<F>(&mut self, f: F)
+    where
+        F: Fn(TransferProgress) + Send + 'static,
+    {
+        self.progress_callback = Some(Box::new(f));
+    }
+
+    /// Sets the callback invoked when the receiver stops acking for longer than the stall
+    /// timeout, with the time elapsed since the last ack.
+    pub fn set_stalled_callback<F>(&mut self, f: F)
+    where
+        F: Fn(Duration) + Send + 'static,
+    {
+        self.stalled_callback = Some(Box::new(f));
+    }
+
+    /// Sets the callback invoked when the receiver reports its disk can't keep up with the
+    /// network, with the time it takes to write a segment. Sending is paced accordingly.
+    pub fn set_receiver_disk_slow_callback<F>(&mut self, f: F)
+    where
+        F: Fn(Duration) + Send + 'static,
+    {
+        self.receiver_disk_slow_callback = Some(Box::new(f));
+    }
+
+    /// Sets the callback invoked when the receiver aborts the transfer because it couldn't write
+    /// the file, with whether sending it again later may succeed and the receiver's error message.
+    pub fn set_receiver_disk_error_callback<F>(&mut self, f: F)
+    where
+        F: Fn(bool, String) + Send + 'static,
+    {
+        self.receiver_disk_error_callback = Some(Box::new(f));
+    }
+
+    /// Sets the callback periodically receiving [`MetricsSnapshot`]s of the running transfer, see
+    /// [`Client::set_metrics_interval`].
+    pub fn set_metrics_callback<F>(&mut self, f: F)
+    where
+        F: Fn(MetricsSnapshot) + Send + 'static,
+    {
+        self.metrics_callback = Some(Box::new(f));
+    }
+
+    /// Sets how often the metrics callback is invoked (every second by default).
+    pub fn set_metrics_interval(&mut self, interval: Duration) {
+        self.metrics_interval = interval;
+    }
+
+    /// Sets how long the receiver may go without acking before the transfer is considered
+    /// stalled (30 seconds by default), `None` disables the check.
+    pub fn set_stall_timeout(&mut self, timeout: Option<Duration>) {
+        self.stall_timeout = timeout;
+    }
+
+    /// Sets how often the receiver is pinged while the connection is idle and how long it may stay
+    /// silent before the connection is given up on, see
+    /// [`Endpoint::set_keepalive`](crate::endpoint::Endpoint::set_keepalive).
+    pub fn set_keepalive(&mut self, interval: Option<Duration>, timeout: Duration) {
+        if let Some(endpoint) = &mut self.endpoint {
+            endpoint.set_keepalive(interval, timeout);
+        }
+    }
+
+    /// Caps the bytes per second sent to and received from the receiver, `None` for no limit (the
+    /// default). Panics if the limit is zero.
+    pub fn set_rate_limit(&mut self, bytes_per_sec: Option<u64>) {
+        if let Some(endpoint) = &mut self.endpoint {
+            endpoint.set_rate_limit(bytes_per_sec);
+        }
+    }
+
+    /// Whether to give up on the transfer once it's stalled, instead of only reporting it.
+    pub fn set_abort_on_stall(&mut self, abort: bool) {
+        self.abort_on_stall = abort;
+    }
+
+    /// Sets how the transfer is checked for corruption ([`VerificationMode::Segments`] by
+    /// default). Receivers speaking an older protocol version get the file unverified.
+    pub fn set_verification(&mut self, mode: VerificationMode) {
+        self.verification = mode;
+    }
+
+    /// Sets the segment size, ack window and number of parallel streams to ask the receiver for.
+    /// The receiver may settle on smaller ones, see [`TransferConfig`]. Files are only sent over
+    /// several streams without TLS or end-to-end encryption. Panics if the config isn't valid.
+    pub fn set_transfer_config(&mut self, config: TransferConfig) {
+        config.assert_valid();
+        self.transfer_config = config;
+    }
+
+    /// Sets how file segments are compressed on the wire ([`CompressionMode::None`] by default).
+    /// Segments that don't compress well are sent as they are, and receivers speaking an older
+    /// protocol version get them uncompressed.
+    pub fn set_compression(&mut self, mode: CompressionMode) {
+        self.compression = mode;
+    }
+
+    /// Pairs with receivers requiring it, see
+    /// [`ServerBuilder::pairing`](crate::ServerBuilder::pairing), keeping the keys in `store`.
+    /// The first time, `prompt` is called on a blocking thread to ask the user for the code the
+    /// receiver shows, or `None` to give up. Receivers requiring pairing are given up on without
+    /// it.
+    pub fn set_pairing<F>(&mut self, store: Arc<PairingStore>, prompt: F)
+    where
+        F: Fn() -> Option<String> + Send + Sync + 'static,
+    {
+        self.pairing = Some(SenderPairing {
+            store,
+            prompt: Arc::new(prompt),
+        });
+    }
+
+    /// Records every file sent, or failed to be, in `history`.
+    pub fn set_history(&mut self, history: Arc<HistoryStore>) {
+        self.history = Some(history);
+    }
+
+    /// The files sent so far, oldest first, if they're recorded, see
+    /// [`set_history`](Self::set_history).
+    pub fn history(&self) -> io::Result<Vec<HistoryEntry>> {
+        match &self.history {
+            Some(history) => history.entries(),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Adds a middleware seeing every frame exchanged with the receiver, see
+    /// [`EndpointMiddleware`].
+    pub fn add_middleware<M>(&mut self, middleware: M)
+    where
+        M: EndpointMiddleware + 'static,
+    {
+        self.middlewares.push(Box::new(middleware));
+    }
+
+    /// Sets the callback invoked whenever the receiver has written one of the files, with its
+    /// position in the queue and its name.
+    pub fn set_file_completed_callback<F>(&mut self, f: F)
+    where
+        F: Fn(u32, String) + Send + 'static,
+    {
+        self.file_complete_callback = Some(Box::new(f));
+    }
+
     pub fn set_completed_callback<F>(&mut self, f: F)
     where
         F: Fn() + Send + 'static,
@@ -48,53 +518,320 @@ impl Client {
         self.complete_callback = Some(Box::new(f));
     }
 
-    pub async fn run(&mut self) {
-        let mut endpoint = Endpoint::new(self.stream.take().unwrap());
+    /// Returns a stream of what happens while [`run`](Self::run) sends the files, as an
+    /// alternative to the callbacks. It ends with [`TransferEvent::Completed`] or
+    /// [`TransferEvent::Failed`] once the session is over. Events are buffered until they're
+    /// taken, so the stream may be polled from another task. Only the stream returned last gets
+    /// the events.
+    pub fn events(&mut self) -> impl Stream<Item = TransferEvent> + Unpin {
+        let (events_tx, events_rx) = unbounded_channel();
+        self.events_tx = Some(events_tx);
+        UnboundedReceiverStream::new(events_rx)
+    }
+
+    /// A handle to pause, resume or cancel sending while [`run`](Self::run) is awaited elsewhere.
+    pub fn control(&self) -> TransferControl {
+        self.control.clone()
+    }
+
+    /// Has the client follow `control` instead of its own one, e.g. one handed out before the
+    /// client was connected. Must be called before [`run`](Self::run).
+    pub fn set_control(&mut self, control: TransferControl) {
+        self.control = control;
+    }
+
+    /// Sends the files and returns a summary of the session once it's over, successful or not.
+    pub async fn run(&mut self) -> TransferSummary {
+        let mut endpoint = self.endpoint.take().unwrap();
+        for middleware in self.middlewares.drain(..) {
+            endpoint.add_middleware(middleware);
+        }
 
-        let file = self.file.take().unwrap();
-        let mut file_transfer_next_handler = FileTransferNextHandler::new(endpoint.handle(), file);
+        let files = std::mem::take(&mut self.files);
+        let mut file_transfer_next_handler = FileTransferNextHandler::new(endpoint.handle(), files);
+        file_transfer_next_handler.set_stall_timeout(self.stall_timeout, self.abort_on_stall);
+        file_transfer_next_handler.set_verification(self.verification);
+        file_transfer_next_handler.set_transfer_config(self.transfer_config);
+        file_transfer_next_handler.set_compression(self.compression);
+        if let Some(pairing) = self.pairing.clone() {
+            file_transfer_next_handler.set_pairing(pairing);
+        }
+        file_transfer_next_handler.set_control(self.control.watch());
+        if self.metrics_callback.is_some() {
+            file_transfer_next_handler.set_metrics_interval(Some(self.metrics_interval));
+        }
         let segment_sent_callback = self.segment_sent_callback.take();
+        let progress_callback = self.progress_callback.take();
+        let stalled_callback = self.stalled_callback.take();
+        let receiver_disk_slow_callback = self.receiver_disk_slow_callback.take();
+        let receiver_disk_error_callback = self.receiver_disk_error_callback.take();
+        let metrics_callback = self.metrics_callback.take();
+        let file_complete_callback = self.file_complete_callback.take();
         let complete_callback = self.complete_callback.take();
-        if segment_sent_callback.is_some() || complete_callback.is_some() {
+        let events: EventSender = Arc::new(Mutex::new(self.events_tx.take()));
+        let events_clone = Arc::clone(&events);
+        if segment_sent_callback.is_some()
+            || progress_callback.is_some()
+            || stalled_callback.is_some()
+            || receiver_disk_slow_callback.is_some()
+            || receiver_disk_error_callback.is_some()
+            || metrics_callback.is_some()
+            || file_complete_callback.is_some()
+            || complete_callback.is_some()
+            || events.lock().unwrap().is_some()
+        {
+            let events = events_clone;
             file_transfer_next_handler.set_callback_fn(move |event| match event {
+                FileTransferEvent::Started => send_event(&events, TransferEvent::Started),
                 FileTransferEvent::SegmentSent(segment_idx, bytes_sent) => {
+                    send_event(
+                        &events,
+                        TransferEvent::SegmentAcked {
+                            segments_acked: segment_idx,
+                            bytes_acked: bytes_sent as u64,
+                        },
+                    );
                     if let Some(cb) = &segment_sent_callback {
-                        cb.call((segment_idx, bytes_sent));
+                        cb(segment_idx, bytes_sent);
+                    }
+                }
+                FileTransferEvent::Progress(progress) => {
+                    send_event(&events, TransferEvent::Progress(progress));
+                    if let Some(cb) = &progress_callback {
+                        cb(progress);
+                    }
+                }
+                FileTransferEvent::Stalled(since_last_ack) => {
+                    if let Some(cb) = &stalled_callback {
+                        cb(since_last_ack);
+                    }
+                }
+                FileTransferEvent::ReceiverDiskSlow(write_latency) => {
+                    if let Some(cb) = &receiver_disk_slow_callback {
+                        cb(write_latency);
+                    }
+                }
+                FileTransferEvent::ReceiverDiskError { retryable, message } => {
+                    if let Some(cb) = &receiver_disk_error_callback {
+                        cb(retryable, message);
+                    }
+                }
+                FileTransferEvent::MetricsSnapshot(snapshot) => {
+                    if let Some(cb) = &metrics_callback {
+                        cb(snapshot);
+                    }
+                }
+                FileTransferEvent::FileComplete {
+                    transfer_id,
+                    file_name,
+                } => {
+                    if let Some(cb) = &file_complete_callback {
+                        cb(transfer_id, file_name);
                     }
                 }
                 FileTransferEvent::Complete => {
                     if let Some(cb) = &complete_callback {
-                        cb.call(());
+                        cb();
                     }
                 }
             });
         }
+        if let Some(addr) = self.peer_addr {
+            let connection = StripeConnection {
+                addr,
+                display_name: self.display_name.clone(),
+                verification: self.verification,
+                transfer_config: self.transfer_config,
+                compression: self.compression,
+                stall_timeout: self.stall_timeout,
+                abort_on_stall: self.abort_on_stall,
+                rate_limiter: endpoint.rate_limiter(),
+                pairing: self.pairing.clone(),
+                control: self.control.clone(),
+            };
+            file_transfer_next_handler.set_stripe_sender(Arc::new(move |file_name, stripe| {
+                Handle::current().spawn(connection.clone().send(file_name, stripe))
+            }));
+        }
+        let summarize = file_transfer_next_handler.summarize();
+        let history_entries = file_transfer_next_handler.history();
+        let peer = endpoint.peer().to_owned();
         endpoint.add_handler(file_transfer_next_handler);
+        self.send_handshake(&endpoint);
 
-        endpoint.add_handler(EndSessionHandler::new(endpoint.handle()));
-
-        let endpoint_handle = endpoint.handle();
-        Handle::current().spawn(async move {
-            let frame = HandshakeRequestFrame {
-                name: "test".to_owned(),
+        let result = endpoint.run().await;
+        if let Err(err) = &result {
+            println!("error happened while talking to server: {:?}", err);
+        }
+        let summary = summarize_failure(summarize(), result);
+        if let Some(history) = &self.history {
+            for entry in history_entries(&peer, summary.error.as_ref()) {
+                history.record_or_log(entry);
+            }
+        }
+        if let Some(events_tx) = events.lock().unwrap().take() {
+            let event = match summary.error.clone() {
+                None => TransferEvent::Completed(summary.clone()),
+                Some(error) => TransferEvent::Failed {
+                    error,
+                    summary: summary.clone(),
+                },
             };
-            endpoint_handle.send_frame(frame).await.unwrap();
-        });
+            events_tx.send(event).ok();
+        }
+        summary
+    }
+
+    /// Streams generated data to the receiver for `duration` instead of sending files, and
+    /// reports the throughput, round-trip times and lost frames measured. Only receivers that
+    /// accept benchmarks take part, see
+    /// [`ServerBuilder::accept_benchmarks`](crate::ServerBuilder::accept_benchmarks).
+    pub async fn bench(&mut self, duration: Duration) -> Result<BenchmarkReport, BenchmarkError> {
+        let mut endpoint = self.endpoint.take().unwrap();
+        for middleware in self.middlewares.drain(..) {
+            endpoint.add_middleware(middleware);
+        }
+
+        let outcome = Arc::new(Mutex::new(None));
+        let handler = BenchmarkHandler::new(endpoint.handle(), duration, Arc::clone(&outcome));
+        endpoint.add_handler(handler);
+        self.send_handshake(&endpoint);
+
+        let result = endpoint.run().await.map_err(|err| err.to_string());
+        let outcome = outcome.lock().unwrap().take();
+        match (outcome, result) {
+            (Some(outcome), _) => outcome,
+            (None, Err(err)) => Err(BenchmarkError::Disconnected(err)),
+            (None, Ok(())) => Err(BenchmarkError::Disconnected(
+                "connection closed by the receiver".to_owned(),
+            )),
+        }
+    }
+
+    /// Lists what's in the folder at `path` the receiver shares, like `photos/2024`, or the roots
+    /// it shares for an empty path, see [`SharedRoots`](crate::SharedRoots). The receiver lets
+    /// this client in like a sender first, by its display name and pairing. Like sending files,
+    /// this uses up the connection.
+    pub async fn list_remote(&mut self, path: &str) -> Result<Vec<RemoteEntry>, Error> {
+        let mut endpoint = self.endpoint.take().unwrap();
+        for middleware in self.middlewares.drain(..) {
+            endpoint.add_middleware(middleware);
+        }
+        let handshake = self.handshake_request();
+        share::list_remote(endpoint, path, handshake, self.pairing.clone()).await
+    }
+
+    /// Pulls the file at `path` the receiver shares, like `photos/beach.jpg`, see
+    /// [`SharedRoots`](crate::SharedRoots), into `dest_dir`. Returns where the file has been
+    /// written to. The receiver lets this client in like a sender first, by its display name and
+    /// pairing. Like sending files, this uses up the connection.
+    pub async fn pull<P>(&mut self, path: &str, dest_dir: P) -> Result<PathBuf, Error>
+    where
+        P: AsRef<Path>,
+    {
+        let mut endpoint = self.endpoint.take().unwrap();
+        for middleware in self.middlewares.drain(..) {
+            endpoint.add_middleware(middleware);
+        }
+        let handshake = self.handshake_request();
+        share::pull_file(endpoint, path, dest_dir, handshake, self.pairing.clone()).await
+    }
+
+    /// Sends `text`, e.g. what's on the clipboard, instead of files, and returns once the
+    /// receiver took it. Only receivers whose delegate takes text do, see
+    /// [`ServerDelegate::text_received`](crate::ServerDelegate::text_received). Like sending
+    /// files, this uses up the connection.
+    pub async fn send_text(&mut self, text: &str) -> Result<(), Error> {
+        if text.len() > MAX_TEXT_LEN {
+            return Err(Error::Protocol(format!(
+                "text is longer than {} bytes",
+                MAX_TEXT_LEN
+            )));
+        }
+        let mut endpoint = self.endpoint.take().unwrap();
+        for middleware in self.middlewares.drain(..) {
+            endpoint.add_middleware(middleware);
+        }
+
+        let outcome = Arc::new(Mutex::new(None));
+        let handler = TextSendingHandler::new(
+            endpoint.handle(),
+            text.to_owned(),
+            self.pairing.clone(),
+            Arc::clone(&outcome),
+        );
+        endpoint.add_handler(handler);
+        self.send_handshake(&endpoint);
 
         let result = endpoint.run().await;
-        if let Some(err) = result.err() {
-            println!("error happened while talking to server: {:?}", err);
+        let outcome = outcome.lock().unwrap().take();
+        match (outcome, result) {
+            (Some(outcome), _) => outcome,
+            (None, Err(err)) => Err(err),
+            (None, Ok(())) => Err(Error::Protocol(
+                "the receiver closed the session before taking the text".to_owned(),
+            )),
+        }
+    }
+
+    fn send_handshake(&self, endpoint: &Endpoint) {
+        let frame = self.handshake_request();
+        send_handshake(endpoint, frame);
+    }
+
+    /// The handshake request introducing this client.
+    fn handshake_request(&self) -> HandshakeRequestFrame {
+        let mut transfer_config = self.transfer_config;
+        if self.peer_addr.is_none() {
+            // There's no opening more connections to the receiver.
+            transfer_config.parallel_streams = 1;
         }
+        handshake_request(self.display_name.clone(), transfer_config, self.compression)
     }
 }
 
+fn handshake_request(
+    display_name: String,
+    transfer_config: TransferConfig,
+    compression: CompressionMode,
+) -> HandshakeRequestFrame {
+    HandshakeRequestFrame {
+        name: display_name,
+        protocol_version: PROTOCOL_VERSION,
+        transfer_config: Some(transfer_config),
+        compression,
+        capabilities: Capabilities::all(),
+    }
+}
+
+fn send_handshake(endpoint: &Endpoint, frame: HandshakeRequestFrame) {
+    let endpoint_handle = endpoint.handle();
+    Handle::current().spawn(async move {
+        endpoint_handle.send_frame(frame).await.unwrap();
+    });
+}
+
 #[cfg(test)]
 mod tests {
-    use super::Client;
+    use super::{Client, TransferEvent};
+    use crate::endpoint::{Endpoint, EndpointMiddleware};
+    use crate::handlers::benchmark::BenchmarkError;
+    use crate::handlers::file_transfer::FileTransferReceivingHandler;
+    use crate::history::{HistoryStore, TransferDirection, TransferStatus};
+    use crate::server::Server;
+
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant, SystemTime};
 
+    use icedrop_proto::compression::CompressionMode;
+    use icedrop_proto::frame_types;
+    use icedrop_proto::transfer::{TransferConfig, VerificationMode};
+    use icedrop_relay::Relay;
     use tokio::fs::File;
     use tokio::io::Result;
+    use tokio::net::TcpListener;
     use tokio::runtime::Runtime;
+    use tokio_stream::StreamExt;
 
     #[test]
     fn simple_test() {
@@ -121,4 +858,544 @@ mod tests {
         })
         .unwrap();
     }
+
+    #[test]
+    fn bench_needs_a_consenting_receiver() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            for accept_benchmarks in [true, false] {
+                let mut server = Server::builder()
+                    .accept_benchmarks(accept_benchmarks)
+                    .bind("127.0.0.1:0")
+                    .await
+                    .unwrap();
+                let addr = server.local_addr().unwrap();
+                let server_task = tokio::spawn(async move { server.run().await });
+
+                let mut client = Client::connect(addr).await.unwrap();
+                let result = client.bench(Duration::from_millis(100)).await;
+                if accept_benchmarks {
+                    let report = result.unwrap();
+                    assert!(report.frames_sent > 0);
+                    assert_eq!(report.frames_lost, 0);
+                    assert_eq!(report.bytes_received, report.bytes_sent);
+                    assert!(report.throughput > 0.0);
+                    assert!(report.rtt_min <= report.rtt_max);
+                } else {
+                    assert!(matches!(result, Err(BenchmarkError::Refused(_))));
+                }
+                server_task.abort();
+            }
+        });
+    }
+
+    #[test]
+    fn several_files_are_sent_in_one_session() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let dir = std::env::temp_dir().join(format!("icedrop-test-{}", std::process::id()));
+            std::fs::create_dir_all(dir.join("out")).unwrap();
+            let contents: [&[u8]; 3] = [b"first file", b"", &[7; 300_000]];
+            for (i, data) in contents.iter().enumerate() {
+                std::fs::write(dir.join(i.to_string()), data).unwrap();
+            }
+            let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1700724864);
+            let first_file = std::fs::File::options().write(true).open(dir.join("0"));
+            first_file.unwrap().set_modified(modified).unwrap();
+            // Received files don't replace existing ones.
+            std::fs::write(dir.join("out").join("received-0"), b"existing").unwrap();
+
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let out_dir = dir.join("out");
+            let server = tokio::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut endpoint = Endpoint::new(stream);
+                let handle = endpoint.handle();
+                endpoint.add_handler(FileTransferReceivingHandler::new(handle, out_dir));
+                endpoint.run().await.map_err(|err| err.to_string())
+            });
+
+            let mut client = Client::connect(addr).await.unwrap();
+            for i in 0..contents.len() {
+                let file = File::open(dir.join(i.to_string())).await.unwrap();
+                client.queue_file(&format!("received-{}", i), file);
+            }
+            let completed = Arc::new(Mutex::new(Vec::new()));
+            let completed_clone = Arc::clone(&completed);
+            client.set_file_completed_callback(move |transfer_id, file_name| {
+                completed_clone
+                    .lock()
+                    .unwrap()
+                    .push((transfer_id, file_name));
+            });
+            let summary = client.run().await;
+            server.await.unwrap().unwrap();
+            assert!(summary.success);
+            assert_eq!(summary.files.len(), contents.len());
+            assert_eq!(summary.bytes, 300_010);
+
+            let completed = completed.lock().unwrap();
+            assert_eq!(completed.len(), contents.len());
+            for i in 0..contents.len() {
+                assert_eq!(completed[i], (i as u32, format!("received-{}", i)));
+            }
+            let received_names = ["received-0 (1)", "received-1", "received-2"];
+            for (data, received_name) in contents.iter().zip(received_names.iter()) {
+                let received = std::fs::read(dir.join("out").join(received_name)).unwrap();
+                assert_eq!(&received, data);
+            }
+            let received_modified = std::fs::metadata(dir.join("out").join("received-0 (1)"))
+                .and_then(|metadata| metadata.modified())
+                .unwrap();
+            assert_eq!(received_modified, modified);
+            std::fs::remove_dir_all(dir).unwrap();
+        });
+    }
+
+    #[test]
+    fn events_tell_how_the_session_went() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let dir = std::env::temp_dir().join(format!("icedrop-events-{}", std::process::id()));
+            std::fs::create_dir_all(dir.join("out")).unwrap();
+            std::fs::write(dir.join("data"), vec![5; 100_000]).unwrap();
+
+            let mut server = Server::builder()
+                .dest_dir(dir.join("out"))
+                .bind("127.0.0.1:0")
+                .await
+                .unwrap();
+            let addr = server.local_addr().unwrap();
+            let server_task = tokio::spawn(async move { server.run().await });
+
+            // Small segments, so the receiver acks some before the end of the file.
+            let mut client = Client::connect(addr).await.unwrap();
+            client.set_transfer_config(TransferConfig {
+                segment_size: 4096,
+                ..TransferConfig::default()
+            });
+            client.queue_file("data", File::open(dir.join("data")).await.unwrap());
+            let mut events = client.events();
+            let client_task = tokio::spawn(async move { client.run().await });
+            let mut received = Vec::new();
+            while let Some(event) = events.next().await {
+                received.push(event);
+            }
+            assert!(client_task.await.unwrap().success);
+            server_task.abort();
+
+            assert!(matches!(received.first(), Some(TransferEvent::Started)));
+            assert!(received
+                .iter()
+                .any(|event| matches!(event, TransferEvent::Progress(_))));
+            assert!(received.iter().any(|event| matches!(
+                event,
+                TransferEvent::SegmentAcked { bytes_acked, .. } if *bytes_acked > 0
+            )));
+            assert!(matches!(
+                received.last(),
+                Some(TransferEvent::Completed(summary)) if summary.bytes == 100_000
+            ));
+            std::fs::remove_dir_all(dir).unwrap();
+        });
+    }
+
+    #[test]
+    fn rate_limit_slows_transfers_down() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let dir = std::env::temp_dir().join(format!("icedrop-limit-{}", std::process::id()));
+            std::fs::create_dir_all(dir.join("out")).unwrap();
+            std::fs::write(dir.join("data"), vec![3; 20_000]).unwrap();
+
+            let mut server = Server::builder()
+                .dest_dir(dir.join("out"))
+                .bind("127.0.0.1:0")
+                .await
+                .unwrap();
+            let addr = server.local_addr().unwrap();
+            let server_task = tokio::spawn(async move { server.run().await });
+
+            // A quarter of a second's worth goes out right away, the rest at 40 KB/s.
+            let mut client = Client::connect(addr).await.unwrap();
+            client.set_rate_limit(Some(40_000));
+            client.queue_file("data", File::open(dir.join("data")).await.unwrap());
+            let started = Instant::now();
+            assert!(client.run().await.success);
+            assert!(started.elapsed() >= Duration::from_millis(250));
+            server_task.abort();
+
+            let received = std::fs::read(dir.join("out").join("data")).unwrap();
+            assert_eq!(received.len(), 20_000);
+            std::fs::remove_dir_all(dir).unwrap();
+        });
+    }
+
+    /// Counts the bytes of the data frames going out.
+    struct DataBytes(Arc<Mutex<usize>>);
+
+    impl EndpointMiddleware for DataBytes {
+        fn on_outgoing(&self, frame_type: u16, payload: &mut Vec<u8>) {
+            if frame_type == frame_types::FILE_TRANSFER_DATA
+                || frame_type == frame_types::FILE_TRANSFER_COMPRESSED_DATA
+            {
+                *self.0.lock().unwrap() += payload.len();
+            }
+        }
+    }
+
+    #[test]
+    fn compressed_segments_are_restored_by_the_receiver() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let dir = std::env::temp_dir().join(format!("icedrop-zstd-{}", std::process::id()));
+            std::fs::create_dir_all(dir.join("out")).unwrap();
+            let text = "2023-11-23 08:14:24 INFO segment written\n".repeat(20_000);
+            std::fs::write(dir.join("log.txt"), &text).unwrap();
+
+            let mut server = Server::builder()
+                .dest_dir(dir.join("out"))
+                .bind("127.0.0.1:0")
+                .await
+                .unwrap();
+            let addr = server.local_addr().unwrap();
+            let server_task = tokio::spawn(async move { server.run().await });
+
+            let data_bytes = Arc::new(Mutex::new(0));
+            let mut client = Client::connect(addr).await.unwrap();
+            client.set_compression(CompressionMode::Zstd);
+            client.add_middleware(DataBytes(Arc::clone(&data_bytes)));
+            client.queue_file("log.txt", File::open(dir.join("log.txt")).await.unwrap());
+            let summary = client.run().await;
+            server_task.abort();
+            assert!(summary.success);
+            assert_eq!(summary.bytes, text.len() as u64);
+            assert!(*data_bytes.lock().unwrap() < text.len() / 10);
+
+            let received = std::fs::read(dir.join("out").join("log.txt")).unwrap();
+            assert_eq!(received, text.as_bytes());
+            std::fs::remove_dir_all(dir).unwrap();
+        });
+    }
+
+    #[test]
+    fn files_are_sent_through_a_relay() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let dir = std::env::temp_dir().join(format!("icedrop-relay-{}", std::process::id()));
+            std::fs::create_dir_all(dir.join("out")).unwrap();
+            std::fs::write(dir.join("plain"), b"sent through a relay").unwrap();
+
+            let mut relay = Relay::bind("127.0.0.1:0").await.unwrap();
+            let relay_addr = relay.local_addr().unwrap();
+            let relay_task = tokio::spawn(async move { relay.run().await });
+
+            let server = Server::builder()
+                .dest_dir(dir.join("out"))
+                .bind("127.0.0.1:0")
+                .await
+                .unwrap();
+            let token = [7; 16];
+            server.accept_relayed(relay_addr, token).await.unwrap();
+
+            let mut client = Client::connect_relay(relay_addr, token).await.unwrap();
+            let file = File::open(dir.join("plain")).await.unwrap();
+            client.queue_file("received", file);
+            assert!(client.run().await.success);
+            relay_task.abort();
+
+            let received = std::fs::read(dir.join("out").join("received")).unwrap();
+            assert_eq!(received, b"sent through a relay");
+            std::fs::remove_dir_all(dir).unwrap();
+        });
+    }
+
+    #[test]
+    fn transfers_are_recorded_in_history() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let dir = std::env::temp_dir().join(format!("icedrop-history-{}", std::process::id()));
+            std::fs::create_dir_all(dir.join("out")).unwrap();
+            std::fs::write(dir.join("a"), b"first file").unwrap();
+            std::fs::write(dir.join("b"), b"second file").unwrap();
+            let sent = Arc::new(HistoryStore::open(dir.join("sent.jsonl")).unwrap());
+            let received = Arc::new(HistoryStore::open(dir.join("received.jsonl")).unwrap());
+
+            let mut server = Server::builder()
+                .dest_dir(dir.join("out"))
+                .history(Arc::clone(&received))
+                .bind("127.0.0.1:0")
+                .await
+                .unwrap();
+            let addr = server.local_addr().unwrap();
+            let server_task = tokio::spawn(async move { server.run().await });
+
+            let mut client = Client::connect(addr).await.unwrap();
+            client.set_history(Arc::clone(&sent));
+            client.set_verification(VerificationMode::Full);
+            client.queue_file("a.txt", File::open(dir.join("a")).await.unwrap());
+            client.queue_file("b.txt", File::open(dir.join("b")).await.unwrap());
+            assert!(client.run().await.success);
+            server_task.abort();
+
+            let entries = client.history().unwrap();
+            let file_names: Vec<_> = entries
+                .iter()
+                .map(|entry| entry.file_name.as_str())
+                .collect();
+            assert_eq!(file_names, ["a.txt", "b.txt"]);
+            assert_eq!(entries[1].direction, TransferDirection::Sent);
+            assert_eq!(entries[1].peer, addr.to_string());
+            assert_eq!(entries[1].size, 11);
+            assert_eq!(entries[1].status, TransferStatus::Completed);
+            assert!(entries[1].sha256.is_some());
+
+            let received_entries = received.entries().unwrap();
+            assert_eq!(received_entries.len(), 2);
+            assert_eq!(received_entries[1].direction, TransferDirection::Received);
+            assert_eq!(received_entries[1].file_name, "b.txt");
+            assert_eq!(received_entries[1].sha256, entries[1].sha256);
+
+            // Nothing can be written into a directory that doesn't exist.
+            let mut server = Server::builder()
+                .dest_dir(dir.join("missing"))
+                .history(Arc::clone(&received))
+                .bind("127.0.0.1:0")
+                .await
+                .unwrap();
+            let addr = server.local_addr().unwrap();
+            let server_task = tokio::spawn(async move { server.run().await });
+
+            let mut client = Client::connect(addr).await.unwrap();
+            client.set_history(Arc::clone(&sent));
+            client.queue_file("c.txt", File::open(dir.join("a")).await.unwrap());
+            assert!(!client.run().await.success);
+            server_task.abort();
+
+            let failed = sent.recent(1).unwrap().remove(0);
+            assert_eq!(failed.file_name, "c.txt");
+            assert!(matches!(failed.status, TransferStatus::Failed(_)));
+            let failed = received.recent(1).unwrap().remove(0);
+            assert_eq!(failed.file_name, "c.txt");
+            assert!(matches!(failed.status, TransferStatus::Failed(_)));
+            std::fs::remove_dir_all(dir).unwrap();
+        });
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn files_are_sent_over_tls() {
+        use crate::rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+        use crate::rustls::{ClientConfig, RootCertStore, ServerConfig};
+
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let cert = certified.cert.der().clone();
+        let key = PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der());
+        let server_config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.clone()], PrivateKeyDer::Pkcs8(key))
+            .unwrap();
+        let mut roots = RootCertStore::empty();
+        roots.add(cert).unwrap();
+        let client_config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let dir = std::env::temp_dir().join(format!("icedrop-tls-{}", std::process::id()));
+            std::fs::create_dir_all(dir.join("out")).unwrap();
+            std::fs::write(dir.join("plain"), b"sent over tls").unwrap();
+
+            let mut server = Server::builder()
+                .dest_dir(dir.join("out"))
+                .tls_config(Arc::new(server_config))
+                .bind("127.0.0.1:0")
+                .await
+                .unwrap();
+            let addr = server.local_addr().unwrap();
+            let server_task = tokio::spawn(async move { server.run().await });
+
+            let mut client = Client::connect_tls(addr, "localhost", Arc::new(client_config))
+                .await
+                .unwrap();
+            let file = File::open(dir.join("plain")).await.unwrap();
+            client.queue_file("received", file);
+            assert!(client.run().await.success);
+            server_task.abort();
+
+            let received = std::fs::read(dir.join("out").join("received")).unwrap();
+            assert_eq!(received, b"sent over tls");
+            std::fs::remove_dir_all(dir).unwrap();
+        });
+    }
+
+    #[cfg(feature = "quic")]
+    #[test]
+    fn files_are_sent_over_quic() {
+        use crate::quinn::rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+        use crate::quinn::rustls::RootCertStore;
+        use crate::quinn::{ClientConfig, ServerConfig};
+
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let cert = certified.cert.der().clone();
+        let key = PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der());
+        let server_config =
+            ServerConfig::with_single_cert(vec![cert.clone()], PrivateKeyDer::Pkcs8(key)).unwrap();
+        let mut roots = RootCertStore::empty();
+        roots.add(cert).unwrap();
+        let client_config = ClientConfig::with_root_certificates(Arc::new(roots)).unwrap();
+
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let dir = std::env::temp_dir().join(format!("icedrop-quic-{}", std::process::id()));
+            std::fs::create_dir_all(dir.join("out")).unwrap();
+            std::fs::write(dir.join("plain"), b"sent over quic").unwrap();
+
+            let mut server = Server::builder()
+                .dest_dir(dir.join("out"))
+                .bind_quic("127.0.0.1:0".parse().unwrap(), server_config)
+                .await
+                .unwrap();
+            let addr = server.local_addr().unwrap();
+            let server_task = tokio::spawn(async move { server.run().await });
+
+            let mut client = Client::connect_quic(addr, "localhost", client_config)
+                .await
+                .unwrap();
+            let file = File::open(dir.join("plain")).await.unwrap();
+            client.queue_file("received", file);
+            assert!(client.run().await.success);
+            server_task.abort();
+
+            let received = std::fs::read(dir.join("out").join("received")).unwrap();
+            assert_eq!(received, b"sent over quic");
+            std::fs::remove_dir_all(dir).unwrap();
+        });
+    }
+
+    #[cfg(feature = "websocket")]
+    #[test]
+    fn files_are_sent_over_websocket() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let dir = std::env::temp_dir().join(format!("icedrop-ws-{}", std::process::id()));
+            std::fs::create_dir_all(dir.join("out")).unwrap();
+            std::fs::write(dir.join("plain"), b"sent over websocket").unwrap();
+
+            let mut server = Server::builder()
+                .dest_dir(dir.join("out"))
+                .bind_ws("127.0.0.1:0")
+                .await
+                .unwrap();
+            let addr = server.local_addr().unwrap();
+            let server_task = tokio::spawn(async move { server.run().await });
+
+            let mut client = Client::connect_ws(&format!("ws://{}/", addr))
+                .await
+                .unwrap();
+            let file = File::open(dir.join("plain")).await.unwrap();
+            client.queue_file("received", file);
+            assert!(client.run().await.success);
+            server_task.abort();
+
+            let received = std::fs::read(dir.join("out").join("received")).unwrap();
+            assert_eq!(received, b"sent over websocket");
+            std::fs::remove_dir_all(dir).unwrap();
+        });
+    }
+
+    #[cfg(feature = "noise")]
+    #[test]
+    fn files_are_sent_encrypted_end_to_end() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let dir = std::env::temp_dir().join(format!("icedrop-noise-{}", std::process::id()));
+            std::fs::create_dir_all(dir.join("out")).unwrap();
+            let data = vec![5; 200_000];
+            std::fs::write(dir.join("plain"), &data).unwrap();
+
+            let server_code = Arc::new(Mutex::new(None));
+            let server_code_clone = Arc::clone(&server_code);
+            let mut server = Server::builder()
+                .dest_dir(dir.join("out"))
+                .end_to_end_encryption(move |_, code| {
+                    *server_code_clone.lock().unwrap() = Some(code);
+                })
+                .bind("127.0.0.1:0")
+                .await
+                .unwrap();
+            let addr = server.local_addr().unwrap();
+            let server_task = tokio::spawn(async move { server.run().await });
+
+            let mut client_code = None;
+            let mut client = Client::connect_encrypted(addr, |code| client_code = Some(code))
+                .await
+                .unwrap();
+            let file = File::open(dir.join("plain")).await.unwrap();
+            client.queue_file("received", file);
+            assert!(client.run().await.success);
+            server_task.abort();
+
+            assert!(client_code.is_some());
+            assert_eq!(*server_code.lock().unwrap(), client_code);
+            let received = std::fs::read(dir.join("out").join("received")).unwrap();
+            assert_eq!(received, data);
+            std::fs::remove_dir_all(dir).unwrap();
+        });
+    }
+
+    #[cfg(feature = "noise")]
+    #[test]
+    fn receivers_with_changed_keys_are_refused() {
+        use crate::identity::IdentityStore;
+
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let dir = std::env::temp_dir().join(format!("icedrop-tofu-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            let client_identity = IdentityStore::open(dir.join("client.json")).unwrap();
+            // The first server's device, set up again with new keys.
+            IdentityStore::open(dir.join("server-0.json")).unwrap();
+            IdentityStore::open(dir.join("server-1.json")).unwrap();
+            let read_json = |name: &str| -> serde_json::Value {
+                serde_json::from_slice(&std::fs::read(dir.join(name)).unwrap()).unwrap()
+            };
+            let mut reset = read_json("server-1.json");
+            reset["device_id"] = read_json("server-0.json")["device_id"].clone();
+            std::fs::write(dir.join("server-1.json"), reset.to_string()).unwrap();
+
+            for (i, expect_ok) in [(0, true), (0, true), (1, false)] {
+                let server_identity =
+                    IdentityStore::open(dir.join(format!("server-{}.json", i))).unwrap();
+                // Told once the server has remembered the client's key.
+                let (verified_tx, mut verified_rx) = tokio::sync::mpsc::unbounded_channel();
+                let mut server = Server::builder()
+                    .dest_dir(&dir)
+                    .end_to_end_encryption(move |_, _| {
+                        verified_tx.send(()).ok();
+                    })
+                    .identity(Arc::new(server_identity))
+                    .bind("127.0.0.1:0")
+                    .await
+                    .unwrap();
+                let addr = server.local_addr().unwrap();
+                let server_task = tokio::spawn(async move { server.run().await });
+
+                let result = Client::connect_encrypted_as(addr, &client_identity, |_| {}).await;
+                match result {
+                    Ok(_) => assert!(expect_ok),
+                    Err(err) => {
+                        assert!(!expect_ok);
+                        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+                    }
+                }
+                verified_rx.recv().await.unwrap();
+                server_task.abort();
+            }
+            assert_eq!(client_identity.known_peers().len(), 1);
+            std::fs::remove_dir_all(dir).unwrap();
+        });
+    }
 }
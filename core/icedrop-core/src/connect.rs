@@ -0,0 +1,190 @@
+use std::error::Error;
+use std::fmt::Display;
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use icedrop_proto::codec::FrameWithHeader;
+use icedrop_proto::relay::{RelayConnectFrame, RelayRole, RelayToken};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{lookup_host, TcpStream, ToSocketAddrs};
+
+/// A failed attempt to connect to one of the addresses a host resolved to.
+#[derive(Debug)]
+pub struct ConnectAttempt {
+    pub addr: SocketAddr,
+    pub error: io::Error,
+    /// How long the attempt took before failing.
+    pub elapsed: Duration,
+}
+
+/// Why connecting to a receiver failed, with everything that was tried along the way.
+#[derive(Debug)]
+pub struct ConnectError {
+    /// Set if the address could not be resolved at all.
+    pub resolve_error: Option<io::Error>,
+    /// Every address the host resolved to, in the order they were tried.
+    pub resolved_addrs: Vec<SocketAddr>,
+    pub attempts: Vec<ConnectAttempt>,
+}
+
+impl ConnectError {
+    /// Likely causes of the failure, meant to be shown to users next to the error.
+    pub fn hints(&self) -> Vec<&'static str> {
+        let mut hints = Vec::new();
+        if self.resolve_error.is_some() {
+            hints.push("the host name could not be resolved, check it for typos");
+        } else if self.resolved_addrs.is_empty() {
+            hints.push("the host name resolved to no addresses");
+        }
+
+        let mut push_hint = |hint| {
+            if !hints.contains(&hint) {
+                hints.push(hint);
+            }
+        };
+        for attempt in &self.attempts {
+            match attempt.error.kind() {
+                io::ErrorKind::ConnectionRefused => {
+                    push_hint("nothing is listening on the port, is the receiver running?")
+                }
+                io::ErrorKind::TimedOut => {
+                    push_hint("the host did not answer, a firewall may be dropping connections")
+                }
+                io::ErrorKind::HostUnreachable | io::ErrorKind::NetworkUnreachable => {
+                    push_hint("the host is not reachable, are both devices on the same network?")
+                }
+                io::ErrorKind::PermissionDenied => {
+                    push_hint("the connection was blocked by a local firewall or sandbox")
+                }
+                _ => {}
+            }
+        }
+        hints
+    }
+
+    /// The error kind best describing the failure, from the last attempt made.
+    pub fn kind(&self) -> io::ErrorKind {
+        if let Some(err) = &self.resolve_error {
+            err.kind()
+        } else if let Some(attempt) = self.attempts.last() {
+            attempt.error.kind()
+        } else {
+            io::ErrorKind::NotFound
+        }
+    }
+}
+
+impl Display for ConnectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(err) = &self.resolve_error {
+            write!(f, "could not resolve address: {}", err)?;
+        } else {
+            write!(
+                f,
+                "could not connect to any of {} address(es)",
+                self.resolved_addrs.len()
+            )?;
+        }
+        for attempt in &self.attempts {
+            write!(
+                f,
+                "\n  {}: {} (after {:?})",
+                attempt.addr, attempt.error, attempt.elapsed
+            )?;
+        }
+        for hint in self.hints() {
+            write!(f, "\n  hint: {}", hint)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for ConnectError {}
+
+impl From<ConnectError> for io::Error {
+    fn from(err: ConnectError) -> Self {
+        io::Error::new(err.kind(), err)
+    }
+}
+
+/// Connects to the first address `addr` resolves to that accepts the connection, recording every
+/// failed attempt.
+pub(crate) async fn connect<A>(addr: A) -> Result<TcpStream, ConnectError>
+where
+    A: ToSocketAddrs,
+{
+    let mut diagnostic = ConnectError {
+        resolve_error: None,
+        resolved_addrs: Vec::new(),
+        attempts: Vec::new(),
+    };
+
+    match lookup_host(addr).await {
+        Ok(addrs) => diagnostic.resolved_addrs.extend(addrs),
+        Err(err) => {
+            diagnostic.resolve_error = Some(err);
+            return Err(diagnostic);
+        }
+    }
+
+    for &addr in &diagnostic.resolved_addrs {
+        let attempt_start = Instant::now();
+        match TcpStream::connect(addr).await {
+            Ok(stream) => return Ok(stream),
+            Err(error) => diagnostic.attempts.push(ConnectAttempt {
+                addr,
+                error,
+                elapsed: attempt_start.elapsed(),
+            }),
+        }
+    }
+    Err(diagnostic)
+}
+
+/// Connects to the relay at `addr` and joins the session `token` stands for as `role`, see
+/// [`icedrop_proto::relay`]. The stream is piped to the other end of the session once it's there.
+pub(crate) async fn connect_relay<A>(
+    addr: A,
+    role: RelayRole,
+    token: RelayToken,
+) -> io::Result<TcpStream>
+where
+    A: ToSocketAddrs,
+{
+    let mut stream = connect(addr).await?;
+    let frame = RelayConnectFrame { role, token };
+    stream
+        .write_all(&FrameWithHeader { frame }.to_bytes(1))
+        .await?;
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::connect;
+
+    use std::io;
+
+    use tokio::net::TcpListener;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn refused_connection_is_diagnosed() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            // Find a port nothing listens on.
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            drop(listener);
+
+            let err = connect(addr).await.unwrap_err();
+            assert!(err.resolve_error.is_none());
+            assert_eq!(err.resolved_addrs, [addr]);
+            assert_eq!(err.attempts.len(), 1);
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+            assert!(err.hints()[0].contains("is the receiver running"));
+            assert!(err.to_string().contains(&addr.to_string()));
+        });
+    }
+}
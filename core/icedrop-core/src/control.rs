@@ -0,0 +1,141 @@
+//! Pausing and cancelling a running [`Client`](crate::Client), see [`TransferControl`].
+
+use std::sync::Arc;
+
+use tokio::sync::watch;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ControlState {
+    Running,
+    Paused,
+    Cancelled,
+}
+
+/// Pauses, resumes or cancels what a [`Client`](crate::Client) sends from another task or
+/// thread, see [`Client::control`](crate::Client::control). Clones control the same client.
+#[derive(Clone)]
+pub struct TransferControl {
+    state: Arc<watch::Sender<ControlState>>,
+}
+
+impl TransferControl {
+    /// A handle for a client yet to be made, see
+    /// [`Client::set_control`](crate::Client::set_control).
+    pub fn new() -> Self {
+        let (state, _) = watch::channel(ControlState::Running);
+        Self {
+            state: Arc::new(state),
+        }
+    }
+
+    /// Stops sending file data until [`resume`](Self::resume) is called. The connection is kept
+    /// alive meanwhile, and the receiver isn't considered stalled.
+    pub fn pause(&self) {
+        self.transition(ControlState::Running, ControlState::Paused);
+    }
+
+    /// Goes on sending after [`pause`](Self::pause).
+    pub fn resume(&self) {
+        self.transition(ControlState::Paused, ControlState::Running);
+    }
+
+    /// Gives up on the transfer, paused or not, which then fails with
+    /// [`Error::Cancelled`](crate::Error::Cancelled). Files the receiver already confirmed stay
+    /// sent.
+    pub fn cancel(&self) {
+        self.state.send_replace(ControlState::Cancelled);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.state.borrow() == ControlState::Paused
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self.state.borrow() == ControlState::Cancelled
+    }
+
+    fn transition(&self, from: ControlState, to: ControlState) {
+        if *self.state.borrow() == from {
+            self.state.send_replace(to);
+        }
+    }
+
+    /// Lets a running session follow what it's told.
+    pub(crate) fn watch(&self) -> ControlWatch {
+        ControlWatch(self.state.subscribe())
+    }
+}
+
+impl Default for TransferControl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What a running session is told by its [`TransferControl`].
+#[derive(Clone)]
+pub(crate) struct ControlWatch(watch::Receiver<ControlState>);
+
+impl ControlWatch {
+    /// Waits while the session is paused, returns `false` once it's cancelled.
+    pub(crate) async fn proceed(&mut self) -> bool {
+        loop {
+            match *self.0.borrow_and_update() {
+                ControlState::Running => return true,
+                ControlState::Cancelled => return false,
+                ControlState::Paused => {}
+            }
+            if self.0.changed().await.is_err() {
+                return true;
+            }
+        }
+    }
+
+    /// Waits until the session is cancelled, forever if it never is.
+    pub(crate) async fn cancelled(&mut self) {
+        while *self.0.borrow_and_update() != ControlState::Cancelled {
+            if self.0.changed().await.is_err() {
+                std::future::pending::<()>().await;
+            }
+        }
+    }
+
+    pub(crate) fn is_paused(&self) -> bool {
+        *self.0.borrow() == ControlState::Paused
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TransferControl;
+
+    use std::time::Duration;
+
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn paused_sessions_wait_until_resumed_or_cancelled() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let control = TransferControl::new();
+            let mut watch = control.watch();
+            assert!(watch.proceed().await);
+
+            control.pause();
+            assert!(watch.is_paused());
+            let waiting = tokio::spawn(async move { watch.proceed().await });
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            assert!(!waiting.is_finished());
+            control.resume();
+            assert!(waiting.await.unwrap());
+
+            let mut watch = control.watch();
+            control.pause();
+            control.cancel();
+            // Resuming a cancelled session doesn't bring it back.
+            control.resume();
+            assert!(!watch.proceed().await);
+            watch.cancelled().await;
+        });
+    }
+}
@@ -0,0 +1,89 @@
+//! Lets an app embedding a server decide about every file a sender offers, take the text senders
+//! send, and decide about what peers pull and browse.
+
+use crate::handlers::file_transfer::PeerIdentity;
+
+use std::fmt;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use tokio::io::AsyncWrite;
+
+/// A file a sender is about to send, as given to [`ServerDelegate::should_accept`].
+#[derive(Debug, Clone)]
+pub struct TransferOffer {
+    pub sender: PeerIdentity,
+    /// The name the sender gave the file. It's only a name, never a path.
+    pub file_name: String,
+    /// The size of the file, unless the sender didn't describe it.
+    pub file_size: Option<u64>,
+}
+
+/// Where to write a file received with [`Decision::AcceptInto`] instead of a file on disk, e.g.
+/// an upload to an object store or a decompression pipeline.
+pub struct TransferSink(pub(crate) Pin<Box<dyn AsyncWrite + Send + Sync>>);
+
+impl TransferSink {
+    pub fn new<W>(writer: W) -> Self
+    where
+        W: AsyncWrite + Send + Sync + 'static,
+    {
+        Self(Box::pin(writer))
+    }
+}
+
+impl fmt::Debug for TransferSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("TransferSink")
+    }
+}
+
+/// What to do with a [`TransferOffer`].
+#[derive(Debug)]
+pub enum Decision {
+    /// Receive the file into the server's destination directory.
+    Accept,
+    /// Receive the file to the given path instead. Its directory must exist, and the name is
+    /// numbered like in the destination directory if a file of that name exists already.
+    AcceptAs(PathBuf),
+    /// Receive the file into the given sink, which is shut down once the whole file has been
+    /// written to it. Files sent in stripes can't be put back together in a sink, and turn the
+    /// sender away like [`Decision::Reject`].
+    AcceptInto(TransferSink),
+    /// Turn the sender away with the given message, ending its session.
+    Reject(String),
+}
+
+/// Decides about every file offered to a server, e.g. by asking the user, see
+/// [`ServerBuilder::delegate`](crate::ServerBuilder::delegate).
+#[async_trait]
+pub trait ServerDelegate: Send + Sync {
+    /// Called once the sender has described a file, before anything is written. Files sent in
+    /// stripes over several connections are offered once.
+    async fn should_accept(&self, offer: TransferOffer) -> Decision;
+
+    /// Called with text a sender sent instead of files, see
+    /// [`Client::send_text`](crate::Client::send_text). Returning an error turns the text down
+    /// with its message, which is what servers do unless their delegate takes text.
+    async fn text_received(&self, _sender: PeerIdentity, _text: String) -> Result<(), String> {
+        Err("text messages are not accepted".to_owned())
+    }
+
+    /// Called once a peer asking for the file at `path` below the shared roots has been let in
+    /// like a sender would be, see
+    /// [`ServerBuilder::shared_roots`](crate::ServerBuilder::shared_roots). Returning an error
+    /// turns the peer down with its message. Peers are served by default.
+    async fn should_serve_pull(&self, _peer: PeerIdentity, _path: &str) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Called like [`ServerDelegate::should_serve_pull`] once a peer asking for the listing of
+    /// the shared folder at `path` has been let in.
+    async fn should_serve_listing(&self, _peer: PeerIdentity, _path: &str) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+pub(crate) type ServerDelegateRef = Arc<dyn ServerDelegate>;
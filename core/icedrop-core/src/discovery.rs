@@ -0,0 +1,398 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::Duration;
+
+use icedrop_proto::handshake::validate_display_name;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+
+use crate::metrics;
+use crate::proto::PROTOCOL_VERSION;
+
+/// The multicast group receivers announce themselves to on the local network.
+pub const DISCOVERY_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 73, 68);
+
+pub const DISCOVERY_PORT: u16 = 7368;
+
+/// How often receivers announce themselves once they have been online for a while, unless told
+/// otherwise.
+pub const DEFAULT_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How soon a receiver announces itself again after coming online. The interval then doubles
+/// with every announcement until it reaches the steady one.
+const FIRST_ANNOUNCE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Announcement intervals are randomly stretched or shrunk by up to this fraction, so receivers
+/// that came online together don't keep announcing in lockstep.
+const ANNOUNCE_JITTER: f64 = 0.2;
+
+/// Starts every beacon, so unrelated datagrams sent to the discovery port are ignored.
+const BEACON_MAGIC: &[u8; 4] = b"ICDP";
+
+/// Magic, protocol version, port and flags.
+const BEACON_HEADER_LEN: usize = 9;
+
+const BEACON_FLAG_BENCHMARKS: u8 = 0x1;
+const BEACON_FLAG_TLS: u8 = 0x2;
+const BEACON_FLAG_ENCRYPTED: u8 = 0x4;
+
+/// What a discovered receiver supports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerCapabilities {
+    pub protocol_version: u16,
+    /// Whether it takes part in benchmarks, see
+    /// [`ServerBuilder::accept_benchmarks`](crate::ServerBuilder::accept_benchmarks).
+    pub benchmarks: bool,
+    /// Whether it only takes TLS connections.
+    pub tls: bool,
+    /// Whether it only takes end-to-end encrypted connections.
+    pub encrypted: bool,
+}
+
+impl Default for PeerCapabilities {
+    fn default() -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            benchmarks: false,
+            tls: false,
+            encrypted: false,
+        }
+    }
+}
+
+/// A receiver found on the local network.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerInfo {
+    /// The display name it announces itself with.
+    pub name: String,
+    /// Where it accepts connections.
+    pub addr: SocketAddr,
+    pub capabilities: PeerCapabilities,
+}
+
+fn encode_beacon(name: &str, port: u16, capabilities: PeerCapabilities) -> Vec<u8> {
+    let mut flags = 0;
+    if capabilities.benchmarks {
+        flags |= BEACON_FLAG_BENCHMARKS;
+    }
+    if capabilities.tls {
+        flags |= BEACON_FLAG_TLS;
+    }
+    if capabilities.encrypted {
+        flags |= BEACON_FLAG_ENCRYPTED;
+    }
+
+    let mut buf = Vec::with_capacity(BEACON_HEADER_LEN + name.len());
+    buf.extend(BEACON_MAGIC);
+    buf.extend(capabilities.protocol_version.to_le_bytes());
+    buf.extend(port.to_le_bytes());
+    buf.push(flags);
+    buf.extend(name.as_bytes());
+    buf
+}
+
+/// Reads a beacon sent from `source`, `None` if it isn't a valid one.
+fn parse_beacon(buf: &[u8], source: SocketAddr) -> Option<PeerInfo> {
+    if buf.len() < BEACON_HEADER_LEN || &buf[..4] != BEACON_MAGIC {
+        return None;
+    }
+    let protocol_version = u16::from_le_bytes(buf[4..6].try_into().unwrap());
+    let port = u16::from_le_bytes(buf[6..8].try_into().unwrap());
+    let flags = buf[8];
+    let name = std::str::from_utf8(&buf[BEACON_HEADER_LEN..]).ok()?;
+    let name = validate_display_name(name).ok()?;
+
+    Some(PeerInfo {
+        name,
+        addr: SocketAddr::new(source.ip(), port),
+        capabilities: PeerCapabilities {
+            protocol_version,
+            benchmarks: flags & BEACON_FLAG_BENCHMARKS != 0,
+            tls: flags & BEACON_FLAG_TLS != 0,
+            encrypted: flags & BEACON_FLAG_ENCRYPTED != 0,
+        },
+    })
+}
+
+/// Randomly spreads `interval` by up to [`ANNOUNCE_JITTER`] either way.
+fn jittered(interval: Duration) -> Duration {
+    let random = RandomState::new().build_hasher().finish();
+    let spread = (random as f64 / u64::MAX as f64) * 2.0 - 1.0;
+    interval.mul_f64(1.0 + spread * ANNOUNCE_JITTER)
+}
+
+/// Binds a socket to the discovery port and joins the multicast group, sharing the port with
+/// anything else listening on it.
+fn bind_multicast() -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    let addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, DISCOVERY_PORT));
+    socket.bind(&addr.into())?;
+
+    let socket = UdpSocket::from_std(socket.into())?;
+    socket.join_multicast_v4(DISCOVERY_GROUP, Ipv4Addr::UNSPECIFIED)?;
+    Ok(socket)
+}
+
+/// Announces a receiver on the local network, so browsers can find it without being told its
+/// address.
+///
+/// Announcements start out frequent and slow down to the steady interval, see
+/// [`DiscoveryAnnouncer::set_interval`]. With [`DiscoveryAnnouncer::suppress_duplicates`], an
+/// announcement is skipped when another one for the same receiver has just been heard.
+pub struct DiscoveryAnnouncer {
+    socket: UdpSocket,
+    target: SocketAddr,
+    beacon: Vec<u8>,
+    interval: Duration,
+    /// Hears the announcements of others, to skip repeating them.
+    listener: Option<UdpSocket>,
+}
+
+impl DiscoveryAnnouncer {
+    /// Prepares announcing a receiver named `name` accepting connections on `port`. The name is
+    /// checked like a display name.
+    pub async fn bind(name: &str, port: u16, capabilities: PeerCapabilities) -> io::Result<Self> {
+        let name = validate_display_name(name)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+        Ok(Self {
+            socket,
+            target: SocketAddrV4::new(DISCOVERY_GROUP, DISCOVERY_PORT).into(),
+            beacon: encode_beacon(&name, port, capabilities),
+            interval: DEFAULT_ANNOUNCE_INTERVAL,
+            listener: None,
+        })
+    }
+
+    /// Sends the announcements to `target` instead of the discovery multicast group, e.g. a
+    /// browser bound with [`DiscoveryBrowser::bind_to`].
+    pub fn set_target(&mut self, target: SocketAddr) {
+        self.target = target;
+    }
+
+    /// Sets how often the receiver is announced once it has been online for a while
+    /// ([`DEFAULT_ANNOUNCE_INTERVAL`] by default).
+    pub fn set_interval(&mut self, interval: Duration) {
+        self.interval = interval;
+    }
+
+    /// Listens to the discovery multicast group, and skips announcing the receiver when another
+    /// announcement for it, e.g. from another process serving it, was heard since the last one.
+    pub async fn suppress_duplicates(&mut self) -> io::Result<()> {
+        self.listener = Some(bind_multicast()?);
+        Ok(())
+    }
+
+    /// Like [`DiscoveryAnnouncer::suppress_duplicates`], but listens for announcements arriving
+    /// on `socket` instead.
+    pub fn suppress_duplicates_from(&mut self, socket: UdpSocket) {
+        self.listener = Some(socket);
+    }
+
+    /// Announces the receiver until the task is cancelled or sending fails.
+    #[tracing::instrument(name = "discovery_announcer", skip_all, fields(target = %self.target))]
+    pub async fn run(&self) -> io::Result<()> {
+        let own_port = self.socket.local_addr()?.port();
+        let mut interval = FIRST_ANNOUNCE_INTERVAL.min(self.interval);
+        let mut duplicate_heard = false;
+        loop {
+            if duplicate_heard {
+                tracing::trace!("skipped announcement heard from elsewhere");
+            } else {
+                self.socket.send_to(&self.beacon, self.target).await?;
+                metrics::count_beacon_sent();
+            }
+            duplicate_heard = false;
+
+            let wait = tokio::time::sleep(jittered(interval));
+            tokio::pin!(wait);
+            match &self.listener {
+                Some(listener) => {
+                    let mut buf = [0u8; 512];
+                    loop {
+                        tokio::select! {
+                            _ = &mut wait => break,
+                            received = listener.recv_from(&mut buf) => {
+                                let (len, source) = received?;
+                                // Our own announcements loop back to the group as well.
+                                if buf[..len] == self.beacon[..] && source.port() != own_port {
+                                    duplicate_heard = true;
+                                }
+                            }
+                        }
+                    }
+                }
+                None => wait.await,
+            }
+            interval = (interval * 2).min(self.interval);
+        }
+    }
+}
+
+/// Finds receivers announcing themselves on the local network.
+pub struct DiscoveryBrowser {
+    socket: UdpSocket,
+    /// Every receiver found so far, by address.
+    peers: HashMap<SocketAddr, PeerInfo>,
+}
+
+impl DiscoveryBrowser {
+    /// Listens to the discovery multicast group. Several browsers on the same host can listen at
+    /// the same time.
+    pub async fn bind() -> io::Result<Self> {
+        Ok(Self::with_socket(bind_multicast()?))
+    }
+
+    /// Listens for announcements sent straight to `addr` rather than to the multicast group.
+    pub async fn bind_to(addr: SocketAddr) -> io::Result<Self> {
+        Ok(Self::with_socket(UdpSocket::bind(addr).await?))
+    }
+
+    fn with_socket(socket: UdpSocket) -> Self {
+        Self {
+            socket,
+            peers: HashMap::new(),
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Waits for the next receiver to be found. Receivers announcing themselves again are only
+    /// returned again once their name or capabilities have changed.
+    pub async fn next(&mut self) -> io::Result<PeerInfo> {
+        let mut buf = [0u8; 512];
+        loop {
+            let (len, source) = self.socket.recv_from(&mut buf).await?;
+            let peer = match parse_beacon(&buf[..len], source) {
+                Some(peer) => peer,
+                None => continue,
+            };
+            metrics::count_beacon_received();
+            if self.peers.get(&peer.addr) != Some(&peer) {
+                tracing::debug!(addr = %peer.addr, name = %peer.name, "found receiver");
+                self.peers.insert(peer.addr, peer.clone());
+                return Ok(peer);
+            }
+        }
+    }
+
+    /// Every receiver found so far, with the last announcement of each.
+    pub fn peers(&self) -> impl Iterator<Item = &PeerInfo> {
+        self.peers.values()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode_beacon, DiscoveryAnnouncer, DiscoveryBrowser, PeerCapabilities};
+
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use tokio::net::UdpSocket;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn browser_finds_announced_receivers_once() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let mut browser = DiscoveryBrowser::bind_to("127.0.0.1:0".parse().unwrap())
+                .await
+                .unwrap();
+            let browser_addr = browser.local_addr().unwrap();
+
+            // Datagrams that aren't beacons are ignored.
+            let stray = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            stray.send_to(b"hello", browser_addr).await.unwrap();
+
+            let capabilities = PeerCapabilities {
+                benchmarks: true,
+                encrypted: true,
+                ..PeerCapabilities::default()
+            };
+            let mut announcer = DiscoveryAnnouncer::bind("Living room 📺", 8080, capabilities)
+                .await
+                .unwrap();
+            announcer.set_target(browser_addr);
+            announcer.set_interval(Duration::from_millis(10));
+            let announcer_task = tokio::spawn(async move { announcer.run().await });
+
+            let peer = browser.next().await.unwrap();
+            assert_eq!(peer.name, "Living room 📺");
+            assert_eq!(peer.addr, "127.0.0.1:8080".parse::<SocketAddr>().unwrap());
+            assert_eq!(peer.capabilities, capabilities);
+
+            // Repeated announcements don't show up again.
+            let next = tokio::time::timeout(Duration::from_millis(100), browser.next()).await;
+            assert!(next.is_err());
+            assert_eq!(browser.peers().count(), 1);
+            announcer_task.abort();
+        });
+    }
+
+    #[test]
+    fn announcements_slow_down_and_skip_duplicates() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let count_announcements = |duration| {
+                let target = &target;
+                async move {
+                    let mut buf = [0u8; 512];
+                    let mut count = 0;
+                    let deadline = tokio::time::Instant::now() + duration;
+                    while let Ok(received) =
+                        tokio::time::timeout_at(deadline, target.recv_from(&mut buf)).await
+                    {
+                        received.unwrap();
+                        count += 1;
+                    }
+                    count
+                }
+            };
+
+            // A newly online receiver announces itself several times well within the steady
+            // interval.
+            let capabilities = PeerCapabilities::default();
+            let mut announcer = DiscoveryAnnouncer::bind("Desk", 8080, capabilities)
+                .await
+                .unwrap();
+            announcer.set_target(target.local_addr().unwrap());
+            announcer.set_interval(Duration::from_secs(10));
+            let announcer_task = tokio::spawn(async move { announcer.run().await });
+            assert!(count_announcements(Duration::from_millis(600)).await >= 2);
+            announcer_task.abort();
+
+            // Once someone else announces the same receiver, only the first one goes out.
+            let listener = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let listener_addr = listener.local_addr().unwrap();
+            let mut announcer = DiscoveryAnnouncer::bind("Desk", 8080, capabilities)
+                .await
+                .unwrap();
+            announcer.set_target(target.local_addr().unwrap());
+            announcer.suppress_duplicates_from(listener);
+            let announcer_task = tokio::spawn(async move { announcer.run().await });
+            let duplicate_task = tokio::spawn(async move {
+                let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+                let beacon = encode_beacon("Desk", 8080, capabilities);
+                loop {
+                    socket.send_to(&beacon, listener_addr).await.unwrap();
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                }
+            });
+            assert_eq!(count_announcements(Duration::from_millis(600)).await, 1);
+            announcer_task.abort();
+            duplicate_task.abort();
+        });
+    }
+}
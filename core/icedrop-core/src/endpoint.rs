@@ -1,60 +1,87 @@
+use crate::error::Error;
+use crate::metrics::{self, ActiveSession};
 use crate::proto::{Frame, FrameHandler, FrameParsingResult};
+use crate::rate_limit::{throttle, RateLimiter, TokenBucket};
 
-use std::error::Error;
-use std::fmt::Display;
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::error::Error as StdError;
+use std::io;
+use std::sync::atomic::{AtomicU16, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex as StdMutex, RwLock};
+use std::time::{Duration, Instant};
 
 use async_trait::async_trait;
-use byteorder::{ByteOrder, LittleEndian};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use icedrop_proto::codec::FrameHeader;
+use icedrop_proto::handshake::Capabilities;
+use icedrop_proto::keepalive::{PingFrame, PongFrame, KEEPALIVE_PROTOCOL_VERSION};
+use icedrop_proto::transfer::MAX_SEGMENT_SIZE;
+use icedrop_proto::FrameFlags;
+use log::trace;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
+use tokio::runtime::Handle;
 use tokio::select;
+use tokio::sync::mpsc::error::TrySendError;
 use tokio::sync::mpsc::{channel, Receiver, Sender};
 use tokio::sync::Mutex;
 
-struct FrameWithHeader<F>
-where
-    F: Frame,
-{
-    frame: F,
-}
+/// Log target for the per-frame handler dispatch trace, enable it with e.g.
+/// `RUST_LOG=icedrop::dispatch=trace` to see which handlers skipped and consumed each frame.
+pub const DISPATCH_TRACE_TARGET: &str = "icedrop::dispatch";
 
-impl<F> FrameWithHeader<F>
-where
-    F: Frame,
-{
-    fn to_bytes(self) -> Vec<u8> {
-        let frame_type = self.frame.frame_type();
-        let payload = self.frame.to_bytes();
+/// How long a peer may go without sending anything in the middle of a frame, unless told
+/// otherwise. Waiting for the next frame to start isn't limited.
+pub const DEFAULT_FRAME_READ_TIMEOUT: Duration = Duration::from_secs(30);
 
-        let mut frame_type_buf = [0 as u8; 2];
-        LittleEndian::write_u16(&mut frame_type_buf, frame_type);
+/// The longest frame payload a peer may send, a data frame carrying the largest segment with
+/// room to spare for its other fields. Anything longer fails with [`Error::Protocol`] before
+/// memory is set aside for it.
+pub const MAX_FRAME_LEN: usize = MAX_SEGMENT_SIZE + 64 * 1024;
 
-        let mut frame_len_buf = [0 as u8; 4];
-        LittleEndian::write_u32(&mut frame_len_buf, payload.len() as u32);
+/// How many encoded frames wait for the writer of an endpoint before sending more waits for room.
+const WRITE_QUEUE_CAPACITY: usize = 32;
 
-        let mut buf = Vec::with_capacity(6 + payload.len());
-        buf.extend(frame_type_buf);
-        buf.extend(frame_len_buf);
-        buf.extend(payload);
+/// How often an endpoint pings its peer unless told otherwise.
+pub const DEFAULT_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
 
-        buf
-    }
+/// How long an endpoint waits for anything from its peer, pings included, before giving up on it
+/// unless told otherwise.
+pub const DEFAULT_KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(45);
+
+/// Hooks into every frame an endpoint sends or receives, e.g. to collect metrics, record the
+/// conversation or tamper with frames in tests. Both hooks get the frame type and the encoded
+/// payload, which they may modify in place.
+///
+/// Outgoing frames pass the middlewares in the order they were added, incoming frames in reverse
+/// order, so a middleware wrapping payloads (e.g. encrypting them) sees the same bytes on both
+/// ends.
+pub trait EndpointMiddleware: Send + Sync {
+    fn on_outgoing(&self, _frame_type: u16, _payload: &mut Vec<u8>) {}
+
+    fn on_incoming(&self, _frame_type: u16, _payload: &mut Vec<u8>) {}
 }
 
+type Middlewares = Arc<RwLock<Vec<Box<dyn EndpointMiddleware>>>>;
+
+/// The halves of the stream an endpoint talks over, be it plain TCP or e.g. TLS on top of it.
+type StreamReadHalf = Box<dyn AsyncRead + Send + Unpin>;
+type StreamWriteHalf = Box<dyn AsyncWrite + Send + Unpin>;
+
 pub enum AnyFrameHandlerResult {
     Ok,
     Skip(Vec<u8>),
-    Err(Box<dyn Error + Send>),
+    Err(Box<dyn StdError + Send>),
 }
 
 #[async_trait]
 trait AnyFrameHandler {
+    fn frame_types(&self) -> Vec<u16>;
+
     async fn parse_and_handle_frame(
         &mut self,
         frame_type: u16,
         frame_payload: Vec<u8>,
+        peer: &str,
     ) -> AnyFrameHandlerResult;
 }
 
@@ -70,18 +97,52 @@ impl<H> AnyFrameHandler for AnyFrameHandlerImpl<H>
 where
     H: FrameHandler + Send,
 {
+    fn frame_types(&self) -> Vec<u16> {
+        <H as FrameHandler>::IncomingFrame::frame_types()
+    }
+
     async fn parse_and_handle_frame(
         &mut self,
         frame_type: u16,
         frame_payload: Vec<u8>,
+        peer: &str,
     ) -> AnyFrameHandlerResult {
+        let parse_start = Instant::now();
         let parsing_result =
             <H as FrameHandler>::IncomingFrame::try_parse(frame_type, frame_payload);
+        let parse_duration = parse_start.elapsed();
+
+        let handler_name = std::any::type_name::<H>();
         if let FrameParsingResult::Skip(payload) = parsing_result {
+            trace!(
+                target: DISPATCH_TRACE_TARGET,
+                "[{}] frame {}: skipped by {} ({:?})",
+                peer,
+                frame_type,
+                handler_name,
+                parse_duration,
+            );
             return AnyFrameHandlerResult::Skip(payload);
         } else if let FrameParsingResult::Err(err) = parsing_result {
+            trace!(
+                target: DISPATCH_TRACE_TARGET,
+                "[{}] frame {}: rejected by {} ({:?}): {}",
+                peer,
+                frame_type,
+                handler_name,
+                parse_duration,
+                err,
+            );
             return AnyFrameHandlerResult::Err(err);
         } else if let FrameParsingResult::Ok(frame) = parsing_result {
+            trace!(
+                target: DISPATCH_TRACE_TARGET,
+                "[{}] frame {}: consumed by {} ({:?})",
+                peer,
+                frame_type,
+                handler_name,
+                parse_duration,
+            );
             let fut = self.inner.handle_frame(frame);
             fut.await;
             return AnyFrameHandlerResult::Ok;
@@ -90,42 +151,99 @@ where
     }
 }
 
-#[derive(Debug)]
-pub struct EndpointError {
-    message: String,
+pub struct EndpointHandle {
+    write_queue: WriteQueue,
+    protocol_version: Arc<AtomicU16>,
+    capabilities: Arc<AtomicU32>,
+    middlewares: Middlewares,
+    rate_limiter: RateLimiter,
+    shutdown_tx: Sender<()>,
 }
 
-impl EndpointError {
-    fn new(message: &str) -> Self {
-        Self {
-            message: message.to_owned(),
-        }
-    }
+/// Where the handles of an endpoint queue encoded frames for its writer, along with why the writer
+/// stopped once it has.
+#[derive(Clone)]
+struct WriteQueue {
+    frames_tx: Sender<Vec<u8>>,
+    write_error: Arc<StdMutex<Option<Error>>>,
 }
 
-impl Display for EndpointError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str(self.message.as_str())
+impl WriteQueue {
+    /// Why frames can't be queued anymore.
+    fn closed_error(&self) -> Error {
+        self.write_error
+            .lock()
+            .unwrap()
+            .clone()
+            .unwrap_or_else(|| io::Error::from(io::ErrorKind::BrokenPipe).into())
     }
 }
 
-impl Error for EndpointError {}
+impl EndpointHandle {
+    /// Queues `frame` for the writer of the endpoint, waiting for room if the peer doesn't keep up
+    /// with the frames queued before. Fails once a write has failed, with its error.
+    pub async fn send_frame<F>(&self, frame: F) -> Result<(), Error>
+    where
+        F: Frame,
+    {
+        let frame_type = frame.frame_type();
+        let buf = self.encode_frame(frame);
+        throttle(&self.rate_limiter, buf.len()).await;
+        let write_queue = &self.write_queue;
+        write_queue
+            .frames_tx
+            .send(buf)
+            .await
+            .map_err(|_| write_queue.closed_error())?;
+        metrics::count_frame_sent(frame_type);
+        Ok(())
+    }
 
-pub struct EndpointHandle {
-    stream_wr: Arc<Mutex<OwnedWriteHalf>>,
-    shutdown_tx: Sender<()>,
-}
+    /// Queues `frame` like [`EndpointHandle::send_frame`] if there's room right away, failing with
+    /// a `WouldBlock` error otherwise. The frame counts against the rate limit without waiting for
+    /// it.
+    pub fn try_send_frame<F>(&self, frame: F) -> Result<(), Error>
+    where
+        F: Frame,
+    {
+        let frame_type = frame.frame_type();
+        let buf = self.encode_frame(frame);
+        let len = buf.len();
+        match self.write_queue.frames_tx.try_send(buf) {
+            Ok(()) => {
+                metrics::count_frame_sent(frame_type);
+                if let Some(bucket) = &mut *self.rate_limiter.lock().unwrap() {
+                    bucket.take(len, Instant::now());
+                }
+                Ok(())
+            }
+            Err(TrySendError::Full(_)) => Err(io::Error::from(io::ErrorKind::WouldBlock).into()),
+            Err(TrySendError::Closed(_)) => Err(self.write_queue.closed_error()),
+        }
+    }
 
-impl EndpointHandle {
-    pub async fn send_frame<F>(&self, frame: F) -> Result<(), Box<dyn Error>>
+    /// Encodes `frame` with the header of the current protocol version, after the middlewares have
+    /// seen it.
+    fn encode_frame<F>(&self, frame: F) -> Vec<u8>
     where
         F: Frame,
     {
-        #[cfg(debug_assertions)]
         let frame_type = frame.frame_type();
+        let mut payload = frame.to_bytes();
+        for middleware in self.middlewares.read().unwrap().iter() {
+            middleware.on_outgoing(frame_type, &mut payload);
+        }
 
-        let frame_with_header = FrameWithHeader { frame };
-        let buf = frame_with_header.to_bytes();
+        let protocol_version = self.protocol_version();
+        let header = FrameHeader {
+            frame_type,
+            flags: FrameFlags::empty(),
+            stream_id: 0,
+            frame_len: payload.len() as u32,
+        };
+        let mut buf = Vec::with_capacity(FrameHeader::size(protocol_version) + payload.len());
+        header.write(protocol_version, &mut buf);
+        buf.extend(payload);
 
         #[cfg(debug_assertions)]
         println!(
@@ -133,43 +251,126 @@ impl EndpointHandle {
             frame_type,
             buf.len()
         );
+        buf
+    }
 
-        let mut stream_wr_locked = self.stream_wr.lock().await;
-        stream_wr_locked.write_all(&buf).await?;
-        Ok(())
+    /// The protocol version frames are currently exchanged with, `1` until the handshake has
+    /// negotiated a newer one.
+    pub fn protocol_version(&self) -> u16 {
+        self.protocol_version.load(Ordering::SeqCst)
     }
 
-    pub async fn shutdown(&self) -> Result<(), Box<dyn Error>> {
+    /// Switches both directions of the endpoint to `version`. Frames sent afterwards and frames
+    /// read after the current one use the matching header format.
+    pub fn set_protocol_version(&self, version: u16) {
+        self.protocol_version.store(version, Ordering::SeqCst);
+    }
+
+    /// The optional features both peers support, none until the handshake has been answered.
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities::from_bits(self.capabilities.load(Ordering::SeqCst))
+    }
+
+    /// Records what the handshake found both peers to support, for every handler to check before
+    /// using an optional feature.
+    pub fn set_capabilities(&self, capabilities: Capabilities) {
+        self.capabilities
+            .store(capabilities.bits(), Ordering::SeqCst);
+    }
+
+    pub async fn shutdown(&self) -> Result<(), Box<dyn StdError>> {
         self.shutdown_tx.try_send(())?;
         Ok(())
     }
+
+    /// Resolves once the endpoint has stopped running.
+    pub async fn closed(&self) {
+        self.shutdown_tx.closed().await
+    }
 }
 
 impl Clone for EndpointHandle {
     fn clone(&self) -> Self {
         Self {
-            stream_wr: Arc::clone(&self.stream_wr),
+            write_queue: self.write_queue.clone(),
+            protocol_version: Arc::clone(&self.protocol_version),
+            capabilities: Arc::clone(&self.capabilities),
+            middlewares: Arc::clone(&self.middlewares),
+            rate_limiter: Arc::clone(&self.rate_limiter),
             shutdown_tx: self.shutdown_tx.clone(),
         }
     }
 }
 
 pub struct Endpoint {
-    stream_rd: Arc<Mutex<OwnedReadHalf>>,
-    stream_wr: Arc<Mutex<OwnedWriteHalf>>,
+    peer: String,
+    stream_rd: Arc<Mutex<StreamReadHalf>>,
+    write_queue: WriteQueue,
+    protocol_version: Arc<AtomicU16>,
+    capabilities: Arc<AtomicU32>,
     handlers: Option<Vec<Box<dyn AnyFrameHandler + Send>>>,
+    /// Indices into `handlers` of the handlers accepting each frame type, in registration order.
+    routes: HashMap<u16, Vec<usize>>,
+    /// A frame read before the endpoint ran that the handlers get first, see
+    /// [`Endpoint::read_first_frame`].
+    pending_frame: Option<(u16, Vec<u8>)>,
+    middlewares: Middlewares,
+    rate_limiter: RateLimiter,
+    frame_read_timeout: Option<Duration>,
+    keepalive_interval: Option<Duration>,
+    keepalive_timeout: Duration,
     shutdown_tx: Sender<()>,
     shutdown_rx: Receiver<()>,
 }
 
 impl Endpoint {
     pub fn new(stream: TcpStream) -> Self {
+        let peer = peer_name(&stream);
         let (rd_half, wr_half) = stream.into_split();
+        Self::with_halves(peer, Box::new(rd_half), Box::new(wr_half))
+    }
+
+    /// Creates an endpoint talking over any stream, e.g. a TLS one. `peer` names the other end in
+    /// logs.
+    pub fn with_stream<S>(stream: S, peer: String) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (rd_half, wr_half) = tokio::io::split(stream);
+        Self::with_halves(peer, Box::new(rd_half), Box::new(wr_half))
+    }
+
+    /// What the other end is called in logs, its address for most streams.
+    pub fn peer(&self) -> &str {
+        &self.peer
+    }
+
+    fn with_halves(peer: String, rd_half: StreamReadHalf, wr_half: StreamWriteHalf) -> Self {
         let (tx, rx) = channel(1);
+        let (frames_tx, frames_rx) = channel(WRITE_QUEUE_CAPACITY);
+        let write_queue = WriteQueue {
+            frames_tx,
+            write_error: Arc::default(),
+        };
+        Handle::current().spawn(Self::write_frames(
+            wr_half,
+            frames_rx,
+            Arc::clone(&write_queue.write_error),
+        ));
         Self {
+            peer,
             stream_rd: Arc::new(Mutex::new(rd_half)),
-            stream_wr: Arc::new(Mutex::new(wr_half)),
+            write_queue,
+            protocol_version: Arc::new(AtomicU16::new(1)),
+            capabilities: Arc::new(AtomicU32::new(0)),
             handlers: Some(Vec::new()),
+            routes: HashMap::new(),
+            pending_frame: None,
+            middlewares: Arc::new(RwLock::new(Vec::new())),
+            rate_limiter: Arc::new(StdMutex::new(None)),
+            frame_read_timeout: Some(DEFAULT_FRAME_READ_TIMEOUT),
+            keepalive_interval: Some(DEFAULT_KEEPALIVE_INTERVAL),
+            keepalive_timeout: DEFAULT_KEEPALIVE_TIMEOUT,
             shutdown_tx: tx,
             shutdown_rx: rx,
         }
@@ -182,94 +383,633 @@ impl Endpoint {
         if let Some(handlers) = &mut self.handlers {
             let type_erased_handler: AnyFrameHandlerImpl<H> =
                 AnyFrameHandlerImpl { inner: handler };
+            for frame_type in type_erased_handler.frame_types() {
+                let route = self.routes.entry(frame_type).or_default();
+                if !route.contains(&handlers.len()) {
+                    route.push(handlers.len());
+                }
+            }
             handlers.push(Box::new(type_erased_handler));
         } else {
             panic!("Cannot add handlers after the endpoint runs!");
         }
     }
 
+    /// Adds a middleware seeing every frame sent and received from now on.
+    pub fn add_middleware(&mut self, middleware: Box<dyn EndpointMiddleware>) {
+        self.middlewares.write().unwrap().push(middleware);
+    }
+
+    /// Sets how long the peer may go without sending anything once it has started sending a
+    /// frame ([`DEFAULT_FRAME_READ_TIMEOUT`] by default), `None` to wait forever. Large frames
+    /// may take longer than that over slow links, as long as data keeps coming. A peer stalling
+    /// in the middle of a frame makes the endpoint stop with a `TimedOut` error.
+    pub fn set_frame_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.frame_read_timeout = timeout;
+    }
+
+    /// Sets how often the peer is pinged, `None` not to, and how long it may stay silent before
+    /// the endpoint stops with an [`Error::Timeout`]. Only peers speaking protocol version 7
+    /// or newer are pinged and timed out.
+    pub fn set_keepalive(&mut self, interval: Option<Duration>, timeout: Duration) {
+        self.keepalive_interval = interval;
+        self.keepalive_timeout = timeout;
+    }
+
+    /// Caps the bytes sent and received per second, counting both directions together, `None` for
+    /// no limit. Sending waits until frames fit within the limit, and reading the next frame waits
+    /// as well, which makes the peer wait in turn.
+    pub fn set_rate_limit(&mut self, bytes_per_sec: Option<u64>) {
+        *self.rate_limiter.lock().unwrap() = bytes_per_sec.map(TokenBucket::new);
+    }
+
+    /// The rate limit of the endpoint, for other endpoints to share with
+    /// [`Endpoint::share_rate_limit`].
+    pub(crate) fn rate_limiter(&self) -> RateLimiter {
+        Arc::clone(&self.rate_limiter)
+    }
+
+    /// Counts the endpoint against `rate_limiter` along with the endpoints it came from, so several
+    /// connections to the same peer stay within one limit together. Must be called before taking
+    /// handles.
+    pub(crate) fn share_rate_limit(&mut self, rate_limiter: RateLimiter) {
+        self.rate_limiter = rate_limiter;
+    }
+
     pub fn handle(&self) -> EndpointHandle {
         EndpointHandle {
-            stream_wr: Arc::clone(&self.stream_wr),
+            write_queue: self.write_queue.clone(),
+            protocol_version: Arc::clone(&self.protocol_version),
+            capabilities: Arc::clone(&self.capabilities),
+            middlewares: Arc::clone(&self.middlewares),
+            rate_limiter: Arc::clone(&self.rate_limiter),
             shutdown_tx: self.shutdown_tx.clone(),
         }
     }
 }
 
+/// Answers the peer's keepalive pings.
+struct PingHandler {
+    endpoint_handle: EndpointHandle,
+}
+
+#[async_trait]
+impl FrameHandler for PingHandler {
+    type IncomingFrame = PingFrame;
+
+    async fn handle_frame(&mut self, _frame: Self::IncomingFrame) {
+        self.endpoint_handle.send_frame(PongFrame).await.ok();
+    }
+}
+
+/// Takes the answers to keepalive pings, which only matter for having been received.
+struct PongHandler;
+
+#[async_trait]
+impl FrameHandler for PongHandler {
+    type IncomingFrame = PongFrame;
+
+    async fn handle_frame(&mut self, _frame: Self::IncomingFrame) {}
+}
+
+/// Fills `buf` from `stream_rd`, failing with a `TimedOut` error if nothing arrives for
+/// `timeout`. Every read getting some of the data starts the timeout over.
+async fn read_unless_stalled(
+    stream_rd: &mut StreamReadHalf,
+    buf: &mut [u8],
+    timeout: Option<Duration>,
+) -> io::Result<()> {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return stream_rd.read_exact(buf).await.map(|_| ()),
+    };
+    let mut filled = 0;
+    while filled < buf.len() {
+        match tokio::time::timeout(timeout, stream_rd.read(&mut buf[filled..])).await {
+            Ok(Ok(0)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Peer has closed unexpectedly",
+                ))
+            }
+            Ok(Ok(read_size)) => filled += read_size,
+            Ok(Err(err)) => return Err(err),
+            Err(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "peer stalled in the middle of a frame",
+                ))
+            }
+        }
+    }
+    Ok(())
+}
+
+/// How logs refer to the other end of `stream`.
+pub(crate) fn peer_name(stream: &TcpStream) -> String {
+    stream
+        .peer_addr()
+        .map(|addr| addr.to_string())
+        .unwrap_or_else(|_| "unknown peer".to_owned())
+}
+
 impl Endpoint {
-    pub async fn run(mut self) -> Result<(), Box<dyn Error>> {
+    /// Handles the frames the peer sends until either end shuts the endpoint down or the
+    /// connection fails.
+    #[tracing::instrument(name = "endpoint", skip_all, fields(peer = %self.peer))]
+    pub async fn run(self) -> Result<(), Error> {
+        let _session = ActiveSession::start();
+        let result = self.handle_frames().await;
+        if let Err(err) = &result {
+            metrics::count_error();
+            tracing::debug!(error = %err, "endpoint stopped");
+        }
+        result
+    }
+
+    /// Reads the first frame the peer sends before the endpoint runs and returns it if it's an `F`,
+    /// e.g. to tell what the peer connected for. Any other frame goes to the handlers once the
+    /// endpoint runs.
+    pub(crate) async fn read_first_frame<F>(&mut self) -> Result<Option<F>, Error>
+    where
+        F: Frame,
+    {
+        let (frame_type, frame_buf, _) = Self::read_frame(
+            &self.peer,
+            &self.stream_rd,
+            &self.protocol_version,
+            self.frame_read_timeout,
+            &self.middlewares,
+        )
+        .await?;
+        if !F::frame_types().contains(&frame_type) {
+            self.pending_frame = Some((frame_type, frame_buf));
+            return Ok(None);
+        }
+
+        match F::try_parse(frame_type, frame_buf) {
+            FrameParsingResult::Ok(frame) => Ok(Some(frame)),
+            FrameParsingResult::Skip(frame_buf) => {
+                self.pending_frame = Some((frame_type, frame_buf));
+                Ok(None)
+            }
+            FrameParsingResult::Err(err) => Err(Error::Protocol(err.to_string())),
+        }
+    }
+
+    /// Like [`read_first_frame`](Self::read_first_frame), but fails with [`Error::Timeout`] if the
+    /// peer sends nothing for the frame read timeout, for peers that have to speak first.
+    pub(crate) async fn read_first_frame_promptly<F>(&mut self) -> Result<Option<F>, Error>
+    where
+        F: Frame,
+    {
+        match self.frame_read_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, self.read_first_frame())
+                .await
+                .unwrap_or(Err(Error::Timeout(timeout))),
+            None => self.read_first_frame().await,
+        }
+    }
+
+    async fn handle_frames(mut self) -> Result<(), Error> {
+        let handle = self.handle();
+        self.add_handler(PingHandler {
+            endpoint_handle: handle.clone(),
+        });
+        self.add_handler(PongHandler);
+        let last_received = Arc::new(StdMutex::new(Instant::now()));
+        let (timeout_tx, mut timeout_rx) = channel(1);
+        let keepalive_timeout = self.keepalive_timeout;
+        if let Some(interval) = self.keepalive_interval {
+            Handle::current().spawn(Self::keep_alive(
+                handle,
+                Arc::clone(&last_received),
+                interval,
+                keepalive_timeout,
+                timeout_tx,
+            ));
+        }
+
         let mut handlers = self.handlers.take().unwrap();
+        let routes = self.routes;
         let stream_rd_clone = Arc::clone(&self.stream_rd);
-        let net_fut = async move {
-            loop {
-                let fut = Self::handle_incoming_frames(&stream_rd_clone, &mut handlers);
-                if let Err(err) = fut.await {
-                    return Err(err);
-                }
+        let protocol_version = Arc::clone(&self.protocol_version);
+        let middlewares = Arc::clone(&self.middlewares);
+        let frame_read_timeout = self.frame_read_timeout;
+        let rate_limiter = self.rate_limiter;
+        let peer = self.peer;
+        let mut shutdown_rx = self.shutdown_rx;
+        if let Some((frame_type, frame_buf)) = self.pending_frame {
+            Self::dispatch_frame(&peer, &routes, &mut handlers, frame_type, frame_buf).await?;
+        }
+        loop {
+            let fut = Self::handle_incoming_frames(
+                &peer,
+                &stream_rd_clone,
+                &protocol_version,
+                frame_read_timeout,
+                &middlewares,
+                &routes,
+                &mut handlers,
+            );
+            // A handler shutting the endpoint down may also lead to the peer closing the
+            // connection, so the shutdown is looked at before every read and has to win over the
+            // read error that causes.
+            let result = select! {
+                biased;
+                _ = shutdown_rx.recv() => { return Ok(()) },
+                Some(()) = timeout_rx.recv() => {
+                    return Err(Error::Timeout(keepalive_timeout));
+                },
+                result = fut => { result },
+            };
+            let frame_size = match result {
+                Ok(frame_size) => frame_size,
+                Err(err) => return Err(err),
+            };
+            *last_received.lock().unwrap() = Instant::now();
+            throttle(&rate_limiter, frame_size).await;
+        }
+    }
+
+    /// Writes the frames queued by the handles of the endpoint in order, so a slow peer only holds
+    /// up the writer and whoever waits for room in the queue. Stops at the first failed write,
+    /// keeping its error for later sends to fail with, and shuts the stream down once every handle
+    /// is gone.
+    async fn write_frames(
+        mut stream_wr: StreamWriteHalf,
+        mut frames_rx: Receiver<Vec<u8>>,
+        write_error: Arc<StdMutex<Option<Error>>>,
+    ) {
+        while let Some(buf) = frames_rx.recv().await {
+            // Some streams, e.g. WebSocket ones, hold on to what's written until flushed.
+            let result = match stream_wr.write_all(&buf).await {
+                Ok(()) => stream_wr.flush().await,
+                Err(err) => Err(err),
+            };
+            if let Err(err) = result {
+                *write_error.lock().unwrap() = Some(err.into());
+                return;
             }
-            #[allow(unreachable_code)]
-            Ok(()) // Help the compiler to infer a return type.
-        };
+            metrics::count_bytes_sent(buf.len());
+        }
+        stream_wr.shutdown().await.ok();
+    }
 
-        let mut shutdown_rx = self.shutdown_rx;
-        let result = select! {
-            result = net_fut => { result },
-            _ = shutdown_rx.recv() => { Ok(()) },
-        };
-        if let Err(err) = result {
-            return Err(err);
+    /// Pings the peer every `interval` once it speaks a protocol version with keepalive pings, and
+    /// reports a timeout once nothing has been received from it for `timeout`.
+    async fn keep_alive(
+        handle: EndpointHandle,
+        last_received: Arc<StdMutex<Instant>>,
+        interval: Duration,
+        timeout: Duration,
+        timeout_tx: Sender<()>,
+    ) {
+        loop {
+            select! {
+                _ = handle.closed() => { return; },
+                _ = tokio::time::sleep(interval) => {},
+            }
+            if handle.protocol_version() < KEEPALIVE_PROTOCOL_VERSION {
+                continue;
+            }
+            if last_received.lock().unwrap().elapsed() >= timeout {
+                timeout_tx.try_send(()).ok();
+                return;
+            }
+
+            // A connection that went away may block writes, which mustn't hold up the timeout. A
+            // full queue needs no ping on top.
+            handle.try_send_frame(PingFrame).ok();
         }
-        Ok(())
     }
 
     async fn handle_incoming_frames(
-        stream_rd: &Arc<Mutex<OwnedReadHalf>>,
-        handlers: &mut Vec<Box<dyn AnyFrameHandler + Send>>,
-    ) -> Result<(), Box<dyn Error + Send>> {
+        peer: &str,
+        stream_rd: &Arc<Mutex<StreamReadHalf>>,
+        protocol_version: &AtomicU16,
+        frame_read_timeout: Option<Duration>,
+        middlewares: &RwLock<Vec<Box<dyn EndpointMiddleware>>>,
+        routes: &HashMap<u16, Vec<usize>>,
+        handlers: &mut [Box<dyn AnyFrameHandler + Send>],
+    ) -> Result<usize, Error> {
+        let (frame_type, frame_buf, frame_size) = Self::read_frame(
+            peer,
+            stream_rd,
+            protocol_version,
+            frame_read_timeout,
+            middlewares,
+        )
+        .await?;
+        Self::dispatch_frame(peer, routes, handlers, frame_type, frame_buf).await?;
+        Ok(frame_size)
+    }
+
+    /// Reads the next frame, returning its type, its payload as the middlewares leave it and its
+    /// size on the wire.
+    async fn read_frame(
+        peer: &str,
+        stream_rd: &Arc<Mutex<StreamReadHalf>>,
+        protocol_version: &AtomicU16,
+        frame_read_timeout: Option<Duration>,
+        middlewares: &RwLock<Vec<Box<dyn EndpointMiddleware>>>,
+    ) -> Result<(u16, Vec<u8>, usize), Error> {
         let mut stream_rd_locked = stream_rd.lock().await;
 
-        // Read the frame type.
-        let mut frame_header_buf = [0 as u8; 6];
-        let read_size = stream_rd_locked.read_exact(&mut frame_header_buf).await;
+        // Read the frame header, its layout depends on the negotiated protocol version.
+        let protocol_version = protocol_version.load(Ordering::SeqCst);
+        // Waiting for a frame to start can take any time, but once it has the peer may not stall
+        // for longer than the frame read timeout.
+        let mut frame_header_buf = vec![0u8; FrameHeader::size(protocol_version)];
+        if let Err(err) = stream_rd_locked
+            .read_exact(&mut frame_header_buf[..1])
+            .await
+        {
+            return Err(err.into());
+        }
+        read_unless_stalled(
+            &mut stream_rd_locked,
+            &mut frame_header_buf[1..],
+            frame_read_timeout,
+        )
+        .await?;
+
+        let frame_header = FrameHeader::parse(protocol_version, &frame_header_buf);
+        if !frame_header.flags.is_empty() {
+            let msg = format!(
+                "Unsupported flags {:#04x} on frame: {}",
+                frame_header.flags.bits(),
+                frame_header.frame_type
+            );
+            return Err(Error::Protocol(msg));
+        }
 
-        if let Ok(read_size) = read_size {
-            if read_size != frame_header_buf.len() {
-                return Err(Box::new(EndpointError::new("Peer has closed unexpectedly")));
-            }
-        } else {
-            return Err(Box::new(read_size.unwrap_err()));
+        let frame_type = frame_header.frame_type;
+        let frame_len = frame_header.frame_len as usize;
+        if frame_len > MAX_FRAME_LEN {
+            let msg = format!("Frame {} is too long: {} bytes", frame_type, frame_len);
+            return Err(Error::Protocol(msg));
         }
 
-        let frame_type = LittleEndian::read_u16(&frame_header_buf);
-        let frame_len = LittleEndian::read_u32(&frame_header_buf[2..]) as usize;
+        let mut frame_buf = vec![0u8; frame_len];
+        read_unless_stalled(&mut stream_rd_locked, &mut frame_buf, frame_read_timeout).await?;
 
-        let mut frame_buf = Vec::with_capacity(frame_len);
-        unsafe {
-            frame_buf.set_len(frame_len as usize);
-        }
-        let read_size = stream_rd_locked.read_exact(&mut frame_buf).await;
+        trace!(
+            target: DISPATCH_TRACE_TARGET,
+            "[{}] frame {}: received ({} bytes)",
+            peer,
+            frame_type,
+            frame_len,
+        );
+        metrics::count_frame_received(frame_type, frame_header_buf.len() + frame_len);
 
-        if read_size.is_err() {
-            return Err(Box::new(read_size.unwrap_err()));
-        } else if read_size.unwrap() != frame_len {
-            return Err(Box::new(EndpointError::new("Peer has closed unexpectedly")));
+        for middleware in middlewares.read().unwrap().iter().rev() {
+            middleware.on_incoming(frame_type, &mut frame_buf);
         }
+        Ok((frame_type, frame_buf, frame_header_buf.len() + frame_len))
+    }
 
-        // Find the first handler that can handle the frame.
-        for handler in handlers {
-            let maybe_result = handler.parse_and_handle_frame(frame_type, frame_buf).await;
+    async fn dispatch_frame(
+        peer: &str,
+        routes: &HashMap<u16, Vec<usize>>,
+        handlers: &mut [Box<dyn AnyFrameHandler + Send>],
+        frame_type: u16,
+        mut frame_buf: Vec<u8>,
+    ) -> Result<(), Error> {
+        // Find the first handler that can handle the frame, only asking those registered for its
+        // type. A handler may still skip a frame it has been routed, e.g. based on the payload.
+        let route = routes
+            .get(&frame_type)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        for &handler_idx in route {
+            let maybe_result = handlers[handler_idx]
+                .parse_and_handle_frame(frame_type, frame_buf, peer)
+                .await;
 
             if let AnyFrameHandlerResult::Skip(buf) = maybe_result {
                 frame_buf = buf;
                 continue;
             } else if let AnyFrameHandlerResult::Err(err) = maybe_result {
-                return Err(err);
+                return Err(Error::Protocol(err.to_string()));
             } else {
                 return Ok(());
             }
         }
 
+        trace!(
+            target: DISPATCH_TRACE_TARGET,
+            "[{}] frame {}: no handler left",
+            peer,
+            frame_type,
+        );
         let msg = format!("No handlers can handle frame: {}", frame_type);
-        return Err(Box::new(EndpointError::new(msg.as_str())));
+        Err(Error::Protocol(msg))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Endpoint, EndpointMiddleware, WRITE_QUEUE_CAPACITY};
+    use crate::error::Error;
+    use crate::proto::FrameHandler;
+
+    use std::io;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+    use icedrop_proto::file_transfer::FileTransferAckFrame;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::runtime::{Builder, Runtime};
+    use tokio::select;
+
+    /// Flips every payload bit, in both directions.
+    struct Inverter;
+
+    impl EndpointMiddleware for Inverter {
+        fn on_outgoing(&self, _frame_type: u16, payload: &mut Vec<u8>) {
+            payload.iter_mut().for_each(|byte| *byte = !*byte);
+        }
+
+        fn on_incoming(&self, _frame_type: u16, payload: &mut Vec<u8>) {
+            payload.iter_mut().for_each(|byte| *byte = !*byte);
+        }
+    }
+
+    struct AckHandler(mpsc::Sender<u32>);
+
+    #[async_trait]
+    impl FrameHandler for AckHandler {
+        type IncomingFrame = FileTransferAckFrame;
+
+        async fn handle_frame(&mut self, frame: Self::IncomingFrame) {
+            self.0.send(frame.segment_idx).unwrap();
+        }
+    }
+
+    #[test]
+    fn middlewares_see_frames_in_both_directions() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let stream = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (mut peer_stream, _) = listener.accept().await.unwrap();
+
+            let mut endpoint = Endpoint::new(stream);
+            endpoint.add_middleware(Box::new(Inverter));
+            let (ack_tx, ack_rx) = mpsc::channel();
+            endpoint.add_handler(AckHandler(ack_tx));
+            let handle = endpoint.handle();
+
+            let exchange = async {
+                // The payload goes out inverted...
+                handle
+                    .send_frame(FileTransferAckFrame { segment_idx: 8 })
+                    .await
+                    .unwrap();
+                let mut buf = [0u8; 10];
+                peer_stream.read_exact(&mut buf).await.unwrap();
+                assert_eq!(buf, [4, 0, 4, 0, 0, 0, !8, !0, !0, !0]);
+
+                // ...and comes back restored.
+                peer_stream.write_all(&buf).await.unwrap();
+                tokio::task::spawn_blocking(move || ack_rx.recv().unwrap())
+                    .await
+                    .unwrap()
+            };
+            select! {
+                result = endpoint.run() => panic!("endpoint stopped: {:?}", result),
+                segment_idx = exchange => assert_eq!(segment_idx, 8),
+            }
+        });
+    }
+
+    #[test]
+    fn slow_peers_fill_the_write_queue() {
+        // A single thread has the writer run only when the test yields.
+        let rt = Builder::new_current_thread().enable_all().build().unwrap();
+        rt.block_on(async {
+            let (stream, mut peer_stream) = tokio::io::duplex(4);
+            let endpoint = Endpoint::with_stream(stream, "peer".to_owned());
+            let handle = endpoint.handle();
+
+            // The writer gets stuck on the first frame, the rest wait in the queue until it's full.
+            let ack = |segment_idx| FileTransferAckFrame { segment_idx };
+            let mut queued = 0;
+            let err = loop {
+                match handle.try_send_frame(ack(queued)) {
+                    Ok(()) => queued += 1,
+                    Err(err) => break err,
+                }
+                tokio::task::yield_now().await;
+            };
+            assert!(queued as usize >= WRITE_QUEUE_CAPACITY);
+            assert!(matches!(err, Error::Io(err) if err.kind() == io::ErrorKind::WouldBlock));
+            let send = tokio::time::timeout(Duration::from_millis(50), handle.send_frame(ack(99)));
+            assert!(send.await.is_err(), "sending didn't wait for room");
+
+            // Once the peer reads, everything goes out in order.
+            let sender = tokio::spawn(async move { handle.send_frame(ack(99)).await });
+            let mut buf = [0u8; 10];
+            for segment_idx in (0..queued).chain([99]) {
+                peer_stream.read_exact(&mut buf).await.unwrap();
+                assert_eq!(buf[6..], segment_idx.to_le_bytes());
+            }
+            sender.await.unwrap().unwrap();
+        });
+    }
+
+    #[test]
+    fn peer_stalling_mid_frame_times_out() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let stream = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (mut peer_stream, _) = listener.accept().await.unwrap();
+
+            let mut endpoint = Endpoint::new(stream);
+            endpoint.set_frame_read_timeout(Some(Duration::from_millis(100)));
+            // Half of a header, then nothing.
+            peer_stream.write_all(&[4, 0, 4]).await.unwrap();
+
+            let result = tokio::time::timeout(Duration::from_secs(5), endpoint.run()).await;
+            let err = result.expect("endpoint kept waiting").unwrap_err();
+            assert!(matches!(err, Error::Io(err) if err.kind() == io::ErrorKind::TimedOut));
+        });
+    }
+
+    #[test]
+    fn slow_frames_are_read_while_data_keeps_coming() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (stream, mut peer_stream) = tokio::io::duplex(64);
+            let mut endpoint = Endpoint::with_stream(stream, "peer".to_owned());
+            endpoint.set_frame_read_timeout(Some(Duration::from_millis(100)));
+            let (ack_tx, ack_rx) = mpsc::channel();
+            endpoint.add_handler(AckHandler(ack_tx));
+
+            // The whole ack takes longer than the timeout, but no gap between bytes does.
+            let trickle = async {
+                for byte in [4, 0, 4, 0, 0, 0, 8, 0, 0, 0] {
+                    peer_stream.write_all(&[byte]).await.unwrap();
+                    tokio::time::sleep(Duration::from_millis(30)).await;
+                }
+                tokio::task::spawn_blocking(move || ack_rx.recv().unwrap())
+                    .await
+                    .unwrap()
+            };
+            select! {
+                result = endpoint.run() => panic!("endpoint stopped: {:?}", result),
+                segment_idx = trickle => assert_eq!(segment_idx, 8),
+            }
+        });
+    }
+
+    #[test]
+    fn overlong_frames_are_refused() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (stream, mut peer_stream) = tokio::io::duplex(64);
+            let endpoint = Endpoint::with_stream(stream, "peer".to_owned());
+            // An ack claiming to be 4 GiB long.
+            peer_stream
+                .write_all(&[4, 0, 0xff, 0xff, 0xff, 0xff])
+                .await
+                .unwrap();
+
+            let err = endpoint.run().await.unwrap_err();
+            assert!(matches!(err, Error::Protocol(msg) if msg.contains("too long")));
+        });
+    }
+
+    #[test]
+    fn silent_peer_times_out_after_pings() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let stream = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (mut peer_stream, _) = listener.accept().await.unwrap();
+
+            let mut endpoint = Endpoint::new(stream);
+            endpoint.set_keepalive(Some(Duration::from_millis(20)), Duration::from_millis(200));
+            endpoint.handle().set_protocol_version(7);
+
+            let result = tokio::time::timeout(Duration::from_secs(5), endpoint.run()).await;
+            let err = result.expect("endpoint kept waiting").unwrap_err();
+            assert!(matches!(err, Error::Timeout(_)));
+
+            // The peer has been pinged in the meantime.
+            let mut ping = [0u8; 12];
+            peer_stream.read_exact(&mut ping).await.unwrap();
+            assert_eq!(ping, [13, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        });
     }
 }
@@ -0,0 +1,83 @@
+//! Why a connection or a transfer failed.
+
+use std::fmt::Display;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use icedrop_proto::file_transfer::RejectionReason;
+use icedrop_proto::transfer::SessionError;
+
+use crate::connect::ConnectError;
+
+/// Why a connection or a transfer failed, as reported in
+/// [`TransferSummary::error`](crate::TransferSummary::error).
+#[derive(Debug, Clone)]
+pub enum Error {
+    /// Reading from or writing to the connection failed, e.g. because the peer went away.
+    Io(Arc<io::Error>),
+    /// The peer didn't follow the protocol, with what it did wrong.
+    Protocol(String),
+    /// The peers couldn't agree on how to talk to each other, e.g. because pairing failed.
+    Handshake(String),
+    /// The session was given up on this end before it was over, e.g. because the user didn't
+    /// enter a pairing code.
+    Cancelled,
+    /// Nothing has been heard from the peer for the given time.
+    Timeout(Duration),
+    /// The receiver turned the session down or gave up on it, e.g. because it declined a file or
+    /// its disk is full, with its message and whether trying again later may succeed. Receivers
+    /// speaking protocol version 13 or newer tell why they turned a file down, but not why they
+    /// gave up on one halfway.
+    Rejected {
+        retryable: bool,
+        message: String,
+        reason: Option<RejectionReason>,
+    },
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(err) => err.fmt(f),
+            Self::Protocol(message) => write!(f, "protocol error: {}", message),
+            Self::Handshake(message) => write!(f, "handshake failed: {}", message),
+            Self::Cancelled => f.write_str("cancelled"),
+            Self::Timeout(timeout) => write!(f, "peer has been silent for {:?}", timeout),
+            Self::Rejected { message, .. } => write!(f, "rejected by the receiver: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(err) => Some(err.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Self::Io(Arc::new(err))
+    }
+}
+
+impl From<SessionError> for Error {
+    fn from(err: SessionError) -> Self {
+        Self::Protocol(err.to_string())
+    }
+}
+
+impl From<ConnectError> for Error {
+    /// Keeps the kind of the last error connecting ran into, with the whole story as message.
+    fn from(err: ConnectError) -> Self {
+        let kind = match (&err.resolve_error, err.attempts.last()) {
+            (Some(resolve_error), _) => resolve_error.kind(),
+            (None, Some(attempt)) => attempt.error.kind(),
+            (None, None) => io::ErrorKind::NotFound,
+        };
+        io::Error::new(kind, err.to_string()).into()
+    }
+}
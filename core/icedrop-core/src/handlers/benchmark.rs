@@ -0,0 +1,315 @@
+use crate::endpoint::EndpointHandle;
+use crate::proto::FrameHandler;
+
+use std::error::Error;
+use std::fmt::Display;
+use std::sync::{Arc, Mutex};
+use std::time::{self, Duration};
+
+use async_trait::async_trait;
+use icedrop_proto::benchmark::{BenchmarkFrame, BENCHMARK_PROTOCOL_VERSION};
+use icedrop_proto::def_frame_selector;
+use icedrop_proto::file_transfer::FileTransferErrorFrame;
+use icedrop_proto::handshake::HandshakeResponseFrame;
+use icedrop_proto::transfer::SEGMENT_SIZE;
+use tokio::select;
+
+def_frame_selector!(
+    BenchmarkNextFrame,
+    HandshakeResponseFrame,
+    BenchmarkFrame,
+    FileTransferErrorFrame
+);
+
+/// What a finished benchmark measured.
+#[derive(Debug, Clone, Copy)]
+pub struct BenchmarkReport {
+    /// From the first frame sent to the receiver echoing the end of the benchmark.
+    pub duration: Duration,
+    pub bytes_sent: u64,
+    /// Bytes of the frames the receiver echoed.
+    pub bytes_received: u64,
+    /// Bytes per second the receiver got.
+    pub throughput: f64,
+    pub frames_sent: u32,
+    /// Frames the receiver never echoed.
+    pub frames_lost: u32,
+    pub rtt_min: Duration,
+    pub rtt_avg: Duration,
+    pub rtt_max: Duration,
+}
+
+/// Why a benchmark could not be completed.
+#[derive(Debug)]
+pub enum BenchmarkError {
+    /// The receiver speaks the given protocol version, which has no benchmarks.
+    Unsupported(u16),
+    /// The receiver doesn't take part in benchmarks, with its message.
+    Refused(String),
+    /// The connection broke down before the benchmark was over.
+    Disconnected(String),
+}
+
+impl Display for BenchmarkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unsupported(protocol_version) => write!(
+                f,
+                "receiver speaks protocol version {}, which has no benchmarks",
+                protocol_version
+            ),
+            Self::Refused(message) => write!(f, "receiver refused the benchmark: {}", message),
+            Self::Disconnected(message) => write!(f, "benchmark interrupted: {}", message),
+        }
+    }
+}
+
+impl Error for BenchmarkError {}
+
+pub(crate) type BenchmarkOutcome = Arc<Mutex<Option<Result<BenchmarkReport, BenchmarkError>>>>;
+
+/// Send times and round trips of the benchmark frames, indexed by their sequence number.
+struct BenchmarkStats {
+    started: time::Instant,
+    /// When each frame was sent and how much data it carried.
+    sent: Vec<(time::Instant, usize)>,
+    /// The sequence number of the frame ending the benchmark, once it has been sent.
+    end_seq: Option<u32>,
+    frames_echoed: u32,
+    bytes_received: u64,
+    rtt_sum: Duration,
+    rtt_min: Duration,
+    rtt_max: Duration,
+}
+
+impl BenchmarkStats {
+    fn new() -> Self {
+        Self {
+            started: time::Instant::now(),
+            sent: Vec::new(),
+            end_seq: None,
+            frames_echoed: 0,
+            bytes_received: 0,
+            rtt_sum: Duration::ZERO,
+            rtt_min: Duration::MAX,
+            rtt_max: Duration::ZERO,
+        }
+    }
+
+    /// Records a frame about to be sent, and returns its sequence number.
+    fn frame_sent(&mut self, data_len: usize) -> u32 {
+        let now = time::Instant::now();
+        if self.sent.is_empty() {
+            self.started = now;
+        }
+        self.sent.push((now, data_len));
+        if data_len == 0 {
+            self.end_seq = Some(self.sent.len() as u32 - 1);
+        }
+        self.sent.len() as u32 - 1
+    }
+
+    /// Records the receiver echoing frame `seq`, returns whether it ended the benchmark.
+    fn frame_echoed(&mut self, seq: u32, now: time::Instant) -> bool {
+        let (sent_at, data_len) = match self.sent.get(seq as usize) {
+            Some(&sent) => sent,
+            None => return false,
+        };
+        let rtt = now - sent_at;
+        self.rtt_sum += rtt;
+        self.rtt_min = self.rtt_min.min(rtt);
+        self.rtt_max = self.rtt_max.max(rtt);
+
+        if self.end_seq == Some(seq) {
+            return true;
+        }
+        self.frames_echoed += 1;
+        self.bytes_received += data_len as u64;
+        false
+    }
+
+    fn report(&self, now: time::Instant) -> BenchmarkReport {
+        let duration = now - self.started;
+        let frames_sent = self.end_seq.unwrap_or(self.sent.len() as u32);
+        let round_trips = self.frames_echoed + self.end_seq.map_or(0, |_| 1);
+        BenchmarkReport {
+            duration,
+            bytes_sent: self.sent.iter().map(|&(_, data_len)| data_len as u64).sum(),
+            bytes_received: self.bytes_received,
+            throughput: self.bytes_received as f64 / duration.as_secs_f64(),
+            frames_sent,
+            frames_lost: frames_sent - self.frames_echoed,
+            rtt_min: self.rtt_min.min(self.rtt_max),
+            rtt_avg: self.rtt_sum.checked_div(round_trips).unwrap_or_default(),
+            rtt_max: self.rtt_max,
+        }
+    }
+}
+
+/// Streams generated data to the receiver for a while once the handshake is done, and measures
+/// how fast it gets there.
+pub struct BenchmarkHandler {
+    endpoint_handle: EndpointHandle,
+    duration: Duration,
+    stats: Arc<Mutex<BenchmarkStats>>,
+    outcome: BenchmarkOutcome,
+}
+
+impl BenchmarkHandler {
+    pub fn new(
+        endpoint_handle: EndpointHandle,
+        duration: Duration,
+        outcome: BenchmarkOutcome,
+    ) -> Self {
+        Self {
+            endpoint_handle,
+            duration,
+            stats: Arc::new(Mutex::new(BenchmarkStats::new())),
+            outcome,
+        }
+    }
+
+    /// Sends full segments of filler data until `duration` has passed, then the end of the
+    /// benchmark.
+    async fn stream(handle: EndpointHandle, stats: Arc<Mutex<BenchmarkStats>>, duration: Duration) {
+        let deadline = time::Instant::now() + duration;
+        loop {
+            let data_len = if time::Instant::now() < deadline {
+                SEGMENT_SIZE
+            } else {
+                0
+            };
+            let seq = stats.lock().unwrap().frame_sent(data_len);
+            let frame = BenchmarkFrame {
+                seq,
+                data: vec![0; data_len],
+            };
+            let sent = select! {
+                _ = handle.closed() => { false },
+                result = handle.send_frame(frame) => { result.is_ok() },
+            };
+            if !sent || data_len == 0 {
+                return;
+            }
+        }
+    }
+
+    async fn finish(&self, result: Result<BenchmarkReport, BenchmarkError>) {
+        *self.outcome.lock().unwrap() = Some(result);
+        self.endpoint_handle.shutdown().await.ok();
+    }
+}
+
+#[async_trait]
+impl FrameHandler for BenchmarkHandler {
+    type IncomingFrame = BenchmarkNextFrame;
+
+    async fn handle_frame(&mut self, frame: Self::IncomingFrame) {
+        if let BenchmarkNextFrame::BenchmarkFrame(frame) = frame {
+            let now = time::Instant::now();
+            let report = {
+                let mut stats = self.stats.lock().unwrap();
+                if stats.frame_echoed(frame.seq, now) {
+                    Some(stats.report(now))
+                } else {
+                    None
+                }
+            };
+            if let Some(report) = report {
+                self.finish(Ok(report)).await;
+            }
+        } else if let BenchmarkNextFrame::FileTransferErrorFrame(frame) = frame {
+            self.finish(Err(BenchmarkError::Refused(frame.message)))
+                .await;
+        } else if let BenchmarkNextFrame::HandshakeResponseFrame(frame) = frame {
+            let protocol_version = frame.protocol_version;
+            if protocol_version < BENCHMARK_PROTOCOL_VERSION {
+                return self
+                    .finish(Err(BenchmarkError::Unsupported(protocol_version)))
+                    .await;
+            }
+            self.endpoint_handle.set_protocol_version(protocol_version);
+
+            tokio::runtime::Handle::current().spawn(Self::stream(
+                self.endpoint_handle.clone(),
+                Arc::clone(&self.stats),
+                self.duration,
+            ));
+        }
+    }
+}
+
+/// Echoes the frames of a benchmark back to its sender, if benchmarks are accepted.
+pub struct BenchmarkEchoHandler {
+    endpoint_handle: EndpointHandle,
+    accept: bool,
+}
+
+impl BenchmarkEchoHandler {
+    pub fn new(endpoint_handle: EndpointHandle, accept: bool) -> Self {
+        Self {
+            endpoint_handle,
+            accept,
+        }
+    }
+}
+
+#[async_trait]
+impl FrameHandler for BenchmarkEchoHandler {
+    type IncomingFrame = BenchmarkFrame;
+
+    async fn handle_frame(&mut self, frame: Self::IncomingFrame) {
+        if !self.accept {
+            println!("refusing a benchmark");
+            self.endpoint_handle
+                .send_frame(FileTransferErrorFrame {
+                    retryable: false,
+                    message: "benchmarks are not accepted".to_owned(),
+                })
+                .await
+                .ok();
+            self.endpoint_handle.shutdown().await.ok();
+            return;
+        }
+
+        let end = frame.data.is_empty();
+        let echo = BenchmarkFrame {
+            seq: frame.seq,
+            data: Vec::new(),
+        };
+        self.endpoint_handle.send_frame(echo).await.unwrap();
+        if end {
+            println!("benchmark finished after {} frames", frame.seq);
+            self.endpoint_handle.shutdown().await.ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BenchmarkStats;
+
+    use std::time::{self, Duration};
+
+    #[test]
+    fn stats_count_frames_never_echoed_as_lost() {
+        let mut stats = BenchmarkStats::new();
+        for _ in 0..3 {
+            stats.frame_sent(100);
+        }
+        let end_seq = stats.frame_sent(0);
+        let now = time::Instant::now() + Duration::from_millis(10);
+
+        assert!(!stats.frame_echoed(0, now));
+        assert!(!stats.frame_echoed(2, now));
+        assert!(stats.frame_echoed(end_seq, now));
+
+        let report = stats.report(now);
+        assert_eq!(report.frames_sent, 3);
+        assert_eq!(report.frames_lost, 1);
+        assert_eq!(report.bytes_sent, 300);
+        assert_eq!(report.bytes_received, 200);
+        assert!(report.rtt_min >= Duration::from_millis(10));
+        assert!(report.rtt_min <= report.rtt_avg && report.rtt_avg <= report.rtt_max);
+    }
+}
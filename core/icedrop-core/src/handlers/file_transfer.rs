@@ -1,55 +1,115 @@
-use super::handshake::HandshakeResponseFrame;
-use super::session::EndSessionFrame;
-use super::utils::def_frame_selector;
+use crate::control::ControlWatch;
+use crate::delegate::{Decision, ServerDelegateRef, TransferOffer, TransferSink};
 use crate::endpoint::EndpointHandle;
-use crate::proto::{Frame, FrameHandler, FrameParsingResult};
+use crate::error::Error;
+use crate::history::{HistoryEntry, HistoryStore, TransferDirection, TransferStatus};
+use crate::metrics;
+use crate::pairing::{
+    random_bytes, random_pairing_code, PairingCodeCallbackFn, PairingPromptFn, PairingRequest,
+    PairingStore,
+};
+use crate::policy::{AdmittedTransfer, ReceiveLimits};
+use crate::proto::FrameHandler;
 
-use std::path::Path;
-use std::time;
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, SeekFrom};
+use std::mem;
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{self, Duration, SystemTime};
 
 use async_trait::async_trait;
-use byteorder::{ByteOrder, LittleEndian};
+use icedrop_proto::auth::{
+    auth_proof, format_pairing_code, parse_pairing_code, proofs_match, AuthChallengeFrame,
+    AuthCredential, AuthRequestFrame, DeviceId, PairedFrame,
+};
+use icedrop_proto::compression::CompressionMode;
+use icedrop_proto::def_frame_selector;
+use icedrop_proto::file_transfer::{
+    FileTransferAckFrame, FileTransferBeginFrame, FileTransferCompleteFrame, FileTransferDataFrame,
+    FileTransferErrorFrame, FileTransferMetadataFrame, FileTransferRetransmitFrame,
+    FileTransferSlowDownFrame, FileTransferStripeFrame, FileTransferVerifyFrame, RejectionReason,
+    TransferRejectedFrame, REJECTION_PROTOCOL_VERSION,
+};
+use icedrop_proto::handshake::{Capabilities, HandshakeRequestFrame, HandshakeResponseFrame};
+use icedrop_proto::session::EndSessionFrame;
+use icedrop_proto::text::TextMessageFrame;
+use icedrop_proto::transfer::{
+    stripe_range, ReceiverAction, ReceiverSession, SenderSession, SessionError, SessionState,
+    TransferConfig, VerificationMode,
+};
+use serde::{Deserialize, Serialize};
 use tokio::{
-    fs::File,
-    io::{AsyncReadExt, AsyncWriteExt},
+    fs::{File, OpenOptions},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt},
+    select,
+    sync::{watch, Mutex as AsyncMutex, Notify},
+    task::JoinHandle,
 };
+use tracing::Instrument;
 
-#[derive(Debug)]
-pub struct FileTransferAckFrame {
-    segment_idx: u32,
-}
+/// The name files get when the sender doesn't name them.
+pub(crate) const DEFAULT_FILE_NAME: &str = "test";
 
-#[async_trait]
-impl Frame for FileTransferAckFrame {
-    fn frame_type(&self) -> u16 {
-        return 4;
-    }
+/// Writing a segment to disk taking longer than this means the receiver's disk can't keep up.
+const SLOW_WRITE_THRESHOLD: Duration = Duration::from_millis(200);
 
-    fn try_parse(frame_type: u16, buf: Vec<u8>) -> FrameParsingResult<Self> {
-        if frame_type != 4 {
-            return FrameParsingResult::Skip(buf);
-        }
+/// Minimum time between two slow-down requests from the receiver.
+const SLOW_DOWN_INTERVAL: Duration = Duration::from_secs(1);
 
-        let segment_idx = LittleEndian::read_u32(&buf);
+/// How many numbered names, like `file (1).ext`, the receiver tries when a file already exists.
+const MAX_FILE_NAME_COLLISIONS: u32 = 1000;
 
-        FrameParsingResult::Ok(FileTransferAckFrame { segment_idx })
-    }
+/// What's appended to the name of a file while it's being received, see
+/// [`ServerBuilder::atomic_writes`](crate::ServerBuilder::atomic_writes).
+const PARTIAL_FILE_SUFFIX: &str = ".icedrop.partial";
 
-    fn to_bytes(self) -> Vec<u8> {
-        let mut segment_idx_buf = [0 as u8; 4];
-        LittleEndian::write_u32(&mut segment_idx_buf, self.segment_idx);
+/// What's appended to the name of a partial file for the sidecar recording how far its transfer
+/// got.
+const PARTIAL_STATE_SUFFIX: &str = ".state";
 
-        let mut buf = Vec::<u8>::with_capacity(4);
-        buf.extend(segment_idx_buf);
+/// How many times the receiver retries a failed disk write before giving up on the transfer.
+const DISK_WRITE_MAX_RETRIES: u32 = 5;
 
-        buf
-    }
-}
+/// Delay before the first disk write retry, doubled on every further attempt.
+const DISK_WRITE_RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Files are only split into stripes at least this many segments long, so smaller files go over
+/// fewer connections.
+const MIN_STRIPE_SEGMENTS: u64 = 8;
 
 def_frame_selector!(
     FileTransferNextFrame,
     HandshakeResponseFrame,
-    FileTransferAckFrame
+    AuthChallengeFrame,
+    PairedFrame,
+    FileTransferAckFrame,
+    FileTransferSlowDownFrame,
+    FileTransferErrorFrame,
+    TransferRejectedFrame,
+    FileTransferRetransmitFrame,
+    FileTransferCompleteFrame,
+    EndSessionFrame
+);
+
+def_frame_selector!(
+    FileTransferReceivingFrame,
+    HandshakeRequestFrame,
+    AuthRequestFrame,
+    FileTransferStripeFrame,
+    FileTransferBeginFrame,
+    FileTransferMetadataFrame,
+    FileTransferDataFrame,
+    FileTransferVerifyFrame,
+    TextMessageFrame,
+    EndSessionFrame
 );
 
 def_frame_selector!(
@@ -58,223 +118,2466 @@ def_frame_selector!(
     EndSessionFrame
 );
 
-#[derive(Debug)]
-pub struct FileTransferDataFrame {
-    segment_idx: u32,
-    chunk_size: u32,
-    data: Vec<u8>,
+pub enum FileTransferEvent {
+    /// The receiver answered the handshake, sending starts.
+    Started,
+    SegmentSent(u32, usize),
+    Progress(TransferProgress),
+    /// The receiver hasn't acked anything for the given duration.
+    Stalled(Duration),
+    /// The receiver's disk is the bottleneck, writing a segment takes the given duration.
+    ReceiverDiskSlow(Duration),
+    /// The receiver failed to write the file, or found it corrupted, and aborted the transfer.
+    ReceiverDiskError {
+        retryable: bool,
+        message: String,
+    },
+    MetricsSnapshot(MetricsSnapshot),
+    /// The receiver has written one of the files, `transfer_id` is its position in the queue.
+    FileComplete {
+        transfer_id: u32,
+        file_name: String,
+    },
+    Complete,
+}
+
+/// How far sending the current file has come, reported whenever it changes.
+#[derive(Debug, Clone, Copy)]
+pub struct TransferProgress {
+    /// Bytes of the file written to the connection.
+    pub bytes_sent: u64,
+    /// Bytes of the file the receiver confirmed to have written. Only these tell how much of the
+    /// file has actually arrived.
+    pub bytes_confirmed: u64,
+    /// The size of the file, unless it couldn't be read.
+    pub total_bytes: Option<u64>,
+    /// Bytes per second the receiver confirmed since the file started.
+    pub throughput: f64,
+    /// Time left until the receiver has the whole file at the current throughput, once known.
+    pub eta: Option<Duration>,
 }
 
-#[async_trait]
-impl Frame for FileTransferDataFrame {
-    fn frame_type(&self) -> u16 {
-        return 3;
+impl TransferProgress {
+    /// The share of the file the receiver confirmed, from 0 to 100.
+    pub fn percentage(&self) -> Option<f64> {
+        self.total_bytes.map(|total_bytes| match total_bytes {
+            0 => 100.0,
+            _ => self.bytes_confirmed as f64 * 100.0 / total_bytes as f64,
+        })
+    }
+}
+
+/// A point-in-time view of a running transfer, reported periodically while sending.
+#[derive(Debug, Clone, Copy)]
+pub struct MetricsSnapshot {
+    /// Total bytes handed to the connection so far.
+    pub bytes_sent: u64,
+    /// Bytes the receiver confirmed to have written.
+    pub bytes_acked: u64,
+    pub segments_sent: u32,
+    /// Segments the receiver confirmed to have written.
+    pub segments_acked: u32,
+    /// Segments sent but not acked yet.
+    pub segments_in_flight: u32,
+    /// Bytes per second sent since the previous snapshot.
+    pub throughput: f64,
+    pub since_last_ack: Duration,
+}
+
+/// What a session amounted to, reported once it's over.
+#[derive(Debug, Clone)]
+pub struct TransferSummary {
+    /// Whether every file has been received and the session was closed normally.
+    pub success: bool,
+    /// Names of the files the receiver confirmed, in order.
+    pub files: Vec<String>,
+    /// Bytes the receiver confirmed to have written, over all files.
+    pub bytes: u64,
+    pub duration: Duration,
+    /// Bytes per second over the whole session.
+    pub average_speed: f64,
+    /// Highest rate, in bytes per second, the receiver confirmed data at between two acks.
+    pub peak_speed: f64,
+    /// SHA-256 digest of the last file confirmed, only computed with [`VerificationMode::Full`].
+    pub sha256: Option<[u8; 32]>,
+    /// Segments the receiver asked for again.
+    pub retransmits: u32,
+    /// Why the session failed, `None` if it succeeded.
+    pub error: Option<Error>,
+}
+
+/// A file the receiver confirmed, as the history of the session records it.
+struct CompletedFile {
+    file_name: String,
+    size: u64,
+    duration: Duration,
+    sha256: Option<[u8; 32]>,
+    finished_at: SystemTime,
+}
+
+/// Running totals of a session, the source of its [`TransferSummary`].
+#[derive(Default)]
+struct TransferTally {
+    started: Option<time::Instant>,
+    finished: Option<time::Instant>,
+    success: bool,
+    files: Vec<String>,
+    completed_files: Vec<CompletedFile>,
+    /// The file being sent and when sending it started, until the receiver confirmed it.
+    current_file: Option<(String, time::Instant)>,
+    /// Bytes of the files completed so far.
+    bytes_completed: u64,
+    /// Bytes of the current file acked so far.
+    bytes_acked: u64,
+    /// When the last ack arrived, and the bytes confirmed in total then.
+    last_ack: Option<(time::Instant, u64)>,
+    peak_speed: f64,
+    sha256: Option<[u8; 32]>,
+    retransmits: u32,
+    error: Option<Error>,
+}
+
+impl TransferTally {
+    fn start(&mut self) {
+        let now = time::Instant::now();
+        self.started = Some(now);
+        self.last_ack = Some((now, 0));
     }
 
-    fn try_parse(frame_type: u16, mut buf: Vec<u8>) -> FrameParsingResult<Self> {
-        if frame_type != 3 {
-            return FrameParsingResult::Skip(buf);
+    fn acked(&mut self, bytes_acked: u64) {
+        self.bytes_acked = bytes_acked;
+        let now = time::Instant::now();
+        let bytes = self.bytes_completed + bytes_acked;
+        if let Some((last_ack_timestamp, last_bytes)) = self.last_ack {
+            let elapsed = (now - last_ack_timestamp).as_secs_f64();
+            if elapsed > 0.0 {
+                let speed = (bytes - last_bytes) as f64 / elapsed;
+                self.peak_speed = self.peak_speed.max(speed);
+            }
         }
+        self.last_ack = Some((now, bytes));
+    }
+
+    fn begin_file(&mut self, file_name: &str) {
+        self.current_file = Some((file_name.to_owned(), time::Instant::now()));
+    }
 
-        let segment_idx = LittleEndian::read_u32(&buf[0..4]);
-        let chunk_size = LittleEndian::read_u32(&buf[4..8]) as usize;
+    fn file_completed(&mut self, file_name: String, file_size: u64, sha256: Option<[u8; 32]>) {
+        metrics::count_file(TransferDirection::Sent);
+        tracing::info!(file = %file_name, size = file_size, "file sent");
+        let started = self.current_file.take().map(|(_, started)| started);
+        self.completed_files.push(CompletedFile {
+            file_name: file_name.clone(),
+            size: file_size,
+            duration: started.map_or(Duration::ZERO, |started| started.elapsed()),
+            sha256,
+            finished_at: SystemTime::now(),
+        });
+        self.files.push(file_name);
+        self.bytes_completed += file_size;
+        self.bytes_acked = 0;
+        self.last_ack = Some((time::Instant::now(), self.bytes_completed));
+        self.sha256 = sha256;
+    }
 
-        let mut data = buf.split_off(8);
-        data.resize(chunk_size, 0);
+    fn complete(&mut self) {
+        self.success = true;
+        self.finished = Some(time::Instant::now());
+    }
 
-        FrameParsingResult::Ok(FileTransferDataFrame {
-            segment_idx,
-            chunk_size: chunk_size as u32,
-            data,
-        })
+    /// Records why the session failed, unless an earlier failure led to this one.
+    fn fail(&mut self, err: Error) {
+        self.error.get_or_insert(err);
+    }
+
+    fn summary(&self) -> TransferSummary {
+        let finished = self.finished.unwrap_or_else(time::Instant::now);
+        let duration = self
+            .started
+            .map_or(Duration::ZERO, |started| finished - started);
+        let bytes = self.bytes_completed + self.bytes_acked;
+        let average_speed = if duration.is_zero() {
+            0.0
+        } else {
+            bytes as f64 / duration.as_secs_f64()
+        };
+        TransferSummary {
+            success: self.success,
+            files: self.files.clone(),
+            bytes,
+            duration,
+            average_speed,
+            // A single burst of acks can't be faster than the session as a whole was.
+            peak_speed: self.peak_speed.max(average_speed),
+            sha256: self.sha256,
+            retransmits: self.retransmits,
+            error: self.error.clone(),
+        }
     }
 
-    fn to_bytes(self) -> Vec<u8> {
-        let mut segment_idx_buf = [0 as u8; 4];
-        LittleEndian::write_u32(&mut segment_idx_buf, self.segment_idx);
+    /// The files of the session as entries of its history, along with the one it failed on with
+    /// `error`, if it did.
+    fn history(&self, peer: &str, error: Option<&Error>) -> Vec<HistoryEntry> {
+        let mut entries: Vec<_> = self
+            .completed_files
+            .iter()
+            .map(|file| HistoryEntry {
+                finished_at: file.finished_at,
+                direction: TransferDirection::Sent,
+                peer: peer.to_owned(),
+                file_name: file.file_name.clone(),
+                size: file.size,
+                duration: file.duration,
+                status: TransferStatus::Completed,
+                sha256: file.sha256,
+            })
+            .collect();
+        if let (Some((file_name, started)), Some(error)) = (&self.current_file, error) {
+            entries.push(HistoryEntry {
+                finished_at: SystemTime::now(),
+                direction: TransferDirection::Sent,
+                peer: peer.to_owned(),
+                file_name: file_name.clone(),
+                size: self.bytes_acked,
+                duration: started.elapsed(),
+                status: TransferStatus::Failed(error.to_string()),
+                sha256: None,
+            });
+        }
+        entries
+    }
+}
 
-        let mut chunk_size_buf = [0 as u8; 4];
-        LittleEndian::write_u32(&mut chunk_size_buf, self.chunk_size);
+type FileTransferCallbackFn = Box<dyn Fn(FileTransferEvent) + Send>;
 
-        let mut buf = Vec::<u8>::with_capacity(8 + self.data.len());
-        buf.extend(segment_idx_buf);
-        buf.extend(chunk_size_buf);
-        buf.extend(self.data);
+/// The file being sent: its size, if known, and when sending it started.
+struct CurrentFile {
+    size: Option<u64>,
+    started: time::Instant,
+}
 
-        buf
+impl Default for CurrentFile {
+    fn default() -> Self {
+        Self {
+            size: None,
+            started: time::Instant::now(),
+        }
     }
 }
 
-pub enum FileTransferEvent {
-    SegmentSent(u32, usize),
-    Complete,
+/// A file shared by the connections sending its stripes.
+type SharedFile = Arc<AsyncMutex<File>>;
+
+/// How far every stripe of a file has come, indexed by stripe.
+struct StripeProgress {
+    bytes_sent: Vec<AtomicU64>,
+    bytes_acked: Vec<AtomicU64>,
+}
+
+impl StripeProgress {
+    fn new(stripe_count: u32) -> Self {
+        Self {
+            bytes_sent: (0..stripe_count).map(|_| AtomicU64::new(0)).collect(),
+            bytes_acked: (0..stripe_count).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    /// The bytes sent and acked over all stripes.
+    fn totals(&self) -> (u64, u64) {
+        let sum = |counters: &[AtomicU64]| {
+            counters
+                .iter()
+                .map(|counter| counter.load(Ordering::SeqCst))
+                .sum()
+        };
+        (sum(&self.bytes_sent), sum(&self.bytes_acked))
+    }
+}
+
+/// One of the stripes a file is split into to be sent over several connections at once, see
+/// [`TransferConfig::parallel_streams`].
+#[derive(Clone)]
+pub(crate) struct Stripe {
+    file: SharedFile,
+    group_id: u64,
+    stripe_idx: u32,
+    stripe_count: u32,
+    file_size: u64,
+    progress: Arc<StripeProgress>,
+    /// When any stripe of the file was last acked, so a connection done with its own stripe
+    /// doesn't look stalled while it waits for the others.
+    last_ack_timestamp: Arc<Mutex<time::Instant>>,
+}
+
+impl Stripe {
+    /// The bytes of the file the stripe carries.
+    fn range(&self) -> Range<u64> {
+        stripe_range(self.file_size, self.stripe_idx, self.stripe_count)
+    }
+}
+
+/// Sends a stripe of a file over a connection of its own, resolving once the receiver got it or
+/// to why it didn't.
+type StripeTask = JoinHandle<Result<(), Error>>;
+
+/// Starts a [`StripeTask`] for a stripe of the named file.
+pub(crate) type StripeSenderFn = Arc<dyn Fn(String, Stripe) -> StripeTask + Send + Sync>;
+
+/// A file waiting to be sent, or a stripe of one, with the name the receiver stores it as.
+enum QueuedFile {
+    Whole(String, File),
+    Stripe(String, Stripe),
+}
+
+/// What the sending task works through.
+struct SendQueue {
+    files: VecDeque<QueuedFile>,
+    /// Set if large files may be split into stripes sent over connections of their own.
+    stripe_sender: Option<StripeSenderFn>,
+    last_ack_timestamp: Arc<Mutex<time::Instant>>,
+    /// Pauses sending, set if the session may be paused or cancelled.
+    control: Option<ControlWatch>,
+}
+
+/// Progress counters shared between the handler and its sending task.
+#[derive(Default)]
+struct TransferCounters {
+    bytes_sent: AtomicU64,
+    bytes_acked: AtomicU64,
+    segments_sent: AtomicU32,
+    segments_acked: AtomicU32,
+    current_file: Mutex<CurrentFile>,
+    /// Set while the file being sent is a stripe of a larger one.
+    stripe: Mutex<Option<Stripe>>,
+    tally: Mutex<TransferTally>,
+}
+
+impl TransferCounters {
+    /// Copies the counters of the stripe being sent over to the progress of the whole file.
+    fn share_stripe_progress(&self) {
+        if let Some(stripe) = &*self.stripe.lock().unwrap() {
+            let idx = stripe.stripe_idx as usize;
+            let bytes_sent = self.bytes_sent.load(Ordering::SeqCst);
+            stripe.progress.bytes_sent[idx].store(bytes_sent, Ordering::SeqCst);
+            let bytes_acked = self.bytes_acked.load(Ordering::SeqCst);
+            stripe.progress.bytes_acked[idx].store(bytes_acked, Ordering::SeqCst);
+        }
+    }
+
+    /// The progress of the file being sent, over all of its stripes if it has been split up.
+    fn progress(&self) -> TransferProgress {
+        let current_file = self.current_file.lock().unwrap();
+        let (bytes_sent, bytes_confirmed) = match &*self.stripe.lock().unwrap() {
+            Some(stripe) => stripe.progress.totals(),
+            None => (
+                self.bytes_sent.load(Ordering::SeqCst),
+                self.bytes_acked.load(Ordering::SeqCst),
+            ),
+        };
+        let elapsed = current_file.started.elapsed().as_secs_f64();
+        let throughput = if elapsed > 0.0 {
+            bytes_confirmed as f64 / elapsed
+        } else {
+            0.0
+        };
+        let eta = current_file.size.filter(|_| throughput > 0.0).map(|size| {
+            Duration::from_secs_f64(size.saturating_sub(bytes_confirmed) as f64 / throughput)
+        });
+        TransferProgress {
+            bytes_sent,
+            bytes_confirmed,
+            total_bytes: current_file.size,
+            throughput,
+            eta,
+        }
+    }
+}
+
+/// What a sender authenticates with to receivers requiring pairing.
+#[derive(Clone)]
+pub(crate) struct SenderPairing {
+    pub(crate) store: Arc<PairingStore>,
+    pub(crate) prompt: PairingPromptFn,
+}
+
+impl SenderPairing {
+    /// Answers a receiver's challenge with proof of the key shared with it if there is one.
+    /// Otherwise asks it for a pairing code, and the user for the code it shows once it does.
+    /// `None` if the user gave up on entering the code.
+    pub(crate) async fn answer(&self, challenge: &AuthChallengeFrame) -> Option<AuthRequestFrame> {
+        let device_id = self.store.device_id();
+        let credential = if challenge.code_required {
+            let prompt = Arc::clone(&self.prompt);
+            let input = tokio::task::spawn_blocking(move || prompt())
+                .await
+                .ok()
+                .flatten();
+            AuthCredential::Code(input.as_deref().and_then(parse_pairing_code)?)
+        } else {
+            match self.store.key(&challenge.receiver_id) {
+                Some(key) => AuthCredential::Proof(auth_proof(
+                    &key,
+                    &challenge.receiver_id,
+                    &device_id,
+                    &challenge.nonce,
+                )),
+                None => AuthCredential::None,
+            }
+        };
+        Some(AuthRequestFrame {
+            device_id,
+            credential,
+        })
+    }
+
+    /// Keeps the key the receiver `receiver_id` handed out once pairing with it succeeded.
+    pub(crate) fn paired(&self, receiver_id: &DeviceId, frame: PairedFrame) {
+        match self.store.pair(receiver_id, None, &frame.key) {
+            Ok(()) => println!("paired with the receiver"),
+            Err(err) => println!("could not store the pairing: {}", err),
+        }
+    }
+}
+
+/// How fast the sending task may go.
+#[derive(Default)]
+struct Pacing {
+    /// The delay between segments, set when the receiver's disk can't keep up.
+    delay_ms: AtomicU32,
+    /// Wakes the sending task up when acks make room in the send window.
+    window_opened: Notify,
 }
 
 pub struct FileTransferNextHandler {
     endpoint_handle: EndpointHandle,
-    file: Option<File>,
-    cur_segment: u32,
-    callback_fn: Option<Box<dyn Fn(FileTransferEvent) + Send>>,
+    /// The files to send, in order.
+    files: VecDeque<QueuedFile>,
+    stripe_sender: Option<StripeSenderFn>,
+    session: Arc<Mutex<SenderSession>>,
+    /// Wakes the sending task up when the receiver asks for segments again or confirms the file,
+    /// after the end of the file has been sent.
+    after_eof: Arc<Notify>,
+    callback_fn: Arc<Mutex<Option<FileTransferCallbackFn>>>,
+    last_ack_timestamp: Arc<Mutex<time::Instant>>,
+    stall_timeout: Option<Duration>,
+    abort_on_stall: bool,
+    pacing: Arc<Pacing>,
+    counters: Arc<TransferCounters>,
+    metrics_interval: Option<Duration>,
+    pairing: Option<SenderPairing>,
+    /// The receiver that challenged the sender, which a key from a [`PairedFrame`] is stored for.
+    challenged_by: Option<DeviceId>,
+    control: Option<ControlWatch>,
 }
 
 impl FileTransferNextHandler {
-    pub fn new(endpoint_handle: EndpointHandle, file: File) -> Self {
+    pub fn new(endpoint_handle: EndpointHandle, files: VecDeque<(String, File)>) -> Self {
         Self {
             endpoint_handle,
-            file: Some(file),
-            cur_segment: 0,
-            callback_fn: None,
+            files: files
+                .into_iter()
+                .map(|(file_name, file)| QueuedFile::Whole(file_name, file))
+                .collect(),
+            stripe_sender: None,
+            session: Arc::new(Mutex::new(SenderSession::new())),
+            after_eof: Arc::new(Notify::new()),
+            callback_fn: Arc::new(Mutex::new(None)),
+            last_ack_timestamp: Arc::new(Mutex::new(time::Instant::now())),
+            stall_timeout: None,
+            abort_on_stall: false,
+            pacing: Arc::new(Pacing::default()),
+            counters: Arc::new(TransferCounters::default()),
+            metrics_interval: None,
+            pairing: None,
+            challenged_by: None,
+            control: None,
         }
     }
 
+    /// Returns a function summarizing the session, to be called once the endpoint has stopped.
+    pub(crate) fn summarize(&self) -> impl Fn() -> TransferSummary {
+        let counters = Arc::clone(&self.counters);
+        move || counters.tally.lock().unwrap().summary()
+    }
+
+    /// Returns a function listing the files of the session as entries of its history, given the
+    /// receiver's name and why the session failed, if it did. To be called once the endpoint has
+    /// stopped.
+    pub(crate) fn history(&self) -> impl Fn(&str, Option<&Error>) -> Vec<HistoryEntry> {
+        let counters = Arc::clone(&self.counters);
+        move |peer, error| counters.tally.lock().unwrap().history(peer, error)
+    }
+
     pub fn set_callback_fn<F>(&mut self, f: F)
     where
         F: Fn(FileTransferEvent) + Send + 'static,
     {
-        self.callback_fn = Some(Box::new(f));
+        *self.callback_fn.lock().unwrap() = Some(Box::new(f));
     }
 
-    async fn send_segment(file: &mut File, segment_idx: u32, handle: &EndpointHandle) -> usize {
-        // Read the file as much as possible (within the chunk size limit).
-        let chunk_size = 1024 * 512;
-        let mut total_read_size = 0 as usize;
-        let mut buf = Vec::<u8>::with_capacity(chunk_size);
-        unsafe {
-            buf.set_len(chunk_size);
-        }
-        while total_read_size < chunk_size {
-            let read_size = file.read(&mut buf[total_read_size..]).await.unwrap();
-            if read_size == 0 {
-                // Eof encountered, stop reading.
-                break;
+    /// Reports a [`FileTransferEvent::Stalled`] event once the receiver hasn't acked anything for
+    /// `timeout`, and shuts the endpoint down as well if `abort` is set.
+    pub fn set_stall_timeout(&mut self, timeout: Option<Duration>, abort: bool) {
+        self.stall_timeout = timeout;
+        self.abort_on_stall = abort;
+    }
+
+    /// Sets how the transfer is checked for corruption, see [`VerificationMode`].
+    pub fn set_verification(&mut self, mode: VerificationMode) {
+        self.session.lock().unwrap().set_verification(mode);
+    }
+
+    /// Sets the segment size, ack window and number of streams to ask the receiver for, see
+    /// [`TransferConfig`].
+    pub fn set_transfer_config(&mut self, config: TransferConfig) {
+        self.session.lock().unwrap().set_transfer_config(config);
+    }
+
+    /// Sets how segments are compressed on the wire, see [`CompressionMode`].
+    pub fn set_compression(&mut self, mode: CompressionMode) {
+        self.session.lock().unwrap().set_compression(mode);
+    }
+
+    /// Splits files into stripes once the receiver agreed to several streams, and has `f` send
+    /// all but the first stripe of each over connections of their own.
+    pub(crate) fn set_stripe_sender(&mut self, f: StripeSenderFn) {
+        self.stripe_sender = Some(f);
+    }
+
+    /// Adds a stripe of a file split up by another connection's handler to the files to send.
+    pub(crate) fn queue_stripe(&mut self, file_name: &str, stripe: Stripe) {
+        // Acks of any stripe keep every connection of the file from looking stalled.
+        self.last_ack_timestamp = Arc::clone(&stripe.last_ack_timestamp);
+        self.files
+            .push_back(QueuedFile::Stripe(file_name.to_owned(), stripe));
+    }
+
+    /// Authenticates with receivers requiring pairing, see [`SenderPairing`]. Without it such
+    /// receivers are given up on.
+    pub(crate) fn set_pairing(&mut self, pairing: SenderPairing) {
+        self.pairing = Some(pairing);
+    }
+
+    /// Reports a [`FileTransferEvent::MetricsSnapshot`] event every `interval` while sending.
+    /// Pauses and cancels the session as `control` is told to.
+    pub(crate) fn set_control(&mut self, control: ControlWatch) {
+        self.control = Some(control);
+    }
+
+    pub fn set_metrics_interval(&mut self, interval: Option<Duration>) {
+        self.metrics_interval = interval;
+    }
+
+    async fn report_metrics(
+        handle: EndpointHandle,
+        counters: Arc<TransferCounters>,
+        last_ack_timestamp: Arc<Mutex<time::Instant>>,
+        callback_fn: Arc<Mutex<Option<FileTransferCallbackFn>>>,
+        interval: Duration,
+    ) {
+        let mut last_bytes_sent = 0;
+        let mut last_report_timestamp = time::Instant::now();
+        loop {
+            select! {
+                _ = handle.closed() => { return; },
+                _ = tokio::time::sleep(interval) => {},
+            }
+
+            let now = time::Instant::now();
+            let bytes_sent = counters.bytes_sent.load(Ordering::SeqCst);
+            let segments_sent = counters.segments_sent.load(Ordering::SeqCst);
+            let segments_acked = counters.segments_acked.load(Ordering::SeqCst);
+            let snapshot = MetricsSnapshot {
+                bytes_sent,
+                bytes_acked: counters.bytes_acked.load(Ordering::SeqCst),
+                segments_sent,
+                segments_acked,
+                segments_in_flight: segments_sent.saturating_sub(segments_acked),
+                throughput: (bytes_sent - last_bytes_sent) as f64
+                    / (now - last_report_timestamp).as_secs_f64(),
+                since_last_ack: last_ack_timestamp.lock().unwrap().elapsed(),
+            };
+            last_bytes_sent = bytes_sent;
+            last_report_timestamp = now;
+
+            if let Some(fn_box) = &*callback_fn.lock().unwrap() {
+                fn_box(FileTransferEvent::MetricsSnapshot(snapshot));
             }
-            total_read_size += read_size;
         }
+    }
+
+    async fn watch_acks(
+        handle: EndpointHandle,
+        last_ack_timestamp: Arc<Mutex<time::Instant>>,
+        callback_fn: Arc<Mutex<Option<FileTransferCallbackFn>>>,
+        counters: Arc<TransferCounters>,
+        stall_timeout: Duration,
+        abort_on_stall: bool,
+        control: Option<ControlWatch>,
+    ) {
+        let mut stalled = false;
+        loop {
+            select! {
+                _ = handle.closed() => { return; },
+                _ = tokio::time::sleep(stall_timeout / 4) => {},
+            }
 
-        // Resize the buffer to the final read size.
-        buf.resize(total_read_size, 0);
+            // No acks are expected while nothing is sent.
+            if matches!(&control, Some(control) if control.is_paused()) {
+                *last_ack_timestamp.lock().unwrap() = time::Instant::now();
+                continue;
+            }
+            let since_last_ack = last_ack_timestamp.lock().unwrap().elapsed();
+            if since_last_ack < stall_timeout {
+                stalled = false;
+                continue;
+            } else if stalled {
+                // Only report once until the receiver makes progress again.
+                continue;
+            }
+            stalled = true;
 
-        handle
-            .send_frame(FileTransferDataFrame {
-                segment_idx,
-                chunk_size: total_read_size as u32,
-                data: buf,
-            })
-            .await
-            .unwrap();
+            println!("receiver has not acked for {:?}", since_last_ack);
+            if let Some(fn_box) = &*callback_fn.lock().unwrap() {
+                fn_box(FileTransferEvent::Stalled(since_last_ack));
+            }
+
+            if abort_on_stall {
+                counters
+                    .tally
+                    .lock()
+                    .unwrap()
+                    .fail(Error::Timeout(since_last_ack));
+                handle.shutdown().await.ok();
+                return;
+            }
+        }
+    }
 
-        total_read_size
+    /// Gives up on the session once it's cancelled.
+    async fn watch_control(
+        handle: EndpointHandle,
+        mut control: ControlWatch,
+        session: Arc<Mutex<SenderSession>>,
+        counters: Arc<TransferCounters>,
+    ) {
+        select! {
+            _ = handle.closed() => {},
+            _ = control.cancelled() => {
+                println!("transfer cancelled");
+                session.lock().unwrap().fail();
+                counters.tally.lock().unwrap().fail(Error::Cancelled);
+                handle.shutdown().await.ok();
+            }
+        }
     }
-}
 
-#[async_trait]
-impl FrameHandler for FileTransferNextHandler {
-    type IncomingFrame = FileTransferNextFrame;
+    /// Sends the next segment of the file and returns its size, or `None` once the session has
+    /// stopped streaming. `position` is where the file has been read up to, counted from the start
+    /// of `range` if only that part of the file is sent.
+    async fn send_segment(
+        file: &AsyncMutex<File>,
+        range: Option<&Range<u64>>,
+        position: &mut u64,
+        session: &Mutex<SenderSession>,
+        handle: &EndpointHandle,
+        counters: &TransferCounters,
+    ) -> Option<usize> {
+        loop {
+            let offset = session.lock().unwrap().bytes_sent();
+            let mut chunk_size = session.lock().unwrap().transfer_config().segment_size;
+            let mut buf = Vec::<u8>::with_capacity(chunk_size);
+            let mut total_read_size = 0usize;
+            {
+                let mut file = file.lock().await;
 
-    async fn handle_frame(&mut self, frame: Self::IncomingFrame) {
-        if let FileTransferNextFrame::FileTransferAckFrame(frame) = frame {
-            if frame.segment_idx < self.cur_segment {
-                panic!("Unexpected next segment.");
+                // Go back in the file if the receiver asked for segments again. Stripes share the
+                // file with other connections, which move it elsewhere in between.
+                if offset != *position || range.is_some() {
+                    let start = range.map_or(0, |range| range.start);
+                    file.seek(SeekFrom::Start(start + offset)).await.unwrap();
+                    *position = offset;
+                }
+
+                // Read the file as much as possible (within the agreed segment size), but not past
+                // the end of the stripe.
+                if let Some(range) = range {
+                    let left = (range.end - range.start).saturating_sub(offset);
+                    chunk_size = chunk_size.min(left as usize);
+                }
+                unsafe {
+                    buf.set_len(chunk_size);
+                }
+                while total_read_size < chunk_size {
+                    let read_size = file.read(&mut buf[total_read_size..]).await.unwrap();
+                    if read_size == 0 {
+                        // Eof encountered, stop reading.
+                        break;
+                    }
+                    total_read_size += read_size;
+                }
             }
+            *position += total_read_size as u64;
 
-            self.cur_segment = frame.segment_idx;
+            // Resize the buffer to the final read size.
+            buf.resize(total_read_size, 0);
 
-            // Invoke event callback if necessary.
-            if let Some(fn_box) = &mut self.callback_fn {
-                fn_box.call((FileTransferEvent::SegmentSent(
-                    self.cur_segment,
-                    self.cur_segment as usize * 1024 * 512,
-                ),));
+            let (frame, digest) = {
+                let mut session = session.lock().unwrap();
+                if session.bytes_sent() != offset {
+                    // The session went back while reading, the chunk is stale.
+                    continue;
+                }
+                let frame = session.next_segment(buf).ok()?;
+                counters
+                    .bytes_sent
+                    .store(session.bytes_sent(), Ordering::SeqCst);
+                counters
+                    .segments_sent
+                    .store(session.segments_sent(), Ordering::SeqCst);
+                let digest = if total_read_size == 0 {
+                    session.file_digest()
+                } else {
+                    None
+                };
+                (frame, digest)
+            };
+            counters.share_stripe_progress();
+            if let Some(digest) = digest {
+                handle.send_frame(digest).await.unwrap();
             }
-        } else {
-            let mut file = self.file.take().unwrap();
-            let handle = self.endpoint_handle.clone();
+            handle.send_frame(frame).await.unwrap();
 
-            // Start sending "thread".
-            let rt = tokio::runtime::Handle::current();
-            rt.spawn(async move {
-                let mut segment_id = 0;
-                loop {
-                    let bytes_sent = Self::send_segment(&mut file, segment_id, &handle).await;
-                    segment_id += 1;
+            return Some(total_read_size);
+        }
+    }
+
+    /// Splits a `file_size` bytes long file into as many stripes as the receiver agreed to take
+    /// streams, keeping each at least [`MIN_STRIPE_SEGMENTS`] segments long. Has every stripe but
+    /// the first sent over connections of their own and returns the first one, unless the file
+    /// isn't worth splitting.
+    fn split_file(
+        queue: &SendQueue,
+        session: &Mutex<SenderSession>,
+        file_name: &str,
+        file: &SharedFile,
+        file_size: u64,
+    ) -> Option<(Stripe, Vec<StripeTask>)> {
+        let stripe_sender = queue.stripe_sender.as_ref()?;
+        let config = session.lock().unwrap().transfer_config();
+        let min_stripe_len = config.segment_size as u64 * MIN_STRIPE_SEGMENTS;
+        let stripe_count = (file_size / min_stripe_len).min(config.parallel_streams as u64) as u32;
+        if stripe_count < 2 {
+            return None;
+        }
+
+        let group_id = RandomState::new().build_hasher().finish();
+        let progress = Arc::new(StripeProgress::new(stripe_count));
+        let mut stripes = (0..stripe_count).map(|stripe_idx| Stripe {
+            file: Arc::clone(file),
+            group_id,
+            stripe_idx,
+            stripe_count,
+            file_size,
+            progress: Arc::clone(&progress),
+            last_ack_timestamp: Arc::clone(&queue.last_ack_timestamp),
+        });
+        let first = stripes.next().unwrap();
+        let others = stripes
+            .map(|stripe| stripe_sender(file_name.to_owned(), stripe))
+            .collect();
+        Some((first, others))
+    }
+
+    /// Sends the files one after the other, then ends the session. Peers older than protocol
+    /// version 4 take a single file and end the session themselves.
+    async fn send_files(
+        mut queue: SendQueue,
+        session: Arc<Mutex<SenderSession>>,
+        after_eof: Arc<Notify>,
+        handle: EndpointHandle,
+        callback_fn: Arc<Mutex<Option<FileTransferCallbackFn>>>,
+        pacing: Arc<Pacing>,
+        counters: Arc<TransferCounters>,
+    ) {
+        while let Some(queued) = queue.files.pop_front() {
+            let (file_name, file, stripe) = match queued {
+                QueuedFile::Whole(file_name, file) => {
+                    (file_name, Arc::new(AsyncMutex::new(file)), None)
+                }
+                QueuedFile::Stripe(file_name, stripe) => {
+                    let file = Arc::clone(&stripe.file);
+                    (file_name, file, Some(stripe))
+                }
+            };
+            let metadata = file.lock().await.metadata().await;
+            let (stripe, other_stripes) = match (stripe, &metadata) {
+                (Some(stripe), _) => (Some(stripe), Vec::new()),
+                (None, Ok(metadata)) => {
+                    match Self::split_file(&queue, &session, &file_name, &file, metadata.len()) {
+                        Some((stripe, other_stripes)) => (Some(stripe), other_stripes),
+                        None => (None, Vec::new()),
+                    }
+                }
+                (None, Err(_)) => (None, Vec::new()),
+            };
+
+            if let Some(stripe) = &stripe {
+                let result = session.lock().unwrap().begin_stripe(
+                    stripe.group_id,
+                    stripe.stripe_idx,
+                    stripe.stripe_count,
+                    stripe.file_size,
+                );
+                match result {
+                    Ok(frame) => handle.send_frame(frame).await.unwrap(),
+                    Err(err) => {
+                        println!("could not send {}: {}", file_name, err);
+                        counters.tally.lock().unwrap().fail(err.into());
+                        handle.shutdown().await.ok();
+                        return;
+                    }
+                }
+            }
+            let result = session.lock().unwrap().begin_file(&file_name);
+            match result {
+                Ok(Some(frame)) => handle.send_frame(frame).await.unwrap(),
+                Ok(None) => {}
+                Err(err) => {
+                    println!("could not send {}: {}", file_name, err);
+                    counters.tally.lock().unwrap().fail(err.into());
+                    handle.shutdown().await.ok();
+                    return;
+                }
+            }
+            let range = stripe.as_ref().map(Stripe::range);
+            counters.tally.lock().unwrap().begin_file(&file_name);
+            *counters.current_file.lock().unwrap() = CurrentFile {
+                size: metadata.as_ref().ok().map(|metadata| metadata.len()),
+                started: time::Instant::now(),
+            };
+            *counters.stripe.lock().unwrap() = stripe.clone();
+            match metadata {
+                Ok(metadata) => {
+                    let modified = metadata.modified().ok().map_or(0, unix_time);
+                    let size = range
+                        .as_ref()
+                        .map_or(metadata.len(), |range| range.end - range.start);
+                    let result = session.lock().unwrap().describe_file(size, modified);
+                    if let Some(frame) = result.unwrap() {
+                        handle.send_frame(frame).await.unwrap();
+                    }
+                }
+                Err(err) => println!("could not read the metadata of {}: {}", file_name, err),
+            }
+            counters.bytes_acked.store(0, Ordering::SeqCst);
+            counters.segments_acked.store(0, Ordering::SeqCst);
 
-                    // Invoke event callback with complete event when there is no more data to send.
-                    if bytes_sent == 0 {
+            let mut position = 0;
+            loop {
+                // Hold off while paused, cancelling is up to `watch_control`.
+                if let Some(control) = &mut queue.control {
+                    select! {
+                        _ = handle.closed() => { return; },
+                        proceed = control.proceed() => if !proceed { return; },
+                    }
+                }
+
+                // Wait for acks once the send window is full.
+                loop {
+                    let window_open = session.lock().unwrap().window_open();
+                    if window_open {
                         break;
                     }
+                    select! {
+                        _ = handle.closed() => { return; },
+                        _ = pacing.window_opened.notified() => {},
+                    }
                 }
-            });
+
+                let send_fut = Self::send_segment(
+                    &file,
+                    range.as_ref(),
+                    &mut position,
+                    &session,
+                    &handle,
+                    &counters,
+                );
+                let bytes_sent = match send_fut.await {
+                    Some(bytes_sent) => bytes_sent,
+                    None => return,
+                };
+
+                // Wait for the receiver to confirm the file, unless it asks for segments again.
+                if bytes_sent == 0 {
+                    select! {
+                        _ = handle.closed() => { return; },
+                        _ = after_eof.notified() => {},
+                    }
+                    let state = session.lock().unwrap().state();
+                    match state {
+                        SessionState::Streaming => continue,
+                        SessionState::Idle => break,
+                        _ => return,
+                    }
+                }
+
+                if let Some(fn_box) = &*callback_fn.lock().unwrap() {
+                    fn_box(FileTransferEvent::Progress(counters.progress()));
+                }
+
+                let pacing_delay_ms = pacing.delay_ms.load(Ordering::SeqCst);
+                if pacing_delay_ms > 0 {
+                    tokio::time::sleep(Duration::from_millis(pacing_delay_ms as u64)).await;
+                }
+            }
+
+            // A file split up here is complete once the receiver confirmed every stripe.
+            if let Some(stripe) = stripe.filter(|_| !other_stripes.is_empty()) {
+                for other_stripe in other_stripes {
+                    let result = select! {
+                        _ = handle.closed() => { return; },
+                        result = other_stripe => result.unwrap_or(Err(Error::Cancelled)),
+                    };
+                    if let Err(err) = result {
+                        println!("could not send every stripe of {}: {}", file_name, err);
+                        counters.tally.lock().unwrap().fail(err);
+                        session.lock().unwrap().fail();
+                        handle.shutdown().await.ok();
+                        return;
+                    }
+                }
+
+                let transfer_id = session.lock().unwrap().transfer_id();
+                counters.tally.lock().unwrap().file_completed(
+                    file_name.clone(),
+                    stripe.file_size,
+                    None,
+                );
+                if let Some(fn_box) = &*callback_fn.lock().unwrap() {
+                    fn_box(FileTransferEvent::FileComplete {
+                        transfer_id,
+                        file_name,
+                    });
+                }
+            }
+        }
+
+        let result = session.lock().unwrap().end_session();
+        match result {
+            Ok(frame) => {
+                handle.send_frame(frame).await.unwrap();
+                counters.tally.lock().unwrap().complete();
+                if let Some(fn_box) = &*callback_fn.lock().unwrap() {
+                    fn_box(FileTransferEvent::Complete);
+                }
+            }
+            Err(err) => {
+                println!("could not end the session: {}", err);
+                counters.tally.lock().unwrap().fail(err.into());
+            }
+        }
+        handle.shutdown().await.ok();
+    }
+
+    /// Adds the file the receiver just confirmed to the session's totals.
+    fn record_file_completed(&self, file_name: &str) {
+        let (file_size, sha256) = {
+            let session = self.session.lock().unwrap();
+            let sha256 = session.file_digest().map(|frame| frame.sha256);
+            (session.bytes_sent(), sha256)
         };
+        self.counters
+            .tally
+            .lock()
+            .unwrap()
+            .file_completed(file_name.to_owned(), file_size, sha256);
     }
-}
 
-pub struct FileTransferReceivingHandler {
-    endpoint_handle: EndpointHandle,
-    file: File,
-    windowed_recv_segments: u32,
-    #[cfg(debug_assertions)]
-    last_recv_timestamp: Option<time::Instant>,
-}
+    /// Gives up on a transfer the receiver doesn't follow the protocol in.
+    async fn abort(&self, err: SessionError) {
+        self.give_up(err.into()).await
+    }
 
-impl FileTransferReceivingHandler {
-    pub async fn new<P>(endpoint_handle: EndpointHandle, path: P) -> Self
-    where
-        P: AsRef<Path>,
-    {
-        let file_path = path.as_ref().join("test");
-        let file = File::create(file_path).await.unwrap();
-        Self {
-            endpoint_handle,
-            file,
-            windowed_recv_segments: 0,
-            #[cfg(debug_assertions)]
-            last_recv_timestamp: None,
-        }
+    /// Answers a receiver requiring pairing, see [`SenderPairing::answer`].
+    async fn handle_auth_challenge(&mut self, frame: AuthChallengeFrame) {
+        let pairing = match &self.pairing {
+            Some(pairing) => pairing.clone(),
+            None => {
+                let err = Error::Handshake("the receiver requires pairing".to_owned());
+                return self.give_up(err).await;
+            }
+        };
+        let request = match pairing.answer(&frame).await {
+            Some(request) => request,
+            None => return self.give_up(Error::Cancelled).await,
+        };
+        self.challenged_by = Some(frame.receiver_id);
+        self.endpoint_handle.send_frame(request).await.unwrap();
+    }
+
+    /// Gives up on the transfer because of `err`.
+    async fn give_up(&self, err: Error) {
+        println!("aborting transfer: {}", err);
+        self.session.lock().unwrap().fail();
+        self.counters.tally.lock().unwrap().fail(err);
+        self.endpoint_handle.shutdown().await.ok();
     }
 }
 
 #[async_trait]
-impl FrameHandler for FileTransferReceivingHandler {
-    type IncomingFrame = FileTransferDataFrame;
+impl FrameHandler for FileTransferNextHandler {
+    type IncomingFrame = FileTransferNextFrame;
 
     async fn handle_frame(&mut self, frame: Self::IncomingFrame) {
-        #[cfg(debug_assertions)]
-        {
-            let now = time::Instant::now();
-            let speed = if let Some(ts) = self.last_recv_timestamp {
-                (frame.chunk_size as f64 / 1048576_f64) / (now - ts).as_secs_f64()
-            } else {
-                0_f64
+        if let FileTransferNextFrame::AuthChallengeFrame(frame) = frame {
+            self.handle_auth_challenge(frame).await;
+        } else if let FileTransferNextFrame::PairedFrame(frame) = frame {
+            if let (Some(pairing), Some(receiver_id)) = (&self.pairing, &self.challenged_by) {
+                pairing.paired(receiver_id, frame);
+            }
+        } else if let FileTransferNextFrame::FileTransferAckFrame(frame) = frame {
+            let result = {
+                let mut session = self.session.lock().unwrap();
+                let result = session.handle_ack(frame);
+                result.map(|segments_acked| (segments_acked, session.bytes_acked()))
             };
-            self.last_recv_timestamp = Some(now);
-            println!(
+            let (segments_acked, bytes_acked) = match result {
+                Ok(acked) => acked,
+                Err(err) => return self.abort(err).await,
+            };
+
+            *self.last_ack_timestamp.lock().unwrap() = time::Instant::now();
+            self.counters.tally.lock().unwrap().acked(bytes_acked);
+            self.counters
+                .segments_acked
+                .store(segments_acked, Ordering::SeqCst);
+            self.counters
+                .bytes_acked
+                .store(bytes_acked, Ordering::SeqCst);
+            self.counters.share_stripe_progress();
+
+            // The receiver is making progress, gradually go back to full speed.
+            let pacing_delay_ms = self.pacing.delay_ms.load(Ordering::SeqCst);
+            self.pacing
+                .delay_ms
+                .store(pacing_delay_ms / 2, Ordering::SeqCst);
+            self.pacing.window_opened.notify_one();
+
+            // Invoke event callback if necessary.
+            if let Some(fn_box) = &*self.callback_fn.lock().unwrap() {
+                fn_box(FileTransferEvent::SegmentSent(
+                    segments_acked,
+                    bytes_acked as usize,
+                ));
+                fn_box(FileTransferEvent::Progress(self.counters.progress()));
+            }
+        } else if let FileTransferNextFrame::FileTransferSlowDownFrame(frame) = frame {
+            // Pace segments to roughly the rate the receiver manages to write them.
+            self.pacing
+                .delay_ms
+                .store(frame.write_latency_ms, Ordering::SeqCst);
+
+            if let Some(fn_box) = &*self.callback_fn.lock().unwrap() {
+                fn_box(FileTransferEvent::ReceiverDiskSlow(Duration::from_millis(
+                    frame.write_latency_ms as u64,
+                )));
+            }
+        } else if let FileTransferNextFrame::FileTransferErrorFrame(frame) = frame {
+            println!(
+                "receiver aborted the transfer (retryable: {}): {}",
+                frame.retryable, frame.message
+            );
+            self.session.lock().unwrap().fail();
+            self.counters.tally.lock().unwrap().fail(Error::Rejected {
+                retryable: frame.retryable,
+                message: frame.message.clone(),
+                reason: None,
+            });
+            if let Some(fn_box) = &*self.callback_fn.lock().unwrap() {
+                fn_box(FileTransferEvent::ReceiverDiskError {
+                    retryable: frame.retryable,
+                    message: frame.message,
+                });
+            }
+            self.endpoint_handle.shutdown().await.ok();
+        } else if let FileTransferNextFrame::TransferRejectedFrame(frame) = frame {
+            println!(
+                "receiver turned the file down ({:?}): {}",
+                frame.reason, frame.message
+            );
+            self.session.lock().unwrap().fail();
+            self.counters.tally.lock().unwrap().fail(Error::Rejected {
+                retryable: frame.reason.is_retryable(),
+                message: frame.message,
+                reason: Some(frame.reason),
+            });
+            self.endpoint_handle.shutdown().await.ok();
+        } else if let FileTransferNextFrame::FileTransferRetransmitFrame(frame) = frame {
+            let segment_idx = frame.segment_idx;
+            let result = {
+                let mut session = self.session.lock().unwrap();
+                let finalizing = session.state() == SessionState::Finalizing;
+                session.handle_retransmit(frame).map(|_| finalizing)
+            };
+            let finalizing = match result {
+                Ok(finalizing) => finalizing,
+                Err(err) => return self.abort(err).await,
+            };
+            self.counters.tally.lock().unwrap().retransmits += 1;
+
+            println!(
+                "segment {} arrived corrupted, sending it again",
+                segment_idx
+            );
+            if finalizing {
+                self.after_eof.notify_one();
+            } else {
+                self.pacing.window_opened.notify_one();
+            }
+        } else if let FileTransferNextFrame::FileTransferCompleteFrame(frame) = frame {
+            let result = {
+                let mut session = self.session.lock().unwrap();
+                let result = session.handle_file_complete(frame);
+                result.map(|transfer_id| (transfer_id, session.file_name().to_owned()))
+            };
+            let (transfer_id, file_name) = match result {
+                Ok(completed) => completed,
+                Err(err) => return self.abort(err).await,
+            };
+
+            // A stripe is only part of the file, the sending task completes the whole of it.
+            if self.counters.stripe.lock().unwrap().is_none() {
+                self.record_file_completed(&file_name);
+                if let Some(fn_box) = &*self.callback_fn.lock().unwrap() {
+                    fn_box(FileTransferEvent::FileComplete {
+                        transfer_id,
+                        file_name,
+                    });
+                }
+            }
+            self.after_eof.notify_one();
+        } else if let FileTransferNextFrame::EndSessionFrame(frame) = frame {
+            let result = {
+                let mut session = self.session.lock().unwrap();
+                let result = session.handle_end_session(frame);
+                result.map(|_| (session.transfer_id(), session.file_name().to_owned()))
+            };
+            let (transfer_id, file_name) = match result {
+                Ok(completed) => completed,
+                Err(err) => return self.abort(err).await,
+            };
+            self.record_file_completed(&file_name);
+            self.counters.tally.lock().unwrap().complete();
+
+            if let Some(fn_box) = &*self.callback_fn.lock().unwrap() {
+                fn_box(FileTransferEvent::FileComplete {
+                    transfer_id,
+                    file_name,
+                });
+                fn_box(FileTransferEvent::Complete);
+            }
+            self.endpoint_handle.shutdown().await.ok();
+        } else if let FileTransferNextFrame::HandshakeResponseFrame(frame) = frame {
+            let result = {
+                let mut session = self.session.lock().unwrap();
+                session
+                    .handle_handshake_response(frame)
+                    .map(|protocol_version| (protocol_version, session.capabilities()))
+            };
+            let (protocol_version, capabilities) = match result {
+                Ok(negotiated) => negotiated,
+                Err(err) => return self.give_up(Error::Handshake(err.to_string())).await,
+            };
+            self.endpoint_handle.set_protocol_version(protocol_version);
+            self.endpoint_handle.set_capabilities(capabilities);
+            self.counters.tally.lock().unwrap().start();
+            if let Some(fn_box) = &*self.callback_fn.lock().unwrap() {
+                fn_box(FileTransferEvent::Started);
+            }
+
+            let queue = SendQueue {
+                files: mem::take(&mut self.files),
+                stripe_sender: self.stripe_sender.take(),
+                last_ack_timestamp: Arc::clone(&self.last_ack_timestamp),
+                control: self.control.clone(),
+            };
+            let handle = self.endpoint_handle.clone();
+
+            // Start sending "thread".
+            let rt = tokio::runtime::Handle::current();
+            if let Some(stall_timeout) = self.stall_timeout {
+                *self.last_ack_timestamp.lock().unwrap() = time::Instant::now();
+                rt.spawn(Self::watch_acks(
+                    handle.clone(),
+                    Arc::clone(&self.last_ack_timestamp),
+                    Arc::clone(&self.callback_fn),
+                    Arc::clone(&self.counters),
+                    stall_timeout,
+                    self.abort_on_stall,
+                    self.control.clone(),
+                ));
+            }
+            if let Some(control) = self.control.clone() {
+                rt.spawn(Self::watch_control(
+                    handle.clone(),
+                    control,
+                    Arc::clone(&self.session),
+                    Arc::clone(&self.counters),
+                ));
+            }
+            if let Some(metrics_interval) = self.metrics_interval {
+                rt.spawn(Self::report_metrics(
+                    handle.clone(),
+                    Arc::clone(&self.counters),
+                    Arc::clone(&self.last_ack_timestamp),
+                    Arc::clone(&self.callback_fn),
+                    metrics_interval,
+                ));
+            }
+            rt.spawn(
+                Self::send_files(
+                    queue,
+                    Arc::clone(&self.session),
+                    Arc::clone(&self.after_eof),
+                    handle,
+                    Arc::clone(&self.callback_fn),
+                    Arc::clone(&self.pacing),
+                    Arc::clone(&self.counters),
+                )
+                .in_current_span(),
+            );
+        };
+    }
+}
+
+/// Who a sender claims to be, as given to the server's handshake callback.
+#[derive(Debug, Clone)]
+pub struct PeerIdentity {
+    pub addr: SocketAddr,
+    /// The display name the sender introduced itself with.
+    pub name: String,
+    /// The protocol version negotiated with the sender.
+    pub protocol_version: u16,
+    /// The optional features both the sender and this receiver support.
+    pub capabilities: Capabilities,
+}
+
+/// What a peer the server's handshake callback let in may do. Peers asking for something their
+/// role doesn't allow are turned down with [`RejectionReason::Unauthorized`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerRole {
+    /// Send files and text, and browse and pull what the server shares.
+    Full,
+    /// Send files and text only, e.g. a guest dropping files off.
+    Sender,
+    /// Only browse and pull what the server shares, read-only.
+    Puller,
+    /// Only list what the server shares, without pulling any of it.
+    Browser,
+}
+
+impl PeerRole {
+    pub fn may_send(self) -> bool {
+        matches!(self, Self::Full | Self::Sender)
+    }
+
+    pub fn may_pull(self) -> bool {
+        matches!(self, Self::Full | Self::Puller)
+    }
+
+    pub fn may_browse(self) -> bool {
+        self != Self::Sender
+    }
+}
+
+type HandshakeCallbackFuture = Pin<Box<dyn Future<Output = Result<PeerRole, String>> + Send>>;
+
+/// Decides whether to go on with a peer and what it may do, denying it with a message otherwise.
+pub(crate) type HandshakeCallbackFn =
+    Arc<dyn Fn(PeerIdentity) -> HandshakeCallbackFuture + Send + Sync>;
+
+/// What happens while a server receives files, as reported to its event callback.
+#[derive(Debug, Clone)]
+pub enum ReceiveEvent {
+    /// A segment of the file being received has been written.
+    Progress {
+        bytes_received: u64,
+        /// The size of the file, if the sender described it.
+        file_size: Option<u64>,
+    },
+    /// A file has been received completely and written to `path`.
+    FileReceived { path: PathBuf, bytes: u64 },
+}
+
+pub(crate) type ReceiveEventCallbackFn = Arc<dyn Fn(ReceiveEvent) + Send + Sync>;
+
+/// What a file being received is written to.
+enum ReceivedFile {
+    /// A file on disk, or the part file the stripes of one are put back together in.
+    File(File),
+    /// Where the delegate had the file received into.
+    Sink(TransferSink),
+}
+
+/// A file received under a temporary name next to where it goes, moved in place once complete.
+struct PartialFile {
+    path: PathBuf,
+    /// The directory and name the file goes to, numbered if a file of that name exists by then.
+    dir: PathBuf,
+    file_name: String,
+}
+
+impl PartialFile {
+    /// Where the sidecar recording how far the transfer got is kept.
+    fn state_path(&self) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(PARTIAL_STATE_SUFFIX);
+        PathBuf::from(path)
+    }
+}
+
+/// What the sidecar of a partial file records, so an interrupted transfer can be told apart from
+/// a complete file and picked up again.
+#[derive(Debug, Serialize, Deserialize)]
+struct PartialFileState {
+    file_name: String,
+    /// The name the sender gave in the handshake.
+    sender: String,
+    file_size: Option<u64>,
+    /// How many bytes from the start of the file have been written and acknowledged.
+    bytes_received: u64,
+}
+
+/// What a receiver requiring senders to pair with it checks them against.
+#[derive(Clone)]
+pub(crate) struct ReceiverPairing {
+    pub(crate) store: Arc<PairingStore>,
+    pub(crate) on_code: PairingCodeCallbackFn,
+}
+
+/// A handshake response held back until the sender has authenticated.
+struct PendingAuth {
+    response: HandshakeResponseFrame,
+    nonce: [u8; 16],
+    /// The pairing code shown to the user, once the sender asked for one.
+    code: Option<u32>,
+}
+
+/// A file whose stripes are being received over several connections.
+pub(crate) struct StripeAssembly {
+    file_name: String,
+    /// Where the stripes are written to until the last one has been received.
+    part_path: PathBuf,
+    stripes_left: u32,
+    /// Bytes received over all stripes.
+    bytes_received: u64,
+    /// What the server's delegate decided about the file, asked by the first stripe to arrive
+    /// while the others wait.
+    decision: Arc<AsyncMutex<Option<Decision>>>,
+}
+
+/// The files being put back together from stripes, by group id. Shared by all connections of a
+/// server, as every stripe comes over a connection of its own.
+pub(crate) type StripeAssemblies = Arc<Mutex<HashMap<u64, StripeAssembly>>>;
+
+pub struct FileTransferReceivingHandler {
+    endpoint_handle: EndpointHandle,
+    /// Checks the sender once it has introduced itself, along with its address.
+    handshake_check: Option<(HandshakeCallbackFn, SocketAddr)>,
+    /// What the handshake callback lets the sender do.
+    role: PeerRole,
+    /// Requires the sender to pair first, along with its address.
+    pairing: Option<(ReceiverPairing, SocketAddr)>,
+    pending_auth: Option<PendingAuth>,
+    /// Set once the sender has been let in after the handshake and pairing.
+    let_in_tx: watch::Sender<bool>,
+    /// Decides about every file the sender offers, along with its address.
+    delegate: Option<(ServerDelegateRef, SocketAddr)>,
+    /// The name of the file announced last, until the delegate has decided about it.
+    offered_file: Option<String>,
+    /// The limits of the server files are checked against, along with the sender's address.
+    limits: Option<(Arc<ReceiveLimits>, SocketAddr)>,
+    /// The file being received once it passed the limits.
+    admitted: Option<AdmittedTransfer>,
+    event_callback: Option<ReceiveEventCallbackFn>,
+    /// The directory files are received into.
+    dir: PathBuf,
+    /// The file being received, opened on its first segment, and where it's written to.
+    file: Option<ReceivedFile>,
+    file_path: Option<PathBuf>,
+    /// Whether files are written under a temporary name first, and the one being received if so.
+    atomic_writes: bool,
+    partial: Option<PartialFile>,
+    /// The modification time to give the file once it has been received, if the sender sent one.
+    modified: Option<SystemTime>,
+    session: ReceiverSession,
+    stripe_assemblies: StripeAssemblies,
+    /// Where the files received are recorded, along with the sender's address.
+    history: Option<(Arc<HistoryStore>, SocketAddr)>,
+    /// The file being received and when it began, until it's recorded in the history.
+    receiving: Option<(String, time::Instant)>,
+    last_slow_down_timestamp: Option<time::Instant>,
+    #[cfg(debug_assertions)]
+    last_recv_timestamp: Option<time::Instant>,
+}
+
+impl FileTransferReceivingHandler {
+    pub fn new<P>(endpoint_handle: EndpointHandle, path: P) -> Self
+    where
+        P: AsRef<Path>,
+    {
+        Self {
+            endpoint_handle,
+            handshake_check: None,
+            role: PeerRole::Full,
+            pairing: None,
+            pending_auth: None,
+            let_in_tx: watch::channel(false).0,
+            delegate: None,
+            offered_file: None,
+            limits: None,
+            admitted: None,
+            event_callback: None,
+            dir: path.as_ref().to_owned(),
+            file: None,
+            file_path: None,
+            atomic_writes: true,
+            partial: None,
+            modified: None,
+            session: ReceiverSession::new(),
+            stripe_assemblies: StripeAssemblies::default(),
+            history: None,
+            receiving: None,
+            last_slow_down_timestamp: None,
+            #[cfg(debug_assertions)]
+            last_recv_timestamp: None,
+        }
+    }
+
+    /// Has `callback` decide whether to receive from the sender at `addr` after the handshake.
+    pub fn set_handshake_callback(&mut self, callback: HandshakeCallbackFn, addr: SocketAddr) {
+        self.handshake_check = Some((callback, addr));
+    }
+
+    /// Only receives from the sender at `addr` once it's paired, see [`ReceiverPairing`].
+    pub(crate) fn set_pairing(&mut self, pairing: ReceiverPairing, addr: SocketAddr) {
+        self.pairing = Some((pairing, addr));
+    }
+
+    /// Has `delegate` decide about every file the sender at `addr` offers, see
+    /// [`ServerDelegate`](crate::ServerDelegate).
+    pub(crate) fn set_delegate(&mut self, delegate: ServerDelegateRef, addr: SocketAddr) {
+        self.delegate = Some((delegate, addr));
+    }
+
+    /// Sets whether files are written under a temporary `.icedrop.partial` name and moved in
+    /// place once complete, which they are by default.
+    pub fn set_atomic_writes(&mut self, atomic: bool) {
+        self.atomic_writes = atomic;
+    }
+
+    pub fn set_event_callback(&mut self, callback: ReceiveEventCallbackFn) {
+        self.event_callback = Some(callback);
+    }
+
+    /// Sets the largest segment size, ack window and number of streams to agree to, see
+    /// [`TransferConfig`].
+    pub fn set_transfer_config(&mut self, config: TransferConfig) {
+        self.session.set_transfer_config(config);
+    }
+
+    /// Puts stripes back together with the ones received by the other handlers sharing
+    /// `assemblies`, which only happens within the handler by default.
+    pub(crate) fn set_stripe_assemblies(&mut self, assemblies: StripeAssemblies) {
+        self.stripe_assemblies = assemblies;
+    }
+
+    /// Records every file received from the sender at `addr` in `history`, or failed to be.
+    pub(crate) fn set_history(&mut self, history: Arc<HistoryStore>, addr: SocketAddr) {
+        self.history = Some((history, addr));
+    }
+
+    /// Checks every file the sender at `addr` offers against `limits` before receiving it.
+    pub(crate) fn set_limits(&mut self, limits: Arc<ReceiveLimits>, addr: SocketAddr) {
+        self.limits = Some((limits, addr));
+    }
+
+    /// Whether files are offered to the limits or the delegate before they're received, which
+    /// waits for the sender to tell their size.
+    fn decides_offers(&self) -> bool {
+        self.limits.is_some() || self.delegate.is_some()
+    }
+
+    fn report(&self, event: ReceiveEvent) {
+        if let Some(callback) = &self.event_callback {
+            callback(event);
+        }
+    }
+
+    /// Stops counting the file being received against the limits once it's done with, and adds
+    /// it to the history if it's kept.
+    fn record(&mut self, size: u64, status: TransferStatus) {
+        // The bytes have been counted against the limits as they were received.
+        self.admitted = None;
+        let (file_name, started) = match self.receiving.take() {
+            Some(receiving) => receiving,
+            None => return,
+        };
+        match &status {
+            TransferStatus::Completed => {
+                metrics::count_file(TransferDirection::Received);
+                tracing::info!(file = %file_name, size, "file received");
+            }
+            TransferStatus::Failed(reason) => {
+                metrics::count_error();
+                tracing::warn!(file = %file_name, size, %reason, "file not received");
+            }
+        }
+        let (history, addr) = match &self.history {
+            Some(history) => history,
+            None => return,
+        };
+        let sha256 = match status {
+            TransferStatus::Completed => self.session.expected_digest(),
+            TransferStatus::Failed(_) => None,
+        };
+        history.record_or_log(HistoryEntry {
+            finished_at: SystemTime::now(),
+            direction: TransferDirection::Received,
+            peer: addr.to_string(),
+            file_name,
+            size,
+            duration: started.elapsed(),
+            status,
+            sha256,
+        });
+    }
+
+    /// Records the file being received as failed for `reason`.
+    fn record_failure(&mut self, reason: String) {
+        let size = self.session.bytes_received();
+        self.record(size, TransferStatus::Failed(reason));
+    }
+
+    /// Asks the sender to slow down when writing a segment took long enough for the disk to be
+    /// the bottleneck rather than the network.
+    async fn check_write_latency(&mut self, write_latency: Duration) {
+        if write_latency < SLOW_WRITE_THRESHOLD {
+            return;
+        }
+        if let Some(ts) = self.last_slow_down_timestamp {
+            if ts.elapsed() < SLOW_DOWN_INTERVAL {
+                return;
+            }
+        }
+        self.last_slow_down_timestamp = Some(time::Instant::now());
+
+        println!(
+            "receiver disk is the bottleneck ({:?} per segment)",
+            write_latency
+        );
+        self.endpoint_handle
+            .send_frame(FileTransferSlowDownFrame {
+                write_latency_ms: write_latency.as_millis() as u32,
+            })
+            .await
+            .unwrap();
+    }
+
+    /// Writes a whole segment, retrying transient failures with exponential backoff. Bytes
+    /// already written by a failed attempt are not written again.
+    async fn write_segment(&mut self, data: &[u8]) -> io::Result<()> {
+        if self.file.is_none() {
+            // The sender didn't announce the file, it's the only one of the session.
+            if self.atomic_writes {
+                self.create_destination(self.dir.clone(), DEFAULT_FILE_NAME.to_owned())
+                    .await?;
+            } else {
+                let file_path = self.dir.join(DEFAULT_FILE_NAME);
+                self.file = Some(ReceivedFile::File(File::create(&file_path).await?));
+                self.file_path = Some(file_path);
+            }
+        }
+        let file: &mut (dyn AsyncWrite + Send + Unpin) = match self.file.as_mut().unwrap() {
+            ReceivedFile::File(file) => file,
+            ReceivedFile::Sink(sink) => &mut sink.0,
+        };
+
+        let mut written = 0;
+        let mut retries = 0;
+        let mut backoff = DISK_WRITE_RETRY_BACKOFF;
+        while written < data.len() {
+            match file.write(&data[written..]).await {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+                Ok(write_size) => written += write_size,
+                Err(err) if is_retryable_disk_error(&err) && retries < DISK_WRITE_MAX_RETRIES => {
+                    retries += 1;
+                    println!("disk write failed: {}, retrying in {:?}", err, backoff);
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle_data_frame(&mut self, frame: FileTransferDataFrame) {
+        #[cfg(debug_assertions)]
+        {
+            let now = time::Instant::now();
+            let speed = if let Some(ts) = self.last_recv_timestamp {
+                (frame.chunk_size as f64 / 1048576_f64) / (now - ts).as_secs_f64()
+            } else {
+                0_f64
+            };
+            self.last_recv_timestamp = Some(now);
+            println!(
                 "receive data frame: {} ({} bytes, {:.2} MB/s)",
                 frame.segment_idx, frame.chunk_size, speed
             );
         }
 
-        if frame.chunk_size == 0 {
-            self.file.flush().await.unwrap();
-            self.endpoint_handle
-                .send_frame(FileTransferAckOrEndFrame::EndSessionFrame(EndSessionFrame))
-                .await
-                .unwrap();
+        // Senders that didn't describe the file are offered it on its first segment.
+        let undecided = self.decides_offers()
+            && self.file.is_none()
+            && self.session.stripe().is_none()
+            && self.session.state() == SessionState::Streaming;
+        if undecided && !self.offer_file().await {
             return;
         }
 
-        self.file.write_all(&frame.data).await.unwrap();
+        let segment_idx = frame.segment_idx;
+        let data = match self.session.handle_data(frame) {
+            Ok(ReceiverAction::Write(data)) => data,
+            Ok(ReceiverAction::Finish(frame)) => {
+                if let Err(err) = self.finish_file().await {
+                    return self.fail_on_disk_error(err).await;
+                }
+                self.endpoint_handle
+                    .send_frame(FileTransferAckOrEndFrame::EndSessionFrame(frame))
+                    .await
+                    .unwrap();
+                return;
+            }
+            Ok(ReceiverAction::FinishFile(frame)) => {
+                if let Err(err) = self.finish_file().await {
+                    return self.fail_on_disk_error(err).await;
+                }
+                println!("received file {}", frame.transfer_id);
+                self.endpoint_handle.send_frame(frame).await.unwrap();
+                return;
+            }
+            Ok(ReceiverAction::Retransmit(frame)) => {
+                println!("segment {} is corrupted, asking for it again", segment_idx);
+                self.endpoint_handle.send_frame(frame).await.unwrap();
+                return;
+            }
+            Ok(ReceiverAction::Discard) => return,
+            Ok(ReceiverAction::Fail(frame)) => {
+                println!("received file is corrupted: {}", frame.message);
+                self.record_failure(frame.message.clone());
+                self.endpoint_handle.send_frame(frame).await.ok();
+                self.endpoint_handle.shutdown().await.ok();
+                return;
+            }
+            Err(err) => return self.abort(err).await,
+        };
+
+        if let Some(admitted) = &mut self.admitted {
+            if let Err(rejection) = admitted.receive(data.len() as u64) {
+                return self.reject(rejection.reason, rejection.message).await;
+            }
+        }
+
+        let write_start = time::Instant::now();
+        if let Err(err) = self.write_segment(&data).await {
+            println!("could not write segment {}", segment_idx);
+            return self.fail_on_disk_error(err).await;
+        }
+        self.check_write_latency(write_start.elapsed()).await;
+        let progress = match self.session.stripe() {
+            // Stripes report the progress of the whole file.
+            Some(stripe) => {
+                let mut assemblies = self.stripe_assemblies.lock().unwrap();
+                let assembly = assemblies.get_mut(&stripe.group_id);
+                let bytes_received = assembly.map_or(0, |assembly| {
+                    assembly.bytes_received += data.len() as u64;
+                    assembly.bytes_received
+                });
+                ReceiveEvent::Progress {
+                    bytes_received,
+                    file_size: Some(stripe.file_size),
+                }
+            }
+            None => ReceiveEvent::Progress {
+                bytes_received: self.session.bytes_received(),
+                file_size: self.session.expected_size(),
+            },
+        };
+        self.report(progress);
 
-        self.windowed_recv_segments += 1;
-        if self.windowed_recv_segments >= 8 {
-            self.windowed_recv_segments = 0;
+        if let Some(ack) = self.session.segment_written() {
+            if let Err(err) = self.save_partial_state().await {
+                return self.fail_on_disk_error(err).await;
+            }
             self.endpoint_handle
-                .send_frame(FileTransferAckOrEndFrame::FileTransferAckFrame(
-                    FileTransferAckFrame {
-                        segment_idx: frame.segment_idx + 1,
-                    },
-                ))
+                .send_frame(FileTransferAckOrEndFrame::FileTransferAckFrame(ack))
                 .await
                 .unwrap();
         }
     }
+
+    /// Flushes and closes the file that has been received completely, restoring its
+    /// modification time and moving it in place if it was written under a temporary name. Sinks
+    /// are shut down instead.
+    async fn finish_file(&mut self) -> io::Result<()> {
+        if self.file.is_none() {
+            // An empty file had no segment to be created on.
+            self.write_segment(&[]).await?;
+        }
+        let mut file = match self.file.take().unwrap() {
+            ReceivedFile::File(file) => file,
+            ReceivedFile::Sink(mut sink) => {
+                sink.0.shutdown().await?;
+                self.record(self.session.bytes_received(), TransferStatus::Completed);
+                return Ok(());
+            }
+        };
+        file.flush().await?;
+        if let Some(stripe) = self.session.stripe().cloned() {
+            return self.finish_stripe(&stripe).await;
+        }
+        let file = file.into_std().await;
+        if let Some(modified) = self.modified.take() {
+            file.set_modified(modified)?;
+        }
+        drop(file);
+        if let Some(partial) = self.partial.take() {
+            let (_, created_name) = Self::create_file(&partial.dir, &partial.file_name, "").await?;
+            let path = partial.dir.join(created_name);
+            tokio::fs::rename(&partial.path, &path).await?;
+            tokio::fs::remove_file(partial.state_path()).await?;
+            self.file_path = Some(path);
+        }
+        if let Some(path) = self.file_path.take() {
+            self.report(ReceiveEvent::FileReceived {
+                path,
+                bytes: self.session.bytes_received(),
+            });
+        }
+        self.record(self.session.bytes_received(), TransferStatus::Completed);
+        Ok(())
+    }
+
+    /// Calls `f` with the assembly of the file `stripe` is part of, which the first stripe to
+    /// arrive creates.
+    fn with_assembly<F, R>(&self, file_name: &str, stripe: &FileTransferStripeFrame, f: F) -> R
+    where
+        F: FnOnce(&mut StripeAssembly) -> R,
+    {
+        let mut assemblies = self.stripe_assemblies.lock().unwrap();
+        let assembly = assemblies
+            .entry(stripe.group_id)
+            .or_insert_with(|| StripeAssembly {
+                file_name: file_name.to_owned(),
+                part_path: self
+                    .dir
+                    .join(format!(".icedrop-{:016x}.part", stripe.group_id)),
+                stripes_left: stripe.stripe_count,
+                bytes_received: 0,
+                decision: Arc::default(),
+            });
+        f(assembly)
+    }
+
+    /// Opens the part file the stripes of a file are put back together in, positioned at the
+    /// start of `stripe`. The first stripe to arrive creates it.
+    async fn open_stripe(
+        &self,
+        file_name: &str,
+        stripe: &FileTransferStripeFrame,
+    ) -> io::Result<File> {
+        let part_path =
+            self.with_assembly(file_name, stripe, |assembly| assembly.part_path.clone());
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(part_path)
+            .await?;
+        let range = stripe_range(stripe.file_size, stripe.stripe_idx, stripe.stripe_count);
+        file.seek(SeekFrom::Start(range.start)).await?;
+        Ok(file)
+    }
+
+    /// Counts a stripe as received, and moves the file it's part of in place under a name of its
+    /// own once it was the last one.
+    async fn finish_stripe(&mut self, stripe: &FileTransferStripeFrame) -> io::Result<()> {
+        let assembly = {
+            let mut assemblies = self.stripe_assemblies.lock().unwrap();
+            let assembly = match assemblies.get_mut(&stripe.group_id) {
+                Some(assembly) => assembly,
+                None => return Err(io::Error::from(io::ErrorKind::NotFound)),
+            };
+            assembly.stripes_left = assembly.stripes_left.saturating_sub(1);
+            if assembly.stripes_left > 0 {
+                // The connection of the last stripe records the whole file.
+                self.receiving = None;
+                self.admitted = None;
+                return Ok(());
+            }
+            assemblies.remove(&stripe.group_id).unwrap()
+        };
+
+        let destination = match &*assembly.decision.lock().await {
+            Some(Decision::AcceptAs(path)) => Some(path.clone()),
+            _ => None,
+        };
+        let (dir, file_name) = destination_parts(&self.dir, &assembly.file_name, destination);
+        let (_, created_name) = Self::create_file(&dir, &file_name, "").await?;
+        let path = dir.join(created_name);
+        tokio::fs::rename(&assembly.part_path, &path).await?;
+        if let Some(modified) = self.modified.take() {
+            let file = OpenOptions::new().write(true).open(&path).await?;
+            file.into_std().await.set_modified(modified)?;
+        }
+        println!(
+            "put {} back together from {} stripes",
+            path.display(),
+            stripe.stripe_count
+        );
+        self.report(ReceiveEvent::FileReceived {
+            path,
+            bytes: stripe.file_size,
+        });
+        self.record(stripe.file_size, TransferStatus::Completed);
+        Ok(())
+    }
+
+    /// Creates `file_name` followed by `suffix` in `dir`, numbering the name if a file of that
+    /// name already exists. Returns the file with the name it has been created under.
+    async fn create_file(dir: &Path, file_name: &str, suffix: &str) -> io::Result<(File, String)> {
+        let mut candidate = format!("{}{}", file_name, suffix);
+        for n in 1..=MAX_FILE_NAME_COLLISIONS {
+            let result = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(dir.join(&candidate))
+                .await;
+            match result {
+                Ok(file) => return Ok((file, candidate)),
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                    candidate = format!("{}{}", numbered_file_name(file_name, n), suffix);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Err(io::Error::from(io::ErrorKind::AlreadyExists))
+    }
+
+    /// Creates the file `file_name` is received into, at `destination` if the delegate picked one
+    /// and in the receiving directory otherwise. Gives up on the transfer if that fails.
+    async fn open_file(&mut self, file_name: &str, destination: Option<PathBuf>) -> bool {
+        let (dir, name) = destination_parts(&self.dir, file_name, destination);
+        match self.create_destination(dir, name).await {
+            Ok(path) => {
+                if path == self.dir.join(file_name) {
+                    println!("receiving {}", file_name);
+                } else {
+                    println!("receiving {} as {}", file_name, path.display());
+                }
+                true
+            }
+            Err(err) => {
+                self.fail_on_disk_error(err).await;
+                false
+            }
+        }
+    }
+
+    /// Creates the file `file_name` in `dir` is received into, under a temporary name and with a
+    /// sidecar if writes are atomic. Returns where the file goes.
+    async fn create_destination(&mut self, dir: PathBuf, file_name: String) -> io::Result<PathBuf> {
+        if !self.atomic_writes {
+            let (file, created_name) = Self::create_file(&dir, &file_name, "").await?;
+            let path = dir.join(created_name);
+            self.file = Some(ReceivedFile::File(file));
+            self.file_path = Some(path.clone());
+            return Ok(path);
+        }
+        let (file, partial_name) = Self::create_file(&dir, &file_name, PARTIAL_FILE_SUFFIX).await?;
+        let path = dir.join(&file_name);
+        self.file = Some(ReceivedFile::File(file));
+        self.file_path = None;
+        self.partial = Some(PartialFile {
+            path: dir.join(partial_name),
+            dir,
+            file_name,
+        });
+        self.save_partial_state().await?;
+        Ok(path)
+    }
+
+    /// Records how far the file being received under a temporary name got in its sidecar.
+    async fn save_partial_state(&self) -> io::Result<()> {
+        let partial = match &self.partial {
+            Some(partial) => partial,
+            None => return Ok(()),
+        };
+        let state = PartialFileState {
+            file_name: partial.file_name.clone(),
+            sender: self.session.peer_name().to_owned(),
+            file_size: self.session.expected_size(),
+            bytes_received: self.session.bytes_received(),
+        };
+        let buf = serde_json::to_vec_pretty(&state)?;
+        tokio::fs::write(partial.state_path(), buf).await
+    }
+
+    /// Offers the file announced last, or the only one of the session if the sender didn't
+    /// announce it, to the limits and the delegate and opens it unless either rejects it. Returns
+    /// whether to receive the file.
+    async fn offer_file(&mut self) -> bool {
+        let file_name = self
+            .offered_file
+            .take()
+            .unwrap_or_else(|| DEFAULT_FILE_NAME.to_owned());
+        let file_size = self.session.expected_size();
+        if !self.admit(file_size).await {
+            return false;
+        }
+        let (delegate, addr) = match self.delegate.clone() {
+            Some(delegate) => delegate,
+            None => return self.open_file(&file_name, None).await,
+        };
+        let offer = TransferOffer {
+            sender: self.peer_identity(addr),
+            file_name: file_name.clone(),
+            file_size,
+        };
+        match delegate.should_accept(offer).await {
+            Decision::Accept => self.open_file(&file_name, None).await,
+            Decision::AcceptAs(path) => self.open_file(&file_name, Some(path)).await,
+            Decision::AcceptInto(sink) => {
+                println!("receiving {} into a sink", file_name);
+                self.file = Some(ReceivedFile::Sink(sink));
+                self.file_path = None;
+                true
+            }
+            Decision::Reject(message) => {
+                self.reject(RejectionReason::Declined, message).await;
+                false
+            }
+        }
+    }
+
+    /// Checks the file about to be received against the limits, if there are any, and turns it
+    /// down if it's over one. Returns whether to receive the file.
+    async fn admit(&mut self, file_size: Option<u64>) -> bool {
+        let (limits, addr) = match &self.limits {
+            Some(limits) => limits,
+            None => return true,
+        };
+        match limits.admit(addr.ip(), file_size, &self.dir) {
+            Ok(admitted) => {
+                self.admitted = Some(admitted);
+                true
+            }
+            Err(rejection) => {
+                self.reject(rejection.reason, rejection.message).await;
+                false
+            }
+        }
+    }
+
+    /// Checks `stripe` against the limits, and has the delegate decide about the file it's part
+    /// of, unless another stripe of it did already. Returns whether to receive the stripe.
+    async fn offer_stripe(&mut self, file_name: &str, stripe: &FileTransferStripeFrame) -> bool {
+        // Limited like the whole file, as that's what ends up on disk.
+        if !self.admit(Some(stripe.file_size)).await {
+            return false;
+        }
+        let (delegate, addr) = match self.delegate.clone() {
+            Some(delegate) => delegate,
+            None => return true,
+        };
+        let decision =
+            self.with_assembly(file_name, stripe, |assembly| Arc::clone(&assembly.decision));
+        let mut decision = decision.lock().await;
+        if decision.is_none() {
+            let offer = TransferOffer {
+                sender: self.peer_identity(addr),
+                file_name: file_name.to_owned(),
+                file_size: Some(stripe.file_size),
+            };
+            *decision = Some(delegate.should_accept(offer).await);
+        }
+        let message = match &*decision {
+            Some(Decision::Reject(message)) => message.clone(),
+            Some(Decision::AcceptInto(_)) => "files sent in stripes can't be received".to_owned(),
+            _ => return true,
+        };
+        drop(decision);
+        self.reject(RejectionReason::Declined, message).await;
+        false
+    }
+
+    /// Whether the sender has been let in after the handshake and pairing, `None` while it's still
+    /// going through them.
+    pub(crate) fn vetted(&self) -> Option<bool> {
+        match self.session.state() {
+            SessionState::Handshaking => None,
+            SessionState::Failed => Some(false),
+            _ if self.pending_auth.is_some() => None,
+            _ => Some(true),
+        }
+    }
+
+    /// Tells when the sender has been let in after the handshake and pairing. Closed once the
+    /// handler is dropped.
+    pub(crate) fn let_in(&self) -> watch::Receiver<bool> {
+        self.let_in_tx.subscribe()
+    }
+
+    /// What the handshake callback lets the sender do.
+    pub(crate) fn role(&self) -> PeerRole {
+        self.role
+    }
+
+    /// Who the sender at `addr` is, as far as the handshake told.
+    pub(crate) fn peer_identity(&self, addr: SocketAddr) -> PeerIdentity {
+        PeerIdentity {
+            addr,
+            name: self.session.peer_name().to_owned(),
+            protocol_version: self.session.protocol_version(),
+            capabilities: self.session.capabilities(),
+        }
+    }
+
+    /// Gives up on the transfer, telling the sender whether trying again later may work.
+    async fn fail_on_disk_error(&mut self, err: io::Error) {
+        println!("could not write the file: {}", err);
+        self.record_failure(err.to_string());
+        self.session.fail();
+        self.endpoint_handle
+            .send_frame(FileTransferErrorFrame {
+                retryable: is_retryable_disk_error(&err),
+                message: err.to_string(),
+            })
+            .await
+            .ok();
+        self.endpoint_handle.shutdown().await.ok();
+    }
+
+    /// Turns the sender away after the handshake, telling it why.
+    async fn deny(&mut self, message: String) {
+        self.reject(RejectionReason::Declined, message).await;
+    }
+
+    /// Turns the sender or the file it offered down for `reason`, with a frame telling the reason
+    /// apart if the sender understands it.
+    async fn reject(&mut self, reason: RejectionReason, message: String) {
+        println!("denied {}: {}", self.session.peer_name(), message);
+        self.record_failure(message.clone());
+        self.session.fail();
+        let sent = if self.session.protocol_version() >= REJECTION_PROTOCOL_VERSION {
+            let frame = TransferRejectedFrame { reason, message };
+            self.endpoint_handle.send_frame(frame).await
+        } else {
+            let frame = FileTransferErrorFrame {
+                retryable: reason.is_retryable(),
+                message,
+            };
+            self.endpoint_handle.send_frame(frame).await
+        };
+        sent.ok();
+        self.endpoint_handle.shutdown().await.ok();
+    }
+
+    /// Gives up on a transfer the sender doesn't follow the protocol in.
+    async fn abort(&mut self, err: SessionError) {
+        println!("aborting transfer: {}", err);
+        self.record_failure(err.to_string());
+        self.session.fail();
+        self.endpoint_handle.shutdown().await.ok();
+    }
+
+    /// Answers the handshake, the sender starts sending files once it reads the response.
+    async fn accept(&mut self, response: HandshakeResponseFrame) {
+        let protocol_version = response.protocol_version;
+        let capabilities = response.capabilities;
+        println!("receiving a file from {}", self.session.peer_name());
+        self.endpoint_handle.send_frame(response).await.unwrap();
+
+        // The response itself still goes out in the old format, the peer switches once it
+        // reads it.
+        self.endpoint_handle.set_protocol_version(protocol_version);
+        self.endpoint_handle.set_capabilities(capabilities);
+        self.let_in_tx.send_replace(true);
+    }
+
+    /// Hands text the sender sent instead of files to the server's delegate, and ends the session
+    /// once it took the text. Without a delegate text is turned down.
+    async fn receive_text(&mut self, frame: TextMessageFrame) {
+        if self.pending_auth.is_some() {
+            return self.deny("not paired with this device".to_owned()).await;
+        }
+        let text = match self.session.handle_text(frame) {
+            Ok(text) => text,
+            Err(err) => return self.abort(err).await,
+        };
+        let taken = match &self.delegate {
+            Some((delegate, addr)) => {
+                let sender = self.peer_identity(*addr);
+                delegate.text_received(sender, text).await
+            }
+            None => Err("text messages are not accepted".to_owned()),
+        };
+        if let Err(message) = taken {
+            return self.deny(message).await;
+        }
+        println!("received text from {}", self.session.peer_name());
+        self.endpoint_handle.send_frame(EndSessionFrame).await.ok();
+        self.endpoint_handle.shutdown().await.ok();
+    }
+
+    /// Challenges the sender to prove it's paired, or to supply the code shown to the user.
+    async fn challenge(&mut self, pending: PendingAuth) {
+        let (pairing, _) = self.pairing.as_ref().unwrap();
+        let frame = AuthChallengeFrame {
+            receiver_id: pairing.store.device_id(),
+            nonce: pending.nonce,
+            code_required: pending.code.is_some(),
+        };
+        self.pending_auth = Some(pending);
+        self.endpoint_handle.send_frame(frame).await.unwrap();
+    }
+
+    /// Checks the sender's answer to a challenge. Senders proving a key they share with the
+    /// receiver are accepted, any other sender has to pair with a code shown to the user first.
+    /// A wrong code or proof turns the sender away, the user has to start pairing over.
+    async fn handle_auth_request(&mut self, frame: AuthRequestFrame) {
+        let ((pairing, addr), mut pending) = match (&self.pairing, self.pending_auth.take()) {
+            (Some(pairing), Some(pending)) => (pairing.clone(), pending),
+            _ => return self.deny("unexpected authentication".to_owned()).await,
+        };
+        let key = pairing.store.key(&frame.device_id);
+        match (frame.credential, key, pending.code) {
+            (AuthCredential::Proof(proof), Some(key), None) => {
+                let receiver_id = pairing.store.device_id();
+                let expected = auth_proof(&key, &receiver_id, &frame.device_id, &pending.nonce);
+                if !proofs_match(&proof, &expected) {
+                    return self.deny("not paired with this device".to_owned()).await;
+                }
+                self.accept(pending.response).await;
+            }
+            // The receiver forgot about the sender, it has to pair again.
+            (AuthCredential::Proof(_), None, None) | (AuthCredential::None, _, None) => {
+                let code = random_pairing_code();
+                println!(
+                    "pairing with {}, code {}",
+                    self.session.peer_name(),
+                    format_pairing_code(code)
+                );
+                (pairing.on_code)(PairingRequest {
+                    addr,
+                    name: self.session.peer_name().to_owned(),
+                    code: format_pairing_code(code),
+                });
+                pending.code = Some(code);
+                self.challenge(pending).await;
+            }
+            (AuthCredential::Code(code), _, Some(expected)) if code == expected => {
+                let key = random_bytes();
+                let name = Some(self.session.peer_name().to_owned());
+                if let Err(err) = pairing.store.pair(&frame.device_id, name, &key) {
+                    return self
+                        .deny(format!("could not store the pairing: {}", err))
+                        .await;
+                }
+                println!("paired with {}", self.session.peer_name());
+                self.endpoint_handle
+                    .send_frame(PairedFrame { key })
+                    .await
+                    .unwrap();
+                self.accept(pending.response).await;
+            }
+            _ => self.deny("wrong pairing code".to_owned()).await,
+        }
+    }
+}
+
+/// Inserts ` (n)` before the extension of `file_name`, e.g. `photo (1).jpg`. A leading dot, as in
+/// `.profile`, doesn't start an extension.
+fn numbered_file_name(file_name: &str, n: u32) -> String {
+    match file_name.rfind('.') {
+        Some(dot) if dot > 0 => format!("{} ({}){}", &file_name[..dot], n, &file_name[dot..]),
+        _ => format!("{} ({})", file_name, n),
+    }
+}
+
+/// Splits where a file is received to into its directory and name, `destination` if the
+/// delegate picked one and `file_name` in `dir` otherwise.
+fn destination_parts(
+    dir: &Path,
+    file_name: &str,
+    destination: Option<PathBuf>,
+) -> (PathBuf, String) {
+    match destination {
+        Some(path) => {
+            let name = path.file_name().map_or_else(
+                || file_name.to_owned(),
+                |name| name.to_string_lossy().into_owned(),
+            );
+            let dir = match path.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent.to_owned(),
+                _ => PathBuf::from("."),
+            };
+            (dir, name)
+        }
+        None => (dir.to_owned(), file_name.to_owned()),
+    }
+}
+
+/// Seconds since the Unix epoch as carried by [`FileTransferMetadataFrame`], zero for times before
+/// it.
+pub(crate) fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_secs())
+}
+
+/// Whether a disk error may go away by itself, e.g. once some space has been freed or a network
+/// filesystem has recovered.
+fn is_retryable_disk_error(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::StorageFull
+            | io::ErrorKind::ResourceBusy
+            | io::ErrorKind::StaleNetworkFileHandle
+    )
+}
+
+impl Drop for FileTransferReceivingHandler {
+    fn drop(&mut self) {
+        self.record_failure("the connection was closed".to_owned());
+    }
+}
+
+#[async_trait]
+impl FrameHandler for FileTransferReceivingHandler {
+    type IncomingFrame = FileTransferReceivingFrame;
+
+    async fn handle_frame(&mut self, frame: Self::IncomingFrame) {
+        let sends = matches!(
+            frame,
+            FileTransferReceivingFrame::FileTransferBeginFrame(_)
+                | FileTransferReceivingFrame::TextMessageFrame(_)
+        );
+        if sends && !self.role.may_send() {
+            let message = "not allowed to send files or text".to_owned();
+            return self.reject(RejectionReason::Unauthorized, message).await;
+        }
+
+        if let FileTransferReceivingFrame::HandshakeRequestFrame(frame) = frame {
+            let response = match self.session.handle_handshake_request(frame) {
+                Ok(response) => response,
+                Err(err) => return self.abort(err).await,
+            };
+            let protocol_version = response.protocol_version;
+            if let Some((callback, addr)) = &self.handshake_check {
+                let identity = PeerIdentity {
+                    addr: *addr,
+                    name: self.session.peer_name().to_owned(),
+                    protocol_version,
+                    capabilities: response.capabilities,
+                };
+                match callback(identity).await {
+                    Ok(role) => self.role = role,
+                    Err(message) => return self.deny(message).await,
+                }
+            }
+            if self.pairing.is_some() {
+                if !response.capabilities.contains(Capabilities::PAIRING) {
+                    let message = "pairing requires a newer version of icedrop".to_owned();
+                    return self.deny(message).await;
+                }
+                let pending = PendingAuth {
+                    response,
+                    nonce: random_bytes(),
+                    code: None,
+                };
+                return self.challenge(pending).await;
+            }
+            self.accept(response).await;
+        } else if let FileTransferReceivingFrame::AuthRequestFrame(frame) = frame {
+            self.handle_auth_request(frame).await;
+        } else if let FileTransferReceivingFrame::FileTransferBeginFrame(frame) = frame {
+            let file_name = match self.session.handle_begin(frame) {
+                Ok(file_name) => file_name,
+                Err(err) => return self.abort(err).await,
+            };
+            self.receiving = Some((file_name.clone(), time::Instant::now()));
+            self.modified = None;
+            if let Some(stripe) = self.session.stripe().cloned() {
+                if self.decides_offers() && !self.offer_stripe(&file_name, &stripe).await {
+                    return;
+                }
+                match self.open_stripe(&file_name, &stripe).await {
+                    Ok(file) => {
+                        println!(
+                            "receiving stripe {} of {} of {}",
+                            stripe.stripe_idx + 1,
+                            stripe.stripe_count,
+                            file_name
+                        );
+                        self.file = Some(ReceivedFile::File(file));
+                        self.file_path = None;
+                    }
+                    Err(err) => self.fail_on_disk_error(err).await,
+                }
+                return;
+            }
+            if self.decides_offers() {
+                // The file is offered once the sender has described it.
+                self.offered_file = Some(file_name);
+                return;
+            }
+            self.open_file(&file_name, None).await;
+        } else if let FileTransferReceivingFrame::FileTransferMetadataFrame(frame) = frame {
+            let modified = frame.modified;
+            if let Err(err) = self.session.handle_metadata(frame) {
+                return self.abort(err).await;
+            }
+            if modified > 0 {
+                self.modified = Some(SystemTime::UNIX_EPOCH + Duration::from_secs(modified));
+            }
+            if self.offered_file.is_some() {
+                self.offer_file().await;
+            }
+        } else if let FileTransferReceivingFrame::FileTransferDataFrame(frame) = frame {
+            self.handle_data_frame(frame).await;
+        } else if let FileTransferReceivingFrame::FileTransferStripeFrame(frame) = frame {
+            if let Err(err) = self.session.handle_stripe(frame) {
+                self.abort(err).await;
+            }
+        } else if let FileTransferReceivingFrame::FileTransferVerifyFrame(frame) = frame {
+            if let Err(err) = self.session.handle_verify(frame) {
+                self.abort(err).await;
+            }
+        } else if let FileTransferReceivingFrame::TextMessageFrame(frame) = frame {
+            self.receive_text(frame).await;
+        } else if let FileTransferReceivingFrame::EndSessionFrame(frame) = frame {
+            if let Err(err) = self.session.handle_end_session(frame) {
+                return self.abort(err).await;
+            }
+            self.endpoint_handle.shutdown().await.ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        is_retryable_disk_error, numbered_file_name, FileTransferEvent, FileTransferNextFrame,
+        FileTransferNextHandler, TransferCounters, TransferProgress,
+    };
+    use crate::endpoint::Endpoint;
+    use crate::error::Error;
+    use crate::proto::Frame;
+
+    use std::io;
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::time::{self, Duration};
+
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn selector_routes_all_member_frame_types() {
+        assert_eq!(
+            FileTransferNextFrame::frame_types(),
+            [2, 17, 19, 4, 5, 6, 21, 7, 10, 99]
+        );
+    }
+
+    #[test]
+    fn colliding_file_names_are_numbered() {
+        assert_eq!(numbered_file_name("photo.jpg", 1), "photo (1).jpg");
+        assert_eq!(
+            numbered_file_name("archive.tar.gz", 2),
+            "archive.tar (2).gz"
+        );
+        assert_eq!(numbered_file_name("README", 1), "README (1)");
+        assert_eq!(numbered_file_name(".profile", 1), ".profile (1)");
+    }
+
+    #[test]
+    fn progress_percentage_needs_the_file_size() {
+        let mut progress = TransferProgress {
+            bytes_sent: 300,
+            bytes_confirmed: 100,
+            total_bytes: None,
+            throughput: 0.0,
+            eta: None,
+        };
+        assert_eq!(progress.percentage(), None);
+        progress.total_bytes = Some(400);
+        assert_eq!(progress.percentage(), Some(25.0));
+        progress.total_bytes = Some(0);
+        assert_eq!(progress.percentage(), Some(100.0));
+    }
+
+    #[test]
+    fn transient_disk_errors_are_retryable() {
+        let disk_full = io::Error::from(io::ErrorKind::StorageFull);
+        assert!(is_retryable_disk_error(&disk_full));
+
+        let read_only = io::Error::from(io::ErrorKind::ReadOnlyFilesystem);
+        assert!(!is_retryable_disk_error(&read_only));
+    }
+
+    #[test]
+    fn stalled_receiver_aborts_transfer() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let stream = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            // Keep the peer connected but silent.
+            let (_peer_stream, _) = listener.accept().await.unwrap();
+
+            let endpoint = Endpoint::new(stream);
+            let (event_tx, event_rx) = mpsc::channel();
+            let callback_fn: super::FileTransferCallbackFn = Box::new(move |event| {
+                if let FileTransferEvent::Stalled(since_last_ack) = event {
+                    event_tx.send(since_last_ack).unwrap();
+                }
+            });
+
+            let counters = Arc::new(TransferCounters::default());
+            tokio::spawn(FileTransferNextHandler::watch_acks(
+                endpoint.handle(),
+                Arc::new(Mutex::new(time::Instant::now())),
+                Arc::new(Mutex::new(Some(callback_fn))),
+                Arc::clone(&counters),
+                Duration::from_millis(40),
+                true,
+                None,
+            ));
+
+            tokio::time::timeout(Duration::from_secs(5), endpoint.run())
+                .await
+                .expect("stalled transfer was not aborted")
+                .unwrap();
+            assert!(event_rx.try_recv().unwrap() >= Duration::from_millis(40));
+            let summary = counters.tally.lock().unwrap().summary();
+            assert!(matches!(summary.error, Some(Error::Timeout(_))));
+        });
+    }
 }
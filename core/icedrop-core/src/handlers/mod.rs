@@ -1,4 +1,3 @@
+pub(crate) mod benchmark;
 pub(crate) mod file_transfer;
-pub(crate) mod handshake;
-pub(crate) mod session;
-mod utils;
+pub(crate) mod text;
@@ -0,0 +1,138 @@
+use crate::endpoint::EndpointHandle;
+use crate::error::Error;
+use crate::handlers::file_transfer::SenderPairing;
+use crate::proto::FrameHandler;
+
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use icedrop_proto::auth::{AuthChallengeFrame, DeviceId, PairedFrame};
+use icedrop_proto::def_frame_selector;
+use icedrop_proto::file_transfer::{FileTransferErrorFrame, TransferRejectedFrame};
+use icedrop_proto::handshake::HandshakeResponseFrame;
+use icedrop_proto::negotiate_protocol_version;
+use icedrop_proto::session::EndSessionFrame;
+use icedrop_proto::text::{TextMessageFrame, TEXT_PROTOCOL_VERSION};
+
+def_frame_selector!(
+    TextSendingFrame,
+    HandshakeResponseFrame,
+    AuthChallengeFrame,
+    PairedFrame,
+    FileTransferErrorFrame,
+    TransferRejectedFrame,
+    EndSessionFrame
+);
+
+pub(crate) type TextOutcome = Arc<Mutex<Option<Result<(), Error>>>>;
+
+/// Sends a text message once the handshake is done, and waits for the receiver to take it.
+pub struct TextSendingHandler {
+    endpoint_handle: EndpointHandle,
+    /// Taken once it's been sent.
+    text: Option<String>,
+    pairing: Option<SenderPairing>,
+    /// The receiver that challenged the sender, which a key from a [`PairedFrame`] is stored for.
+    challenged_by: Option<DeviceId>,
+    outcome: TextOutcome,
+}
+
+impl TextSendingHandler {
+    pub(crate) fn new(
+        endpoint_handle: EndpointHandle,
+        text: String,
+        pairing: Option<SenderPairing>,
+        outcome: TextOutcome,
+    ) -> Self {
+        Self {
+            endpoint_handle,
+            text: Some(text),
+            pairing,
+            challenged_by: None,
+            outcome,
+        }
+    }
+
+    async fn finish(&self, result: Result<(), Error>) {
+        if let Err(err) = &result {
+            println!("could not send the text: {}", err);
+        }
+        *self.outcome.lock().unwrap() = Some(result);
+        self.endpoint_handle.shutdown().await.ok();
+    }
+}
+
+#[async_trait]
+impl FrameHandler for TextSendingHandler {
+    type IncomingFrame = TextSendingFrame;
+
+    async fn handle_frame(&mut self, frame: Self::IncomingFrame) {
+        match frame {
+            TextSendingFrame::HandshakeResponseFrame(frame) => {
+                let protocol_version = negotiate_protocol_version(frame.protocol_version);
+                if protocol_version < TEXT_PROTOCOL_VERSION {
+                    let err = Error::Handshake(format!(
+                        "receiver speaks protocol version {}, which has no text messages",
+                        protocol_version
+                    ));
+                    return self.finish(Err(err)).await;
+                }
+                self.endpoint_handle.set_protocol_version(protocol_version);
+
+                let text = self.text.take().unwrap_or_default();
+                if let Err(err) = self
+                    .endpoint_handle
+                    .send_frame(TextMessageFrame { text })
+                    .await
+                {
+                    self.finish(Err(err)).await;
+                }
+            }
+            TextSendingFrame::AuthChallengeFrame(frame) => {
+                let pairing = match &self.pairing {
+                    Some(pairing) => pairing.clone(),
+                    None => {
+                        let err = Error::Handshake("the receiver requires pairing".to_owned());
+                        return self.finish(Err(err)).await;
+                    }
+                };
+                let request = match pairing.answer(&frame).await {
+                    Some(request) => request,
+                    None => return self.finish(Err(Error::Cancelled)).await,
+                };
+                self.challenged_by = Some(frame.receiver_id);
+                self.endpoint_handle.send_frame(request).await.ok();
+            }
+            TextSendingFrame::PairedFrame(frame) => {
+                if let (Some(pairing), Some(receiver_id)) = (&self.pairing, &self.challenged_by) {
+                    pairing.paired(receiver_id, frame);
+                }
+            }
+            TextSendingFrame::FileTransferErrorFrame(frame) => {
+                let err = Error::Rejected {
+                    retryable: frame.retryable,
+                    message: frame.message,
+                    reason: None,
+                };
+                self.finish(Err(err)).await;
+            }
+            TextSendingFrame::TransferRejectedFrame(frame) => {
+                let err = Error::Rejected {
+                    retryable: frame.reason.is_retryable(),
+                    message: frame.message,
+                    reason: Some(frame.reason),
+                };
+                self.finish(Err(err)).await;
+            }
+            TextSendingFrame::EndSessionFrame(_) => {
+                let result = match self.text {
+                    None => Ok(()),
+                    Some(_) => Err(Error::Protocol(
+                        "the receiver ended the session before the text was sent".to_owned(),
+                    )),
+                };
+                self.finish(result).await;
+            }
+        }
+    }
+}
@@ -0,0 +1,238 @@
+//! A record of the files sent and received, e.g. for apps to show a list of recent transfers.
+//!
+//! Every file a [`Client`](crate::Client) or [`Server`](crate::Server) given a [`HistoryStore`]
+//! sends or receives is added to it once it's done with, whether it made it or not. Entries are
+//! appended to a file as one JSON object per line and never changed afterwards, so the file
+//! doubles as an audit log and several clients and servers may share one.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use crate::pairing::{decode_hex, encode_hex};
+
+/// Whether a file was sent or received.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransferDirection {
+    Sent,
+    Received,
+}
+
+/// How a transfer ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransferStatus {
+    Completed,
+    /// The file didn't make it, for the reason given.
+    Failed(String),
+}
+
+/// A file sent or received.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryEntry {
+    /// When the transfer was over.
+    pub finished_at: SystemTime,
+    pub direction: TransferDirection,
+    /// The address of the other end, or of the relay or URL that stood in for it.
+    pub peer: String,
+    pub file_name: String,
+    /// Bytes of the file that made it, its size if the transfer completed.
+    pub size: u64,
+    pub duration: Duration,
+    pub status: TransferStatus,
+    /// SHA-256 digest of the file, only known with
+    /// [`VerificationMode::Full`](crate::VerificationMode::Full).
+    pub sha256: Option<[u8; 32]>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredEntry {
+    /// Milliseconds since the Unix epoch.
+    finished_at: u64,
+    direction: TransferDirection,
+    peer: String,
+    file_name: String,
+    size: u64,
+    duration_ms: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+}
+
+impl From<&HistoryEntry> for StoredEntry {
+    fn from(entry: &HistoryEntry) -> Self {
+        let finished_at = entry
+            .finished_at
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        Self {
+            finished_at: finished_at.as_millis() as u64,
+            direction: entry.direction,
+            peer: entry.peer.clone(),
+            file_name: entry.file_name.clone(),
+            size: entry.size,
+            duration_ms: entry.duration.as_millis() as u64,
+            error: match &entry.status {
+                TransferStatus::Completed => None,
+                TransferStatus::Failed(error) => Some(error.clone()),
+            },
+            sha256: entry.sha256.as_ref().map(|sha256| encode_hex(sha256)),
+        }
+    }
+}
+
+impl From<StoredEntry> for HistoryEntry {
+    fn from(entry: StoredEntry) -> Self {
+        Self {
+            finished_at: SystemTime::UNIX_EPOCH + Duration::from_millis(entry.finished_at),
+            direction: entry.direction,
+            peer: entry.peer,
+            file_name: entry.file_name,
+            size: entry.size,
+            duration: Duration::from_millis(entry.duration_ms),
+            status: match entry.error {
+                None => TransferStatus::Completed,
+                Some(error) => TransferStatus::Failed(error),
+            },
+            sha256: entry.sha256.and_then(|sha256| decode_hex(&sha256)),
+        }
+    }
+}
+
+/// The transfers done so far, kept in a file entries are only ever appended to.
+pub struct HistoryStore {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl HistoryStore {
+    /// Opens the history kept at `path`, creating the file if there is none.
+    pub fn open<P>(path: P) -> io::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref().to_owned();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(Self {
+            path,
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Every transfer recorded, oldest first. Lines that can't be read, such as one cut off by a
+    /// crash, are skipped.
+    pub fn entries(&self) -> io::Result<Vec<HistoryEntry>> {
+        let buf = fs::read_to_string(&self.path)?;
+        Ok(buf
+            .lines()
+            .filter_map(|line| serde_json::from_str::<StoredEntry>(line).ok())
+            .map(HistoryEntry::from)
+            .collect())
+    }
+
+    /// The last `count` transfers recorded, most recent first.
+    pub fn recent(&self, count: usize) -> io::Result<Vec<HistoryEntry>> {
+        let mut entries = self.entries()?;
+        entries.reverse();
+        entries.truncate(count);
+        Ok(entries)
+    }
+
+    /// Appends `entry` to the history.
+    pub fn record(&self, entry: &HistoryEntry) -> io::Result<()> {
+        let mut line = serde_json::to_vec(&StoredEntry::from(entry))?;
+        line.push(b'\n');
+        // A single write, so entries appended from other processes never end up interleaved.
+        self.file.lock().unwrap().write_all(&line)
+    }
+
+    /// Records `entry`, only logging if it can't be, as transfers go on regardless.
+    pub(crate) fn record_or_log(&self, entry: HistoryEntry) {
+        if let Err(err) = self.record(&entry) {
+            println!(
+                "could not record the transfer of {} in {}: {}",
+                entry.file_name,
+                self.path.display(),
+                err
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HistoryEntry, HistoryStore, TransferDirection, TransferStatus};
+
+    use std::io::Write;
+    use std::time::{Duration, SystemTime};
+
+    fn temp_store_path(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("icedrop-history-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        std::fs::remove_file(&path).ok();
+        path
+    }
+
+    fn entry(file_name: &str, status: TransferStatus) -> HistoryEntry {
+        HistoryEntry {
+            finished_at: SystemTime::UNIX_EPOCH + Duration::from_millis(1_700_000_000_123),
+            direction: TransferDirection::Sent,
+            peer: "192.168.1.20:7360".to_owned(),
+            file_name: file_name.to_owned(),
+            size: 1024,
+            duration: Duration::from_millis(250),
+            status,
+            sha256: Some([7; 32]),
+        }
+    }
+
+    #[test]
+    fn entries_survive_reopening() {
+        let path = temp_store_path("reopened.jsonl");
+        let completed = entry("a.txt", TransferStatus::Completed);
+        let failed = HistoryEntry {
+            sha256: None,
+            ..entry(
+                "b.txt",
+                TransferStatus::Failed("connection reset".to_owned()),
+            )
+        };
+
+        let store = HistoryStore::open(&path).unwrap();
+        store.record(&completed).unwrap();
+        drop(store);
+        let store = HistoryStore::open(&path).unwrap();
+        store.record(&failed).unwrap();
+
+        assert_eq!(
+            store.entries().unwrap(),
+            vec![completed.clone(), failed.clone()]
+        );
+        assert_eq!(store.recent(1).unwrap(), vec![failed]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn unreadable_lines_are_skipped() {
+        let path = temp_store_path("truncated.jsonl");
+        let completed = entry("a.txt", TransferStatus::Completed);
+
+        let store = HistoryStore::open(&path).unwrap();
+        store.record(&completed).unwrap();
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        file.write_all(b"{\"finished_at\":17000\n").unwrap();
+        store.record(&completed).unwrap();
+
+        assert_eq!(store.entries().unwrap().len(), 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
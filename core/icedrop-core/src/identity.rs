@@ -0,0 +1,308 @@
+//! Long-term keys for end-to-end encrypted connections, trusted on first use.
+//!
+//! Without an identity, every encrypted connection uses keys made up for it, and only comparing
+//! the [`ShortAuthString`](crate::ShortAuthString) tells who is on the other end. With one, a
+//! device keeps using the same key, and remembers the key every peer used the first time it
+//! connected, much like SSH does with host keys. A peer showing up with a different key later
+//! may be someone in the middle, so it's warned about or refused, see [`KeyChangePolicy`].
+//! Peers are told apart by the [`DeviceId`] they send in the handshake, not by their address, which
+//! changes with DHCP, is shared behind NAT and is the relay's for relayed connections. Peers
+//! without an identity make up a key for every connection, there's nothing to remember of them.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use crate::noise;
+use crate::pairing::{decode_hex, encode_hex, random_bytes, write_private_file};
+
+/// The public half of a device's key, what its peers know it by.
+pub type PublicKey = [u8; 32];
+
+/// Made up along with a device's keys, so its peers can tell it's the same device wherever it
+/// connects from.
+pub type DeviceId = [u8; 16];
+
+/// What happens when a known peer connects with a different key than before.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum KeyChangePolicy {
+    /// Logs a warning and goes on, keeping the key seen first.
+    Warn,
+    /// Drops the connection.
+    #[default]
+    Refuse,
+}
+
+/// A peer whose key has been seen before.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KnownPeer {
+    pub device_id: DeviceId,
+    pub key: PublicKey,
+    /// Where it last connected from, the relay's address if it came through one.
+    pub last_addr: IpAddr,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredIdentity {
+    private_key: String,
+    public_key: String,
+    /// Stores from before device ids get one when they're opened.
+    #[serde(default = "new_device_id")]
+    device_id: String,
+    /// By device id. Stores from before kept peers by address under `known_peers`, those are
+    /// dropped, since the address didn't tell the devices apart.
+    #[serde(default)]
+    peers: HashMap<String, StoredPeer>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredPeer {
+    key: String,
+    last_addr: IpAddr,
+}
+
+fn new_device_id() -> String {
+    encode_hex(&random_bytes::<16>())
+}
+
+/// This device's key pair and the keys of the peers it has talked to, saved to a JSON file
+/// whenever they change. The file holds the private key, it's only readable by its owner.
+pub struct IdentityStore {
+    path: PathBuf,
+    identity: Mutex<StoredIdentity>,
+    key_change_policy: KeyChangePolicy,
+}
+
+impl IdentityStore {
+    /// Opens the store saved at `path`, or creates one with a new key pair if there is none.
+    pub fn open<P>(path: P) -> io::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref().to_owned();
+        let identity = match fs::read(&path) {
+            Ok(buf) => {
+                let identity: StoredIdentity = serde_json::from_slice(&buf)?;
+                if decode_hex::<32>(&identity.private_key).is_none()
+                    || decode_hex::<32>(&identity.public_key).is_none()
+                    || decode_hex::<16>(&identity.device_id).is_none()
+                {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid key"));
+                }
+                identity
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                let (private_key, public_key) = noise::generate_keypair()?;
+                StoredIdentity {
+                    private_key: encode_hex(&private_key),
+                    public_key: encode_hex(&public_key),
+                    device_id: new_device_id(),
+                    peers: HashMap::new(),
+                }
+            }
+            Err(err) => return Err(err),
+        };
+        let store = Self {
+            path,
+            identity: Mutex::new(identity),
+            key_change_policy: KeyChangePolicy::default(),
+        };
+        store.save(&store.identity.lock().unwrap())?;
+        Ok(store)
+    }
+
+    /// Sets what happens when a known peer's key changes, refusing the connection by default.
+    pub fn set_key_change_policy(&mut self, policy: KeyChangePolicy) {
+        self.key_change_policy = policy;
+    }
+
+    /// The key this device is known by to its peers.
+    pub fn public_key(&self) -> PublicKey {
+        decode_hex(&self.identity.lock().unwrap().public_key).unwrap()
+    }
+
+    /// The id this device sends its peers along with its key.
+    pub fn device_id(&self) -> DeviceId {
+        decode_hex(&self.identity.lock().unwrap().device_id).unwrap()
+    }
+
+    /// The peers whose keys have been seen, in no particular order.
+    pub fn known_peers(&self) -> Vec<KnownPeer> {
+        let identity = self.identity.lock().unwrap();
+        identity
+            .peers
+            .iter()
+            .filter_map(|(device_id, peer)| {
+                Some(KnownPeer {
+                    device_id: decode_hex(device_id)?,
+                    key: decode_hex(&peer.key)?,
+                    last_addr: peer.last_addr,
+                })
+            })
+            .collect()
+    }
+
+    /// Forgets the key of the peer with `device_id`, e.g. after it was set up again, so whatever
+    /// key it uses next is trusted. Returns whether it was known.
+    pub fn forget(&self, device_id: &DeviceId) -> io::Result<bool> {
+        let mut identity = self.identity.lock().unwrap();
+        if identity.peers.remove(&encode_hex(device_id)).is_none() {
+            return Ok(false);
+        }
+        self.save(&identity)?;
+        Ok(true)
+    }
+
+    pub(crate) fn private_key(&self) -> [u8; 32] {
+        decode_hex(&self.identity.lock().unwrap().private_key).unwrap()
+    }
+
+    /// Checks the key the peer with `device_id` connected with from `addr` against the one it
+    /// used before, and remembers it if it's the first. Peers without a device id have no
+    /// identity, their keys are made up for the connection and pass.
+    pub(crate) fn verify_peer(
+        &self,
+        device_id: Option<&DeviceId>,
+        key: &PublicKey,
+        addr: IpAddr,
+    ) -> io::Result<()> {
+        let device_id = match device_id {
+            Some(device_id) => encode_hex(device_id),
+            None => return Ok(()),
+        };
+        let mut identity = self.identity.lock().unwrap();
+        let known_key = identity
+            .peers
+            .get(&device_id)
+            .and_then(|peer| decode_hex(&peer.key));
+        match known_key {
+            None => {
+                let peer = StoredPeer {
+                    key: encode_hex(key),
+                    last_addr: addr,
+                };
+                identity.peers.insert(device_id, peer);
+                self.save(&identity)
+            }
+            Some(known_key) if known_key == *key => {
+                let peer = identity.peers.get_mut(&device_id).unwrap();
+                if peer.last_addr == addr {
+                    return Ok(());
+                }
+                peer.last_addr = addr;
+                self.save(&identity)
+            }
+            Some(_) => {
+                let message = format!(
+                    "device {} connected from {} with a different key than before, someone may be \
+                     in the middle",
+                    device_id, addr
+                );
+                match self.key_change_policy {
+                    KeyChangePolicy::Warn => {
+                        println!("warning: {}", message);
+                        Ok(())
+                    }
+                    KeyChangePolicy::Refuse => {
+                        Err(io::Error::new(io::ErrorKind::PermissionDenied, message))
+                    }
+                }
+            }
+        }
+    }
+
+    fn save(&self, identity: &StoredIdentity) -> io::Result<()> {
+        write_private_file(&self.path, &serde_json::to_vec_pretty(identity)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{IdentityStore, KeyChangePolicy, KnownPeer};
+
+    use std::io;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    fn temp_store_path(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("icedrop-identity-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        std::fs::remove_file(&path).ok();
+        path
+    }
+
+    #[test]
+    fn identities_survive_reopening() {
+        let path = temp_store_path("reopened.json");
+        let addr = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 20));
+
+        let store = IdentityStore::open(&path).unwrap();
+        let public_key = store.public_key();
+        let device_id = store.device_id();
+        store.verify_peer(Some(&[7; 16]), &[1; 32], addr).unwrap();
+        drop(store);
+
+        let store = IdentityStore::open(&path).unwrap();
+        assert_eq!(store.public_key(), public_key);
+        assert_eq!(store.device_id(), device_id);
+        let peer = KnownPeer {
+            device_id: [7; 16],
+            key: [1; 32],
+            last_addr: addr,
+        };
+        assert_eq!(store.known_peers(), vec![peer]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn changed_keys_are_refused_or_warned_about() {
+        let path = temp_store_path("changed.json");
+        let addr = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 20));
+        let device_id = [7; 16];
+
+        let mut store = IdentityStore::open(&path).unwrap();
+        store.verify_peer(Some(&device_id), &[1; 32], addr).unwrap();
+        store.verify_peer(Some(&device_id), &[1; 32], addr).unwrap();
+        let err = store
+            .verify_peer(Some(&device_id), &[2; 32], addr)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+
+        store.set_key_change_policy(KeyChangePolicy::Warn);
+        store.verify_peer(Some(&device_id), &[2; 32], addr).unwrap();
+        assert_eq!(store.known_peers()[0].key, [1; 32]);
+
+        assert!(store.forget(&device_id).unwrap());
+        store.verify_peer(Some(&device_id), &[2; 32], addr).unwrap();
+        assert_eq!(store.known_peers()[0].key, [2; 32]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn peers_are_told_apart_by_device_not_address() {
+        let path = temp_store_path("moved.json");
+        let addr = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 20));
+        let new_addr = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 31));
+
+        let store = IdentityStore::open(&path).unwrap();
+        store.verify_peer(Some(&[7; 16]), &[1; 32], addr).unwrap();
+        // The same device after its address changed, e.g. with DHCP.
+        store
+            .verify_peer(Some(&[7; 16]), &[1; 32], new_addr)
+            .unwrap();
+        assert_eq!(store.known_peers()[0].last_addr, new_addr);
+        // Another device behind the same address, e.g. NAT or a relay.
+        store
+            .verify_peer(Some(&[8; 16]), &[2; 32], new_addr)
+            .unwrap();
+        // Without an identity, with a key made up for the connection.
+        store.verify_peer(None, &[3; 32], new_addr).unwrap();
+        assert_eq!(store.known_peers().len(), 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,10 +1,66 @@
-#![feature(fn_traits)]
 #![allow(dead_code)]
 
 mod client;
+mod connect;
+mod control;
+mod delegate;
+mod discovery;
 mod endpoint;
+mod error;
 mod handlers;
+mod history;
+#[cfg(feature = "noise")]
+mod identity;
+mod metrics;
+#[cfg(feature = "noise")]
+mod noise;
+mod pairing;
+mod policy;
+pub mod prelude;
 mod proto;
+#[cfg(feature = "quic")]
+mod quic;
+mod quick;
+mod rate_limit;
 mod server;
+mod share;
+#[cfg(feature = "websocket")]
+mod websocket;
 
-pub use client::Client;
+pub use client::{Client, TransferEvent};
+pub use connect::{ConnectAttempt, ConnectError};
+pub use control::TransferControl;
+pub use delegate::{Decision, ServerDelegate, TransferOffer, TransferSink};
+pub use discovery::{DiscoveryAnnouncer, DiscoveryBrowser, PeerCapabilities, PeerInfo};
+pub use endpoint::{EndpointHandle, EndpointMiddleware};
+pub use error::Error;
+pub use handlers::benchmark::{BenchmarkError, BenchmarkReport};
+pub use handlers::file_transfer::{
+    MetricsSnapshot, PeerIdentity, PeerRole, ReceiveEvent, TransferProgress, TransferSummary,
+};
+pub use history::{HistoryEntry, HistoryStore, TransferDirection, TransferStatus};
+pub use icedrop_proto::compression::CompressionMode;
+pub use icedrop_proto::file_transfer::RejectionReason;
+pub use icedrop_proto::frame_types;
+pub use icedrop_proto::handshake::{Capabilities, DisplayNameError};
+pub use icedrop_proto::relay::RelayToken;
+pub use icedrop_proto::transfer::{TransferConfig, VerificationMode};
+#[cfg(feature = "noise")]
+pub use identity::{DeviceId, IdentityStore, KeyChangePolicy, KnownPeer, PublicKey};
+pub use metrics::{MetricsExporter, NodeMetrics};
+#[cfg(feature = "noise")]
+pub use noise::ShortAuthString;
+pub use pairing::{PairedDevice, PairingRequest, PairingStore};
+pub use policy::ReceivePolicy;
+pub use proto::{Frame, FrameHandler, FrameParsingResult};
+pub use quick::{
+    receive_into, send, ReceiveOptions, SendError, SendTarget, DEFAULT_PORT, DISCOVERY_TIMEOUT,
+};
+#[cfg(feature = "quic")]
+pub use quinn;
+pub use server::{
+    Admission, ConnectionSetup, Server, ServerBuilder, ShutdownHandle, DEFAULT_DRAIN_TIMEOUT,
+};
+pub use share::{pull, RemoteEntry, SharedRoots};
+#[cfg(feature = "tls")]
+pub use tokio_rustls::rustls;
@@ -0,0 +1,342 @@
+//! Counters of what the process has sent and received, e.g. to monitor a long-running receiver.
+//!
+//! Every endpoint adds to the same counters, whether it belongs to a [`Client`](crate::Client) or
+//! a [`Server`](crate::Server), so they describe the whole process. [`NodeMetrics::current`] reads
+//! them, and a [`MetricsExporter`] serves them to Prometheus, see
+//! [`ServerBuilder::metrics_addr`](crate::ServerBuilder::metrics_addr).
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+use crate::history::TransferDirection;
+
+/// How long a scrape has to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Requests for the metrics are a lot shorter, anything longer is cut off.
+const MAX_REQUEST_LEN: usize = 8 * 1024;
+
+static BYTES_SENT: AtomicU64 = AtomicU64::new(0);
+static BYTES_RECEIVED: AtomicU64 = AtomicU64::new(0);
+static FRAMES_SENT: Mutex<BTreeMap<u16, u64>> = Mutex::new(BTreeMap::new());
+static FRAMES_RECEIVED: Mutex<BTreeMap<u16, u64>> = Mutex::new(BTreeMap::new());
+static ACTIVE_SESSIONS: AtomicU64 = AtomicU64::new(0);
+static ERRORS: AtomicU64 = AtomicU64::new(0);
+static FILES_SENT: AtomicU64 = AtomicU64::new(0);
+static FILES_RECEIVED: AtomicU64 = AtomicU64::new(0);
+static BEACONS_SENT: AtomicU64 = AtomicU64::new(0);
+static BEACONS_RECEIVED: AtomicU64 = AtomicU64::new(0);
+
+/// The counters of the process at one point, totals since it started unless noted otherwise.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NodeMetrics {
+    /// Bytes written to peers, frame headers included.
+    pub bytes_sent: u64,
+    /// Bytes read from peers, frame headers included.
+    pub bytes_received: u64,
+    /// Frames sent, by frame type.
+    pub frames_sent: BTreeMap<u16, u64>,
+    /// Frames received, by frame type.
+    pub frames_received: BTreeMap<u16, u64>,
+    /// Connections currently open.
+    pub active_sessions: u64,
+    /// Connections that ended with an error, and files that failed to be received.
+    pub errors: u64,
+    pub files_sent: u64,
+    pub files_received: u64,
+    /// Discovery announcements sent.
+    pub beacons_sent: u64,
+    /// Discovery announcements heard from receivers.
+    pub beacons_received: u64,
+}
+
+impl NodeMetrics {
+    /// The counters as they are now.
+    pub fn current() -> Self {
+        Self {
+            bytes_sent: BYTES_SENT.load(Ordering::Relaxed),
+            bytes_received: BYTES_RECEIVED.load(Ordering::Relaxed),
+            frames_sent: FRAMES_SENT.lock().unwrap().clone(),
+            frames_received: FRAMES_RECEIVED.lock().unwrap().clone(),
+            active_sessions: ACTIVE_SESSIONS.load(Ordering::Relaxed),
+            errors: ERRORS.load(Ordering::Relaxed),
+            files_sent: FILES_SENT.load(Ordering::Relaxed),
+            files_received: FILES_RECEIVED.load(Ordering::Relaxed),
+            beacons_sent: BEACONS_SENT.load(Ordering::Relaxed),
+            beacons_received: BEACONS_RECEIVED.load(Ordering::Relaxed),
+        }
+    }
+
+    /// The counters in the Prometheus text exposition format, every metric named `icedrop_*`.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let counters = [
+            (
+                "bytes_sent_total",
+                "Bytes written to peers.",
+                self.bytes_sent,
+            ),
+            (
+                "bytes_received_total",
+                "Bytes read from peers.",
+                self.bytes_received,
+            ),
+            (
+                "errors_total",
+                "Connections ended by an error and files failed to be received.",
+                self.errors,
+            ),
+            (
+                "files_sent_total",
+                "Files sent completely.",
+                self.files_sent,
+            ),
+            (
+                "files_received_total",
+                "Files received completely.",
+                self.files_received,
+            ),
+            (
+                "discovery_beacons_sent_total",
+                "Discovery announcements sent.",
+                self.beacons_sent,
+            ),
+            (
+                "discovery_beacons_received_total",
+                "Discovery announcements heard.",
+                self.beacons_received,
+            ),
+        ];
+        for (name, help, value) in counters {
+            write_metric(&mut out, name, help, "counter");
+            writeln!(out, "icedrop_{} {}", name, value).unwrap();
+        }
+
+        write_metric(
+            &mut out,
+            "active_sessions",
+            "Connections currently open.",
+            "gauge",
+        );
+        writeln!(out, "icedrop_active_sessions {}", self.active_sessions).unwrap();
+
+        let frames = [
+            (
+                "frames_sent_total",
+                "Frames sent, by frame type.",
+                &self.frames_sent,
+            ),
+            (
+                "frames_received_total",
+                "Frames received, by frame type.",
+                &self.frames_received,
+            ),
+        ];
+        for (name, help, counts) in frames {
+            write_metric(&mut out, name, help, "counter");
+            for (frame_type, count) in counts {
+                writeln!(
+                    out,
+                    "icedrop_{}{{frame_type=\"{}\"}} {}",
+                    name, frame_type, count
+                )
+                .unwrap();
+            }
+        }
+        out
+    }
+}
+
+fn write_metric(out: &mut String, name: &str, help: &str, kind: &str) {
+    writeln!(out, "# HELP icedrop_{} {}", name, help).unwrap();
+    writeln!(out, "# TYPE icedrop_{} {}", name, kind).unwrap();
+}
+
+/// Counts an open connection until dropped.
+pub(crate) struct ActiveSession(());
+
+impl ActiveSession {
+    pub(crate) fn start() -> Self {
+        ACTIVE_SESSIONS.fetch_add(1, Ordering::Relaxed);
+        Self(())
+    }
+}
+
+impl Drop for ActiveSession {
+    fn drop(&mut self) {
+        ACTIVE_SESSIONS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub(crate) fn count_frame_sent(frame_type: u16) {
+    *FRAMES_SENT.lock().unwrap().entry(frame_type).or_default() += 1;
+}
+
+pub(crate) fn count_bytes_sent(len: usize) {
+    BYTES_SENT.fetch_add(len as u64, Ordering::Relaxed);
+}
+
+pub(crate) fn count_frame_received(frame_type: u16, len: usize) {
+    *FRAMES_RECEIVED
+        .lock()
+        .unwrap()
+        .entry(frame_type)
+        .or_default() += 1;
+    BYTES_RECEIVED.fetch_add(len as u64, Ordering::Relaxed);
+}
+
+pub(crate) fn count_error() {
+    ERRORS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn count_file(direction: TransferDirection) {
+    match direction {
+        TransferDirection::Sent => FILES_SENT.fetch_add(1, Ordering::Relaxed),
+        TransferDirection::Received => FILES_RECEIVED.fetch_add(1, Ordering::Relaxed),
+    };
+}
+
+pub(crate) fn count_beacon_sent() {
+    BEACONS_SENT.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn count_beacon_received() {
+    BEACONS_RECEIVED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Serves the counters of the process over HTTP, for Prometheus to scrape at `/metrics`.
+pub struct MetricsExporter {
+    listener: TcpListener,
+}
+
+impl MetricsExporter {
+    /// Starts listening on `addr`.
+    pub async fn bind<A>(addr: A) -> io::Result<Self>
+    where
+        A: ToSocketAddrs,
+    {
+        Ok(Self {
+            listener: TcpListener::bind(addr).await?,
+        })
+    }
+
+    /// The address the exporter listens on, e.g. to find out the port picked when binding to port
+    /// 0.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Answers scrapes until the task is cancelled.
+    pub async fn run(&self) {
+        loop {
+            match self.listener.accept().await {
+                Ok((stream, addr)) => {
+                    tokio::spawn(async move {
+                        if let Err(err) = respond(stream).await {
+                            tracing::debug!(%addr, error = %err, "could not serve metrics");
+                        }
+                    });
+                }
+                Err(err) => println!("could not accept metrics scrape: {:?}", err),
+            }
+        }
+    }
+}
+
+/// Answers the request on `stream` with the current counters if it asks for `/metrics`.
+async fn respond(mut stream: TcpStream) -> io::Result<()> {
+    let request = tokio::time::timeout(REQUEST_TIMEOUT, read_request_head(&mut stream))
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+    let mut request_line = request.lines().next().unwrap_or_default().split(' ');
+    let (status, body) = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", NodeMetrics::current().to_prometheus()),
+        _ => ("404 Not Found", "not found\n".to_owned()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Reads an HTTP request up to the end of its headers, the body of scrapes is empty.
+async fn read_request_head(stream: &mut TcpStream) -> io::Result<String> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 1024];
+    while !buf.windows(4).any(|window| window == b"\r\n\r\n") && buf.len() < MAX_REQUEST_LEN {
+        let len = stream.read(&mut chunk).await?;
+        if len == 0 {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..len]);
+    }
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MetricsExporter, NodeMetrics};
+
+    use std::collections::BTreeMap;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn counters_are_rendered_for_prometheus() {
+        let metrics = NodeMetrics {
+            bytes_sent: 1024,
+            frames_received: BTreeMap::from([(4, 2), (5, 10)]),
+            active_sessions: 1,
+            ..NodeMetrics::default()
+        };
+
+        let text = metrics.to_prometheus();
+        assert!(text
+            .contains("# TYPE icedrop_bytes_sent_total counter\nicedrop_bytes_sent_total 1024\n"));
+        assert!(text.contains("# TYPE icedrop_active_sessions gauge\nicedrop_active_sessions 1\n"));
+        assert!(text.contains("icedrop_frames_received_total{frame_type=\"4\"} 2\n"));
+        assert!(text.contains("icedrop_frames_received_total{frame_type=\"5\"} 10\n"));
+        assert!(!text.contains("icedrop_frames_sent_total{"));
+    }
+
+    #[test]
+    fn exporter_answers_scrapes() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let exporter = MetricsExporter::bind("127.0.0.1:0").await.unwrap();
+            let addr = exporter.local_addr().unwrap();
+            let exporter_task = tokio::spawn(async move { exporter.run().await });
+
+            let get = |path: &'static str| async move {
+                let mut stream = TcpStream::connect(addr).await.unwrap();
+                let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+                stream.write_all(request.as_bytes()).await.unwrap();
+                let mut response = String::new();
+                stream.read_to_string(&mut response).await.unwrap();
+                response
+            };
+
+            let response = get("/metrics").await;
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+            assert!(response.contains("Content-Type: text/plain; version=0.0.4\r\n"));
+            assert!(response.contains("\r\n\r\n# HELP icedrop_bytes_sent_total"));
+            assert!(get("/").await.starts_with("HTTP/1.1 404 Not Found\r\n"));
+            exporter_task.abort();
+        });
+    }
+}
@@ -0,0 +1,444 @@
+//! End-to-end encryption of connections with the Noise protocol, independent of TLS.
+//!
+//! Right after connecting, both ends run a `Noise_XX_25519_ChaChaPoly_BLAKE2s` handshake with
+//! keys made up for the connection, and encrypt everything sent afterwards. Since neither end
+//! knows the other's key beforehand, users make sure nobody is in the middle by comparing the
+//! [`ShortAuthString`] both devices show.
+
+use std::convert::TryInto;
+use std::fmt::Display;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use snow::{Builder, TransportState};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::identity::{DeviceId, IdentityStore, PublicKey};
+
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+
+/// Mixed into the handshake, so it can't be replayed against other protocols using Noise.
+const PROLOGUE: &[u8] = b"icedrop";
+
+/// How long the other end has to complete the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The longest Noise message, each is sent after its `u16` length.
+const MAX_MESSAGE_LEN: usize = 65535;
+
+/// Bytes the authentication tag adds to every encrypted message.
+const TAG_LEN: usize = 16;
+
+/// The most plaintext one message carries.
+const MAX_CHUNK_LEN: usize = MAX_MESSAGE_LEN - TAG_LEN;
+
+/// What the [`ShortAuthString::emoji`] are picked from, six bits each.
+const EMOJI: [&str; 64] = [
+    "🐶", "🐱", "🦁", "🐴", "🦄", "🐷", "🐘", "🐰", "🐼", "🐓", "🐧", "🐢", "🐟", "🐙", "🦋", "🌷",
+    "🌳", "🌵", "🍄", "🌏", "🌙", "☁️", "🔥", "🍌", "🍎", "🍓", "🌽", "🍕", "🎂", "❤️", "😀", "🤖",
+    "🎩", "👓", "🔧", "🎅", "👍", "☂️", "⌛", "⏰", "🎁", "💡", "📕", "✏️", "📎", "✂️", "🔒", "🔑",
+    "🔨", "☎️", "🏁", "🚂", "🚲", "✈️", "🚀", "🏆", "⚽", "🎸", "🎺", "🔔", "⚓", "🎧", "📁", "📌",
+];
+
+/// A code derived from the keys of an encrypted connection, the same on both ends unless someone
+/// is in the middle. Users compare the codes both devices show, all of it, before trusting the
+/// connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShortAuthString {
+    handshake_hash: Vec<u8>,
+}
+
+impl ShortAuthString {
+    /// Six digits, e.g. to be read out.
+    pub fn pin(&self) -> String {
+        let bytes = [
+            self.handshake_hash[0],
+            self.handshake_hash[1],
+            self.handshake_hash[2],
+            self.handshake_hash[3],
+        ];
+        format!("{:06}", u32::from_le_bytes(bytes) % 1_000_000)
+    }
+
+    /// Four emoji, easier to compare at a glance than digits.
+    pub fn emoji(&self) -> [&'static str; 4] {
+        let bits = u32::from_le_bytes([
+            self.handshake_hash[4],
+            self.handshake_hash[5],
+            self.handshake_hash[6],
+            0,
+        ]);
+        [0, 1, 2, 3].map(|i| EMOJI[(bits >> (6 * i)) as usize & 0x3f])
+    }
+}
+
+impl Display for ShortAuthString {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.pin(), self.emoji().join(" "))
+    }
+}
+
+fn noise_error(err: snow::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+async fn write_message<S>(stream: &mut S, message: &[u8]) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    stream
+        .write_all(&(message.len() as u16).to_le_bytes())
+        .await?;
+    stream.write_all(message).await?;
+    stream.flush().await
+}
+
+async fn read_message<S>(stream: &mut S) -> io::Result<Vec<u8>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut len_buf = [0u8; 2];
+    stream.read_exact(&mut len_buf).await?;
+    let mut message = vec![0; u16::from_le_bytes(len_buf) as usize];
+    stream.read_exact(&mut message).await?;
+    Ok(message)
+}
+
+/// A new key pair, private key first.
+pub(crate) fn generate_keypair() -> io::Result<([u8; 32], PublicKey)> {
+    let keypair = Builder::new(NOISE_PARAMS.parse().unwrap())
+        .generate_keypair()
+        .map_err(noise_error)?;
+    let private_key = keypair.private.as_slice().try_into().unwrap();
+    let public_key = keypair.public.as_slice().try_into().unwrap();
+    Ok((private_key, public_key))
+}
+
+async fn handshake<S>(
+    mut stream: S,
+    initiator: bool,
+    identity: Option<&IdentityStore>,
+) -> io::Result<Handshake<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let private_key = match identity {
+        Some(identity) => identity.private_key(),
+        None => generate_keypair()?.0,
+    };
+    let device_id = identity.map(|identity| identity.device_id());
+    let builder = Builder::new(NOISE_PARAMS.parse().unwrap())
+        .local_private_key(&private_key)
+        .prologue(PROLOGUE);
+    let mut state = if initiator {
+        builder.build_initiator()
+    } else {
+        builder.build_responder()
+    }
+    .map_err(noise_error)?;
+
+    // The device ids go with the second and third messages, the first isn't encrypted yet.
+    let mut buf = vec![0; MAX_MESSAGE_LEN];
+    let mut remote_device_id = None;
+    let mut messages = 0;
+    while !state.is_handshake_finished() {
+        if state.is_my_turn() {
+            let payload = match &device_id {
+                Some(device_id) if messages > 0 => &device_id[..],
+                _ => &[],
+            };
+            let len = state
+                .write_message(payload, &mut buf)
+                .map_err(noise_error)?;
+            write_message(&mut stream, &buf[..len]).await?;
+        } else {
+            let message = read_message(&mut stream).await?;
+            let len = state
+                .read_message(&message, &mut buf)
+                .map_err(noise_error)?;
+            if messages > 0 && len > 0 {
+                let device_id = buf[..len]
+                    .try_into()
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid device id"))?;
+                remote_device_id = Some(device_id);
+            }
+        }
+        messages += 1;
+    }
+
+    let short_auth_string = ShortAuthString {
+        handshake_hash: state.get_handshake_hash().to_vec(),
+    };
+    // XX always sends the static keys.
+    let remote_key = state
+        .get_remote_static()
+        .and_then(|key| key.try_into().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no key from the peer"))?;
+    let transport = state.into_transport_mode().map_err(noise_error)?;
+    Ok((
+        NoiseStream::new(stream, transport),
+        short_auth_string,
+        remote_key,
+        remote_device_id,
+    ))
+}
+
+/// The stream to talk over after the handshake, and the peer's key and [`DeviceId`], if it has
+/// an [`IdentityStore`].
+pub(crate) type Handshake<S> = (NoiseStream<S>, ShortAuthString, PublicKey, Option<DeviceId>);
+
+/// Runs the handshake as the connecting end with the key and device id of `identity`, or a key
+/// made up for the connection and no device id.
+pub(crate) async fn connect<S>(
+    stream: S,
+    identity: Option<&IdentityStore>,
+) -> io::Result<Handshake<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake(stream, true, identity)).await?
+}
+
+/// Runs the handshake as the accepting end, see [`connect`].
+pub(crate) async fn accept<S>(
+    stream: S,
+    identity: Option<&IdentityStore>,
+) -> io::Result<Handshake<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake(stream, false, identity)).await?
+}
+
+/// Encrypts everything written to `stream` and decrypts everything read from it, in messages of
+/// up to [`MAX_MESSAGE_LEN`] bytes, each following its `u16` length.
+pub(crate) struct NoiseStream<S> {
+    stream: S,
+    transport: TransportState,
+    /// Decrypted bytes not read yet, from `read_pos` on.
+    plaintext: Vec<u8>,
+    read_pos: usize,
+    /// The length and as much of the encrypted message being received as has arrived.
+    incoming: Vec<u8>,
+    /// Encrypted messages not written to `stream` yet, from `write_pos` on.
+    outgoing: Vec<u8>,
+    write_pos: usize,
+}
+
+impl<S> NoiseStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn new(stream: S, transport: TransportState) -> Self {
+        Self {
+            stream,
+            transport,
+            plaintext: Vec::new(),
+            read_pos: 0,
+            incoming: Vec::new(),
+            outgoing: Vec::new(),
+            write_pos: 0,
+        }
+    }
+
+    /// How long `incoming` has to get before it can be decrypted.
+    fn incoming_len(&self) -> io::Result<usize> {
+        if self.incoming.len() < 2 {
+            return Ok(2);
+        }
+        let len = u16::from_le_bytes([self.incoming[0], self.incoming[1]]) as usize;
+        if len < TAG_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "encrypted message too short",
+            ));
+        }
+        Ok(2 + len)
+    }
+
+    fn poll_write_outgoing(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.write_pos < self.outgoing.len() {
+            let outgoing = &self.outgoing[self.write_pos..];
+            let written = ready!(Pin::new(&mut self.stream).poll_write(cx, outgoing))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_pos += written;
+        }
+        self.outgoing.clear();
+        self.write_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> AsyncRead for NoiseStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.read_pos < this.plaintext.len() {
+                let len = buf.remaining().min(this.plaintext.len() - this.read_pos);
+                buf.put_slice(&this.plaintext[this.read_pos..this.read_pos + len]);
+                this.read_pos += len;
+                return Poll::Ready(Ok(()));
+            }
+
+            let needed = this.incoming_len()?;
+            if this.incoming.len() == needed && needed > 2 {
+                this.plaintext.resize(MAX_MESSAGE_LEN, 0);
+                let len = this
+                    .transport
+                    .read_message(&this.incoming[2..], &mut this.plaintext)
+                    .map_err(noise_error)?;
+                this.plaintext.truncate(len);
+                this.read_pos = 0;
+                this.incoming.clear();
+                continue;
+            }
+
+            let start = this.incoming.len();
+            this.incoming.resize(needed, 0);
+            let mut read_buf = ReadBuf::new(&mut this.incoming[start..]);
+            let result = Pin::new(&mut this.stream).poll_read(cx, &mut read_buf);
+            let read = read_buf.filled().len();
+            this.incoming.truncate(start + read);
+            ready!(result)?;
+            if read == 0 {
+                // The stream may only end between messages.
+                if start == 0 {
+                    return Poll::Ready(Ok(()));
+                }
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+        }
+    }
+}
+
+impl<S> AsyncWrite for NoiseStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_outgoing(cx))?;
+
+        let chunk = &buf[..buf.len().min(MAX_CHUNK_LEN)];
+        this.outgoing.resize(2 + chunk.len() + TAG_LEN, 0);
+        let len = this
+            .transport
+            .write_message(chunk, &mut this.outgoing[2..])
+            .map_err(noise_error)?;
+        this.outgoing[..2].copy_from_slice(&(len as u16).to_le_bytes());
+        this.outgoing.truncate(2 + len);
+
+        // The chunk is taken either way, what doesn't go out now goes out on the next write or
+        // flush.
+        if let Poll::Ready(Err(err)) = this.poll_write_outgoing(cx) {
+            return Poll::Ready(Err(err));
+        }
+        Poll::Ready(Ok(chunk.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_outgoing(cx))?;
+        Pin::new(&mut this.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_outgoing(cx))?;
+        Pin::new(&mut this.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{accept, connect, MAX_CHUNK_LEN};
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn both_ends_agree_and_talk_encrypted() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (client, server) = tokio::io::duplex(4096);
+            let accepting = tokio::spawn(accept(server, None));
+            let (mut client, client_code, _, _) = connect(client, None).await.unwrap();
+            let (mut server, server_code, _, _) = accepting.await.unwrap().unwrap();
+            assert_eq!(client_code, server_code);
+            assert_eq!(client_code.pin().len(), 6);
+
+            // Larger than a single message.
+            let data: Vec<u8> = (0..3 * MAX_CHUNK_LEN).map(|i| i as u8).collect();
+            let expected = data.clone();
+            let writing = tokio::spawn(async move {
+                client.write_all(&data).await.unwrap();
+                client.shutdown().await.unwrap();
+                client
+            });
+            let mut received = Vec::new();
+            server.read_to_end(&mut received).await.unwrap();
+            assert_eq!(received, expected);
+            writing.await.unwrap();
+        });
+    }
+
+    #[test]
+    fn tampered_messages_are_rejected() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (client, middle) = tokio::io::duplex(4096);
+            let (middle_out, server) = tokio::io::duplex(4096);
+
+            // Passes the handshake through, then flips a bit of the first encrypted message.
+            let relay = tokio::spawn(async move {
+                let (mut middle_rd, mut middle_wr) = tokio::io::split(middle);
+                let (mut out_rd, mut out_wr) = tokio::io::split(middle_out);
+                let backwards = tokio::spawn(async move {
+                    tokio::io::copy(&mut out_rd, &mut middle_wr).await.ok();
+                });
+                let mut buf = vec![0; 4096];
+                let mut messages = 0;
+                loop {
+                    let mut len_buf = [0u8; 2];
+                    if middle_rd.read_exact(&mut len_buf).await.is_err() {
+                        break;
+                    }
+                    let len = u16::from_le_bytes(len_buf) as usize;
+                    middle_rd.read_exact(&mut buf[..len]).await.unwrap();
+                    messages += 1;
+                    if messages == 3 {
+                        buf[0] ^= 1;
+                    }
+                    out_wr.write_all(&len_buf).await.unwrap();
+                    out_wr.write_all(&buf[..len]).await.unwrap();
+                }
+                backwards.abort();
+            });
+
+            let accepting = tokio::spawn(accept(server, None));
+            let (mut client, _, _, _) = connect(client, None).await.unwrap();
+            let (mut server, _, _, _) = accepting.await.unwrap().unwrap();
+            client.write_all(b"hello").await.unwrap();
+            client.flush().await.unwrap();
+            let mut buf = [0u8; 5];
+            assert!(server.read_exact(&mut buf).await.is_err());
+            drop(client);
+            relay.await.unwrap();
+        });
+    }
+}
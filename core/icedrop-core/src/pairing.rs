@@ -0,0 +1,250 @@
+//! Devices paired with this one, kept on disk so transfers between them are trusted right away.
+//!
+//! The first time a sender talks to a receiver requiring pairing, the receiver shows a code its
+//! user reads out to the sender's user, see
+//! [`ServerBuilder::pairing`](crate::ServerBuilder::pairing) and
+//! [`Client::set_pairing`](crate::Client::set_pairing). Both then store a key for the other, which
+//! proves who they are from then on. The code and the key are sent in the clear, pair over TLS or
+//! end-to-end encrypted connections where others may listen in.
+
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use icedrop_proto::auth::{DeviceId, PairingKey, PAIRING_CODE_DIGITS};
+use serde::{Deserialize, Serialize};
+
+/// A sender asking to pair, as given to the server's pairing callback.
+#[derive(Debug, Clone)]
+pub struct PairingRequest {
+    pub addr: SocketAddr,
+    /// The display name the sender introduced itself with.
+    pub name: String,
+    /// The code to show the user, for the sender's user to type in.
+    pub code: String,
+}
+
+/// A device paired with this one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PairedDevice {
+    pub id: DeviceId,
+    /// The display name the device had when pairing, only known for senders.
+    pub name: Option<String>,
+}
+
+/// Shows the user the code of a sender asking to pair.
+pub(crate) type PairingCodeCallbackFn = Arc<dyn Fn(PairingRequest) + Send + Sync>;
+
+/// Asks the user for the code the receiver shows, `None` if they gave up.
+pub(crate) type PairingPromptFn = Arc<dyn Fn() -> Option<String> + Send + Sync>;
+
+#[derive(Serialize, Deserialize)]
+struct StoredPeer {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    key: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredPairings {
+    device_id: String,
+    #[serde(default)]
+    peers: HashMap<String, StoredPeer>,
+}
+
+/// This device's id and the keys of the devices paired with it, saved to a JSON file whenever
+/// they change. The file holds the keys, it's only readable by its owner.
+pub struct PairingStore {
+    path: PathBuf,
+    pairings: Mutex<StoredPairings>,
+}
+
+impl PairingStore {
+    /// Opens the store saved at `path`, or creates one with a new device id if there is none.
+    pub fn open<P>(path: P) -> io::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref().to_owned();
+        let pairings = match fs::read(&path) {
+            Ok(buf) => {
+                let pairings: StoredPairings = serde_json::from_slice(&buf)?;
+                if decode_hex::<16>(&pairings.device_id).is_none() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "invalid device id",
+                    ));
+                }
+                pairings
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => StoredPairings {
+                device_id: encode_hex(&random_bytes::<16>()),
+                peers: HashMap::new(),
+            },
+            Err(err) => return Err(err),
+        };
+        let store = Self {
+            path,
+            pairings: Mutex::new(pairings),
+        };
+        store.save(&store.pairings.lock().unwrap())?;
+        Ok(store)
+    }
+
+    /// The id this device is known by to the ones it's paired with.
+    pub fn device_id(&self) -> DeviceId {
+        decode_hex(&self.pairings.lock().unwrap().device_id).unwrap()
+    }
+
+    /// The devices paired with this one, in no particular order.
+    pub fn paired_devices(&self) -> Vec<PairedDevice> {
+        let pairings = self.pairings.lock().unwrap();
+        pairings
+            .peers
+            .iter()
+            .filter_map(|(id, peer)| {
+                Some(PairedDevice {
+                    id: decode_hex(id)?,
+                    name: peer.name.clone(),
+                })
+            })
+            .collect()
+    }
+
+    /// Forgets the device with the given id, which has to pair again the next time. Returns
+    /// whether it was paired.
+    pub fn unpair(&self, id: &DeviceId) -> io::Result<bool> {
+        let mut pairings = self.pairings.lock().unwrap();
+        if pairings.peers.remove(&encode_hex(id)).is_none() {
+            return Ok(false);
+        }
+        self.save(&pairings)?;
+        Ok(true)
+    }
+
+    /// The key shared with the device with the given id, if it's paired.
+    pub(crate) fn key(&self, id: &DeviceId) -> Option<PairingKey> {
+        let pairings = self.pairings.lock().unwrap();
+        decode_hex(&pairings.peers.get(&encode_hex(id))?.key)
+    }
+
+    /// Stores the key shared with a newly paired device, replacing any older one.
+    pub(crate) fn pair(
+        &self,
+        id: &DeviceId,
+        name: Option<String>,
+        key: &PairingKey,
+    ) -> io::Result<()> {
+        let mut pairings = self.pairings.lock().unwrap();
+        let peer = StoredPeer {
+            name,
+            key: encode_hex(key),
+        };
+        pairings.peers.insert(encode_hex(id), peer);
+        self.save(&pairings)
+    }
+
+    fn save(&self, pairings: &StoredPairings) -> io::Result<()> {
+        write_private_file(&self.path, &serde_json::to_vec_pretty(pairings)?)
+    }
+}
+
+/// Writes `buf` to a file only its owner can read next to `path`, then moves it in place so a
+/// crash midway doesn't lose what was there before.
+pub(crate) fn write_private_file(path: &Path, buf: &[u8]) -> io::Result<()> {
+    let mut tmp_path = path.to_owned().into_os_string();
+    tmp_path.push(".tmp");
+
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(&tmp_path)?;
+    file.write_all(buf)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)
+}
+
+/// Random bytes fit for keys, from the operating system.
+pub(crate) fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut buf = [0; N];
+    getrandom::getrandom(&mut buf).expect("no randomness available");
+    buf
+}
+
+/// A random pairing code, evenly spread over all codes.
+pub(crate) fn random_pairing_code() -> u32 {
+    let codes = 10u32.pow(PAIRING_CODE_DIGITS as u32);
+    // Drop the values past the last whole multiple of `codes`, which would favor small codes.
+    let limit = u32::MAX - u32::MAX % codes;
+    loop {
+        let value = u32::from_le_bytes(random_bytes());
+        if value < limit {
+            return value % codes;
+        }
+    }
+}
+
+pub(crate) fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub(crate) fn decode_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    if hex.len() != N * 2 || !hex.is_ascii() {
+        return None;
+    }
+    let mut bytes = [0; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{random_pairing_code, PairedDevice, PairingStore};
+
+    #[test]
+    fn pairings_survive_reopening() {
+        let dir = std::env::temp_dir().join(format!("icedrop-pairing-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("pairings.json");
+        std::fs::remove_file(&path).ok();
+
+        let store = PairingStore::open(&path).unwrap();
+        let device_id = store.device_id();
+        store
+            .pair(&[1; 16], Some("laptop".to_owned()), &[2; 32])
+            .unwrap();
+        store.pair(&[3; 16], None, &[4; 32]).unwrap();
+        assert!(store.unpair(&[3; 16]).unwrap());
+        assert!(!store.unpair(&[3; 16]).unwrap());
+        drop(store);
+
+        let store = PairingStore::open(&path).unwrap();
+        assert_eq!(store.device_id(), device_id);
+        assert_eq!(store.key(&[1; 16]), Some([2; 32]));
+        assert_eq!(store.key(&[3; 16]), None);
+        assert_eq!(
+            store.paired_devices(),
+            vec![PairedDevice {
+                id: [1; 16],
+                name: Some("laptop".to_owned()),
+            }]
+        );
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn pairing_codes_have_six_digits() {
+        for _ in 0..100 {
+            assert!(random_pairing_code() < 1_000_000);
+        }
+    }
+}
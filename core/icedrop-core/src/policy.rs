@@ -0,0 +1,273 @@
+//! Limits on what a [`Server`](crate::Server) receives, see [`ReceivePolicy`].
+
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use icedrop_proto::file_transfer::RejectionReason;
+
+/// What a server takes from senders, checked before every file is received. Files over a limit
+/// are turned down, telling the sender which one, see [`RejectionReason`]. Nothing is limited by
+/// default.
+#[derive(Debug, Clone, Default)]
+pub struct ReceivePolicy {
+    max_file_size: Option<u64>,
+    max_concurrent_transfers: Option<usize>,
+    min_free_space: Option<u64>,
+    peer_quota: Option<u64>,
+}
+
+impl ReceivePolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Turns down files larger than `size` bytes, and stops receiving any file once more has been
+    /// sent of it. Files whose size the sender didn't tell upfront, as senders older than protocol
+    /// version 5 don't, are turned down.
+    pub fn max_file_size(mut self, size: u64) -> Self {
+        self.max_file_size = Some(size);
+        self
+    }
+
+    /// Turns down files while `count` others are being received, from any sender.
+    pub fn max_concurrent_transfers(mut self, count: usize) -> Self {
+        self.max_concurrent_transfers = Some(count);
+        self
+    }
+
+    /// Turns down files that would leave less than `bytes` free on the disk files are received
+    /// to. Only checked on Unix.
+    pub fn min_free_space(mut self, bytes: u64) -> Self {
+        self.min_free_space = Some(bytes);
+        self
+    }
+
+    /// Turns down files once a sender, told apart by its IP address, has sent `bytes` in total,
+    /// counted as files are received while the server runs. A file going over the quota is
+    /// stopped right then, and files whose size the sender didn't tell upfront are turned down.
+    pub fn peer_quota(mut self, bytes: u64) -> Self {
+        self.peer_quota = Some(bytes);
+        self
+    }
+}
+
+/// Why a file was turned down, for the sender.
+pub(crate) struct Rejection {
+    pub(crate) reason: RejectionReason,
+    pub(crate) message: String,
+}
+
+impl Rejection {
+    fn new(reason: RejectionReason, message: String) -> Self {
+        Self { reason, message }
+    }
+}
+
+/// A [`ReceivePolicy`] along with what it's checked against, shared by all connections of a
+/// server.
+pub(crate) struct ReceiveLimits {
+    policy: ReceivePolicy,
+    /// Files being received.
+    transfers: AtomicUsize,
+    /// Bytes received from every sender so far.
+    received: Mutex<HashMap<IpAddr, u64>>,
+}
+
+impl ReceiveLimits {
+    pub(crate) fn new(policy: ReceivePolicy) -> Self {
+        Self {
+            policy,
+            transfers: AtomicUsize::new(0),
+            received: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Checks whether the file of `size` bytes `addr` offers may be received into `dir`. Once it
+    /// may, it counts as being received until the returned transfer is dropped.
+    pub(crate) fn admit(
+        self: &Arc<Self>,
+        addr: IpAddr,
+        size: Option<u64>,
+        dir: &Path,
+    ) -> Result<AdmittedTransfer, Rejection> {
+        let policy = &self.policy;
+        let limits_size = policy.max_file_size.is_some() || policy.peer_quota.is_some();
+        if limits_size && size.is_none() {
+            let message = "files have to be sent with their size".to_owned();
+            return Err(Rejection::new(RejectionReason::Declined, message));
+        }
+        if let (Some(max_size), Some(size)) = (policy.max_file_size, size) {
+            if size > max_size {
+                let message = format!("files may be {} bytes at most", max_size);
+                return Err(Rejection::new(RejectionReason::FileTooLarge, message));
+            }
+        }
+        if let Some(quota) = policy.peer_quota {
+            let received = self.received.lock().unwrap().get(&addr).copied();
+            if received.unwrap_or(0) + size.unwrap_or(0) > quota {
+                let message = format!("only {} bytes may be sent in total", quota);
+                return Err(Rejection::new(RejectionReason::QuotaExceeded, message));
+            }
+        }
+        if let Some(min_free) = policy.min_free_space {
+            match free_space(dir) {
+                Ok(Some(free)) if free.saturating_sub(size.unwrap_or(0)) < min_free => {
+                    let message = "not enough disk space left".to_owned();
+                    return Err(Rejection::new(RejectionReason::InsufficientSpace, message));
+                }
+                Ok(_) => {}
+                Err(err) => println!(
+                    "could not tell the free space in {}: {}",
+                    dir.display(),
+                    err
+                ),
+            }
+        }
+
+        let transfers = self.transfers.fetch_add(1, Ordering::SeqCst);
+        let transfer = AdmittedTransfer {
+            limits: Arc::clone(self),
+            addr,
+            received: 0,
+        };
+        match policy.max_concurrent_transfers {
+            Some(max_transfers) if transfers >= max_transfers => {
+                let message = format!("already receiving {} files", max_transfers);
+                Err(Rejection::new(RejectionReason::TooManyTransfers, message))
+            }
+            _ => Ok(transfer),
+        }
+    }
+}
+
+/// A file that passed the [`ReceivePolicy`], counting as being received until dropped.
+pub(crate) struct AdmittedTransfer {
+    limits: Arc<ReceiveLimits>,
+    addr: IpAddr,
+    /// Bytes of the file received so far.
+    received: u64,
+}
+
+impl AdmittedTransfer {
+    /// Counts `bytes` more of the file as received, against the sender's quota too, and checks
+    /// the file is still within the limits.
+    pub(crate) fn receive(&mut self, bytes: u64) -> Result<(), Rejection> {
+        self.received += bytes;
+        let total = {
+            let mut received = self.limits.received.lock().unwrap();
+            let total = received.entry(self.addr).or_default();
+            *total += bytes;
+            *total
+        };
+        let policy = &self.limits.policy;
+        if let Some(max_size) = policy.max_file_size.filter(|&max| self.received > max) {
+            let message = format!("files may be {} bytes at most", max_size);
+            return Err(Rejection::new(RejectionReason::FileTooLarge, message));
+        }
+        if let Some(quota) = policy.peer_quota.filter(|&quota| total > quota) {
+            let message = format!("only {} bytes may be sent in total", quota);
+            return Err(Rejection::new(RejectionReason::QuotaExceeded, message));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for AdmittedTransfer {
+    fn drop(&mut self) {
+        self.limits.transfers.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// The bytes free for unprivileged users on the filesystem `dir` is on, `None` where that can't
+/// be told.
+#[cfg(unix)]
+fn free_space(dir: &Path) -> io::Result<Option<u64>> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(dir.as_os_str().as_bytes())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let stat = unsafe { stat.assume_init() };
+    // The field types differ between platforms.
+    #[allow(clippy::unnecessary_cast)]
+    Ok(Some(stat.f_bavail as u64 * stat.f_frsize as u64))
+}
+
+#[cfg(not(unix))]
+fn free_space(_dir: &Path) -> io::Result<Option<u64>> {
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ReceiveLimits, ReceivePolicy, Rejection};
+
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::sync::Arc;
+
+    use icedrop_proto::file_transfer::RejectionReason;
+
+    #[test]
+    fn files_over_a_limit_are_rejected() {
+        let dir = std::env::temp_dir();
+        let addr = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 20));
+        let other_addr = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 21));
+        let policy = ReceivePolicy::new()
+            .max_file_size(1000)
+            .max_concurrent_transfers(1)
+            .peer_quota(1500);
+        let limits = Arc::new(ReceiveLimits::new(policy));
+        fn reason<T>(result: Result<T, Rejection>) -> Option<RejectionReason> {
+            result.err().map(|err| err.reason)
+        }
+
+        assert_eq!(
+            reason(limits.admit(addr, Some(1001), &dir)),
+            Some(RejectionReason::FileTooLarge)
+        );
+        assert_eq!(
+            reason(limits.admit(addr, None, &dir)),
+            Some(RejectionReason::Declined)
+        );
+        let mut transfer = limits.admit(addr, Some(1000), &dir).ok().unwrap();
+        assert_eq!(
+            reason(limits.admit(other_addr, Some(10), &dir)),
+            Some(RejectionReason::TooManyTransfers)
+        );
+        assert!(transfer.receive(1000).is_ok());
+        drop(transfer);
+        assert_eq!(
+            reason(limits.admit(addr, Some(600), &dir)),
+            Some(RejectionReason::QuotaExceeded)
+        );
+        let mut transfer = limits.admit(other_addr, Some(600), &dir).ok().unwrap();
+        // Senders sending more than they said are stopped once they're over a limit.
+        assert!(transfer.receive(1000).is_ok());
+        assert_eq!(
+            reason(transfer.receive(1)),
+            Some(RejectionReason::FileTooLarge)
+        );
+        drop(transfer);
+        let mut transfer = limits.admit(other_addr, Some(0), &dir).ok().unwrap();
+        assert_eq!(
+            reason(transfer.receive(500)),
+            Some(RejectionReason::QuotaExceeded)
+        );
+
+        let full_disk = Arc::new(ReceiveLimits::new(
+            ReceivePolicy::new().min_free_space(u64::MAX),
+        ));
+        assert_eq!(
+            reason(full_disk.admit(addr, None, &dir)),
+            Some(RejectionReason::InsufficientSpace)
+        );
+    }
+}
@@ -0,0 +1,20 @@
+//! The types most applications need, to be glob imported:
+//!
+//! ```no_run
+//! use icedrop_core::prelude::*;
+//!
+//! # async fn example() -> Result<(), SendError> {
+//! let summary = send("photo.jpg", SendTarget::Named("Living room".to_owned())).await?;
+//! println!("sent {} bytes", summary.bytes);
+//! # Ok(())
+//! # }
+//! ```
+
+pub use crate::client::{Client, TransferEvent};
+pub use crate::delegate::{Decision, ServerDelegate, TransferOffer, TransferSink};
+pub use crate::discovery::{DiscoveryAnnouncer, DiscoveryBrowser, PeerInfo};
+pub use crate::handlers::file_transfer::{ReceiveEvent, TransferProgress, TransferSummary};
+pub use crate::quick::{receive_into, send, ReceiveOptions, SendError, SendTarget};
+pub use crate::server::{Server, ServerBuilder};
+pub use icedrop_proto::compression::CompressionMode;
+pub use icedrop_proto::transfer::{TransferConfig, VerificationMode};
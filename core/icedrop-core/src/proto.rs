@@ -1,31 +1,9 @@
-use std::error::Error;
-use std::fmt::Debug;
-
 use async_trait::async_trait;
 
-pub enum FrameParsingResult<F> {
-    Ok(F),
-    Skip(Vec<u8>),
-    Err(Box<dyn Error + Send>),
-}
-
-impl<F> FrameParsingResult<F> {
-    pub(crate) fn unwrap_buf(self) -> Vec<u8> {
-        match self {
-            Self::Skip(val) => val,
-            _ => panic!("called `FrameParsingResult::unwrap_buf()` on a non-skip value"),
-        }
-    }
-}
-
-pub trait Frame: Debug + Send + Sized {
-    fn frame_type(&self) -> u16;
-
-    fn try_parse(frame_type: u16, buf: Vec<u8>) -> FrameParsingResult<Self>;
-
-    fn to_bytes(self) -> Vec<u8>;
-}
+pub use icedrop_proto::{Frame, FrameParsingResult, PROTOCOL_VERSION};
 
+/// Takes the frames of the types [`FrameHandler::IncomingFrame`] accepts as they're received, see
+/// [`ConnectionSetup::add_handler`](crate::ConnectionSetup::add_handler).
 #[async_trait]
 pub trait FrameHandler {
     type IncomingFrame: Frame;
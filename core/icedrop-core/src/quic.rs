@@ -0,0 +1,95 @@
+//! Connections over QUIC, which encrypts them with TLS 1.3 and recovers from lost packets
+//! without holding up everything sent after them.
+//!
+//! A session is carried by a single bidirectional stream the client opens, so the endpoint
+//! talks over QUIC just like over a TCP connection.
+
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use quinn::{ClientConfig, Connection, Incoming, RecvStream, SendStream};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// The stream of a session over QUIC, keeping its connection open as long as it's around.
+pub(crate) struct QuicStream {
+    send: SendStream,
+    recv: RecvStream,
+    connection: Connection,
+    /// The endpoint the connection was made from, only owned by clients.
+    endpoint: Option<quinn::Endpoint>,
+}
+
+impl QuicStream {
+    /// Connects to the receiver at `addr`, checking its certificate against `config` and
+    /// `server_name`.
+    pub(crate) async fn connect(
+        addr: SocketAddr,
+        server_name: &str,
+        config: ClientConfig,
+    ) -> io::Result<Self> {
+        let bind_addr = if addr.is_ipv6() {
+            SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))
+        } else {
+            SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+        };
+        let endpoint = quinn::Endpoint::client(bind_addr)?;
+        let connection = endpoint
+            .connect_with(config, addr, server_name)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?
+            .await?;
+        let (send, recv) = connection.open_bi().await?;
+        Ok(Self {
+            send,
+            recv,
+            connection,
+            endpoint: Some(endpoint),
+        })
+    }
+
+    /// Completes the handshake with a client and waits for it to open the session's stream.
+    pub(crate) async fn accept(incoming: Incoming) -> io::Result<Self> {
+        let connection = incoming.await?;
+        let (send, recv) = connection.accept_bi().await?;
+        Ok(Self {
+            send,
+            recv,
+            connection,
+            endpoint: None,
+        })
+    }
+
+    pub(crate) fn remote_address(&self) -> SocketAddr {
+        self.connection.remote_address()
+    }
+}
+
+impl AsyncRead for QuicStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().recv).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for QuicStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        // Not the inherent `SendStream::poll_write`, which has its own error type.
+        AsyncWrite::poll_write(Pin::new(&mut self.get_mut().send), cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().send).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().send).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,280 @@
+//! One-call helpers for applications that just want to send or receive files with the default
+//! settings. Everything they do can be done by hand with [`Client`] and [`Server`].
+
+use std::error::Error;
+use std::ffi::OsStr;
+use std::fmt::Display;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::time::Duration;
+
+use tokio::fs::File;
+
+use crate::client::Client;
+use crate::connect::ConnectError;
+use crate::discovery::{DiscoveryAnnouncer, DiscoveryBrowser, PeerCapabilities, PeerInfo};
+use crate::handlers::file_transfer::{
+    ReceiveEvent, ReceiveEventCallbackFn, TransferSummary, DEFAULT_FILE_NAME,
+};
+use crate::server::Server;
+
+/// The port [`receive_into`] listens on unless told otherwise.
+pub const DEFAULT_PORT: u16 = 7369;
+
+/// How long [`send`] looks for a receiver given by name.
+pub const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where [`send`] sends to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SendTarget {
+    /// A host and port, like `192.168.1.20:7369` or `desk.local:7369`.
+    Address(String),
+    /// A receiver announcing itself on the local network under this name, see
+    /// [`DiscoveryAnnouncer`].
+    Named(String),
+}
+
+impl From<&str> for SendTarget {
+    fn from(addr: &str) -> Self {
+        Self::Address(addr.to_owned())
+    }
+}
+
+impl From<String> for SendTarget {
+    fn from(addr: String) -> Self {
+        Self::Address(addr)
+    }
+}
+
+impl From<SocketAddr> for SendTarget {
+    fn from(addr: SocketAddr) -> Self {
+        Self::Address(addr.to_string())
+    }
+}
+
+impl From<&PeerInfo> for SendTarget {
+    fn from(peer: &PeerInfo) -> Self {
+        Self::from(peer.addr)
+    }
+}
+
+/// Why [`send`] failed.
+#[derive(Debug)]
+pub enum SendError {
+    /// The file could not be opened.
+    File(io::Error),
+    /// Listening for receivers on the local network failed.
+    Discovery(io::Error),
+    /// No receiver announced itself under the given name in time.
+    NotFound(String),
+    Connect(ConnectError),
+    /// The receiver was reached, but the file didn't make it.
+    Incomplete(TransferSummary),
+}
+
+impl Display for SendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::File(err) => write!(f, "could not open the file: {}", err),
+            Self::Discovery(err) => write!(f, "could not look for receivers: {}", err),
+            Self::NotFound(name) => write!(f, "no receiver called {:?} was found", name),
+            Self::Connect(err) => err.fmt(f),
+            Self::Incomplete(summary) => {
+                write!(f, "the transfer broke off after {} bytes", summary.bytes)
+            }
+        }
+    }
+}
+
+impl Error for SendError {}
+
+/// Waits for a receiver called `name` to announce itself on the local network.
+async fn find_receiver(name: &str) -> Result<PeerInfo, SendError> {
+    let mut browser = DiscoveryBrowser::bind()
+        .await
+        .map_err(SendError::Discovery)?;
+    let search = async {
+        loop {
+            let peer = browser.next().await?;
+            if peer.name == name {
+                return Ok(peer);
+            }
+        }
+    };
+    match tokio::time::timeout(DISCOVERY_TIMEOUT, search).await {
+        Ok(result) => result.map_err(SendError::Discovery),
+        Err(_) => Err(SendError::NotFound(name.to_owned())),
+    }
+}
+
+/// Sends the file at `path` to `target`, which the receiver stores under the same file name.
+pub async fn send<P, T>(path: P, target: T) -> Result<TransferSummary, SendError>
+where
+    P: AsRef<Path>,
+    T: Into<SendTarget>,
+{
+    let path = path.as_ref();
+    let file = File::open(path).await.map_err(SendError::File)?;
+    let file_name = path
+        .file_name()
+        .and_then(OsStr::to_str)
+        .unwrap_or(DEFAULT_FILE_NAME);
+
+    let addr = match target.into() {
+        SendTarget::Address(addr) => addr,
+        SendTarget::Named(name) => find_receiver(&name).await?.addr.to_string(),
+    };
+    let mut client = Client::connect(addr).await.map_err(SendError::Connect)?;
+    client.queue_file(file_name, file);
+
+    let summary = client.run().await;
+    if summary.success {
+        Ok(summary)
+    } else {
+        Err(SendError::Incomplete(summary))
+    }
+}
+
+/// Settings of [`receive_into`].
+pub struct ReceiveOptions {
+    bind_addr: SocketAddr,
+    name: Option<String>,
+    atomic_writes: bool,
+    event_callback: Option<ReceiveEventCallbackFn>,
+}
+
+impl ReceiveOptions {
+    pub fn new() -> Self {
+        Self {
+            bind_addr: SocketAddr::from((Ipv4Addr::UNSPECIFIED, DEFAULT_PORT)),
+            name: None,
+            atomic_writes: true,
+            event_callback: None,
+        }
+    }
+
+    /// Sets where connections are accepted (port [`DEFAULT_PORT`] on every interface by
+    /// default).
+    pub fn bind_addr(mut self, addr: SocketAddr) -> Self {
+        self.bind_addr = addr;
+        self
+    }
+
+    /// Announces the receiver on the local network under `name`, so senders can find it with
+    /// [`SendTarget::Named`]. Receivers aren't announced by default.
+    pub fn announce_as(mut self, name: &str) -> Self {
+        self.name = Some(name.to_owned());
+        self
+    }
+
+    /// Whether files are written under a temporary name and moved in place once complete, see
+    /// [`ServerBuilder::atomic_writes`](crate::ServerBuilder::atomic_writes). On by default.
+    pub fn atomic_writes(mut self, atomic: bool) -> Self {
+        self.atomic_writes = atomic;
+        self
+    }
+
+    /// Sets the callback told about the progress of the files being received, and where they
+    /// have been written to.
+    pub fn on_event<F>(mut self, f: F) -> Self
+    where
+        F: Fn(ReceiveEvent) + Send + Sync + 'static,
+    {
+        self.event_callback = Some(std::sync::Arc::new(f));
+        self
+    }
+}
+
+impl Default for ReceiveOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Receives files into `dir`, creating it if needed, until the task is cancelled. Only returns
+/// early if listening or announcing the receiver fails.
+pub async fn receive_into<P>(dir: P, options: ReceiveOptions) -> io::Result<()>
+where
+    P: AsRef<Path>,
+{
+    tokio::fs::create_dir_all(dir.as_ref()).await?;
+    let mut builder = Server::builder()
+        .dest_dir(dir)
+        .atomic_writes(options.atomic_writes);
+    if let Some(event_callback) = options.event_callback {
+        builder = builder.event_callback(move |event| event_callback(event));
+    }
+    let mut server = builder.bind(options.bind_addr).await?;
+
+    match options.name {
+        Some(name) => {
+            let port = server.local_addr()?.port();
+            let announcer =
+                DiscoveryAnnouncer::bind(&name, port, PeerCapabilities::default()).await?;
+            tokio::select! {
+                _ = server.run() => Ok(()),
+                result = announcer.run() => result,
+            }
+        }
+        None => {
+            server.run().await;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{receive_into, send, ReceiveOptions, SendError};
+    use crate::handlers::file_transfer::ReceiveEvent;
+
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    use tokio::net::TcpListener;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn files_are_sent_and_received_in_one_call() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let dir = std::env::temp_dir().join(format!("icedrop-quick-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::write(dir.join("notes.txt"), b"five lines").unwrap();
+
+            let addr = {
+                let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+                listener.local_addr().unwrap()
+            };
+            let (received_tx, received_rx) = mpsc::channel();
+            let options = ReceiveOptions::new()
+                .bind_addr(addr)
+                .on_event(move |event| {
+                    if let ReceiveEvent::FileReceived { path, .. } = event {
+                        received_tx.send(path).unwrap();
+                    }
+                });
+            let receiver = tokio::spawn(receive_into(dir.join("inbox"), options));
+
+            // The receiver may not be listening yet.
+            let mut result = send(dir.join("notes.txt"), addr).await;
+            for _ in 0..50 {
+                if !matches!(result, Err(SendError::Connect(_))) {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                result = send(dir.join("notes.txt"), addr).await;
+            }
+            assert!(result.unwrap().success);
+            let path = received_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+            assert_eq!(path, dir.join("inbox").join("notes.txt"));
+            assert_eq!(std::fs::read(path).unwrap(), b"five lines");
+
+            let missing = send(dir.join("missing.txt"), addr).await;
+            assert!(matches!(missing, Err(SendError::File(_))));
+            receiver.abort();
+            std::fs::remove_dir_all(dir).unwrap();
+        });
+    }
+}
@@ -0,0 +1,86 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How much a rate limited endpoint may send or receive in one go after having been idle, in
+/// time at the limit.
+const BURST: Duration = Duration::from_millis(250);
+
+/// Caps a byte rate: every frame takes as many tokens as it has bytes, and tokens come back at
+/// the rate.
+pub(crate) struct TokenBucket {
+    bytes_per_sec: u64,
+    /// Negative while a frame larger than the bucket is being paid off.
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(bytes_per_sec: u64) -> Self {
+        assert!(bytes_per_sec > 0, "the rate limit must not be zero");
+        let mut bucket = Self {
+            bytes_per_sec,
+            tokens: 0.0,
+            refilled: Instant::now(),
+        };
+        bucket.tokens = bucket.capacity();
+        bucket
+    }
+
+    fn capacity(&self) -> f64 {
+        self.bytes_per_sec as f64 * BURST.as_secs_f64()
+    }
+
+    /// Takes the tokens for `bytes` at `now`, and returns how long to wait before they may go.
+    pub(crate) fn take(&mut self, bytes: usize, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.bytes_per_sec as f64).min(self.capacity());
+        self.refilled = now;
+
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.bytes_per_sec as f64)
+        }
+    }
+}
+
+/// The rate limit shared by both directions of an endpoint, `None` while there is none.
+pub(crate) type RateLimiter = Arc<Mutex<Option<TokenBucket>>>;
+
+/// Waits until `bytes` more fit within the rate limit.
+pub(crate) async fn throttle(rate_limiter: &RateLimiter, bytes: usize) {
+    let wait = match &mut *rate_limiter.lock().unwrap() {
+        Some(bucket) => bucket.take(bytes, Instant::now()),
+        None => return,
+    };
+    if !wait.is_zero() {
+        tokio::time::sleep(wait).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TokenBucket;
+
+    use std::time::Duration;
+
+    #[test]
+    fn bursts_are_paid_off_at_the_rate() {
+        let mut bucket = TokenBucket::new(1000);
+        let start = bucket.refilled;
+
+        // A quarter of a second's worth goes right away, the rest has to wait.
+        assert_eq!(bucket.take(250, start), Duration::ZERO);
+        assert_eq!(bucket.take(500, start), Duration::from_millis(500));
+
+        // Half a second later the debt is paid off.
+        let later = start + Duration::from_millis(500);
+        assert_eq!(bucket.take(100, later), Duration::from_millis(100));
+
+        // Idling doesn't save up more than the burst.
+        let idle = later + Duration::from_secs(10);
+        assert_eq!(bucket.take(250, idle), Duration::ZERO);
+        assert!(bucket.take(1, idle) > Duration::ZERO);
+    }
+}
@@ -1,29 +1,751 @@
-use crate::endpoint::Endpoint;
-use crate::handlers::{file_transfer::FileTransferReceivingHandler, handshake::HandshakeHandler};
+use crate::connect::connect_relay;
+use crate::delegate::{ServerDelegate, ServerDelegateRef};
+#[cfg(any(feature = "tls", feature = "noise"))]
+use crate::endpoint::peer_name;
+use crate::endpoint::{
+    Endpoint, EndpointHandle, DEFAULT_FRAME_READ_TIMEOUT, DEFAULT_KEEPALIVE_INTERVAL,
+    DEFAULT_KEEPALIVE_TIMEOUT,
+};
+use crate::handlers::benchmark::BenchmarkEchoHandler;
+use crate::handlers::file_transfer::{
+    FileTransferReceivingHandler, HandshakeCallbackFn, PeerIdentity, PeerRole, ReceiveEvent,
+    ReceiveEventCallbackFn, ReceiverPairing, StripeAssemblies,
+};
+use crate::history::{HistoryEntry, HistoryStore};
+#[cfg(feature = "noise")]
+use crate::identity::IdentityStore;
+use crate::metrics::MetricsExporter;
+#[cfg(feature = "noise")]
+use crate::noise::{self, ShortAuthString};
+use crate::pairing::{PairingRequest, PairingStore};
+use crate::policy::{ReceiveLimits, ReceivePolicy};
+use crate::proto::{Frame, FrameHandler};
+#[cfg(feature = "quic")]
+use crate::quic::QuicStream;
+use crate::share::{serve_shared, SharedFolderRequestFrame, SharedRoots};
+#[cfg(feature = "websocket")]
+use crate::websocket;
 
+use std::future::Future;
+#[cfg(feature = "quic")]
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use icedrop_proto::relay::{RelayRole, RelayToken};
+use icedrop_proto::transfer::TransferConfig;
 use tokio::io::Result;
 use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
 use tokio::runtime::Handle;
+use tokio::select;
+use tokio::sync::{watch, Notify};
+#[cfg(feature = "tls")]
+use tokio_rustls::rustls::ServerConfig;
+#[cfg(feature = "tls")]
+use tokio_rustls::TlsAcceptor;
+
+/// Where received files are stored unless told otherwise.
+const DEFAULT_DEST_DIR: &str = "/var/tmp/icedrop";
+
+/// How long a server shutting down waits for open connections to close unless told otherwise.
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+type AcceptCallbackFn = Arc<dyn Fn(SocketAddr) -> bool + Send + Sync>;
+
+type ConnectionCallbackFn = Arc<dyn Fn(&mut ConnectionSetup<'_>) + Send + Sync>;
+
+/// A connection being set up, given to the callback set with
+/// [`ServerBuilder::connection_handlers`] before any of the peer's frames are handled.
+pub struct ConnectionSetup<'a> {
+    endpoint: &'a mut Endpoint,
+    addr: SocketAddr,
+    admission: Admission,
+}
+
+impl ConnectionSetup<'_> {
+    /// The peer's address, the relay's for connections accepted with [`Server::accept_relayed`].
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Hands the frames `handler` accepts to it, unless a handler of the server takes them first.
+    /// Frames of the application's own should use the ids from
+    /// [`frame_types::APPLICATION_MIN`](crate::frame_types::APPLICATION_MIN) on. Only frames
+    /// received once the peer has been let in get to `handler`, a peer sending one before is
+    /// disconnected.
+    pub fn add_handler<H>(&mut self, handler: H)
+    where
+        H: FrameHandler + Send + 'static,
+    {
+        let handler = AdmittedOnly {
+            inner: handler,
+            admission: self.admission.clone(),
+            endpoint_handle: self.endpoint.handle(),
+            addr: self.addr,
+        };
+        self.endpoint.add_handler(handler);
+    }
+
+    /// A handle to send frames to the peer with while the connection is open, e.g. from a task of
+    /// its own, see [`EndpointHandle::send_frame`]. Frames sent before the peer has been let in
+    /// reach it as well, wait for [`ConnectionSetup::admission`] first if that matters.
+    pub fn handle(&self) -> EndpointHandle {
+        self.endpoint.handle()
+    }
+
+    /// Tells when the peer has been let in.
+    pub fn admission(&self) -> Admission {
+        self.admission.clone()
+    }
+}
+
+/// Whether the peer of a connection has been let in, once it introduced itself and got past the
+/// handshake callback and pairing, see [`ConnectionSetup::admission`].
+#[derive(Clone)]
+pub struct Admission(watch::Receiver<bool>);
+
+impl Admission {
+    pub fn is_admitted(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Waits for the peer to be let in, returns `false` if the connection is closed before.
+    pub async fn admitted(&mut self) -> bool {
+        self.0.wait_for(|&let_in| let_in).await.is_ok()
+    }
+}
+
+/// Keeps the frames of a handler added with [`ConnectionSetup::add_handler`] from it until the
+/// peer has been let in.
+struct AdmittedOnly<H> {
+    inner: H,
+    admission: Admission,
+    endpoint_handle: EndpointHandle,
+    addr: SocketAddr,
+}
+
+#[async_trait]
+impl<H> FrameHandler for AdmittedOnly<H>
+where
+    H: FrameHandler + Send,
+{
+    type IncomingFrame = H::IncomingFrame;
+
+    async fn handle_frame(&mut self, frame: Self::IncomingFrame) {
+        if !self.admission.is_admitted() {
+            println!(
+                "dropped client {:?}: sent frame {} before being let in",
+                self.addr,
+                frame.frame_type()
+            );
+            self.endpoint_handle.shutdown().await.ok();
+            return;
+        }
+        self.inner.handle_frame(frame).await;
+    }
+}
+
+#[cfg(feature = "noise")]
+type ShortAuthCallbackFn = Arc<dyn Fn(SocketAddr, ShortAuthString) + Send + Sync>;
+
+/// How connections are secured before the first frame is exchanged.
+#[derive(Clone, Default)]
+struct ConnectionSecurity {
+    #[cfg(feature = "tls")]
+    tls_acceptor: Option<TlsAcceptor>,
+    #[cfg(feature = "noise")]
+    short_auth_callback: Option<ShortAuthCallbackFn>,
+    #[cfg(feature = "noise")]
+    identity: Option<Arc<IdentityStore>>,
+}
+
+impl ConnectionSecurity {
+    /// Sets up the connection from the client at `addr`, returns `None` if the handshake failed.
+    async fn open_endpoint(self, connection: Incoming, addr: SocketAddr) -> Option<Endpoint> {
+        match connection {
+            Incoming::Tcp(stream) => self.secure(stream, addr).await,
+            // QUIC connections are secured by QUIC itself.
+            #[cfg(feature = "quic")]
+            Incoming::Quic(incoming) => match QuicStream::accept(*incoming).await {
+                Ok(stream) => Some(Endpoint::with_stream(stream, addr.to_string())),
+                Err(err) => {
+                    println!("QUIC handshake with {} failed: {:?}", addr, err);
+                    None
+                }
+            },
+            #[cfg(feature = "websocket")]
+            Incoming::WebSocket(stream) => match websocket::accept(stream).await {
+                Ok(stream) => Some(Endpoint::with_stream(stream, addr.to_string())),
+                Err(err) => {
+                    println!("WebSocket handshake with {} failed: {:?}", addr, err);
+                    None
+                }
+            },
+        }
+    }
+
+    /// Secures `stream` from the client at `addr`, returns `None` if the handshake failed.
+    #[cfg_attr(not(feature = "noise"), allow(unused_variables))]
+    async fn secure(self, stream: TcpStream, addr: SocketAddr) -> Option<Endpoint> {
+        #[cfg(feature = "noise")]
+        if let Some(short_auth_callback) = self.short_auth_callback {
+            let peer = peer_name(&stream);
+            let (stream, short_auth_string, peer_key, peer_device_id) =
+                match noise::accept(stream, self.identity.as_deref()).await {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        println!("Noise handshake with {} failed: {:?}", peer, err);
+                        return None;
+                    }
+                };
+            if let Some(identity) = &self.identity {
+                if let Err(err) =
+                    identity.verify_peer(peer_device_id.as_ref(), &peer_key, addr.ip())
+                {
+                    println!("refused {}: {}", peer, err);
+                    return None;
+                }
+            }
+            short_auth_callback(addr, short_auth_string);
+            return Some(Endpoint::with_stream(stream, peer));
+        }
+        #[cfg(feature = "tls")]
+        if let Some(tls_acceptor) = self.tls_acceptor {
+            let peer = peer_name(&stream);
+            return match tls_acceptor.accept(stream).await {
+                Ok(stream) => Some(Endpoint::with_stream(stream, peer)),
+                Err(err) => {
+                    println!("TLS handshake with {} failed: {:?}", peer, err);
+                    None
+                }
+            };
+        }
+        Some(Endpoint::new(stream))
+    }
+}
+
+/// Where a server accepts connections.
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(feature = "quic")]
+    Quic(quinn::Endpoint),
+    /// Accepts WebSocket connections, see [`ServerBuilder::bind_ws`].
+    #[cfg(feature = "websocket")]
+    WebSocket(TcpListener),
+}
+
+/// A connection accepted by a [`Listener`], before it's set up.
+enum Incoming {
+    Tcp(TcpStream),
+    #[cfg(feature = "quic")]
+    Quic(Box<quinn::Incoming>),
+    #[cfg(feature = "websocket")]
+    WebSocket(TcpStream),
+}
+
+impl Listener {
+    async fn accept(&self) -> Result<(Incoming, SocketAddr)> {
+        match self {
+            Self::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Incoming::Tcp(stream), addr))
+            }
+            #[cfg(feature = "quic")]
+            Self::Quic(endpoint) => match endpoint.accept().await {
+                Some(incoming) => {
+                    let addr = incoming.remote_address();
+                    Ok((Incoming::Quic(Box::new(incoming)), addr))
+                }
+                None => Err(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    "the QUIC endpoint was closed",
+                )),
+            },
+            #[cfg(feature = "websocket")]
+            Self::WebSocket(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Incoming::WebSocket(stream), addr))
+            }
+        }
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        match self {
+            Self::Tcp(listener) => listener.local_addr(),
+            #[cfg(feature = "quic")]
+            Self::Quic(endpoint) => endpoint.local_addr(),
+            #[cfg(feature = "websocket")]
+            Self::WebSocket(listener) => listener.local_addr(),
+        }
+    }
+}
+
+/// Configures a [`Server`] before binding it.
+pub struct ServerBuilder {
+    dest_dir: PathBuf,
+    accept_callback: Option<AcceptCallbackFn>,
+    handshake_callback: Option<HandshakeCallbackFn>,
+    event_callback: Option<ReceiveEventCallbackFn>,
+    connection_callback: Option<ConnectionCallbackFn>,
+    delegate: Option<ServerDelegateRef>,
+    accept_benchmarks: bool,
+    atomic_writes: bool,
+    transfer_config: TransferConfig,
+    frame_read_timeout: Option<Duration>,
+    keepalive_interval: Option<Duration>,
+    keepalive_timeout: Duration,
+    rate_limit: Option<u64>,
+    pairing: Option<ReceiverPairing>,
+    history: Option<Arc<HistoryStore>>,
+    policy: Option<ReceivePolicy>,
+    shared_roots: Option<SharedRoots>,
+    metrics_addr: Option<SocketAddr>,
+    drain_timeout: Duration,
+    security: ConnectionSecurity,
+}
+
+impl ServerBuilder {
+    pub fn new() -> Self {
+        Self {
+            dest_dir: PathBuf::from(DEFAULT_DEST_DIR),
+            accept_callback: None,
+            handshake_callback: None,
+            event_callback: None,
+            connection_callback: None,
+            delegate: None,
+            accept_benchmarks: false,
+            atomic_writes: true,
+            transfer_config: TransferConfig::default(),
+            frame_read_timeout: Some(DEFAULT_FRAME_READ_TIMEOUT),
+            keepalive_interval: Some(DEFAULT_KEEPALIVE_INTERVAL),
+            keepalive_timeout: DEFAULT_KEEPALIVE_TIMEOUT,
+            rate_limit: None,
+            pairing: None,
+            history: None,
+            policy: None,
+            shared_roots: None,
+            metrics_addr: None,
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            security: ConnectionSecurity::default(),
+        }
+    }
+
+    /// Sets the directory received files are written to (`/var/tmp/icedrop` by default). It must
+    /// exist.
+    pub fn dest_dir<P>(mut self, dir: P) -> Self
+    where
+        P: AsRef<Path>,
+    {
+        self.dest_dir = dir.as_ref().to_owned();
+        self
+    }
+
+    /// Sets the callback deciding whether to serve a new connection, given the sender's address.
+    /// Connections it returns `false` for are closed right away.
+    pub fn accept_callback<F>(mut self, f: F) -> Self
+    where
+        F: Fn(SocketAddr) -> bool + Send + Sync + 'static,
+    {
+        self.accept_callback = Some(Arc::new(f));
+        self
+    }
+
+    /// Sets the callback deciding whether to go on with a peer once it has introduced itself, and
+    /// what it may do: send files, or only pull them from the shared roots, see [`PeerRole`].
+    /// Peers it returns an error for are sent the error's message and disconnected, and so are
+    /// peers trying something their role doesn't allow.
+    pub fn handshake_callback<F, Fut>(mut self, f: F) -> Self
+    where
+        F: Fn(PeerIdentity) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = std::result::Result<PeerRole, String>> + Send + 'static,
+    {
+        self.handshake_callback = Some(Arc::new(move |identity| Box::pin(f(identity))));
+        self
+    }
+
+    /// Sets the callback told about the progress of the files being received, and where they
+    /// have been written to.
+    pub fn event_callback<F>(mut self, f: F) -> Self
+    where
+        F: Fn(ReceiveEvent) + Send + Sync + 'static,
+    {
+        self.event_callback = Some(Arc::new(f));
+        self
+    }
+
+    /// Sets the callback run for every connection before it's served, which may add handlers for
+    /// frames of the application's own, e.g. chat messages or remote commands, and keep handles
+    /// to send such frames to the peer, see [`ConnectionSetup`]. They ride along with transfers on
+    /// the same connection, and are only handed over once the peer has introduced itself and got
+    /// past the handshake callback and pairing. Peers pulling from the shared roots aren't served
+    /// this way, and don't get to the callback.
+    pub fn connection_handlers<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&mut ConnectionSetup<'_>) + Send + Sync + 'static,
+    {
+        self.connection_callback = Some(Arc::new(f));
+        self
+    }
+
+    /// Has `delegate` decide about every file offered, where to write it or whether to turn the
+    /// sender away, and take text sent instead of files, see [`ServerDelegate`]. Without it every
+    /// file is written to the destination directory and text is turned down.
+    pub fn delegate<D>(mut self, delegate: D) -> Self
+    where
+        D: ServerDelegate + 'static,
+    {
+        self.delegate = Some(Arc::new(delegate));
+        self
+    }
+
+    /// Whether to take part in network benchmarks run by clients, see
+    /// [`Client::bench`](crate::Client::bench). Off by default.
+    pub fn accept_benchmarks(mut self, accept: bool) -> Self {
+        self.accept_benchmarks = accept;
+        self
+    }
+
+    /// Whether to write received files under a temporary `.icedrop.partial` name next to where they
+    /// go and move them in place once complete, so files cut off by a crash or a failed transfer
+    /// are never mistaken for complete ones. A sidecar ending in `.state` records how far such a
+    /// transfer got. On by default.
+    pub fn atomic_writes(mut self, atomic: bool) -> Self {
+        self.atomic_writes = atomic;
+        self
+    }
+
+    /// Sets the largest segment size, ack window and number of parallel streams to agree to with
+    /// clients, see [`TransferConfig`]. Panics if the config isn't valid.
+    pub fn transfer_config(mut self, config: TransferConfig) -> Self {
+        config.assert_valid();
+        self.transfer_config = config;
+        self
+    }
+
+    /// Sets how long a client may go without sending anything once it has started sending a
+    /// frame, see [`Endpoint::set_frame_read_timeout`]. Clients stalling longer are
+    /// disconnected.
+    pub fn frame_read_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.frame_read_timeout = timeout;
+        self
+    }
+
+    /// Sets how often clients are pinged and how long they may stay silent before being
+    /// disconnected, see [`Endpoint::set_keepalive`].
+    pub fn keepalive(mut self, interval: Option<Duration>, timeout: Duration) -> Self {
+        self.keepalive_interval = interval;
+        self.keepalive_timeout = timeout;
+        self
+    }
+
+    /// Caps the bytes per second received from and sent to each client, `None` for no limit (the
+    /// default), see [`Endpoint::set_rate_limit`]. Panics if the limit is zero.
+    pub fn rate_limit(mut self, bytes_per_sec: Option<u64>) -> Self {
+        assert!(bytes_per_sec != Some(0), "the rate limit must not be zero");
+        self.rate_limit = bytes_per_sec;
+        self
+    }
+
+    /// Only receives files from senders paired with this device in `store`. A sender pairing for
+    /// the first time is given to `f` along with a code to show the user, which the sender's user
+    /// has to type in, see [`Client::set_pairing`](crate::Client::set_pairing). Senders that can't
+    /// pair, such as ones older than protocol version 11, are turned away.
+    pub fn pairing<F>(mut self, store: Arc<PairingStore>, f: F) -> Self
+    where
+        F: Fn(PairingRequest) + Send + Sync + 'static,
+    {
+        self.pairing = Some(ReceiverPairing {
+            store,
+            on_code: Arc::new(f),
+        });
+        self
+    }
+
+    /// Records every file received, or failed to be, in `history`.
+    pub fn history(mut self, history: Arc<HistoryStore>) -> Self {
+        self.history = Some(history);
+        self
+    }
+
+    /// Checks every file offered against `policy` before receiving it.
+    pub fn receive_policy(mut self, policy: ReceivePolicy) -> Self {
+        self.policy = Some(policy);
+        self
+    }
+
+    /// Lets peers list what's in the folders of `roots` and pull files from them, see
+    /// [`Client::list_remote`](crate::Client::list_remote) and [`pull`](crate::pull), besides
+    /// sending files as usual. Peers pulling files or browsing go through the handshake callback
+    /// and pairing like senders, and the delegate decides about them, see
+    /// [`ServerDelegate::should_serve_pull`](crate::ServerDelegate::should_serve_pull).
+    pub fn shared_roots(mut self, roots: SharedRoots) -> Self {
+        self.shared_roots = Some(roots);
+        self
+    }
+
+    /// Serves the counters of the process to Prometheus at `http://<addr>/metrics` while the
+    /// server runs, see [`NodeMetrics`](crate::NodeMetrics).
+    pub fn metrics_addr(mut self, addr: SocketAddr) -> Self {
+        self.metrics_addr = Some(addr);
+        self
+    }
+
+    /// Sets how long the server waits for open connections to close once it's shut down, see
+    /// [`Server::shutdown_handle`] ([`DEFAULT_DRAIN_TIMEOUT`] by default).
+    pub fn drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    /// Secures every connection with TLS, using the certificate and key in `config`. Clients then
+    /// have to connect with [`Client::connect_tls`](crate::Client::connect_tls).
+    #[cfg(feature = "tls")]
+    pub fn tls_config(mut self, config: Arc<ServerConfig>) -> Self {
+        self.security.tls_acceptor = Some(TlsAcceptor::from(config));
+        self
+    }
+
+    /// Encrypts every connection end to end, clients then have to connect with
+    /// [`Client::connect_encrypted`](crate::Client::connect_encrypted). `f` is given each
+    /// client's address and the code to show the user, who compares it with the one the client
+    /// shows. Takes the place of TLS if that is set up as well.
+    #[cfg(feature = "noise")]
+    pub fn end_to_end_encryption<F>(mut self, f: F) -> Self
+    where
+        F: Fn(SocketAddr, ShortAuthString) + Send + Sync + 'static,
+    {
+        self.security.short_auth_callback = Some(Arc::new(f));
+        self
+    }
+
+    /// Uses the key of `identity` for end-to-end encrypted connections, and checks the keys of
+    /// clients against the ones they used before, see [`IdentityStore`].
+    #[cfg(feature = "noise")]
+    pub fn identity(mut self, identity: Arc<IdentityStore>) -> Self {
+        self.security.identity = Some(identity);
+        self
+    }
+
+    /// Starts listening on `addr`.
+    pub async fn bind<A>(self, addr: A) -> Result<Server>
+    where
+        A: ToSocketAddrs,
+    {
+        let listener = TcpListener::bind(addr).await?;
+        self.into_server(Listener::Tcp(listener)).await
+    }
+
+    /// Starts listening for QUIC connections on `addr`, which are secured with `config` rather
+    /// than [`tls_config`](Self::tls_config) or end-to-end encryption. Clients have to connect
+    /// with [`Client::connect_quic`](crate::Client::connect_quic).
+    #[cfg(feature = "quic")]
+    pub async fn bind_quic(self, addr: SocketAddr, config: quinn::ServerConfig) -> Result<Server> {
+        let endpoint = quinn::Endpoint::server(config, addr)?;
+        self.into_server(Listener::Quic(endpoint)).await
+    }
+
+    /// Starts listening on `addr` for clients speaking WebSocket, such as web pages, which send
+    /// frames in binary messages. Connections aren't secured with
+    /// [`tls_config`](Self::tls_config) or end-to-end encryption.
+    #[cfg(feature = "websocket")]
+    pub async fn bind_ws<A>(self, addr: A) -> Result<Server>
+    where
+        A: ToSocketAddrs,
+    {
+        let listener = TcpListener::bind(addr).await?;
+        self.into_server(Listener::WebSocket(listener)).await
+    }
+
+    async fn into_server(self, listener: Listener) -> Result<Server> {
+        let metrics_exporter = match self.metrics_addr {
+            Some(addr) => Some(MetricsExporter::bind(addr).await?),
+            None => None,
+        };
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        Ok(Server {
+            listener,
+            dest_dir: self.dest_dir,
+            accept_callback: self.accept_callback,
+            handshake_callback: self.handshake_callback,
+            event_callback: self.event_callback,
+            connection_callback: self.connection_callback,
+            delegate: self.delegate,
+            accept_benchmarks: self.accept_benchmarks,
+            atomic_writes: self.atomic_writes,
+            transfer_config: self.transfer_config,
+            frame_read_timeout: self.frame_read_timeout,
+            keepalive_interval: self.keepalive_interval,
+            keepalive_timeout: self.keepalive_timeout,
+            rate_limit: self.rate_limit,
+            pairing: self.pairing,
+            history: self.history,
+            limits: self
+                .policy
+                .map(|policy| Arc::new(ReceiveLimits::new(policy))),
+            shared_roots: self.shared_roots.map(Arc::new),
+            stripe_assemblies: StripeAssemblies::default(),
+            metrics_exporter,
+            shutdown_tx: Arc::new(shutdown_tx),
+            shutdown_rx,
+            connections: Arc::new(OpenConnections::new()),
+            drain_timeout: self.drain_timeout,
+            security: self.security,
+        })
+    }
+}
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-struct Server {
-    listener: TcpListener,
+/// The receiving side: accepts connections from clients and stores the files they send.
+pub struct Server {
+    listener: Listener,
+    dest_dir: PathBuf,
+    accept_callback: Option<AcceptCallbackFn>,
+    handshake_callback: Option<HandshakeCallbackFn>,
+    event_callback: Option<ReceiveEventCallbackFn>,
+    connection_callback: Option<ConnectionCallbackFn>,
+    delegate: Option<ServerDelegateRef>,
+    accept_benchmarks: bool,
+    atomic_writes: bool,
+    transfer_config: TransferConfig,
+    frame_read_timeout: Option<Duration>,
+    keepalive_interval: Option<Duration>,
+    keepalive_timeout: Duration,
+    rate_limit: Option<u64>,
+    pairing: Option<ReceiverPairing>,
+    history: Option<Arc<HistoryStore>>,
+    limits: Option<Arc<ReceiveLimits>>,
+    shared_roots: Option<Arc<SharedRoots>>,
+    /// Files being received in stripes over several connections, see
+    /// [`TransferConfig::parallel_streams`].
+    stripe_assemblies: StripeAssemblies,
+    metrics_exporter: Option<MetricsExporter>,
+    shutdown_tx: Arc<watch::Sender<bool>>,
+    shutdown_rx: watch::Receiver<bool>,
+    connections: Arc<OpenConnections>,
+    drain_timeout: Duration,
+    security: ConnectionSecurity,
 }
 
 impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::new()
+    }
+
+    /// Binds a server with the default settings, see [`Server::builder`] for more.
     pub async fn bind<A>(addr: A) -> Result<Self>
     where
         A: ToSocketAddrs,
     {
-        let listener = TcpListener::bind(addr).await?;
-        Ok(Self { listener })
+        Self::builder().bind(addr).await
+    }
+
+    /// Binds a server securing connections with TLS, see [`ServerBuilder::tls_config`].
+    #[cfg(feature = "tls")]
+    pub async fn bind_tls<A>(addr: A, config: Arc<ServerConfig>) -> Result<Self>
+    where
+        A: ToSocketAddrs,
+    {
+        Self::builder().tls_config(config).bind(addr).await
+    }
+
+    /// Binds a server accepting QUIC connections, see [`ServerBuilder::bind_quic`].
+    #[cfg(feature = "quic")]
+    pub async fn bind_quic(addr: SocketAddr, config: quinn::ServerConfig) -> Result<Self> {
+        Self::builder().bind_quic(addr, config).await
+    }
+
+    /// Binds a server accepting WebSocket connections, see [`ServerBuilder::bind_ws`].
+    #[cfg(feature = "websocket")]
+    pub async fn bind_ws<A>(addr: A) -> Result<Self>
+    where
+        A: ToSocketAddrs,
+    {
+        Self::builder().bind_ws(addr).await
+    }
+
+    /// The address the server listens on, e.g. to find out the port picked when binding to port
+    /// 0.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// The files received so far, oldest first, if they're recorded, see
+    /// [`ServerBuilder::history`].
+    pub fn history(&self) -> Result<Vec<HistoryEntry>> {
+        match &self.history {
+            Some(history) => history.entries(),
+            None => Ok(Vec::new()),
+        }
     }
 
+    /// The address metrics are served on, if they are, see [`ServerBuilder::metrics_addr`].
+    pub fn metrics_addr(&self) -> Option<SocketAddr> {
+        let exporter = self.metrics_exporter.as_ref()?;
+        exporter.local_addr().ok()
+    }
+
+    /// Lets the server be shut down from elsewhere, e.g. another task, while it runs.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            shutdown_tx: Arc::clone(&self.shutdown_tx),
+        }
+    }
+
+    /// Serves clients until the task is cancelled or the server is shut down, see
+    /// [`Server::shutdown_handle`].
     pub async fn run(&mut self) {
+        let serve = async {
+            match &self.metrics_exporter {
+                Some(exporter) => {
+                    tokio::join!(self.accept_clients(), exporter.run());
+                }
+                None => self.accept_clients().await,
+            }
+        };
+        let mut shutdown_rx = self.shutdown_rx.clone();
+        select! {
+            _ = serve => {},
+            _ = until_set(&mut shutdown_rx) => {},
+        }
+        self.drain().await;
+    }
+
+    /// Waits for the connections still open to close, and drops the ones that haven't once the
+    /// drain timeout has passed.
+    async fn drain(&self) {
+        let open = self.connections.count();
+        if open == 0 {
+            return;
+        }
+        println!("shutting down, waiting for {} clients", open);
+        let all_closed = tokio::time::timeout(self.drain_timeout, self.connections.all_closed());
+        if all_closed.await.is_err() {
+            println!("dropping {} clients", self.connections.count());
+            self.connections.drop_all();
+        }
+    }
+
+    async fn accept_clients(&self) {
         loop {
             match self.listener.accept().await {
-                Ok((stream, addr)) => {
+                Ok((connection, addr)) => {
+                    if let Some(accept_callback) = &self.accept_callback {
+                        if !accept_callback(addr) {
+                            println!("rejected client: {:?}", addr);
+                            continue;
+                        }
+                    }
                     println!("new client: {:?}", addr);
-                    Self::serve_client(stream);
+                    self.serve_client(connection, addr);
                 }
                 Err(e) => {
                     println!("could not accept new client: {:?}", e);
@@ -32,27 +754,250 @@ impl Server {
         }
     }
 
-    fn serve_client(stream: TcpStream) {
-        Handle::current().spawn(async {
-            let mut endpoint = Endpoint::new(stream);
-            endpoint.add_handler(HandshakeHandler::new(endpoint.handle()));
+    /// Joins the session `token` stands for at the relay at `relay_addr`, and serves the client
+    /// connecting to it with [`Client::connect_relay`](crate::Client::connect_relay) in the
+    /// background. The relay's address stands in for the client's, e.g. in
+    /// [`ServerBuilder::handshake_callback`].
+    pub async fn accept_relayed<A>(&self, relay_addr: A, token: RelayToken) -> Result<()>
+    where
+        A: ToSocketAddrs,
+    {
+        let stream = connect_relay(relay_addr, RelayRole::Receiver, token).await?;
+        let addr = stream.peer_addr()?;
+        self.serve_client(Incoming::Tcp(stream), addr);
+        Ok(())
+    }
+
+    fn serve_client(&self, connection: Incoming, addr: SocketAddr) {
+        if *self.shutdown_rx.borrow() {
+            println!("shutting down, turned away client: {:?}", addr);
+            return;
+        }
+        let mut open_connection = OpenConnections::open(&self.connections);
+        let dest_dir = self.dest_dir.clone();
+        let handshake_callback = self.handshake_callback.clone();
+        let event_callback = self.event_callback.clone();
+        let connection_callback = self.connection_callback.clone();
+        let delegate = self.delegate.clone();
+        let accept_benchmarks = self.accept_benchmarks;
+        let atomic_writes = self.atomic_writes;
+        let transfer_config = self.transfer_config;
+        let frame_read_timeout = self.frame_read_timeout;
+        let (keepalive_interval, keepalive_timeout) =
+            (self.keepalive_interval, self.keepalive_timeout);
+        let rate_limit = self.rate_limit;
+        let stripe_assemblies = self.stripe_assemblies.clone();
+        let pairing = self.pairing.clone();
+        let history = self.history.clone();
+        let limits = self.limits.clone();
+        let shared_roots = self.shared_roots.clone();
+        let security = self.security.clone();
+        let serve = async move {
+            let mut endpoint = match security.open_endpoint(connection, addr).await {
+                Some(endpoint) => endpoint,
+                None => return,
+            };
+            endpoint.set_frame_read_timeout(frame_read_timeout);
+            endpoint.set_keepalive(keepalive_interval, keepalive_timeout);
+            endpoint.set_rate_limit(rate_limit);
             let endpoint_handle = endpoint.handle();
-            endpoint.add_handler(
-                FileTransferReceivingHandler::new(endpoint_handle, "/var/tmp/icedrop").await,
-            );
+            let mut receiving_handler =
+                FileTransferReceivingHandler::new(endpoint_handle.clone(), dest_dir);
+            receiving_handler.set_transfer_config(transfer_config);
+            receiving_handler.set_atomic_writes(atomic_writes);
+            receiving_handler.set_stripe_assemblies(stripe_assemblies);
+            if let Some(handshake_callback) = handshake_callback {
+                receiving_handler.set_handshake_callback(handshake_callback, addr);
+            }
+            if let Some(pairing) = pairing {
+                receiving_handler.set_pairing(pairing, addr);
+            }
+            if let Some(event_callback) = event_callback {
+                receiving_handler.set_event_callback(event_callback);
+            }
+            if let Some(history) = history.clone() {
+                receiving_handler.set_history(history, addr);
+            }
+            if let Some(limits) = limits {
+                receiving_handler.set_limits(limits, addr);
+            }
+            if let Some(delegate) = delegate.clone() {
+                receiving_handler.set_delegate(delegate, addr);
+            }
+            if let Some(shared_roots) = shared_roots {
+                match endpoint
+                    .read_first_frame_promptly::<SharedFolderRequestFrame>()
+                    .await
+                {
+                    Ok(Some(request)) => {
+                        // The peer is vetted like a sender, by the handler set up for one.
+                        let vetting = receiving_handler;
+                        let roots = &shared_roots;
+                        serve_shared(endpoint, vetting, roots, request, delegate, history, addr)
+                            .await;
+                        return;
+                    }
+                    Ok(None) => {}
+                    Err(err) => {
+                        println!("error happened while serving a client: {:?}", err);
+                        return;
+                    }
+                }
+            }
+            let let_in = receiving_handler.let_in();
+            endpoint.add_handler(receiving_handler);
+            endpoint.add_handler(BenchmarkEchoHandler::new(
+                endpoint_handle,
+                accept_benchmarks,
+            ));
+            if let Some(connection_callback) = connection_callback {
+                connection_callback(&mut ConnectionSetup {
+                    endpoint: &mut endpoint,
+                    addr,
+                    admission: Admission(let_in),
+                });
+            }
             let result = endpoint.run().await;
             if let Some(err) = result.err() {
                 println!("error happened while serving a client: {:?}", err);
             }
+        };
+        Handle::current().spawn(async move {
+            select! {
+                _ = serve => {},
+                _ = open_connection.dropped() => println!("dropped client: {:?}", addr),
+            }
         });
     }
 }
 
+/// Shuts a [`Server`] down, see [`Server::shutdown_handle`].
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    shutdown_tx: Arc<watch::Sender<bool>>,
+}
+
+impl ShutdownHandle {
+    /// Stops the server from accepting clients, and has [`Server::run`] return once the ones
+    /// connected have finished, or the drain timeout has passed, see
+    /// [`ServerBuilder::drain_timeout`]. A server that has been shut down stays that way.
+    pub fn shutdown(&self) {
+        self.shutdown_tx.send_replace(true);
+    }
+}
+
+/// Waits for `flag` to be set.
+async fn until_set(flag: &mut watch::Receiver<bool>) {
+    while !*flag.borrow_and_update() {
+        if flag.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+/// The connections a server is serving, for a shutdown to wait for.
+struct OpenConnections {
+    count: AtomicUsize,
+    closed: Notify,
+    /// Tells connections still open to drop what they're doing once set.
+    drop_tx: watch::Sender<bool>,
+}
+
+impl OpenConnections {
+    fn new() -> Self {
+        Self {
+            count: AtomicUsize::new(0),
+            closed: Notify::new(),
+            drop_tx: watch::channel(false).0,
+        }
+    }
+
+    /// Counts a connection as open until the returned guard is dropped.
+    fn open(connections: &Arc<Self>) -> OpenConnection {
+        connections.count.fetch_add(1, Ordering::SeqCst);
+        OpenConnection {
+            connections: Arc::clone(connections),
+            drop_rx: connections.drop_tx.subscribe(),
+        }
+    }
+
+    fn count(&self) -> usize {
+        self.count.load(Ordering::SeqCst)
+    }
+
+    /// Waits until no connection is open anymore.
+    async fn all_closed(&self) {
+        loop {
+            // Created before looking at the count, so a connection closing in between still
+            // wakes it.
+            let closed = self.closed.notified();
+            if self.count() == 0 {
+                return;
+            }
+            closed.await;
+        }
+    }
+
+    fn drop_all(&self) {
+        self.drop_tx.send_replace(true);
+    }
+}
+
+/// A connection being served, see [`OpenConnections::open`].
+struct OpenConnection {
+    connections: Arc<OpenConnections>,
+    drop_rx: watch::Receiver<bool>,
+}
+
+impl OpenConnection {
+    /// Waits until the connection is to be dropped.
+    async fn dropped(&mut self) {
+        until_set(&mut self.drop_rx).await;
+    }
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        if self.connections.count.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.connections.closed.notify_waiters();
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::Server;
+    use crate::client::Client;
+    use crate::control::TransferControl;
+    use crate::delegate::{Decision, ServerDelegate, TransferOffer, TransferSink};
+    use crate::endpoint::{Endpoint, EndpointHandle};
+    use crate::error::Error;
+    use crate::handlers::file_transfer::{PeerIdentity, PeerRole, ReceiveEvent};
+    use crate::metrics::NodeMetrics;
+    use crate::pairing::PairingStore;
+    use crate::policy::ReceivePolicy;
+    use crate::proto::FrameHandler;
+    use crate::share::{RemoteEntry, SharedRoots};
+
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::time::{Duration, SystemTime};
 
-    use tokio::io::Result;
+    use async_trait::async_trait;
+    use icedrop_proto::codec::FrameWithHeader;
+    use icedrop_proto::compression::CompressionMode;
+    use icedrop_proto::file_transfer::RejectionReason;
+    use icedrop_proto::frame_types;
+    use icedrop_proto::handshake::{Capabilities, HandshakeRequestFrame, HandshakeResponseFrame};
+    use icedrop_proto::pull::PullRequestFrame;
+    use icedrop_proto::text::MAX_TEXT_LEN;
+    use icedrop_proto::transfer::TransferConfig;
+    use icedrop_proto::{Frame, FrameParsingResult, PROTOCOL_VERSION};
+
+    use tokio::fs::File;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream, Result};
+    use tokio::net::TcpStream;
     use tokio::runtime::Runtime;
 
     #[test]
@@ -65,4 +1010,1247 @@ mod tests {
         })
         .unwrap();
     }
+
+    #[test]
+    fn accept_callback_rejects_connections() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (peer_tx, peer_rx) = mpsc::channel();
+            let mut server = Server::builder()
+                .accept_callback(move |peer| {
+                    peer_tx.send(peer).unwrap();
+                    false
+                })
+                .bind("127.0.0.1:0")
+                .await
+                .unwrap();
+            let addr = server.local_addr().unwrap();
+            tokio::spawn(async move { server.run().await });
+
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            let mut buf = [0u8; 1];
+            assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+            assert_eq!(peer_rx.recv().unwrap(), stream.local_addr().unwrap());
+        });
+    }
+
+    #[test]
+    fn handshake_callback_denies_senders() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let dir = std::env::temp_dir().join(format!("icedrop-deny-{}", std::process::id()));
+            std::fs::create_dir_all(dir.join("out")).unwrap();
+            std::fs::write(dir.join("plain"), b"from a friend").unwrap();
+
+            let mut server = Server::builder()
+                .dest_dir(dir.join("out"))
+                .handshake_callback(|identity| async move {
+                    if identity.name == "friend" {
+                        Ok(PeerRole::Full)
+                    } else {
+                        Err(format!("{} is not a friend", identity.name))
+                    }
+                })
+                .bind("127.0.0.1:0")
+                .await
+                .unwrap();
+            let addr = server.local_addr().unwrap();
+            let server_task = tokio::spawn(async move { server.run().await });
+
+            for name in ["stranger", "friend"] {
+                let mut client = Client::connect(addr).await.unwrap();
+                client.set_display_name(name).unwrap();
+                let file = File::open(dir.join("plain")).await.unwrap();
+                client.queue_file(name, file);
+                assert_eq!(client.run().await.success, name == "friend");
+            }
+            server_task.abort();
+
+            assert!(!dir.join("out").join("stranger").exists());
+            let received = std::fs::read(dir.join("out").join("friend")).unwrap();
+            assert_eq!(received, b"from a friend");
+            std::fs::remove_dir_all(dir).unwrap();
+        });
+    }
+
+    #[test]
+    fn handshake_callback_gives_peers_roles() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let dir = std::env::temp_dir().join(format!("icedrop-role-{}", std::process::id()));
+            std::fs::create_dir_all(dir.join("shared")).unwrap();
+            std::fs::create_dir_all(dir.join("out")).unwrap();
+            std::fs::write(dir.join("shared").join("photo"), vec![4; 1000]).unwrap();
+            std::fs::write(dir.join("plain"), b"from a sender").unwrap();
+
+            let mut server = Server::builder()
+                .dest_dir(dir.join("out"))
+                .shared_roots(SharedRoots::new().root("photos", dir.join("shared")))
+                .handshake_callback(|identity| async move {
+                    match identity.name.as_str() {
+                        "puller" => Ok(PeerRole::Puller),
+                        "browser" => Ok(PeerRole::Browser),
+                        _ => Ok(PeerRole::Sender),
+                    }
+                })
+                .bind("127.0.0.1:0")
+                .await
+                .unwrap();
+            let addr = server.local_addr().unwrap();
+            let server_task = tokio::spawn(async move { server.run().await });
+
+            let unauthorized = |err: Option<Error>| {
+                matches!(
+                    err,
+                    Some(Error::Rejected {
+                        reason: Some(RejectionReason::Unauthorized),
+                        ..
+                    })
+                )
+            };
+            for name in ["sender", "puller", "browser"] {
+                std::fs::create_dir_all(dir.join(name)).unwrap();
+
+                let mut client = Client::connect(addr).await.unwrap();
+                client.set_display_name(name).unwrap();
+                let file = File::open(dir.join("plain")).await.unwrap();
+                client.queue_file(name, file);
+                let summary = client.run().await;
+                assert_eq!(summary.success, name == "sender", "{}", name);
+                assert_eq!(unauthorized(summary.error), name != "sender", "{}", name);
+
+                let mut client = Client::connect(addr).await.unwrap();
+                client.set_display_name(name).unwrap();
+                let result = client.pull("photos/photo", dir.join(name)).await;
+                assert_eq!(result.is_ok(), name == "puller", "{}: {:?}", name, result);
+                assert_eq!(unauthorized(result.err()), name != "puller", "{}", name);
+
+                let mut client = Client::connect(addr).await.unwrap();
+                client.set_display_name(name).unwrap();
+                let result = client.list_remote("photos").await;
+                assert_eq!(result.is_ok(), name != "sender", "{}: {:?}", name, result);
+                assert_eq!(unauthorized(result.err()), name == "sender", "{}", name);
+            }
+            server_task.abort();
+
+            assert!(dir.join("out").join("sender").exists());
+            assert!(!dir.join("out").join("puller").exists());
+            assert!(!dir.join("out").join("browser").exists());
+            assert!(dir.join("puller").join("photo").exists());
+            assert!(!dir.join("browser").join("photo").exists());
+            std::fs::remove_dir_all(dir).unwrap();
+        });
+    }
+
+    /// The names and sizes of the files offered so far.
+    type Offers = Arc<Mutex<Vec<(String, Option<u64>)>>>;
+
+    /// Renames photos into a directory of their own, and turns away anything larger than 50 kB.
+    struct PickyDelegate {
+        photo_dir: PathBuf,
+        offers: Offers,
+    }
+
+    #[async_trait]
+    impl ServerDelegate for PickyDelegate {
+        async fn should_accept(&self, offer: TransferOffer) -> Decision {
+            assert_eq!(offer.sender.name, "icedrop");
+            let file_name = offer.file_name.clone();
+            self.offers
+                .lock()
+                .unwrap()
+                .push((offer.file_name, offer.file_size));
+            if offer.file_size.unwrap_or(0) > 50_000 {
+                Decision::Reject("too large".to_owned())
+            } else if file_name.ends_with(".jpg") {
+                Decision::AcceptAs(self.photo_dir.join(format!("renamed-{}", file_name)))
+            } else {
+                Decision::Accept
+            }
+        }
+    }
+
+    #[test]
+    fn delegate_decides_about_every_file() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let dir = std::env::temp_dir().join(format!("icedrop-offer-{}", std::process::id()));
+            std::fs::create_dir_all(dir.join("out")).unwrap();
+            std::fs::create_dir_all(dir.join("photos")).unwrap();
+            std::fs::write(dir.join("notes.txt"), b"some notes").unwrap();
+            std::fs::write(dir.join("photo.jpg"), vec![7; 40_000]).unwrap();
+            std::fs::write(dir.join("video.mp4"), vec![9; 60_000]).unwrap();
+
+            let config = TransferConfig {
+                segment_size: 1024,
+                parallel_streams: 4,
+                ..TransferConfig::default()
+            };
+            let offers = Offers::default();
+            let mut server = Server::builder()
+                .dest_dir(dir.join("out"))
+                .transfer_config(config)
+                .delegate(PickyDelegate {
+                    photo_dir: dir.join("photos"),
+                    offers: Arc::clone(&offers),
+                })
+                .bind("127.0.0.1:0")
+                .await
+                .unwrap();
+            let addr = server.local_addr().unwrap();
+            let server_task = tokio::spawn(async move { server.run().await });
+
+            // The photo goes in stripes over several connections, and is still offered once.
+            let mut client = Client::connect(addr).await.unwrap();
+            client.set_transfer_config(config);
+            for name in ["notes.txt", "photo.jpg"] {
+                client.queue_file(name, File::open(dir.join(name)).await.unwrap());
+            }
+            assert!(client.run().await.success);
+
+            let mut client = Client::connect(addr).await.unwrap();
+            let file = File::open(dir.join("video.mp4")).await.unwrap();
+            client.queue_file("video.mp4", file);
+            let summary = client.run().await;
+            assert!(!summary.success);
+            assert!(matches!(
+                summary.error,
+                Some(Error::Rejected {
+                    message,
+                    retryable: false,
+                    reason: Some(RejectionReason::Declined),
+                }) if message == "too large"
+            ));
+            server_task.abort();
+
+            assert_eq!(
+                *offers.lock().unwrap(),
+                [
+                    ("notes.txt".to_owned(), Some(10)),
+                    ("photo.jpg".to_owned(), Some(40_000)),
+                    ("video.mp4".to_owned(), Some(60_000)),
+                ]
+            );
+            let notes = std::fs::read(dir.join("out").join("notes.txt")).unwrap();
+            assert_eq!(notes, b"some notes");
+            let photo = std::fs::read(dir.join("photos").join("renamed-photo.jpg")).unwrap();
+            assert_eq!(photo, vec![7; 40_000]);
+            let received: Vec<_> = std::fs::read_dir(dir.join("out")).unwrap().collect();
+            assert_eq!(received.len(), 1, "{:?}", received);
+            std::fs::remove_dir_all(dir).unwrap();
+        });
+    }
+
+    /// Has the one file it's offered received into a pipe.
+    struct PipeDelegate {
+        pipe: Mutex<Option<DuplexStream>>,
+    }
+
+    #[async_trait]
+    impl ServerDelegate for PipeDelegate {
+        async fn should_accept(&self, _offer: TransferOffer) -> Decision {
+            let pipe = self.pipe.lock().unwrap().take().unwrap();
+            Decision::AcceptInto(TransferSink::new(pipe))
+        }
+    }
+
+    #[test]
+    fn delegate_can_receive_into_a_sink() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let dir = std::env::temp_dir().join(format!("icedrop-sink-{}", std::process::id()));
+            std::fs::create_dir_all(dir.join("out")).unwrap();
+            let content: Vec<u8> = (0..10_000).map(|i| (i % 251) as u8).collect();
+            std::fs::write(dir.join("data"), &content).unwrap();
+
+            let (pipe, mut pipe_end) = tokio::io::duplex(64 * 1024);
+            let mut server = Server::builder()
+                .dest_dir(dir.join("out"))
+                .delegate(PipeDelegate {
+                    pipe: Mutex::new(Some(pipe)),
+                })
+                .bind("127.0.0.1:0")
+                .await
+                .unwrap();
+            let addr = server.local_addr().unwrap();
+            let server_task = tokio::spawn(async move { server.run().await });
+
+            let mut client = Client::connect(addr).await.unwrap();
+            client.queue_file("data", File::open(dir.join("data")).await.unwrap());
+            assert!(client.run().await.success);
+            server_task.abort();
+
+            // The sink has been shut down, and nothing went to disk.
+            let mut received = Vec::new();
+            pipe_end.read_to_end(&mut received).await.unwrap();
+            assert_eq!(received, content);
+            assert_eq!(std::fs::read_dir(dir.join("out")).unwrap().count(), 0);
+            std::fs::remove_dir_all(dir).unwrap();
+        });
+    }
+
+    /// Keeps the text it's sent, unless there's none.
+    struct ClipboardDelegate {
+        texts: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl ServerDelegate for ClipboardDelegate {
+        async fn should_accept(&self, _offer: TransferOffer) -> Decision {
+            Decision::Accept
+        }
+
+        async fn text_received(
+            &self,
+            _sender: PeerIdentity,
+            text: String,
+        ) -> std::result::Result<(), String> {
+            if text.is_empty() {
+                return Err("nothing to paste".to_owned());
+            }
+            self.texts.lock().unwrap().push(text);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn text_is_handed_to_the_delegate() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let texts = Arc::new(Mutex::new(Vec::new()));
+            let mut server = Server::builder()
+                .delegate(ClipboardDelegate {
+                    texts: Arc::clone(&texts),
+                })
+                .bind("127.0.0.1:0")
+                .await
+                .unwrap();
+            let addr = server.local_addr().unwrap();
+            let server_task = tokio::spawn(async move { server.run().await });
+
+            let mut client = Client::connect(addr).await.unwrap();
+            client.send_text("hello from icedrop").await.unwrap();
+            let mut client = Client::connect(addr).await.unwrap();
+            let err = client.send_text("").await.unwrap_err();
+            assert!(matches!(
+                err,
+                Error::Rejected { message, .. } if message == "nothing to paste"
+            ));
+            let mut client = Client::connect(addr).await.unwrap();
+            let too_long = "x".repeat(MAX_TEXT_LEN + 1);
+            assert!(matches!(
+                client.send_text(&too_long).await,
+                Err(Error::Protocol(_))
+            ));
+            server_task.abort();
+            assert_eq!(*texts.lock().unwrap(), ["hello from icedrop"]);
+
+            // Without a delegate taking it, text is turned down.
+            let mut server = Server::bind("127.0.0.1:0").await.unwrap();
+            let addr = server.local_addr().unwrap();
+            let server_task = tokio::spawn(async move { server.run().await });
+            let mut client = Client::connect(addr).await.unwrap();
+            let err = client.send_text("hello").await.unwrap_err();
+            assert!(matches!(
+                err,
+                Error::Rejected { message, .. } if message == "text messages are not accepted"
+            ));
+            server_task.abort();
+        });
+    }
+
+    #[test]
+    fn policy_rejects_files_with_a_reason() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let dir = std::env::temp_dir().join(format!("icedrop-policy-{}", std::process::id()));
+            std::fs::create_dir_all(dir.join("out")).unwrap();
+            std::fs::write(dir.join("small"), vec![1; 6_000]).unwrap();
+            std::fs::write(dir.join("large"), vec![2; 20_000]).unwrap();
+
+            let mut server = Server::builder()
+                .dest_dir(dir.join("out"))
+                .receive_policy(
+                    ReceivePolicy::new()
+                        .max_file_size(10_000)
+                        .peer_quota(10_000),
+                )
+                .bind("127.0.0.1:0")
+                .await
+                .unwrap();
+            let addr = server.local_addr().unwrap();
+            let server_task = tokio::spawn(async move { server.run().await });
+
+            let send = |name: &'static str| {
+                let path = dir.join(name);
+                async move {
+                    let mut client = Client::connect(addr).await.unwrap();
+                    client.queue_file(name, File::open(path).await.unwrap());
+                    client.run().await
+                }
+            };
+            let reason = |error: Option<Error>| match error {
+                Some(Error::Rejected { reason, .. }) => reason,
+                _ => None,
+            };
+            assert_eq!(
+                reason(send("large").await.error),
+                Some(RejectionReason::FileTooLarge)
+            );
+            assert!(send("small").await.success);
+            assert_eq!(
+                reason(send("small").await.error),
+                Some(RejectionReason::QuotaExceeded)
+            );
+            server_task.abort();
+
+            let received: Vec<_> = std::fs::read_dir(dir.join("out")).unwrap().collect();
+            assert_eq!(received.len(), 1, "{:?}", received);
+            std::fs::remove_dir_all(dir).unwrap();
+        });
+    }
+
+    #[test]
+    fn shared_files_can_be_pulled() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let dir = std::env::temp_dir().join(format!("icedrop-pull-{}", std::process::id()));
+            std::fs::create_dir_all(dir.join("shared").join("2024")).unwrap();
+            std::fs::create_dir_all(dir.join("out")).unwrap();
+            std::fs::create_dir_all(dir.join("pulled")).unwrap();
+            std::fs::write(
+                dir.join("shared").join("2024").join("photo"),
+                vec![4; 50_000],
+            )
+            .unwrap();
+            std::fs::write(dir.join("secret"), vec![5; 100]).unwrap();
+            #[cfg(unix)]
+            std::os::unix::fs::symlink(dir.join("secret"), dir.join("shared").join("link"))
+                .unwrap();
+
+            let mut server = Server::builder()
+                .dest_dir(dir.join("out"))
+                .shared_roots(SharedRoots::new().root("photos", dir.join("shared")))
+                .bind("127.0.0.1:0")
+                .await
+                .unwrap();
+            let addr = server.local_addr().unwrap();
+            let server_task = tokio::spawn(async move { server.run().await });
+
+            let path = crate::pull(addr, "photos/2024/photo", dir.join("pulled"))
+                .await
+                .unwrap();
+            assert_eq!(path, dir.join("pulled").join("photo"));
+            assert_eq!(std::fs::read(path).unwrap(), vec![4; 50_000]);
+            for path in [
+                "photos/../secret",
+                "photos/link",
+                "photos/missing",
+                "photos",
+                "",
+            ] {
+                let result = crate::pull(addr, path, dir.join("pulled")).await;
+                assert!(
+                    matches!(
+                        result,
+                        Err(Error::Rejected {
+                            reason: Some(RejectionReason::Declined),
+                            ..
+                        })
+                    ),
+                    "{:?}: {:?}",
+                    path,
+                    result
+                );
+            }
+
+            // Files can still be sent to a server sharing folders.
+            let mut client = Client::connect(addr).await.unwrap();
+            client.queue_file("secret", File::open(dir.join("secret")).await.unwrap());
+            assert!(client.run().await.success);
+            server_task.abort();
+
+            assert_eq!(
+                std::fs::read(dir.join("out").join("secret")).unwrap(),
+                vec![5; 100]
+            );
+            std::fs::remove_dir_all(dir).unwrap();
+        });
+    }
+
+    /// Only lets peers pull and browse what's outside of `private/`.
+    struct SharingDelegate;
+
+    #[async_trait]
+    impl ServerDelegate for SharingDelegate {
+        async fn should_accept(&self, _offer: TransferOffer) -> Decision {
+            Decision::Accept
+        }
+
+        async fn should_serve_pull(
+            &self,
+            peer: PeerIdentity,
+            path: &str,
+        ) -> std::result::Result<(), String> {
+            assert_eq!(peer.name, "friend");
+            match path.starts_with("photos/private/") {
+                true => Err("that's private".to_owned()),
+                false => Ok(()),
+            }
+        }
+
+        async fn should_serve_listing(
+            &self,
+            peer: PeerIdentity,
+            path: &str,
+        ) -> std::result::Result<(), String> {
+            self.should_serve_pull(peer, &format!("{}/", path)).await
+        }
+    }
+
+    #[test]
+    fn pulls_and_browsing_are_vetted_like_sends() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let dir = std::env::temp_dir().join(format!("icedrop-vet-{}", std::process::id()));
+            std::fs::create_dir_all(dir.join("shared").join("private")).unwrap();
+            std::fs::create_dir_all(dir.join("pulled")).unwrap();
+            std::fs::write(dir.join("shared").join("photo"), vec![4; 1000]).unwrap();
+            std::fs::write(dir.join("shared").join("private").join("diary"), b"dear").unwrap();
+            let receiver_store = Arc::new(PairingStore::open(dir.join("receiver.json")).unwrap());
+            let sender_store = Arc::new(PairingStore::open(dir.join("sender.json")).unwrap());
+
+            let (code_tx, code_rx) = mpsc::channel();
+            let code_rx = Arc::new(Mutex::new(code_rx));
+            let mut server = Server::builder()
+                .dest_dir(&dir)
+                .shared_roots(SharedRoots::new().root("photos", dir.join("shared")))
+                .handshake_callback(|identity| async move {
+                    match identity.name.as_str() {
+                        "stranger" => Err("no strangers".to_owned()),
+                        _ => Ok(PeerRole::Full),
+                    }
+                })
+                .pairing(Arc::clone(&receiver_store), move |request| {
+                    code_tx.send(request.code).unwrap();
+                })
+                .delegate(SharingDelegate)
+                .bind("127.0.0.1:0")
+                .await
+                .unwrap();
+            let addr = server.local_addr().unwrap();
+            let server_task = tokio::spawn(async move { server.run().await });
+
+            // Peers that can't pair don't get to pull anything.
+            let result = crate::pull(addr, "photos/photo", dir.join("pulled")).await;
+            assert!(matches!(result, Err(Error::Handshake(_))), "{:?}", result);
+            let mut client = Client::connect(addr).await.unwrap();
+            client.set_display_name("stranger").unwrap();
+            let result = client.pull("photos/photo", dir.join("pulled")).await;
+            assert!(
+                matches!(result, Err(Error::Rejected { .. })),
+                "{:?}",
+                result
+            );
+            assert!(!dir.join("pulled").join("photo").exists());
+            let result = Client::connect(addr).await.unwrap().list_remote("").await;
+            assert!(matches!(result, Err(Error::Handshake(_))), "{:?}", result);
+
+            for (path, pulled) in [("photos/photo", true), ("photos/private/diary", false)] {
+                let mut client = Client::connect(addr).await.unwrap();
+                client.set_display_name("friend").unwrap();
+                let code_rx = Arc::clone(&code_rx);
+                client.set_pairing(Arc::clone(&sender_store), move || {
+                    Some(code_rx.lock().unwrap().recv().unwrap())
+                });
+                let result = client.pull(path, dir.join("pulled")).await;
+                assert_eq!(result.is_ok(), pulled, "{}: {:?}", path, result);
+            }
+            for (path, listed) in [("photos", true), ("photos/private", false)] {
+                let mut client = Client::connect(addr).await.unwrap();
+                client.set_display_name("friend").unwrap();
+                client.set_pairing(Arc::clone(&sender_store), || None);
+                let result = client.list_remote(path).await;
+                assert_eq!(result.is_ok(), listed, "{}: {:?}", path, result);
+            }
+            server_task.abort();
+
+            assert_eq!(
+                std::fs::read(dir.join("pulled").join("photo")).unwrap(),
+                vec![4; 1000]
+            );
+            assert!(!dir.join("pulled").join("diary").exists());
+            assert_eq!(receiver_store.paired_devices().len(), 1);
+            std::fs::remove_dir_all(dir).unwrap();
+        });
+    }
+
+    #[test]
+    fn shared_folders_can_be_browsed() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let dir = std::env::temp_dir().join(format!("icedrop-browse-{}", std::process::id()));
+            std::fs::create_dir_all(dir.join("shared").join("2024")).unwrap();
+            std::fs::write(dir.join("shared").join("beach.jpg"), vec![6; 4096]).unwrap();
+            let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1700724864);
+            File::open(dir.join("shared").join("beach.jpg"))
+                .await
+                .unwrap()
+                .into_std()
+                .await
+                .set_modified(modified)
+                .unwrap();
+
+            let mut server = Server::builder()
+                .dest_dir(&dir)
+                .shared_roots(SharedRoots::new().root("photos", dir.join("shared")))
+                .bind("127.0.0.1:0")
+                .await
+                .unwrap();
+            let addr = server.local_addr().unwrap();
+            let server_task = tokio::spawn(async move { server.run().await });
+
+            let list = |path: &'static str| async move {
+                Client::connect(addr).await.unwrap().list_remote(path).await
+            };
+            let roots = list("").await.unwrap();
+            assert_eq!(roots.len(), 1);
+            assert_eq!(roots[0].name, "photos");
+            assert!(roots[0].is_dir);
+
+            let entries = list("photos").await.unwrap();
+            let names: Vec<_> = entries.iter().map(|entry| entry.name.as_str()).collect();
+            assert_eq!(names, ["2024", "beach.jpg"]);
+            assert!(entries[0].is_dir);
+            assert_eq!(
+                entries[1],
+                RemoteEntry {
+                    name: "beach.jpg".to_owned(),
+                    size: 4096,
+                    modified: Some(modified),
+                    is_dir: false,
+                }
+            );
+            assert!(list("photos/2024").await.unwrap().is_empty());
+            for path in ["photos/beach.jpg", "photos/..", "videos"] {
+                let result = list(path).await;
+                assert!(
+                    matches!(result, Err(Error::Rejected { .. })),
+                    "{:?}: {:?}",
+                    path,
+                    result
+                );
+            }
+            server_task.abort();
+            std::fs::remove_dir_all(dir).unwrap();
+        });
+    }
+
+    #[test]
+    fn silent_peers_are_dropped_when_sharing() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let dir = std::env::temp_dir().join(format!("icedrop-silent-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+
+            let mut server = Server::builder()
+                .dest_dir(&dir)
+                .shared_roots(SharedRoots::new().root("photos", &dir))
+                .frame_read_timeout(Some(Duration::from_millis(100)))
+                .bind("127.0.0.1:0")
+                .await
+                .unwrap();
+            let addr = server.local_addr().unwrap();
+            let server_task = tokio::spawn(async move { server.run().await });
+
+            // One peer says nothing at all, the other asks for a file but never introduces itself.
+            let pull_request = PullRequestFrame {
+                path: "photos/beach.jpg".to_owned(),
+            };
+            for first_frame in [
+                Vec::new(),
+                FrameWithHeader {
+                    frame: pull_request,
+                }
+                .to_bytes(1),
+            ] {
+                let mut stream = TcpStream::connect(addr).await.unwrap();
+                stream.write_all(&first_frame).await.unwrap();
+                let mut buf = Vec::new();
+                let read =
+                    tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut buf));
+                assert!(read.await.is_ok(), "the server kept a silent peer");
+            }
+            server_task.abort();
+            std::fs::remove_dir_all(dir).unwrap();
+        });
+    }
+
+    /// A frame of an application's own, sent with an id left to applications.
+    #[derive(Debug)]
+    struct ChatFrame(String);
+
+    impl Frame for ChatFrame {
+        fn frame_type(&self) -> u16 {
+            frame_types::APPLICATION_MIN
+        }
+
+        fn frame_types() -> Vec<u16> {
+            vec![frame_types::APPLICATION_MIN]
+        }
+
+        fn try_parse(_frame_type: u16, buf: Vec<u8>) -> FrameParsingResult<Self> {
+            match String::from_utf8(buf) {
+                Ok(text) => FrameParsingResult::Ok(Self(text)),
+                Err(err) => FrameParsingResult::Err(Box::new(err)),
+            }
+        }
+
+        fn to_bytes(self) -> Vec<u8> {
+            self.0.into_bytes()
+        }
+    }
+
+    /// Answers every chat message with the same one, shouted.
+    struct ShoutingHandler(EndpointHandle);
+
+    #[async_trait]
+    impl FrameHandler for ShoutingHandler {
+        type IncomingFrame = ChatFrame;
+
+        async fn handle_frame(&mut self, frame: Self::IncomingFrame) {
+            let reply = ChatFrame(frame.0.to_uppercase());
+            self.0.send_frame(reply).await.unwrap();
+        }
+    }
+
+    struct ChatReceiver(tokio::sync::mpsc::UnboundedSender<String>);
+
+    #[async_trait]
+    impl FrameHandler for ChatReceiver {
+        type IncomingFrame = ChatFrame;
+
+        async fn handle_frame(&mut self, frame: Self::IncomingFrame) {
+            self.0.send(frame.0).unwrap();
+        }
+    }
+
+    /// Tells the chat the receiver let the client in.
+    struct LetInReceiver(tokio::sync::mpsc::UnboundedSender<String>);
+
+    #[async_trait]
+    impl FrameHandler for LetInReceiver {
+        type IncomingFrame = HandshakeResponseFrame;
+
+        async fn handle_frame(&mut self, _frame: Self::IncomingFrame) {
+            self.0.send("let in".to_owned()).unwrap();
+        }
+    }
+
+    /// A handshake request keeping to the first protocol version, so frames keep their headers.
+    fn plain_handshake_request() -> HandshakeRequestFrame {
+        HandshakeRequestFrame {
+            name: "chat".to_owned(),
+            protocol_version: 1,
+            transfer_config: None,
+            compression: CompressionMode::None,
+            capabilities: Capabilities::empty(),
+        }
+    }
+
+    #[test]
+    fn connection_handlers_get_application_frames() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let mut server = Server::builder()
+                .connection_handlers(|connection| {
+                    assert!(connection.peer_addr().ip().is_loopback());
+                    let handle = connection.handle();
+                    connection.add_handler(ShoutingHandler(handle.clone()));
+                    let mut admission = connection.admission();
+                    tokio::spawn(async move {
+                        if admission.admitted().await {
+                            handle
+                                .send_frame(ChatFrame("welcome".to_owned()))
+                                .await
+                                .ok();
+                        }
+                    });
+                })
+                .bind("127.0.0.1:0")
+                .await
+                .unwrap();
+            let addr = server.local_addr().unwrap();
+            let server_task = tokio::spawn(async move { server.run().await });
+
+            let mut endpoint = Endpoint::new(TcpStream::connect(addr).await.unwrap());
+            let (chat_tx, mut chat_rx) = tokio::sync::mpsc::unbounded_channel();
+            endpoint.add_handler(ChatReceiver(chat_tx.clone()));
+            endpoint.add_handler(LetInReceiver(chat_tx));
+            let handle = endpoint.handle();
+            let client_task = tokio::spawn(endpoint.run());
+
+            handle.send_frame(plain_handshake_request()).await.unwrap();
+            let mut chat = vec![chat_rx.recv().await.unwrap(), chat_rx.recv().await.unwrap()];
+            chat.sort();
+            assert_eq!(chat, ["let in", "welcome"]);
+            handle
+                .send_frame(ChatFrame("hello".to_owned()))
+                .await
+                .unwrap();
+            assert_eq!(chat_rx.recv().await.unwrap(), "HELLO");
+            client_task.abort();
+            server_task.abort();
+        });
+    }
+
+    /// Counts the chat messages it's handed.
+    struct ChatCounter(Arc<AtomicU32>);
+
+    #[async_trait]
+    impl FrameHandler for ChatCounter {
+        type IncomingFrame = ChatFrame;
+
+        async fn handle_frame(&mut self, _frame: Self::IncomingFrame) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn application_frames_wait_for_the_peer_to_be_let_in() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let dir = std::env::temp_dir().join(format!("icedrop-let-in-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            let store = Arc::new(PairingStore::open(dir.join("receiver.json")).unwrap());
+
+            let delivered = Arc::new(AtomicU32::new(0));
+            let server_delivered = Arc::clone(&delivered);
+            let (admitted_tx, mut admitted_rx) = tokio::sync::mpsc::unbounded_channel();
+            let mut server = Server::builder()
+                .dest_dir(&dir)
+                .pairing(store, |_| {})
+                .connection_handlers(move |connection| {
+                    connection.add_handler(ChatCounter(Arc::clone(&server_delivered)));
+                    let mut admission = connection.admission();
+                    let admitted_tx = admitted_tx.clone();
+                    tokio::spawn(async move {
+                        admitted_tx.send(admission.admitted().await).unwrap();
+                    });
+                })
+                .bind("127.0.0.1:0")
+                .await
+                .unwrap();
+            let addr = server.local_addr().unwrap();
+            let server_task = tokio::spawn(async move { server.run().await });
+
+            // Introduces itself, then goes on to chat without pairing, or without introducing
+            // itself at all.
+            let handshake = HandshakeRequestFrame {
+                protocol_version: PROTOCOL_VERSION,
+                transfer_config: Some(TransferConfig::default()),
+                capabilities: Capabilities::all(),
+                ..plain_handshake_request()
+            };
+            let handshake = FrameWithHeader { frame: handshake }.to_bytes(1);
+            for introduction in [handshake, Vec::new()] {
+                let mut stream = TcpStream::connect(addr).await.unwrap();
+                let chat = FrameWithHeader {
+                    frame: ChatFrame("rm -rf".to_owned()),
+                };
+                stream.write_all(&introduction).await.unwrap();
+                stream.write_all(&chat.to_bytes(1)).await.unwrap();
+                let mut buf = Vec::new();
+                let read =
+                    tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut buf));
+                assert!(read.await.is_ok(), "the server kept the peer");
+                assert_eq!(admitted_rx.recv().await, Some(false));
+            }
+            assert_eq!(delivered.load(Ordering::SeqCst), 0);
+            server_task.abort();
+            std::fs::remove_dir_all(dir).unwrap();
+        });
+    }
+
+    #[test]
+    fn transfers_can_be_paused_and_cancelled() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let dir = std::env::temp_dir().join(format!("icedrop-control-{}", std::process::id()));
+            std::fs::create_dir_all(dir.join("out")).unwrap();
+            std::fs::write(dir.join("data"), vec![3; 100_000]).unwrap();
+
+            let mut server = Server::builder()
+                .dest_dir(dir.join("out"))
+                .bind("127.0.0.1:0")
+                .await
+                .unwrap();
+            let addr = server.local_addr().unwrap();
+            let server_task = tokio::spawn(async move { server.run().await });
+
+            let start = |control_fn: fn(&TransferControl)| {
+                let path = dir.join("data");
+                async move {
+                    let mut client = Client::connect(addr).await.unwrap();
+                    client.set_transfer_config(TransferConfig {
+                        segment_size: 1024,
+                        ..TransferConfig::default()
+                    });
+                    client.queue_file("data", File::open(path).await.unwrap());
+                    let bytes_sent = Arc::new(AtomicU32::new(0));
+                    let bytes_sent_clone = Arc::clone(&bytes_sent);
+                    client.set_progress_callback(move |progress| {
+                        bytes_sent_clone.store(progress.bytes_sent as u32, Ordering::SeqCst);
+                    });
+                    let control = client.control();
+                    control_fn(&control);
+                    let run = tokio::spawn(async move { client.run().await });
+                    (control, bytes_sent, run)
+                }
+            };
+
+            // Nothing is sent while paused.
+            let (control, bytes_sent, run) = start(TransferControl::pause).await;
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            assert_eq!(bytes_sent.load(Ordering::SeqCst), 0);
+            control.resume();
+            assert!(run.await.unwrap().success);
+            assert_eq!(bytes_sent.load(Ordering::SeqCst), 100_000);
+
+            let (control, bytes_sent, run) = start(TransferControl::pause).await;
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            control.cancel();
+            let summary = run.await.unwrap();
+            assert!(!summary.success);
+            assert!(matches!(summary.error, Some(Error::Cancelled)));
+            assert_eq!(bytes_sent.load(Ordering::SeqCst), 0);
+            server_task.abort();
+            std::fs::remove_dir_all(dir).unwrap();
+        });
+    }
+
+    #[test]
+    fn metrics_are_served_while_running() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let dir = std::env::temp_dir().join(format!("icedrop-metrics-{}", std::process::id()));
+            std::fs::create_dir_all(dir.join("out")).unwrap();
+            std::fs::write(dir.join("data"), [7u8; 10_000]).unwrap();
+            let before = NodeMetrics::current();
+
+            let mut server = Server::builder()
+                .dest_dir(dir.join("out"))
+                .metrics_addr("127.0.0.1:0".parse().unwrap())
+                .bind("127.0.0.1:0")
+                .await
+                .unwrap();
+            let addr = server.local_addr().unwrap();
+            let metrics_addr = server.metrics_addr().unwrap();
+            let server_task = tokio::spawn(async move { server.run().await });
+
+            let mut client = Client::connect(addr).await.unwrap();
+            client.queue_file("data", File::open(dir.join("data")).await.unwrap());
+            assert!(client.run().await.success);
+
+            let mut stream = TcpStream::connect(metrics_addr).await.unwrap();
+            stream
+                .write_all(b"GET /metrics HTTP/1.1\r\n\r\n")
+                .await
+                .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            server_task.abort();
+
+            // Other tests add to the same counters, so they only tell a lower bound.
+            let value = |name: &str| -> u64 {
+                let prefix = format!("icedrop_{} ", name);
+                let line = response.lines().find(|line| line.starts_with(&prefix));
+                line.unwrap()[prefix.len()..].parse().unwrap()
+            };
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+            assert!(value("files_received_total") > before.files_received);
+            assert!(value("bytes_received_total") >= before.bytes_received + 10_000);
+            std::fs::remove_dir_all(dir).unwrap();
+        });
+    }
+
+    #[test]
+    fn shutdown_waits_for_transfers_to_finish() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let dir = std::env::temp_dir().join(format!("icedrop-drain-{}", std::process::id()));
+            std::fs::create_dir_all(dir.join("out")).unwrap();
+            std::fs::write(dir.join("data"), vec![7u8; 40_000]).unwrap();
+
+            let mut server = Server::builder()
+                .dest_dir(dir.join("out"))
+                .drain_timeout(Duration::from_secs(1))
+                .bind("127.0.0.1:0")
+                .await
+                .unwrap();
+            let addr = server.local_addr().unwrap();
+            let shutdown = server.shutdown_handle();
+            let server_task = tokio::spawn(async move { server.run().await });
+            let mut idle = TcpStream::connect(addr).await.unwrap();
+
+            // A transfer taking a while is let finish.
+            let mut client = Client::connect(addr).await.unwrap();
+            client.set_rate_limit(Some(100_000));
+            client.queue_file("data", File::open(dir.join("data")).await.unwrap());
+            let client_task = tokio::spawn(async move { client.run().await.success });
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            shutdown.shutdown();
+            assert!(client_task.await.unwrap());
+
+            // Connections still open once the drain timeout has passed are dropped.
+            tokio::time::timeout(Duration::from_secs(2), server_task)
+                .await
+                .unwrap()
+                .unwrap();
+            let mut buf = Vec::new();
+            assert!(idle.read_to_end(&mut buf).await.is_ok());
+            assert_eq!(
+                std::fs::read(dir.join("out").join("data")).unwrap().len(),
+                40_000
+            );
+            std::fs::remove_dir_all(dir).unwrap();
+        });
+    }
+
+    #[test]
+    fn files_are_moved_in_place_once_complete() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let dir = std::env::temp_dir().join(format!("icedrop-partial-{}", std::process::id()));
+            std::fs::create_dir_all(dir.join("out")).unwrap();
+            let content: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+            std::fs::write(dir.join("data"), &content).unwrap();
+
+            let mut server = Server::builder()
+                .dest_dir(dir.join("out"))
+                .bind("127.0.0.1:0")
+                .await
+                .unwrap();
+            let addr = server.local_addr().unwrap();
+            let server_task = tokio::spawn(async move { server.run().await });
+
+            // A sender going away halfway leaves a partial file, and a sidecar telling how far it
+            // got.
+            let mut client = Client::connect(addr).await.unwrap();
+            client.set_transfer_config(TransferConfig {
+                segment_size: 1024,
+                ..TransferConfig::default()
+            });
+            client.set_rate_limit(Some(50_000));
+            client.queue_file("data", File::open(dir.join("data")).await.unwrap());
+            let _ = tokio::time::timeout(Duration::from_millis(500), client.run()).await;
+            drop(client);
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            let partial = dir.join("out").join("data.icedrop.partial");
+            assert!(partial.exists());
+            assert!(!dir.join("out").join("data").exists());
+            let state = std::fs::read(dir.join("out").join("data.icedrop.partial.state")).unwrap();
+            let state: serde_json::Value = serde_json::from_slice(&state).unwrap();
+            assert_eq!(state["file_name"], "data");
+            assert!(state["bytes_received"].as_u64().unwrap() > 0);
+
+            // Once complete, only the file itself is left.
+            std::fs::remove_file(partial).unwrap();
+            std::fs::remove_file(dir.join("out").join("data.icedrop.partial.state")).unwrap();
+            let mut client = Client::connect(addr).await.unwrap();
+            client.queue_file("data", File::open(dir.join("data")).await.unwrap());
+            assert!(client.run().await.success);
+            server_task.abort();
+
+            let received: Vec<_> = std::fs::read_dir(dir.join("out")).unwrap().collect();
+            assert_eq!(received.len(), 1, "{:?}", received);
+            assert_eq!(
+                std::fs::read(dir.join("out").join("data")).unwrap(),
+                content
+            );
+            std::fs::remove_dir_all(dir).unwrap();
+        });
+    }
+
+    #[test]
+    fn senders_pair_once() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let dir = std::env::temp_dir().join(format!("icedrop-pair-{}", std::process::id()));
+            std::fs::create_dir_all(dir.join("out")).unwrap();
+            std::fs::write(dir.join("plain"), b"from a friend").unwrap();
+            let receiver_store = Arc::new(PairingStore::open(dir.join("receiver.json")).unwrap());
+            let sender_store = Arc::new(PairingStore::open(dir.join("sender.json")).unwrap());
+
+            let (code_tx, code_rx) = mpsc::channel();
+            let code_rx = Arc::new(Mutex::new(code_rx));
+            let mut server = Server::builder()
+                .dest_dir(dir.join("out"))
+                .pairing(Arc::clone(&receiver_store), move |request| {
+                    assert_eq!(request.name, "friend");
+                    code_tx.send(request.code).unwrap();
+                })
+                .bind("127.0.0.1:0")
+                .await
+                .unwrap();
+            let addr = server.local_addr().unwrap();
+            let server_task = tokio::spawn(async move { server.run().await });
+
+            // Typos cost the user another try, without pairing, and the right code pairs. Paired
+            // senders aren't asked again, unpaired ones are turned away.
+            for (name, typo, prompted, paired) in [
+                ("typo", true, true, false),
+                ("first", false, true, true),
+                ("again", false, false, true),
+                ("unpaired", false, false, false),
+            ] {
+                let mut client = Client::connect(addr).await.unwrap();
+                client.set_display_name("friend").unwrap();
+                if name != "unpaired" {
+                    let code_rx = Arc::clone(&code_rx);
+                    client.set_pairing(Arc::clone(&sender_store), move || {
+                        assert!(prompted, "{} was asked for a code", name);
+                        let code = code_rx.lock().unwrap().recv().unwrap();
+                        if typo {
+                            let wrong_code = (code.parse::<u32>().unwrap() + 1) % 1_000_000;
+                            return Some(format!("{:06}", wrong_code));
+                        }
+                        Some(code)
+                    });
+                }
+                let file = File::open(dir.join("plain")).await.unwrap();
+                client.queue_file(name, file);
+                assert_eq!(client.run().await.success, paired, "{}", name);
+                assert_eq!(dir.join("out").join(name).exists(), paired, "{}", name);
+            }
+            server_task.abort();
+
+            let sender_id = sender_store.device_id();
+            let paired_devices = receiver_store.paired_devices();
+            assert_eq!(paired_devices.len(), 1);
+            assert_eq!(paired_devices[0].id, sender_id);
+            assert_eq!(paired_devices[0].name.as_deref(), Some("friend"));
+            let reopened = PairingStore::open(dir.join("sender.json")).unwrap();
+            assert_eq!(reopened.paired_devices()[0].id, receiver_store.device_id());
+            std::fs::remove_dir_all(dir).unwrap();
+        });
+    }
+
+    #[test]
+    fn segment_size_is_negotiated() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let dir = std::env::temp_dir().join(format!("icedrop-config-{}", std::process::id()));
+            std::fs::create_dir_all(dir.join("out")).unwrap();
+            let content: Vec<u8> = (0..10_000).map(|i| (i % 251) as u8).collect();
+            std::fs::write(dir.join("data"), &content).unwrap();
+
+            let mut server = Server::builder()
+                .dest_dir(dir.join("out"))
+                .transfer_config(TransferConfig {
+                    segment_size: 4096,
+                    window_size: 8,
+                    ..TransferConfig::default()
+                })
+                .bind("127.0.0.1:0")
+                .await
+                .unwrap();
+            let addr = server.local_addr().unwrap();
+            let server_task = tokio::spawn(async move { server.run().await });
+
+            let mut client = Client::connect(addr).await.unwrap();
+            client.set_transfer_config(TransferConfig {
+                segment_size: 65536,
+                window_size: 2,
+                ..TransferConfig::default()
+            });
+            let acks = Arc::new(Mutex::new(Vec::new()));
+            let acks_clone = Arc::clone(&acks);
+            client.set_segment_sent_callback(move |segments_acked, bytes_acked| {
+                acks_clone
+                    .lock()
+                    .unwrap()
+                    .push((segments_acked, bytes_acked));
+            });
+            client.queue_file("data", File::open(dir.join("data")).await.unwrap());
+            assert!(client.run().await.success);
+            server_task.abort();
+
+            // The receiver's segment size and the sender's window win.
+            assert_eq!(acks.lock().unwrap().first(), Some(&(2, 8192)));
+            let received = std::fs::read(dir.join("out").join("data")).unwrap();
+            assert_eq!(received, content);
+            std::fs::remove_dir_all(dir).unwrap();
+        });
+    }
+
+    #[test]
+    fn large_files_are_striped_over_parallel_streams() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let dir = std::env::temp_dir().join(format!("icedrop-stripes-{}", std::process::id()));
+            std::fs::create_dir_all(dir.join("out")).unwrap();
+            let content: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+            std::fs::write(dir.join("data"), &content).unwrap();
+            let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1700724864);
+            let file = std::fs::File::options().write(true).open(dir.join("data"));
+            file.unwrap().set_modified(modified).unwrap();
+
+            let config = TransferConfig {
+                segment_size: 1024,
+                parallel_streams: 4,
+                ..TransferConfig::default()
+            };
+            let connections = Arc::new(AtomicU32::new(0));
+            let connections_clone = Arc::clone(&connections);
+            let (event_tx, event_rx) = mpsc::channel();
+            let mut server = Server::builder()
+                .dest_dir(dir.join("out"))
+                .transfer_config(config)
+                .accept_callback(move |_| {
+                    connections_clone.fetch_add(1, Ordering::SeqCst);
+                    true
+                })
+                .event_callback(move |event| {
+                    if let ReceiveEvent::FileReceived { path, bytes } = event {
+                        event_tx.send((path, bytes)).unwrap();
+                    }
+                })
+                .bind("127.0.0.1:0")
+                .await
+                .unwrap();
+            let addr = server.local_addr().unwrap();
+            let server_task = tokio::spawn(async move { server.run().await });
+
+            let mut client = Client::connect(addr).await.unwrap();
+            client.set_transfer_config(config);
+            let progress = Arc::new(Mutex::new(None));
+            let progress_clone = Arc::clone(&progress);
+            client.set_progress_callback(move |progress| {
+                *progress_clone.lock().unwrap() = Some(progress);
+            });
+            client.queue_file("data", File::open(dir.join("data")).await.unwrap());
+            let summary = client.run().await;
+            assert!(summary.success);
+            assert_eq!(summary.files, ["data"]);
+            assert_eq!(summary.bytes, 100_000);
+            let progress = progress.lock().unwrap().unwrap();
+            assert_eq!(progress.total_bytes, Some(100_000));
+            assert!(progress.bytes_sent > 25_000);
+
+            // The stripes were put back together once, under the file's own name.
+            let (path, bytes) = event_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+            server_task.abort();
+            assert_eq!(connections.load(Ordering::SeqCst), 4);
+            assert_eq!(
+                (path.clone(), bytes),
+                (dir.join("out").join("data"), 100_000)
+            );
+            assert_eq!(std::fs::read(&path).unwrap(), content);
+            let received_modified = std::fs::metadata(&path).unwrap().modified().unwrap();
+            assert_eq!(received_modified, modified);
+            assert_eq!(std::fs::read_dir(dir.join("out")).unwrap().count(), 1);
+            std::fs::remove_dir_all(dir).unwrap();
+        });
+    }
 }
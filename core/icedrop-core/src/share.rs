@@ -0,0 +1,511 @@
+//! Sharing folders with peers, who can list what's in them and pull files from them instead of
+//! waiting to be sent them. Peers are vetted like senders before they're answered, and the peer
+//! asked for a file goes on as the sender, see [`icedrop_proto::browse`] and
+//! [`icedrop_proto::pull`].
+
+use std::fs::Metadata;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use icedrop_proto::auth::{AuthChallengeFrame, PairedFrame};
+use icedrop_proto::browse::{BrowseRequestFrame, DirectoryEntry, DirectoryListingFrame};
+use icedrop_proto::def_frame_selector;
+use icedrop_proto::file_transfer::{
+    FileTransferErrorFrame, RejectionReason, TransferRejectedFrame,
+};
+use icedrop_proto::handshake::{HandshakeRequestFrame, HandshakeResponseFrame};
+use icedrop_proto::negotiate_protocol_version;
+use icedrop_proto::pull::PullRequestFrame;
+use tokio::fs::File;
+use tokio::net::ToSocketAddrs;
+
+use crate::client::Client;
+use crate::delegate::ServerDelegateRef;
+use crate::endpoint::{Endpoint, EndpointHandle};
+use crate::error::Error;
+use crate::handlers::file_transfer::{
+    unix_time, FileTransferReceivingFrame, FileTransferReceivingHandler, PeerIdentity, PeerRole,
+    ReceiveEvent, SenderPairing,
+};
+use crate::history::HistoryStore;
+use crate::proto::FrameHandler;
+
+def_frame_selector!(
+    SharedFolderRequestFrame,
+    PullRequestFrame,
+    BrowseRequestFrame
+);
+
+def_frame_selector!(
+    IntroductionReplyFrame,
+    HandshakeResponseFrame,
+    AuthChallengeFrame,
+    PairedFrame,
+    FileTransferErrorFrame,
+    TransferRejectedFrame
+);
+
+def_frame_selector!(
+    BrowseReplyFrame,
+    DirectoryListingFrame,
+    TransferRejectedFrame
+);
+
+/// The folders a server shares with peers, each under a name, see
+/// [`ServerBuilder::shared_roots`](crate::ServerBuilder::shared_roots). Everything below a root
+/// is shared, but nothing outside of it, not even through symlinks. Nothing is shared by default.
+#[derive(Debug, Clone, Default)]
+pub struct SharedRoots {
+    roots: Vec<(String, PathBuf)>,
+}
+
+impl SharedRoots {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Shares `dir` under `name`, in place of any folder shared under that name before. Panics
+    /// if `name` is empty, `.` or `..`, or contains a `/`.
+    pub fn root<P>(mut self, name: &str, dir: P) -> Self
+    where
+        P: AsRef<Path>,
+    {
+        assert!(is_plain_name(name), "invalid shared root name: {:?}", name);
+        self.roots.retain(|(root_name, _)| root_name != name);
+        self.roots.push((name.to_owned(), dir.as_ref().to_owned()));
+        self
+    }
+
+    /// Where the entry at the path made up of `names` is on disk, if it's shared, along with the
+    /// root it's below.
+    async fn resolve(&self, names: &[&str]) -> Option<(PathBuf, PathBuf)> {
+        let (root_name, names) = names.split_first()?;
+        let (_, root_dir) = self.roots.iter().find(|(name, _)| name == root_name)?;
+        let root_dir = tokio::fs::canonicalize(root_dir).await.ok()?;
+        let path = names
+            .iter()
+            .fold(root_dir.clone(), |path, name| path.join(name));
+        let path = tokio::fs::canonicalize(path).await.ok()?;
+        if !path.starts_with(&root_dir) {
+            return None;
+        }
+        Some((root_dir, path))
+    }
+
+    /// What's in the shared folder at `path`, by name, or the shared roots for an empty path.
+    async fn list(&self, path: &str) -> Option<Vec<DirectoryEntry>> {
+        let names = split_path(path)?;
+        let mut entries = Vec::new();
+        if names.is_empty() {
+            for (name, dir) in &self.roots {
+                if let Ok(metadata) = tokio::fs::metadata(dir).await {
+                    entries.push(directory_entry(name.clone(), &metadata));
+                }
+            }
+            return Some(entries);
+        }
+
+        let (root_dir, dir) = self.resolve(&names).await?;
+        let mut read_dir = tokio::fs::read_dir(dir).await.ok()?;
+        while let Some(entry) = read_dir.next_entry().await.ok()? {
+            // Names that aren't valid UTF-8 couldn't be asked for.
+            let name = match entry.file_name().into_string() {
+                Ok(name) => name,
+                Err(_) => continue,
+            };
+            let path = match tokio::fs::canonicalize(entry.path()).await {
+                Ok(path) if path.starts_with(&root_dir) => path,
+                _ => continue,
+            };
+            if let Ok(metadata) = tokio::fs::metadata(path).await {
+                entries.push(directory_entry(name, &metadata));
+            }
+        }
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        Some(entries)
+    }
+
+    /// Opens the shared file at `path`, returning it with its name.
+    async fn open(&self, path: &str) -> Option<(String, File)> {
+        let names = split_path(path)?;
+        let (_, path) = self.resolve(&names).await?;
+        if !tokio::fs::metadata(&path).await.ok()?.is_file() {
+            return None;
+        }
+        let file = File::open(path).await.ok()?;
+        Some((names.last()?.to_string(), file))
+    }
+}
+
+/// The names along a `/` separated path, `None` if any of them reaches outside of where it's
+/// looked up.
+fn split_path(path: &str) -> Option<Vec<&str>> {
+    let path = path.trim_matches('/');
+    if path.is_empty() {
+        return Some(Vec::new());
+    }
+    let names: Vec<_> = path.split('/').collect();
+    names
+        .iter()
+        .all(|name| is_plain_name(name))
+        .then_some(names)
+}
+
+fn is_plain_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\\', '\0'])
+}
+
+fn directory_entry(name: String, metadata: &Metadata) -> DirectoryEntry {
+    DirectoryEntry {
+        name,
+        size: if metadata.is_dir() { 0 } else { metadata.len() },
+        modified: metadata.modified().ok().map_or(0, unix_time),
+        is_dir: metadata.is_dir(),
+    }
+}
+
+/// A file or folder a peer shares, see [`Client::list_remote`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteEntry {
+    pub name: String,
+    /// The size of a file in bytes, 0 for folders.
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+    pub is_dir: bool,
+}
+
+impl From<DirectoryEntry> for RemoteEntry {
+    fn from(entry: DirectoryEntry) -> Self {
+        let modified = entry.modified;
+        Self {
+            name: entry.name,
+            size: entry.size,
+            modified: (modified > 0)
+                .then(|| SystemTime::UNIX_EPOCH + Duration::from_secs(modified)),
+            is_dir: entry.is_dir,
+        }
+    }
+}
+
+/// Asks the peer at `addr` for the file at `path` below the roots it shares, see
+/// [`SharedRoots`], and receives it into `dest_dir`. Returns where the file has been written to,
+/// numbered if a file of that name existed already. Peers requiring pairing turn this down, see
+/// [`Client::pull`] for pulling as a paired device.
+pub async fn pull<A, P>(addr: A, path: &str, dest_dir: P) -> Result<PathBuf, Error>
+where
+    A: ToSocketAddrs,
+    P: AsRef<Path>,
+{
+    Client::connect(addr).await?.pull(path, dest_dir).await
+}
+
+/// Pulls the file at `path` over `endpoint` into `dest_dir`, introducing this peer with
+/// `handshake` and `pairing` first, see [`Client::pull`].
+pub(crate) async fn pull_file<P>(
+    mut endpoint: Endpoint,
+    path: &str,
+    dest_dir: P,
+    handshake: HandshakeRequestFrame,
+    pairing: Option<SenderPairing>,
+) -> Result<PathBuf, Error>
+where
+    P: AsRef<Path>,
+{
+    let endpoint_handle = endpoint.handle();
+    endpoint_handle
+        .send_frame(PullRequestFrame {
+            path: path.to_owned(),
+        })
+        .await?;
+    introduce(&mut endpoint, handshake, pairing).await?;
+
+    let received = Arc::new(Mutex::new(None));
+    let received_clone = Arc::clone(&received);
+    let mut receiving_handler =
+        FileTransferReceivingHandler::new(endpoint_handle.clone(), dest_dir);
+    receiving_handler.set_event_callback(Arc::new(move |event| {
+        if let ReceiveEvent::FileReceived { path, .. } = event {
+            *received_clone.lock().unwrap() = Some(path);
+        }
+    }));
+    endpoint.add_handler(receiving_handler);
+    let rejection = Arc::new(Mutex::new(None));
+    endpoint.add_handler(PullRejectedHandler {
+        endpoint_handle,
+        rejection: Arc::clone(&rejection),
+    });
+    endpoint.run().await?;
+
+    if let Some(frame) = rejection.lock().unwrap().take() {
+        return Err(rejected(frame));
+    }
+    let path = received.lock().unwrap().take();
+    path.ok_or_else(|| Error::Protocol("the peer closed the session without the file".to_owned()))
+}
+
+/// Introduces the peer asking for something shared with `handshake` after its request, like a
+/// sender would, and pairs with `pairing` if the server requires it. Returns once the server has
+/// let the peer in, before `endpoint` runs.
+async fn introduce(
+    endpoint: &mut Endpoint,
+    handshake: HandshakeRequestFrame,
+    pairing: Option<SenderPairing>,
+) -> Result<(), Error> {
+    let endpoint_handle = endpoint.handle();
+    endpoint_handle.send_frame(handshake).await?;
+    let mut challenged_by = None;
+    loop {
+        let frame = match endpoint
+            .read_first_frame::<IntroductionReplyFrame>()
+            .await?
+        {
+            Some(frame) => frame,
+            None => {
+                return Err(Error::Protocol(
+                    "the peer answered the handshake with an unexpected frame".to_owned(),
+                ))
+            }
+        };
+        match frame {
+            IntroductionReplyFrame::HandshakeResponseFrame(frame) => {
+                let protocol_version = negotiate_protocol_version(frame.protocol_version);
+                endpoint_handle.set_protocol_version(protocol_version);
+                endpoint_handle.set_capabilities(frame.capabilities);
+                return Ok(());
+            }
+            IntroductionReplyFrame::AuthChallengeFrame(frame) => {
+                let pairing = pairing
+                    .as_ref()
+                    .ok_or_else(|| Error::Handshake("the peer requires pairing".to_owned()))?;
+                let request = pairing.answer(&frame).await.ok_or(Error::Cancelled)?;
+                challenged_by = Some(frame.receiver_id);
+                endpoint_handle.send_frame(request).await?;
+            }
+            IntroductionReplyFrame::PairedFrame(frame) => {
+                if let (Some(pairing), Some(receiver_id)) = (&pairing, &challenged_by) {
+                    pairing.paired(receiver_id, frame);
+                }
+            }
+            IntroductionReplyFrame::FileTransferErrorFrame(frame) => {
+                return Err(Error::Rejected {
+                    retryable: frame.retryable,
+                    message: frame.message,
+                    reason: None,
+                })
+            }
+            IntroductionReplyFrame::TransferRejectedFrame(frame) => return Err(rejected(frame)),
+        }
+    }
+}
+
+/// Vets the peer asking for something shared like a sender, with `handler` set up for one: the
+/// peer introduces itself with the handshake and pairs if the server requires it, before
+/// `endpoint` runs. Returns who the peer is and what it may do once it's been let in, `None` if it
+/// was turned away.
+async fn vet(
+    endpoint: &mut Endpoint,
+    mut handler: FileTransferReceivingHandler,
+    addr: SocketAddr,
+) -> Result<Option<(PeerIdentity, PeerRole)>, Error> {
+    let mut introduced = false;
+    loop {
+        match handler.vetted() {
+            Some(true) => return Ok(Some((handler.peer_identity(addr), handler.role()))),
+            Some(false) => return Ok(None),
+            None => {}
+        }
+        // The handshake follows the request right away, but pairing may wait for the user.
+        let frame = if introduced {
+            endpoint.read_first_frame().await?
+        } else {
+            endpoint.read_first_frame_promptly().await?
+        };
+        introduced = true;
+        match frame {
+            Some(frame @ FileTransferReceivingFrame::HandshakeRequestFrame(_))
+            | Some(frame @ FileTransferReceivingFrame::AuthRequestFrame(_)) => {
+                handler.handle_frame(frame).await
+            }
+            _ => {
+                return Err(Error::Protocol(
+                    "the peer didn't introduce itself with a handshake".to_owned(),
+                ))
+            }
+        }
+    }
+}
+
+/// Lists the shared folder at `path` over `endpoint`, introducing this peer with `handshake` and
+/// `pairing` first, see [`Client::list_remote`].
+pub(crate) async fn list_remote(
+    mut endpoint: Endpoint,
+    path: &str,
+    handshake: HandshakeRequestFrame,
+    pairing: Option<SenderPairing>,
+) -> Result<Vec<RemoteEntry>, Error> {
+    let endpoint_handle = endpoint.handle();
+    endpoint_handle
+        .send_frame(BrowseRequestFrame {
+            path: path.to_owned(),
+        })
+        .await?;
+    introduce(&mut endpoint, handshake, pairing).await?;
+
+    let listing = Arc::new(Mutex::new(None));
+    endpoint.add_handler(BrowseReplyHandler {
+        endpoint_handle,
+        listing: Arc::clone(&listing),
+    });
+    endpoint.run().await?;
+
+    let listing = listing.lock().unwrap().take();
+    listing.unwrap_or_else(|| {
+        Err(Error::Protocol(
+            "the peer closed the session without a listing".to_owned(),
+        ))
+    })
+}
+
+/// The error for a peer turning a pull or browse request down.
+fn rejected(frame: TransferRejectedFrame) -> Error {
+    Error::Rejected {
+        retryable: frame.reason.is_retryable(),
+        message: frame.message,
+        reason: Some(frame.reason),
+    }
+}
+
+/// Keeps the rejection of a pull request and ends the session.
+struct PullRejectedHandler {
+    endpoint_handle: EndpointHandle,
+    rejection: Arc<Mutex<Option<TransferRejectedFrame>>>,
+}
+
+#[async_trait]
+impl FrameHandler for PullRejectedHandler {
+    type IncomingFrame = TransferRejectedFrame;
+
+    async fn handle_frame(&mut self, frame: Self::IncomingFrame) {
+        println!("pull rejected ({:?}): {}", frame.reason, frame.message);
+        *self.rejection.lock().unwrap() = Some(frame);
+        self.endpoint_handle.shutdown().await.ok();
+    }
+}
+
+/// The answer to a browse request.
+type Listing = Result<Vec<RemoteEntry>, Error>;
+
+/// Keeps the answer to a browse request and ends the session.
+struct BrowseReplyHandler {
+    endpoint_handle: EndpointHandle,
+    listing: Arc<Mutex<Option<Listing>>>,
+}
+
+#[async_trait]
+impl FrameHandler for BrowseReplyHandler {
+    type IncomingFrame = BrowseReplyFrame;
+
+    async fn handle_frame(&mut self, frame: Self::IncomingFrame) {
+        let listing = match frame {
+            BrowseReplyFrame::DirectoryListingFrame(frame) => {
+                Ok(frame.entries.into_iter().map(RemoteEntry::from).collect())
+            }
+            BrowseReplyFrame::TransferRejectedFrame(frame) => Err(rejected(frame)),
+        };
+        *self.listing.lock().unwrap() = Some(listing);
+        self.endpoint_handle.shutdown().await.ok();
+    }
+}
+
+/// Answers the pull or browse `request` made over `endpoint` from `roots`, turning it down if
+/// nothing is shared at the path asked for. The peer is let in by `vetting` like a sender first,
+/// and then by the delegate.
+pub(crate) async fn serve_shared(
+    mut endpoint: Endpoint,
+    vetting: FileTransferReceivingHandler,
+    roots: &SharedRoots,
+    request: SharedFolderRequestFrame,
+    delegate: Option<ServerDelegateRef>,
+    history: Option<Arc<HistoryStore>>,
+    addr: SocketAddr,
+) {
+    let (peer, role) = match vet(&mut endpoint, vetting, addr).await {
+        Ok(Some(vetted)) => vetted,
+        Ok(None) => return,
+        Err(err) => {
+            println!("error happened while vetting {:?}: {:?}", addr, err);
+            return;
+        }
+    };
+    let permitted = match &request {
+        SharedFolderRequestFrame::PullRequestFrame(_) => role.may_pull(),
+        SharedFolderRequestFrame::BrowseRequestFrame(_) => role.may_browse(),
+    };
+    if !permitted {
+        let message = "not allowed to do that with shared files".to_owned();
+        println!("{:?} was turned down: {}", addr, message);
+        return refuse(endpoint.handle(), RejectionReason::Unauthorized, message).await;
+    }
+    let allowed = match (&delegate, &request) {
+        (Some(delegate), SharedFolderRequestFrame::PullRequestFrame(request)) => {
+            delegate.should_serve_pull(peer, &request.path).await
+        }
+        (Some(delegate), SharedFolderRequestFrame::BrowseRequestFrame(request)) => {
+            delegate.should_serve_listing(peer, &request.path).await
+        }
+        (None, _) => Ok(()),
+    };
+    if let Err(message) = allowed {
+        println!("{:?} was turned down: {}", addr, message);
+        return refuse(endpoint.handle(), RejectionReason::Declined, message).await;
+    }
+
+    let path = match request {
+        SharedFolderRequestFrame::PullRequestFrame(request) => {
+            if let Some((file_name, file)) = roots.open(&request.path).await {
+                let mut client = Client::with_endpoint(endpoint, Some(addr));
+                client.queue_file(&file_name, file);
+                if let Some(history) = history {
+                    client.set_history(history);
+                }
+                match client.run().await.error {
+                    Some(err) => println!("error happened while serving a pull: {:?}", err),
+                    None => println!("{:?} pulled {:?}", addr, request.path),
+                }
+                return;
+            }
+            request.path
+        }
+        SharedFolderRequestFrame::BrowseRequestFrame(request) => {
+            if let Some(entries) = roots.list(&request.path).await {
+                let endpoint_handle = endpoint.handle();
+                endpoint_handle
+                    .send_frame(DirectoryListingFrame { entries })
+                    .await
+                    .ok();
+                endpoint_handle.shutdown().await.ok();
+                return;
+            }
+            request.path
+        }
+    };
+
+    println!("{:?} asked for something not shared: {:?}", addr, path);
+    refuse(
+        endpoint.handle(),
+        RejectionReason::Declined,
+        format!("nothing is shared at {:?}", path),
+    )
+    .await;
+}
+
+/// Turns down the request made over the endpoint of `endpoint_handle` for `reason` with
+/// `message`, ending the session.
+async fn refuse(endpoint_handle: EndpointHandle, reason: RejectionReason, message: String) {
+    let rejection = TransferRejectedFrame { reason, message };
+    endpoint_handle.send_frame(rejection).await.ok();
+    endpoint_handle.shutdown().await.ok();
+}
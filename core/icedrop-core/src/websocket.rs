@@ -0,0 +1,125 @@
+//! Connections over WebSocket, so that web pages, which can't open plain TCP connections, can
+//! take part in transfers.
+//!
+//! Whatever the endpoint writes goes out as one binary message, and the binary messages received
+//! are read back as one stream of bytes, so frames don't have to line up with messages.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use futures_util::{Sink, Stream};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+/// Connects to the WebSocket server at `url`, e.g. `ws://192.168.1.20:7370/`.
+pub(crate) async fn connect(url: &str) -> io::Result<WsStream<MaybeTlsStream<TcpStream>>> {
+    let (ws, _) = tokio_tungstenite::connect_async(url)
+        .await
+        .map_err(ws_error)?;
+    Ok(WsStream::new(ws))
+}
+
+/// Runs the server side of the WebSocket handshake over `stream`.
+pub(crate) async fn accept(stream: TcpStream) -> io::Result<WsStream<TcpStream>> {
+    let ws = tokio_tungstenite::accept_async(stream)
+        .await
+        .map_err(ws_error)?;
+    Ok(WsStream::new(ws))
+}
+
+/// Reads and writes the bytes of the binary messages sent over a WebSocket connection.
+pub(crate) struct WsStream<S> {
+    ws: WebSocketStream<S>,
+    /// The payload of the last message received, not read yet from `read_pos` on.
+    incoming: Vec<u8>,
+    read_pos: usize,
+}
+
+impl<S> WsStream<S> {
+    pub(crate) fn new(ws: WebSocketStream<S>) -> Self {
+        Self {
+            ws,
+            incoming: Vec::new(),
+            read_pos: 0,
+        }
+    }
+}
+
+fn ws_error(err: WsError) -> io::Error {
+    match err {
+        WsError::Io(err) => err,
+        WsError::ConnectionClosed | WsError::AlreadyClosed => io::ErrorKind::BrokenPipe.into(),
+        err => io::Error::new(io::ErrorKind::InvalidData, err),
+    }
+}
+
+impl<S> AsyncRead for WsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.read_pos < this.incoming.len() {
+                let len = buf.remaining().min(this.incoming.len() - this.read_pos);
+                buf.put_slice(&this.incoming[this.read_pos..this.read_pos + len]);
+                this.read_pos += len;
+                return Poll::Ready(Ok(()));
+            }
+
+            match ready!(Pin::new(&mut this.ws).poll_next(cx)) {
+                Some(Ok(Message::Binary(payload))) => {
+                    this.incoming = payload;
+                    this.read_pos = 0;
+                }
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                Some(Ok(Message::Text(_))) => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "frames are only sent in binary messages",
+                    )));
+                }
+                // Pings are answered by tungstenite itself.
+                Some(Ok(_)) => {}
+                Some(Err(err)) => return Poll::Ready(Err(ws_error(err))),
+            }
+        }
+    }
+}
+
+impl<S> AsyncWrite for WsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(Pin::new(&mut this.ws).poll_ready(cx)).map_err(ws_error)?;
+        Pin::new(&mut this.ws)
+            .start_send(Message::Binary(buf.to_vec()))
+            .map_err(ws_error)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().ws)
+            .poll_flush(cx)
+            .map_err(ws_error)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().ws)
+            .poll_close(cx)
+            .map_err(ws_error)
+    }
+}
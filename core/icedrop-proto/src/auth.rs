@@ -0,0 +1,266 @@
+//! Frames pairing two devices, so a receiver only takes files from senders it knows.
+//!
+//! After the handshake request, a receiver requiring pairing holds its response back and sends an
+//! [`AuthChallengeFrame`] instead. A sender it has been paired with before answers with a proof
+//! of the key they share, see [`auth_proof`]. Any other sender has to supply the code the receiver
+//! shows its user, and gets a new key in a [`PairedFrame`] if it's right. These frames are sent
+//! before the handshake response, so they always use the version 1 header.
+
+use crate::frame_types;
+use crate::{Frame, FrameParsingResult};
+
+use std::convert::TryInto;
+use std::io;
+
+use byteorder::{ByteOrder, LittleEndian};
+use sha2::{Digest, Sha256};
+
+/// The first protocol version supporting pairing.
+pub const PAIRING_PROTOCOL_VERSION: u16 = 11;
+
+/// Identifies a device across sessions, picked at random by every device once.
+pub type DeviceId = [u8; 16];
+
+/// The secret two paired devices share.
+pub type PairingKey = [u8; 32];
+
+/// Pairing codes are shown and typed in as this many decimal digits.
+pub const PAIRING_CODE_DIGITS: usize = 6;
+
+/// Asks the sender to authenticate before the receiver answers its handshake.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthChallengeFrame {
+    pub receiver_id: DeviceId,
+    /// Makes every proof good for a single session only.
+    pub nonce: [u8; 16],
+    /// Set once the receiver is showing a pairing code the sender has to supply.
+    pub code_required: bool,
+}
+
+impl Frame for AuthChallengeFrame {
+    fn frame_type(&self) -> u16 {
+        frame_types::AUTH_CHALLENGE
+    }
+
+    fn frame_types() -> Vec<u16> {
+        vec![frame_types::AUTH_CHALLENGE]
+    }
+
+    fn try_parse(frame_type: u16, buf: Vec<u8>) -> FrameParsingResult<Self> {
+        if frame_type != frame_types::AUTH_CHALLENGE {
+            return FrameParsingResult::Skip(buf);
+        }
+        if buf.len() < 33 {
+            return FrameParsingResult::Err(Box::new(io::Error::from(
+                io::ErrorKind::UnexpectedEof,
+            )));
+        }
+
+        FrameParsingResult::Ok(AuthChallengeFrame {
+            receiver_id: buf[0..16].try_into().unwrap(),
+            nonce: buf[16..32].try_into().unwrap(),
+            code_required: buf[32] != 0,
+        })
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(33);
+        buf.extend(self.receiver_id);
+        buf.extend(self.nonce);
+        buf.push(self.code_required as u8);
+        buf
+    }
+}
+
+/// How a sender authenticates itself in an [`AuthRequestFrame`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthCredential {
+    /// The sender isn't paired with the receiver, and asks it to show a pairing code.
+    None,
+    /// The pairing code the receiver shows, as typed in by the user.
+    Code(u32),
+    /// Proof of the key shared with the receiver, see [`auth_proof`].
+    Proof([u8; 32]),
+}
+
+/// The sender's answer to an [`AuthChallengeFrame`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthRequestFrame {
+    pub device_id: DeviceId,
+    pub credential: AuthCredential,
+}
+
+impl Frame for AuthRequestFrame {
+    fn frame_type(&self) -> u16 {
+        frame_types::AUTH_REQUEST
+    }
+
+    fn frame_types() -> Vec<u16> {
+        vec![frame_types::AUTH_REQUEST]
+    }
+
+    fn try_parse(frame_type: u16, buf: Vec<u8>) -> FrameParsingResult<Self> {
+        if frame_type != frame_types::AUTH_REQUEST {
+            return FrameParsingResult::Skip(buf);
+        }
+        let credential_len = match buf.get(16) {
+            Some(0) => 0,
+            Some(1) => 4,
+            Some(2) => 32,
+            Some(_) => {
+                return FrameParsingResult::Err(Box::new(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "unknown credential kind",
+                )));
+            }
+            None => 0,
+        };
+        if buf.len() < 17 + credential_len {
+            return FrameParsingResult::Err(Box::new(io::Error::from(
+                io::ErrorKind::UnexpectedEof,
+            )));
+        }
+
+        let credential = match buf[16] {
+            0 => AuthCredential::None,
+            1 => AuthCredential::Code(LittleEndian::read_u32(&buf[17..21])),
+            _ => AuthCredential::Proof(buf[17..49].try_into().unwrap()),
+        };
+        FrameParsingResult::Ok(AuthRequestFrame {
+            device_id: buf[0..16].try_into().unwrap(),
+            credential,
+        })
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(49);
+        buf.extend(self.device_id);
+        match self.credential {
+            AuthCredential::None => buf.push(0),
+            AuthCredential::Code(code) => {
+                buf.push(1);
+                let mut code_buf = [0u8; 4];
+                LittleEndian::write_u32(&mut code_buf, code);
+                buf.extend(code_buf);
+            }
+            AuthCredential::Proof(proof) => {
+                buf.push(2);
+                buf.extend(proof);
+            }
+        }
+        buf
+    }
+}
+
+/// Hands the sender the key it's paired with the receiver by from now on, after it supplied the
+/// right code. The handshake response follows.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PairedFrame {
+    pub key: PairingKey,
+}
+
+impl Frame for PairedFrame {
+    fn frame_type(&self) -> u16 {
+        frame_types::PAIRED
+    }
+
+    fn frame_types() -> Vec<u16> {
+        vec![frame_types::PAIRED]
+    }
+
+    fn try_parse(frame_type: u16, buf: Vec<u8>) -> FrameParsingResult<Self> {
+        if frame_type != frame_types::PAIRED {
+            return FrameParsingResult::Skip(buf);
+        }
+        if buf.len() < 32 {
+            return FrameParsingResult::Err(Box::new(io::Error::from(
+                io::ErrorKind::UnexpectedEof,
+            )));
+        }
+
+        FrameParsingResult::Ok(PairedFrame {
+            key: buf[0..32].try_into().unwrap(),
+        })
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        self.key.to_vec()
+    }
+}
+
+/// Proves knowledge of `key` to the receiver that sent the challenge, without giving it away.
+pub fn auth_proof(
+    key: &PairingKey,
+    receiver_id: &DeviceId,
+    device_id: &DeviceId,
+    nonce: &[u8; 16],
+) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(b"icedrop pairing");
+    hasher.update(key);
+    hasher.update(receiver_id);
+    hasher.update(device_id);
+    hasher.update(nonce);
+    hasher.finalize().into()
+}
+
+/// Compares two proofs in constant time, so timing doesn't tell how much of a guess was right.
+pub fn proofs_match(a: &[u8; 32], b: &[u8; 32]) -> bool {
+    a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Formats a pairing code the way users are shown it, e.g. `042917`.
+pub fn format_pairing_code(code: u32) -> String {
+    format!("{:0width$}", code, width = PAIRING_CODE_DIGITS)
+}
+
+/// Reads a pairing code as typed in by a user, ignoring spaces and dashes in between.
+pub fn parse_pairing_code(input: &str) -> Option<u32> {
+    let digits: String = input
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .collect();
+    if digits.len() != PAIRING_CODE_DIGITS || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    digits.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{auth_proof, format_pairing_code, parse_pairing_code, proofs_match};
+
+    #[test]
+    fn proofs_are_bound_to_the_session() {
+        let key = [7; 32];
+        let proof = auth_proof(&key, &[1; 16], &[2; 16], &[3; 16]);
+        assert!(proofs_match(
+            &proof,
+            &auth_proof(&key, &[1; 16], &[2; 16], &[3; 16])
+        ));
+
+        // Another nonce, receiver or key gives another proof.
+        assert!(!proofs_match(
+            &proof,
+            &auth_proof(&key, &[1; 16], &[2; 16], &[4; 16])
+        ));
+        assert!(!proofs_match(
+            &proof,
+            &auth_proof(&key, &[5; 16], &[2; 16], &[3; 16])
+        ));
+        assert!(!proofs_match(
+            &proof,
+            &auth_proof(&[8; 32], &[1; 16], &[2; 16], &[3; 16])
+        ));
+    }
+
+    #[test]
+    fn pairing_codes_are_read_as_typed() {
+        assert_eq!(format_pairing_code(42917), "042917");
+        assert_eq!(parse_pairing_code("042917"), Some(42917));
+        assert_eq!(parse_pairing_code(" 042 917\n"), Some(42917));
+        assert_eq!(parse_pairing_code("042-917"), Some(42917));
+        assert_eq!(parse_pairing_code("42917"), None);
+        assert_eq!(parse_pairing_code("04291x"), None);
+    }
+}
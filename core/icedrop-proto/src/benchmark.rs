@@ -0,0 +1,59 @@
+//! Frames of the network benchmark, which streams generated data instead of a file.
+
+use crate::frame_types;
+use crate::{Frame, FrameParsingResult};
+
+use std::io;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+/// The first protocol version supporting benchmarks.
+pub const BENCHMARK_PROTOCOL_VERSION: u16 = 6;
+
+/// Carries filler data from the sender, or echoes a frame back from the receiver.
+///
+/// The receiver answers every frame with one of the same `seq` and no data, so the sender can
+/// measure round trips. A frame without data from the sender ends the benchmark, the receiver
+/// echoes it and closes the session.
+#[derive(Debug)]
+pub struct BenchmarkFrame {
+    pub seq: u32,
+    pub data: Vec<u8>,
+}
+
+impl Frame for BenchmarkFrame {
+    fn frame_type(&self) -> u16 {
+        frame_types::BENCHMARK
+    }
+
+    fn frame_types() -> Vec<u16> {
+        vec![frame_types::BENCHMARK]
+    }
+
+    fn try_parse(frame_type: u16, buf: Vec<u8>) -> FrameParsingResult<Self> {
+        if frame_type != frame_types::BENCHMARK {
+            return FrameParsingResult::Skip(buf);
+        }
+        if buf.len() < 4 {
+            return FrameParsingResult::Err(Box::new(io::Error::from(
+                io::ErrorKind::UnexpectedEof,
+            )));
+        }
+
+        let seq = LittleEndian::read_u32(&buf[0..4]);
+        let data = buf[4..].to_vec();
+
+        FrameParsingResult::Ok(BenchmarkFrame { seq, data })
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        let mut seq_buf = [0u8; 4];
+        LittleEndian::write_u32(&mut seq_buf, self.seq);
+
+        let mut buf = Vec::<u8>::with_capacity(4 + self.data.len());
+        buf.extend(seq_buf);
+        buf.extend(self.data);
+
+        buf
+    }
+}
@@ -0,0 +1,136 @@
+//! Listing what a peer shares, e.g. to pick a file to pull, see [`crate::pull`].
+//!
+//! The peer browsing connects and starts with a [`BrowseRequestFrame`] naming a folder, sent
+//! before the handshake with the version 1 header. It then introduces itself like a peer pulling
+//! a file, and once it's let in is answered with a [`DirectoryListingFrame`] of what's in the
+//! folder, or a [`TransferRejectedFrame`](crate::file_transfer::TransferRejectedFrame) if nothing
+//! is shared there. The session is over after that.
+
+use crate::frame_types;
+use crate::{Frame, FrameParsingResult};
+
+use std::io;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+/// Asks for the listing of a shared folder, given by its path below the shared roots with `/`
+/// between the names, like `photos/2024`. An empty path asks for the roots themselves.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BrowseRequestFrame {
+    pub path: String,
+}
+
+impl Frame for BrowseRequestFrame {
+    fn frame_type(&self) -> u16 {
+        frame_types::BROWSE_REQUEST
+    }
+
+    fn frame_types() -> Vec<u16> {
+        vec![frame_types::BROWSE_REQUEST]
+    }
+
+    fn try_parse(frame_type: u16, buf: Vec<u8>) -> FrameParsingResult<Self> {
+        if frame_type != frame_types::BROWSE_REQUEST {
+            return FrameParsingResult::Skip(buf);
+        }
+
+        match String::from_utf8(buf) {
+            Ok(path) => FrameParsingResult::Ok(BrowseRequestFrame { path }),
+            Err(err) => {
+                FrameParsingResult::Err(Box::new(io::Error::new(io::ErrorKind::InvalidData, err)))
+            }
+        }
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        self.path.into_bytes()
+    }
+}
+
+/// A file or folder in a [`DirectoryListingFrame`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirectoryEntry {
+    pub name: String,
+    /// The size of a file in bytes, 0 for folders.
+    pub size: u64,
+    /// When the entry was last modified, in seconds since the Unix epoch, 0 if unknown.
+    pub modified: u64,
+    pub is_dir: bool,
+}
+
+/// What's in the folder a [`BrowseRequestFrame`] asked for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirectoryListingFrame {
+    pub entries: Vec<DirectoryEntry>,
+}
+
+impl Frame for DirectoryListingFrame {
+    fn frame_type(&self) -> u16 {
+        frame_types::DIRECTORY_LISTING
+    }
+
+    fn frame_types() -> Vec<u16> {
+        vec![frame_types::DIRECTORY_LISTING]
+    }
+
+    fn try_parse(frame_type: u16, buf: Vec<u8>) -> FrameParsingResult<Self> {
+        if frame_type != frame_types::DIRECTORY_LISTING {
+            return FrameParsingResult::Skip(buf);
+        }
+        if buf.len() < 4 {
+            return FrameParsingResult::Err(Box::new(io::Error::from(
+                io::ErrorKind::UnexpectedEof,
+            )));
+        }
+
+        let count = LittleEndian::read_u32(&buf[0..4]) as usize;
+        let mut entries = Vec::with_capacity(count.min(buf.len() / 19));
+        let mut rest = &buf[4..];
+        for _ in 0..count {
+            if rest.len() < 19 {
+                return FrameParsingResult::Err(Box::new(io::Error::from(
+                    io::ErrorKind::UnexpectedEof,
+                )));
+            }
+            let is_dir = rest[0] != 0;
+            let size = LittleEndian::read_u64(&rest[1..9]);
+            let modified = LittleEndian::read_u64(&rest[9..17]);
+            let name_len = LittleEndian::read_u16(&rest[17..19]) as usize;
+            if rest.len() < 19 + name_len {
+                return FrameParsingResult::Err(Box::new(io::Error::from(
+                    io::ErrorKind::UnexpectedEof,
+                )));
+            }
+            let name = String::from_utf8_lossy(&rest[19..19 + name_len]).into_owned();
+            entries.push(DirectoryEntry {
+                name,
+                size,
+                modified,
+                is_dir,
+            });
+            rest = &rest[19 + name_len..];
+        }
+
+        FrameParsingResult::Ok(DirectoryListingFrame { entries })
+    }
+
+    /// Writes the number of entries, then for each whether it's a folder, its size, modification
+    /// time, the length of its name and the name. Names longer than `u16::MAX` bytes are cut off.
+    fn to_bytes(self) -> Vec<u8> {
+        let mut buf = vec![0u8; 4];
+        LittleEndian::write_u32(&mut buf[0..4], self.entries.len() as u32);
+        for entry in self.entries {
+            let mut name = entry.name.into_bytes();
+            name.truncate(u16::MAX as usize);
+
+            let mut fields = [0u8; 19];
+            fields[0] = entry.is_dir as u8;
+            LittleEndian::write_u64(&mut fields[1..9], entry.size);
+            LittleEndian::write_u64(&mut fields[9..17], entry.modified);
+            LittleEndian::write_u16(&mut fields[17..19], name.len() as u16);
+            buf.extend(fields);
+            buf.extend(name);
+        }
+        buf
+    }
+}
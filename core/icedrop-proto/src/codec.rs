@@ -0,0 +1,131 @@
+//! Framing of the byte stream: each frame is a [`FrameHeader`] followed by the frame payload.
+
+use crate::{Frame, FrameFlags};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+/// The header preceding every frame on the wire.
+///
+/// Protocol version 1 uses a 6-byte header: `u16` frame type and `u32` payload length. From
+/// version 2 on the header is 12 bytes: `u16` frame type, `u8` flags, one reserved byte, `u32`
+/// stream id and `u32` payload length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub frame_type: u16,
+    pub flags: FrameFlags,
+    pub stream_id: u32,
+    pub frame_len: u32,
+}
+
+impl FrameHeader {
+    pub fn size(protocol_version: u16) -> usize {
+        if protocol_version >= 2 {
+            12
+        } else {
+            6
+        }
+    }
+
+    pub fn parse(protocol_version: u16, buf: &[u8]) -> Self {
+        if protocol_version >= 2 {
+            Self {
+                frame_type: LittleEndian::read_u16(&buf[0..2]),
+                flags: FrameFlags::from_bits(buf[2]),
+                stream_id: LittleEndian::read_u32(&buf[4..8]),
+                frame_len: LittleEndian::read_u32(&buf[8..12]),
+            }
+        } else {
+            Self {
+                frame_type: LittleEndian::read_u16(&buf[0..2]),
+                flags: FrameFlags::empty(),
+                stream_id: 0,
+                frame_len: LittleEndian::read_u32(&buf[2..6]),
+            }
+        }
+    }
+
+    pub fn write(&self, protocol_version: u16, buf: &mut Vec<u8>) {
+        let mut frame_type_buf = [0u8; 2];
+        LittleEndian::write_u16(&mut frame_type_buf, self.frame_type);
+
+        let mut frame_len_buf = [0u8; 4];
+        LittleEndian::write_u32(&mut frame_len_buf, self.frame_len);
+
+        buf.extend(frame_type_buf);
+        if protocol_version >= 2 {
+            let mut stream_id_buf = [0u8; 4];
+            LittleEndian::write_u32(&mut stream_id_buf, self.stream_id);
+
+            buf.push(self.flags.bits());
+            buf.push(0); // Reserved.
+            buf.extend(stream_id_buf);
+        } else {
+            debug_assert!(
+                self.flags.is_empty() && self.stream_id == 0,
+                "flags and stream ids need protocol version 2"
+            );
+        }
+        buf.extend(frame_len_buf);
+    }
+}
+
+pub struct FrameWithHeader<F>
+where
+    F: Frame,
+{
+    pub frame: F,
+}
+
+impl<F> FrameWithHeader<F>
+where
+    F: Frame,
+{
+    pub fn to_bytes(self, protocol_version: u16) -> Vec<u8> {
+        let frame_type = self.frame.frame_type();
+        let payload = self.frame.to_bytes();
+
+        let header = FrameHeader {
+            frame_type,
+            flags: FrameFlags::empty(),
+            stream_id: 0,
+            frame_len: payload.len() as u32,
+        };
+
+        let mut buf = Vec::with_capacity(FrameHeader::size(protocol_version) + payload.len());
+        header.write(protocol_version, &mut buf);
+        buf.extend(payload);
+
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FrameHeader;
+    use crate::FrameFlags;
+
+    #[test]
+    fn extended_header_round_trip() {
+        let mut flags = FrameFlags::empty();
+        flags.insert(FrameFlags::ENCRYPTED);
+        let header = FrameHeader {
+            frame_type: 3,
+            flags,
+            stream_id: 7,
+            frame_len: 1024,
+        };
+
+        let mut buf = Vec::new();
+        header.write(2, &mut buf);
+        assert_eq!(buf.len(), FrameHeader::size(2));
+        assert_eq!(FrameHeader::parse(2, &buf), header);
+    }
+
+    #[test]
+    fn reserved_header_bits_are_ignored() {
+        let buf = [3, 0, 0xf3, 0xff, 0, 0, 0, 0, 8, 0, 0, 0];
+        let header = FrameHeader::parse(2, &buf);
+        assert_eq!(header.flags, FrameFlags::ENCRYPTED);
+        assert_eq!(header.frame_len, 8);
+    }
+}
@@ -0,0 +1,154 @@
+//! Compression of file segments on the wire.
+//!
+//! The sender picks a [`CompressionMode`] and the receiver agrees to it during the handshake.
+//! Segments that don't get noticeably smaller are sent as they are, so files that are compressed
+//! already, like photos, videos and archives, cost little more than a look at their bytes.
+
+use std::io;
+
+/// Segments shorter than this are never compressed, the framing would eat up the savings.
+const MIN_COMPRESSED_LEN: usize = 64;
+
+/// How many bytes of a segment [`looks_compressible`] looks at.
+const SAMPLE_LEN: usize = 4096;
+
+/// Segments whose sample has more bits of entropy per byte than this are taken to be compressed
+/// already.
+const MAX_ENTROPY: f64 = 7.5;
+
+/// The zstd level segments are compressed at, fast enough to keep up with a gigabit link.
+const ZSTD_LEVEL: i32 = 3;
+
+/// How file segments are compressed on the wire. Only takes effect with peers speaking protocol
+/// version 10, transfers with older peers are not compressed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum CompressionMode {
+    /// Send segments as they are.
+    #[default]
+    None,
+    /// LZ4, cheap enough for fast links.
+    Lz4,
+    /// Zstandard, compresses better at the cost of more CPU time, for slow links.
+    Zstd,
+}
+
+impl CompressionMode {
+    /// The byte the mode is sent as.
+    pub fn to_u8(self) -> u8 {
+        match self {
+            Self::None => 0,
+            Self::Lz4 => 1,
+            Self::Zstd => 2,
+        }
+    }
+
+    /// The mode sent as `value`, `None` for modes this implementation doesn't know.
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Self::None),
+            1 => Some(Self::Lz4),
+            2 => Some(Self::Zstd),
+            _ => None,
+        }
+    }
+}
+
+/// Guesses from the byte histogram of a sample whether `data` compresses at all.
+pub fn looks_compressible(data: &[u8]) -> bool {
+    if data.len() < MIN_COMPRESSED_LEN {
+        return false;
+    }
+
+    let sample = &data[..data.len().min(SAMPLE_LEN)];
+    let mut counts = [0u32; 256];
+    for &byte in sample {
+        counts[byte as usize] += 1;
+    }
+    let len = sample.len() as f64;
+    let entropy: f64 = counts
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f64 / len;
+            -p * p.log2()
+        })
+        .sum();
+    entropy <= MAX_ENTROPY
+}
+
+/// Compresses `data` with `mode`, or returns `None` if that doesn't save at least an eighth of it.
+pub fn compress(mode: CompressionMode, data: &[u8]) -> Option<Vec<u8>> {
+    if !looks_compressible(data) {
+        return None;
+    }
+
+    let compressed = match mode {
+        CompressionMode::None => return None,
+        CompressionMode::Lz4 => lz4_flex::block::compress(data),
+        CompressionMode::Zstd => zstd::bulk::compress(data, ZSTD_LEVEL).ok()?,
+    };
+    if compressed.len() > data.len() - data.len() / 8 {
+        return None;
+    }
+    Some(compressed)
+}
+
+/// Decompresses `data` compressed with `mode` back into the `len` bytes it was made from.
+pub fn decompress(mode: CompressionMode, data: &[u8], len: usize) -> io::Result<Vec<u8>> {
+    let decompressed = match mode {
+        CompressionMode::None => data.to_vec(),
+        CompressionMode::Lz4 => lz4_flex::block::decompress(data, len)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?,
+        CompressionMode::Zstd => zstd::bulk::decompress(data, len)?,
+    };
+    if decompressed.len() != len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "decompressed segment has the wrong length",
+        ));
+    }
+    Ok(decompressed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compress, decompress, looks_compressible, CompressionMode};
+
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+
+    fn noise(len: usize) -> Vec<u8> {
+        let state = RandomState::new();
+        (0..len)
+            .map(|i| {
+                let mut hasher = state.build_hasher();
+                hasher.write_usize(i);
+                hasher.finish() as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn text_is_compressed_and_restored() {
+        let text = "the quick brown fox jumps over the lazy dog\n".repeat(200);
+        for mode in [CompressionMode::Lz4, CompressionMode::Zstd] {
+            let compressed = compress(mode, text.as_bytes()).unwrap();
+            assert!(compressed.len() < text.len() / 4);
+            let restored = decompress(mode, &compressed, text.len()).unwrap();
+            assert_eq!(restored, text.as_bytes());
+
+            assert!(decompress(mode, &compressed, text.len() - 1).is_err());
+        }
+    }
+
+    #[test]
+    fn incompressible_segments_are_skipped() {
+        let noise = noise(16 * 1024);
+        assert!(!looks_compressible(&noise));
+        assert_eq!(compress(CompressionMode::Zstd, &noise), None);
+
+        // Too short to be worth it, however repetitive.
+        assert_eq!(compress(CompressionMode::Lz4, &[0; 32]), None);
+        assert_eq!(compress(CompressionMode::None, &[0; 4096]), None);
+    }
+}
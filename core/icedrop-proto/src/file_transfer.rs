@@ -0,0 +1,641 @@
+use crate::compression::{self, CompressionMode};
+use crate::frame_types;
+use crate::transfer::MAX_SEGMENT_SIZE;
+use crate::{Frame, FrameParsingResult};
+
+use std::io;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+#[derive(Debug)]
+pub struct FileTransferAckFrame {
+    pub segment_idx: u32,
+}
+
+impl Frame for FileTransferAckFrame {
+    fn frame_type(&self) -> u16 {
+        frame_types::FILE_TRANSFER_ACK
+    }
+
+    fn frame_types() -> Vec<u16> {
+        vec![frame_types::FILE_TRANSFER_ACK]
+    }
+
+    fn try_parse(frame_type: u16, buf: Vec<u8>) -> FrameParsingResult<Self> {
+        if frame_type != frame_types::FILE_TRANSFER_ACK {
+            return FrameParsingResult::Skip(buf);
+        }
+
+        if buf.len() < 4 {
+            return FrameParsingResult::Err(Box::new(io::Error::from(
+                io::ErrorKind::UnexpectedEof,
+            )));
+        }
+        let segment_idx = LittleEndian::read_u32(&buf);
+
+        FrameParsingResult::Ok(FileTransferAckFrame { segment_idx })
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        let mut segment_idx_buf = [0u8; 4];
+        LittleEndian::write_u32(&mut segment_idx_buf, self.segment_idx);
+
+        let mut buf = Vec::<u8>::with_capacity(4);
+        buf.extend(segment_idx_buf);
+
+        buf
+    }
+}
+
+/// Sent by the receiver when its disk writes fall behind the network, asking the sender to pace
+/// its segments.
+#[derive(Debug)]
+pub struct FileTransferSlowDownFrame {
+    pub write_latency_ms: u32,
+}
+
+impl Frame for FileTransferSlowDownFrame {
+    fn frame_type(&self) -> u16 {
+        frame_types::FILE_TRANSFER_SLOW_DOWN
+    }
+
+    fn frame_types() -> Vec<u16> {
+        vec![frame_types::FILE_TRANSFER_SLOW_DOWN]
+    }
+
+    fn try_parse(frame_type: u16, buf: Vec<u8>) -> FrameParsingResult<Self> {
+        if frame_type != frame_types::FILE_TRANSFER_SLOW_DOWN {
+            return FrameParsingResult::Skip(buf);
+        }
+
+        if buf.len() < 4 {
+            return FrameParsingResult::Err(Box::new(io::Error::from(
+                io::ErrorKind::UnexpectedEof,
+            )));
+        }
+        let write_latency_ms = LittleEndian::read_u32(&buf);
+
+        FrameParsingResult::Ok(FileTransferSlowDownFrame { write_latency_ms })
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        let mut write_latency_ms_buf = [0u8; 4];
+        LittleEndian::write_u32(&mut write_latency_ms_buf, self.write_latency_ms);
+
+        write_latency_ms_buf.to_vec()
+    }
+}
+
+/// Sent by the receiver when it could not write the file and gives up on the transfer.
+#[derive(Debug)]
+pub struct FileTransferErrorFrame {
+    /// Whether the error is likely transient (e.g. the disk was full or a network filesystem
+    /// timed out), so sending the file again later may succeed.
+    pub retryable: bool,
+    pub message: String,
+}
+
+impl Frame for FileTransferErrorFrame {
+    fn frame_type(&self) -> u16 {
+        frame_types::FILE_TRANSFER_ERROR
+    }
+
+    fn frame_types() -> Vec<u16> {
+        vec![frame_types::FILE_TRANSFER_ERROR]
+    }
+
+    fn try_parse(frame_type: u16, buf: Vec<u8>) -> FrameParsingResult<Self> {
+        if frame_type != frame_types::FILE_TRANSFER_ERROR {
+            return FrameParsingResult::Skip(buf);
+        }
+        if buf.is_empty() {
+            return FrameParsingResult::Err(Box::new(io::Error::from(
+                io::ErrorKind::UnexpectedEof,
+            )));
+        }
+
+        let retryable = buf[0] != 0;
+        let message = String::from_utf8_lossy(&buf[1..]).into_owned();
+
+        FrameParsingResult::Ok(FileTransferErrorFrame { retryable, message })
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        let mut buf = Vec::<u8>::with_capacity(1 + self.message.len());
+        buf.push(self.retryable as u8);
+        buf.extend(self.message.into_bytes());
+
+        buf
+    }
+}
+
+/// The first protocol version receivers turn files down with a [`TransferRejectedFrame`] in,
+/// rather than a [`FileTransferErrorFrame`].
+pub const REJECTION_PROTOCOL_VERSION: u16 = 13;
+
+/// Why a receiver turned a file down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectionReason {
+    /// The receiver or its user doesn't want the file, or the sender. Reasons unknown to this
+    /// version are read as this one.
+    Declined,
+    /// The file is larger than the receiver takes.
+    FileTooLarge,
+    /// The receiver is busy with as many files as it takes at once.
+    TooManyTransfers,
+    /// The receiver's disk doesn't have enough room left.
+    InsufficientSpace,
+    /// The sender has sent the receiver as much as it takes from it.
+    QuotaExceeded,
+    /// The peer's role doesn't allow what it asked for, e.g. a sender only allowed to push files
+    /// asking to pull one.
+    Unauthorized,
+}
+
+impl RejectionReason {
+    pub fn from_u8(code: u8) -> Self {
+        match code {
+            1 => Self::FileTooLarge,
+            2 => Self::TooManyTransfers,
+            3 => Self::InsufficientSpace,
+            4 => Self::QuotaExceeded,
+            5 => Self::Unauthorized,
+            _ => Self::Declined,
+        }
+    }
+
+    pub fn as_u8(&self) -> u8 {
+        match self {
+            Self::Declined => 0,
+            Self::FileTooLarge => 1,
+            Self::TooManyTransfers => 2,
+            Self::InsufficientSpace => 3,
+            Self::QuotaExceeded => 4,
+            Self::Unauthorized => 5,
+        }
+    }
+
+    /// Whether sending the file again later may work, once the receiver has finished other
+    /// files or freed up space.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, Self::TooManyTransfers | Self::InsufficientSpace)
+    }
+}
+
+/// Sent by the receiver instead of a [`FileTransferErrorFrame`] when it turns a file down, to
+/// peers from [`REJECTION_PROTOCOL_VERSION`] on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransferRejectedFrame {
+    pub reason: RejectionReason,
+    pub message: String,
+}
+
+impl Frame for TransferRejectedFrame {
+    fn frame_type(&self) -> u16 {
+        frame_types::TRANSFER_REJECTED
+    }
+
+    fn frame_types() -> Vec<u16> {
+        vec![frame_types::TRANSFER_REJECTED]
+    }
+
+    fn try_parse(frame_type: u16, buf: Vec<u8>) -> FrameParsingResult<Self> {
+        if frame_type != frame_types::TRANSFER_REJECTED {
+            return FrameParsingResult::Skip(buf);
+        }
+        if buf.is_empty() {
+            return FrameParsingResult::Err(Box::new(io::Error::from(
+                io::ErrorKind::UnexpectedEof,
+            )));
+        }
+
+        FrameParsingResult::Ok(TransferRejectedFrame {
+            reason: RejectionReason::from_u8(buf[0]),
+            message: String::from_utf8_lossy(&buf[1..]).into_owned(),
+        })
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        let mut buf = Vec::<u8>::with_capacity(1 + self.message.len());
+        buf.push(self.reason.as_u8());
+        buf.extend(self.message.into_bytes());
+        buf
+    }
+}
+
+#[derive(Debug)]
+pub struct FileTransferDataFrame {
+    pub segment_idx: u32,
+    pub chunk_size: u32,
+    pub data: Vec<u8>,
+    /// CRC32 of `data`, appended after it on the wire when set. Peers that don't verify segments
+    /// ignore the trailing bytes.
+    pub checksum: Option<u32>,
+    /// How `data` is compressed on the wire. Frames with compression are sent as frame type 16
+    /// and carry `data` as it is if compressing doesn't pay off, see [`compression::compress`].
+    /// Received frames tell how they actually came in, `data` is always decompressed.
+    pub compression: CompressionMode,
+}
+
+impl FileTransferDataFrame {
+    /// Whether `data` matches the checksum, if the frame carries one.
+    pub fn verify_checksum(&self) -> bool {
+        self.checksum
+            .is_none_or(|checksum| crc32fast::hash(&self.data) == checksum)
+    }
+
+    /// Reads a frame of type 16: the segment index and its uncompressed size, the compression
+    /// mode, the length of the data on the wire, the data and the optional checksum.
+    fn parse_compressed(buf: &[u8]) -> io::Result<Self> {
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+        if buf.len() < 13 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+
+        let segment_idx = LittleEndian::read_u32(&buf[0..4]);
+        let chunk_size = LittleEndian::read_u32(&buf[4..8]);
+        let compression =
+            CompressionMode::from_u8(buf[8]).ok_or_else(|| invalid("unknown compression"))?;
+        let wire_len = LittleEndian::read_u32(&buf[9..13]) as usize;
+        if chunk_size as usize > MAX_SEGMENT_SIZE {
+            return Err(invalid("segment too large"));
+        }
+        let wire_data = buf
+            .get(13..13 + wire_len)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        let checksum = buf
+            .get(13 + wire_len..17 + wire_len)
+            .map(LittleEndian::read_u32);
+
+        let data = compression::decompress(compression, wire_data, chunk_size as usize)?;
+        Ok(FileTransferDataFrame {
+            segment_idx,
+            chunk_size,
+            data,
+            checksum,
+            compression,
+        })
+    }
+}
+
+impl Frame for FileTransferDataFrame {
+    fn frame_type(&self) -> u16 {
+        match self.compression {
+            CompressionMode::None => frame_types::FILE_TRANSFER_DATA,
+            _ => frame_types::FILE_TRANSFER_COMPRESSED_DATA,
+        }
+    }
+
+    fn frame_types() -> Vec<u16> {
+        vec![
+            frame_types::FILE_TRANSFER_DATA,
+            frame_types::FILE_TRANSFER_COMPRESSED_DATA,
+        ]
+    }
+
+    fn try_parse(frame_type: u16, mut buf: Vec<u8>) -> FrameParsingResult<Self> {
+        if frame_type == frame_types::FILE_TRANSFER_COMPRESSED_DATA {
+            return match Self::parse_compressed(&buf) {
+                Ok(frame) => FrameParsingResult::Ok(frame),
+                Err(err) => FrameParsingResult::Err(Box::new(err)),
+            };
+        }
+        if frame_type != frame_types::FILE_TRANSFER_DATA {
+            return FrameParsingResult::Skip(buf);
+        }
+
+        if buf.len() < 8 {
+            return FrameParsingResult::Err(Box::new(io::Error::from(
+                io::ErrorKind::UnexpectedEof,
+            )));
+        }
+        let segment_idx = LittleEndian::read_u32(&buf[0..4]);
+        let chunk_size = LittleEndian::read_u32(&buf[4..8]) as usize;
+        if chunk_size > MAX_SEGMENT_SIZE {
+            return FrameParsingResult::Err(Box::new(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "segment too large",
+            )));
+        }
+        if buf.len() < 8 + chunk_size {
+            return FrameParsingResult::Err(Box::new(io::Error::from(
+                io::ErrorKind::UnexpectedEof,
+            )));
+        }
+
+        let checksum = if buf.len() >= 8 + chunk_size + 4 {
+            Some(LittleEndian::read_u32(&buf[8 + chunk_size..]))
+        } else {
+            None
+        };
+
+        let mut data = buf.split_off(8);
+        data.truncate(chunk_size);
+
+        FrameParsingResult::Ok(FileTransferDataFrame {
+            segment_idx,
+            chunk_size: chunk_size as u32,
+            data,
+            checksum,
+            compression: CompressionMode::None,
+        })
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        let mut segment_idx_buf = [0u8; 4];
+        LittleEndian::write_u32(&mut segment_idx_buf, self.segment_idx);
+
+        let mut chunk_size_buf = [0u8; 4];
+        LittleEndian::write_u32(&mut chunk_size_buf, self.chunk_size);
+
+        let mut buf = Vec::<u8>::with_capacity(13 + self.data.len() + 4);
+        buf.extend(segment_idx_buf);
+        buf.extend(chunk_size_buf);
+        if self.compression == CompressionMode::None {
+            buf.extend(self.data);
+        } else {
+            let (compression, wire_data) = match compression::compress(self.compression, &self.data)
+            {
+                Some(compressed) => (self.compression, compressed),
+                None => (CompressionMode::None, self.data),
+            };
+            let mut wire_len_buf = [0u8; 4];
+            LittleEndian::write_u32(&mut wire_len_buf, wire_data.len() as u32);
+            buf.push(compression.to_u8());
+            buf.extend(wire_len_buf);
+            buf.extend(wire_data);
+        }
+        if let Some(checksum) = self.checksum {
+            let mut checksum_buf = [0u8; 4];
+            LittleEndian::write_u32(&mut checksum_buf, checksum);
+            buf.extend(checksum_buf);
+        }
+
+        buf
+    }
+}
+
+/// Sent by the receiver when a segment arrived corrupted. The sender goes back to that segment and
+/// sends everything from there on again, the receiver drops the segments in between.
+#[derive(Debug)]
+pub struct FileTransferRetransmitFrame {
+    pub segment_idx: u32,
+}
+
+impl Frame for FileTransferRetransmitFrame {
+    fn frame_type(&self) -> u16 {
+        frame_types::FILE_TRANSFER_RETRANSMIT
+    }
+
+    fn frame_types() -> Vec<u16> {
+        vec![frame_types::FILE_TRANSFER_RETRANSMIT]
+    }
+
+    fn try_parse(frame_type: u16, buf: Vec<u8>) -> FrameParsingResult<Self> {
+        if frame_type != frame_types::FILE_TRANSFER_RETRANSMIT {
+            return FrameParsingResult::Skip(buf);
+        }
+        if buf.len() < 4 {
+            return FrameParsingResult::Err(Box::new(io::Error::from(
+                io::ErrorKind::UnexpectedEof,
+            )));
+        }
+
+        let segment_idx = LittleEndian::read_u32(&buf);
+
+        FrameParsingResult::Ok(FileTransferRetransmitFrame { segment_idx })
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        let mut segment_idx_buf = [0u8; 4];
+        LittleEndian::write_u32(&mut segment_idx_buf, self.segment_idx);
+
+        segment_idx_buf.to_vec()
+    }
+}
+
+/// Sent by the sender right before the end of the file, with the SHA-256 digest of the whole file
+/// for the receiver to check what it has written against.
+#[derive(Debug)]
+pub struct FileTransferVerifyFrame {
+    pub sha256: [u8; 32],
+}
+
+impl Frame for FileTransferVerifyFrame {
+    fn frame_type(&self) -> u16 {
+        frame_types::FILE_TRANSFER_VERIFY
+    }
+
+    fn frame_types() -> Vec<u16> {
+        vec![frame_types::FILE_TRANSFER_VERIFY]
+    }
+
+    fn try_parse(frame_type: u16, buf: Vec<u8>) -> FrameParsingResult<Self> {
+        if frame_type != frame_types::FILE_TRANSFER_VERIFY {
+            return FrameParsingResult::Skip(buf);
+        }
+        if buf.len() < 32 {
+            return FrameParsingResult::Err(Box::new(io::Error::from(
+                io::ErrorKind::UnexpectedEof,
+            )));
+        }
+
+        let mut sha256 = [0u8; 32];
+        sha256.copy_from_slice(&buf[..32]);
+
+        FrameParsingResult::Ok(FileTransferVerifyFrame { sha256 })
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        self.sha256.to_vec()
+    }
+}
+
+/// Sent by the sender before the segments of each file when several files are sent in one
+/// session. `transfer_id` counts the files of the session up from zero.
+#[derive(Debug)]
+pub struct FileTransferBeginFrame {
+    pub transfer_id: u32,
+    pub file_name: String,
+}
+
+impl Frame for FileTransferBeginFrame {
+    fn frame_type(&self) -> u16 {
+        frame_types::FILE_TRANSFER_BEGIN
+    }
+
+    fn frame_types() -> Vec<u16> {
+        vec![frame_types::FILE_TRANSFER_BEGIN]
+    }
+
+    fn try_parse(frame_type: u16, buf: Vec<u8>) -> FrameParsingResult<Self> {
+        if frame_type != frame_types::FILE_TRANSFER_BEGIN {
+            return FrameParsingResult::Skip(buf);
+        }
+        if buf.len() < 4 {
+            return FrameParsingResult::Err(Box::new(io::Error::from(
+                io::ErrorKind::UnexpectedEof,
+            )));
+        }
+
+        let transfer_id = LittleEndian::read_u32(&buf[0..4]);
+        let file_name = String::from_utf8_lossy(&buf[4..]).into_owned();
+
+        FrameParsingResult::Ok(FileTransferBeginFrame {
+            transfer_id,
+            file_name,
+        })
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        let mut transfer_id_buf = [0u8; 4];
+        LittleEndian::write_u32(&mut transfer_id_buf, self.transfer_id);
+
+        let mut buf = Vec::<u8>::with_capacity(4 + self.file_name.len());
+        buf.extend(transfer_id_buf);
+        buf.extend(self.file_name.into_bytes());
+
+        buf
+    }
+}
+
+/// Sent by the receiver once it has written a whole file started with a
+/// [`FileTransferBeginFrame`], the sender may then begin the next one.
+#[derive(Debug)]
+pub struct FileTransferCompleteFrame {
+    pub transfer_id: u32,
+}
+
+impl Frame for FileTransferCompleteFrame {
+    fn frame_type(&self) -> u16 {
+        frame_types::FILE_TRANSFER_COMPLETE
+    }
+
+    fn frame_types() -> Vec<u16> {
+        vec![frame_types::FILE_TRANSFER_COMPLETE]
+    }
+
+    fn try_parse(frame_type: u16, buf: Vec<u8>) -> FrameParsingResult<Self> {
+        if frame_type != frame_types::FILE_TRANSFER_COMPLETE {
+            return FrameParsingResult::Skip(buf);
+        }
+        if buf.len() < 4 {
+            return FrameParsingResult::Err(Box::new(io::Error::from(
+                io::ErrorKind::UnexpectedEof,
+            )));
+        }
+
+        let transfer_id = LittleEndian::read_u32(&buf);
+
+        FrameParsingResult::Ok(FileTransferCompleteFrame { transfer_id })
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        let mut transfer_id_buf = [0u8; 4];
+        LittleEndian::write_u32(&mut transfer_id_buf, self.transfer_id);
+
+        transfer_id_buf.to_vec()
+    }
+}
+
+/// Sent by the sender right after a [`FileTransferBeginFrame`] with what the receiver needs to
+/// restore the file as it was. `modified` is in seconds since the Unix epoch, zero if unknown.
+#[derive(Debug)]
+pub struct FileTransferMetadataFrame {
+    pub transfer_id: u32,
+    pub file_size: u64,
+    pub modified: u64,
+}
+
+impl Frame for FileTransferMetadataFrame {
+    fn frame_type(&self) -> u16 {
+        frame_types::FILE_TRANSFER_METADATA
+    }
+
+    fn frame_types() -> Vec<u16> {
+        vec![frame_types::FILE_TRANSFER_METADATA]
+    }
+
+    fn try_parse(frame_type: u16, buf: Vec<u8>) -> FrameParsingResult<Self> {
+        if frame_type != frame_types::FILE_TRANSFER_METADATA {
+            return FrameParsingResult::Skip(buf);
+        }
+        if buf.len() < 20 {
+            return FrameParsingResult::Err(Box::new(io::Error::from(
+                io::ErrorKind::UnexpectedEof,
+            )));
+        }
+
+        let transfer_id = LittleEndian::read_u32(&buf[0..4]);
+        let file_size = LittleEndian::read_u64(&buf[4..12]);
+        let modified = LittleEndian::read_u64(&buf[12..20]);
+
+        FrameParsingResult::Ok(FileTransferMetadataFrame {
+            transfer_id,
+            file_size,
+            modified,
+        })
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        let mut buf = [0u8; 20];
+        LittleEndian::write_u32(&mut buf[0..4], self.transfer_id);
+        LittleEndian::write_u64(&mut buf[4..12], self.file_size);
+        LittleEndian::write_u64(&mut buf[12..20], self.modified);
+
+        buf.to_vec()
+    }
+}
+
+/// Sent by the sender right before a [`FileTransferBeginFrame`] when the file it begins is only
+/// one stripe of a larger file, the other stripes being sent over other connections at the same
+/// time. The receiver puts the stripes of the same `group_id` back together.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileTransferStripeFrame {
+    pub transfer_id: u32,
+    pub group_id: u64,
+    pub stripe_idx: u32,
+    pub stripe_count: u32,
+    /// The size of the whole file.
+    pub file_size: u64,
+}
+
+impl Frame for FileTransferStripeFrame {
+    fn frame_type(&self) -> u16 {
+        frame_types::FILE_TRANSFER_STRIPE
+    }
+
+    fn frame_types() -> Vec<u16> {
+        vec![frame_types::FILE_TRANSFER_STRIPE]
+    }
+
+    fn try_parse(frame_type: u16, buf: Vec<u8>) -> FrameParsingResult<Self> {
+        if frame_type != frame_types::FILE_TRANSFER_STRIPE {
+            return FrameParsingResult::Skip(buf);
+        }
+        if buf.len() < 28 {
+            return FrameParsingResult::Err(Box::new(io::Error::from(
+                io::ErrorKind::UnexpectedEof,
+            )));
+        }
+
+        FrameParsingResult::Ok(FileTransferStripeFrame {
+            transfer_id: LittleEndian::read_u32(&buf[0..4]),
+            group_id: LittleEndian::read_u64(&buf[4..12]),
+            stripe_idx: LittleEndian::read_u32(&buf[12..16]),
+            stripe_count: LittleEndian::read_u32(&buf[16..20]),
+            file_size: LittleEndian::read_u64(&buf[20..28]),
+        })
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        let mut buf = [0u8; 28];
+        LittleEndian::write_u32(&mut buf[0..4], self.transfer_id);
+        LittleEndian::write_u64(&mut buf[4..12], self.group_id);
+        LittleEndian::write_u32(&mut buf[12..16], self.stripe_idx);
+        LittleEndian::write_u32(&mut buf[16..20], self.stripe_count);
+        LittleEndian::write_u64(&mut buf[20..28], self.file_size);
+
+        buf.to_vec()
+    }
+}
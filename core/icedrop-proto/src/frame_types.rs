@@ -0,0 +1,59 @@
+//! The type ids frames are sent with, in the header of every frame. Ids are never reused, and
+//! frames a peer doesn't know are only sent once the handshake agreed on a version with them.
+
+/// [`HandshakeRequestFrame`](crate::handshake::HandshakeRequestFrame)
+pub const HANDSHAKE_REQUEST: u16 = 1;
+/// [`HandshakeResponseFrame`](crate::handshake::HandshakeResponseFrame)
+pub const HANDSHAKE_RESPONSE: u16 = 2;
+/// [`FileTransferDataFrame`](crate::file_transfer::FileTransferDataFrame) sent as it is.
+pub const FILE_TRANSFER_DATA: u16 = 3;
+/// [`FileTransferAckFrame`](crate::file_transfer::FileTransferAckFrame)
+pub const FILE_TRANSFER_ACK: u16 = 4;
+/// [`FileTransferSlowDownFrame`](crate::file_transfer::FileTransferSlowDownFrame)
+pub const FILE_TRANSFER_SLOW_DOWN: u16 = 5;
+/// [`FileTransferErrorFrame`](crate::file_transfer::FileTransferErrorFrame)
+pub const FILE_TRANSFER_ERROR: u16 = 6;
+/// [`FileTransferRetransmitFrame`](crate::file_transfer::FileTransferRetransmitFrame)
+pub const FILE_TRANSFER_RETRANSMIT: u16 = 7;
+/// [`FileTransferVerifyFrame`](crate::file_transfer::FileTransferVerifyFrame)
+pub const FILE_TRANSFER_VERIFY: u16 = 8;
+/// [`FileTransferBeginFrame`](crate::file_transfer::FileTransferBeginFrame)
+pub const FILE_TRANSFER_BEGIN: u16 = 9;
+/// [`FileTransferCompleteFrame`](crate::file_transfer::FileTransferCompleteFrame)
+pub const FILE_TRANSFER_COMPLETE: u16 = 10;
+/// [`FileTransferMetadataFrame`](crate::file_transfer::FileTransferMetadataFrame)
+pub const FILE_TRANSFER_METADATA: u16 = 11;
+/// [`BenchmarkFrame`](crate::benchmark::BenchmarkFrame)
+pub const BENCHMARK: u16 = 12;
+/// [`PingFrame`](crate::keepalive::PingFrame)
+pub const PING: u16 = 13;
+/// [`PongFrame`](crate::keepalive::PongFrame)
+pub const PONG: u16 = 14;
+/// [`FileTransferStripeFrame`](crate::file_transfer::FileTransferStripeFrame)
+pub const FILE_TRANSFER_STRIPE: u16 = 15;
+/// [`FileTransferDataFrame`](crate::file_transfer::FileTransferDataFrame) with a compression mode.
+pub const FILE_TRANSFER_COMPRESSED_DATA: u16 = 16;
+/// [`AuthChallengeFrame`](crate::auth::AuthChallengeFrame)
+pub const AUTH_CHALLENGE: u16 = 17;
+/// [`AuthRequestFrame`](crate::auth::AuthRequestFrame)
+pub const AUTH_REQUEST: u16 = 18;
+/// [`PairedFrame`](crate::auth::PairedFrame)
+pub const PAIRED: u16 = 19;
+/// [`RelayConnectFrame`](crate::relay::RelayConnectFrame)
+pub const RELAY_CONNECT: u16 = 20;
+/// [`TransferRejectedFrame`](crate::file_transfer::TransferRejectedFrame)
+pub const TRANSFER_REJECTED: u16 = 21;
+/// [`PullRequestFrame`](crate::pull::PullRequestFrame)
+pub const PULL_REQUEST: u16 = 22;
+/// [`BrowseRequestFrame`](crate::browse::BrowseRequestFrame)
+pub const BROWSE_REQUEST: u16 = 23;
+/// [`DirectoryListingFrame`](crate::browse::DirectoryListingFrame)
+pub const DIRECTORY_LISTING: u16 = 24;
+/// [`TextMessageFrame`](crate::text::TextMessageFrame)
+pub const TEXT_MESSAGE: u16 = 25;
+/// [`EndSessionFrame`](crate::session::EndSessionFrame)
+pub const END_SESSION: u16 = 99;
+
+/// The first of the ids left to applications, for frames of their own sent over the same
+/// connection as transfers, e.g. chat messages or remote commands. Icedrop never assigns them.
+pub const APPLICATION_MIN: u16 = 0x8000;
@@ -0,0 +1,391 @@
+use crate::auth::PAIRING_PROTOCOL_VERSION;
+use crate::compression::CompressionMode;
+use crate::frame_types;
+use crate::transfer::{
+    TransferConfig, COMPRESSION_PROTOCOL_VERSION, PARALLEL_STREAMS_PROTOCOL_VERSION,
+};
+use crate::{Frame, FrameParsingResult};
+
+use std::error::Error;
+use std::fmt::Display;
+use std::io;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+/// First protocol version whose handshake carries the peers' [`Capabilities`].
+pub const CAPABILITIES_PROTOCOL_VERSION: u16 = 12;
+
+/// The longest display name peers accept, in characters.
+pub const MAX_DISPLAY_NAME_LEN: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisplayNameError {
+    Empty,
+    TooLong,
+    ControlCharacter,
+}
+
+impl Display for DisplayNameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Empty => f.write_str("display name is empty"),
+            Self::TooLong => write!(
+                f,
+                "display name is longer than {} characters",
+                MAX_DISPLAY_NAME_LEN
+            ),
+            Self::ControlCharacter => f.write_str("display name contains control characters"),
+        }
+    }
+}
+
+impl Error for DisplayNameError {}
+
+/// Checks a display name, returning it without surrounding whitespace. Any printable character,
+/// emoji included, is allowed.
+pub fn validate_display_name(name: &str) -> Result<String, DisplayNameError> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(DisplayNameError::Empty);
+    }
+    if name.chars().count() > MAX_DISPLAY_NAME_LEN {
+        return Err(DisplayNameError::TooLong);
+    }
+    if name.chars().any(char::is_control) {
+        return Err(DisplayNameError::ControlCharacter);
+    }
+    Ok(name.to_owned())
+}
+
+/// Optional features a peer supports, exchanged in the handshake so each of them can be left out
+/// on its own, e.g. by an implementation that doesn't compress, instead of only by protocol
+/// version. Peers older than [`CAPABILITIES_PROTOCOL_VERSION`] are taken to support what their
+/// version brought, see [`Capabilities::implied_by`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    /// Segments may be compressed, see [`CompressionMode`].
+    pub const COMPRESSION: Capabilities = Capabilities(0x01);
+    /// Large files may be sent in stripes over several connections.
+    pub const PARALLEL_STREAMS: Capabilities = Capabilities(0x02);
+    /// Senders can pair with receivers requiring it, see [`crate::auth`].
+    pub const PAIRING: Capabilities = Capabilities(0x04);
+
+    /// All bits with an assigned meaning, the rest are reserved and must be sent as zero.
+    const KNOWN: u32 = 0x07;
+
+    pub fn empty() -> Self {
+        Self(0)
+    }
+
+    /// Everything this implementation supports.
+    pub fn all() -> Self {
+        Self(Self::KNOWN)
+    }
+
+    /// What a peer speaking `protocol_version` supports if it doesn't tell.
+    pub fn implied_by(protocol_version: u16) -> Self {
+        let mut capabilities = Self::empty();
+        if protocol_version >= PARALLEL_STREAMS_PROTOCOL_VERSION {
+            capabilities.insert(Self::PARALLEL_STREAMS);
+        }
+        if protocol_version >= COMPRESSION_PROTOCOL_VERSION {
+            capabilities.insert(Self::COMPRESSION);
+        }
+        if protocol_version >= PAIRING_PROTOCOL_VERSION {
+            capabilities.insert(Self::PAIRING);
+        }
+        capabilities
+    }
+
+    pub fn from_bits(bits: u32) -> Self {
+        Self(bits & Self::KNOWN)
+    }
+
+    pub fn bits(&self) -> u32 {
+        self.0
+    }
+
+    pub fn contains(&self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn insert(&mut self, other: Capabilities) {
+        self.0 |= other.0;
+    }
+
+    /// What both sides support.
+    pub fn intersection(&self, other: Capabilities) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+/// Reads the protocol version trailing a handshake payload. Version 1 peers don't send one.
+fn read_protocol_version(buf: &[u8]) -> u16 {
+    if buf.len() >= 2 {
+        LittleEndian::read_u16(buf)
+    } else {
+        1
+    }
+}
+
+/// Reads the transfer config following the protocol version, if there is one. Peers older than
+/// protocol version 8 don't send it, and only version 9 adds the number of parallel streams.
+fn read_transfer_config(buf: &[u8]) -> Option<TransferConfig> {
+    if buf.len() < 10 {
+        return None;
+    }
+    let parallel_streams = if buf.len() >= 14 {
+        LittleEndian::read_u32(&buf[10..])
+    } else {
+        1
+    };
+    Some(TransferConfig {
+        segment_size: LittleEndian::read_u32(&buf[2..]) as usize,
+        window_size: LittleEndian::read_u32(&buf[6..]),
+        parallel_streams,
+    })
+}
+
+/// Reads the compression following the transfer config, only sent from protocol version 10 on.
+/// Modes this implementation doesn't know are taken as no compression.
+fn read_compression(buf: &[u8]) -> CompressionMode {
+    buf.get(14)
+        .and_then(|&value| CompressionMode::from_u8(value))
+        .unwrap_or_default()
+}
+
+/// Reads the capabilities following the compression, only sent from protocol version 12 on.
+/// Older peers support what their version implies.
+fn read_capabilities(buf: &[u8], protocol_version: u16) -> Capabilities {
+    match buf.get(15..19) {
+        Some(bits) => Capabilities::from_bits(LittleEndian::read_u32(bits)),
+        None => Capabilities::implied_by(protocol_version),
+    }
+}
+
+fn write_transfer_config(buf: &mut Vec<u8>, config: Option<TransferConfig>, protocol_version: u16) {
+    if let Some(config) = config {
+        let mut config_buf = [0u8; 12];
+        LittleEndian::write_u32(&mut config_buf[..4], config.segment_size as u32);
+        LittleEndian::write_u32(&mut config_buf[4..8], config.window_size);
+        LittleEndian::write_u32(&mut config_buf[8..], config.parallel_streams);
+        if protocol_version >= PARALLEL_STREAMS_PROTOCOL_VERSION {
+            buf.extend(config_buf);
+        } else {
+            buf.extend(&config_buf[..8]);
+        }
+    }
+}
+
+fn write_compression(
+    buf: &mut Vec<u8>,
+    compression: CompressionMode,
+    config: Option<TransferConfig>,
+    protocol_version: u16,
+) {
+    if config.is_some() && protocol_version >= COMPRESSION_PROTOCOL_VERSION {
+        buf.push(compression.to_u8());
+    }
+}
+
+fn write_capabilities(
+    buf: &mut Vec<u8>,
+    capabilities: Capabilities,
+    config: Option<TransferConfig>,
+    protocol_version: u16,
+) {
+    if config.is_some() && protocol_version >= CAPABILITIES_PROTOCOL_VERSION {
+        buf.extend(capabilities.bits().to_le_bytes());
+    }
+}
+
+#[derive(Debug)]
+pub struct HandshakeRequestFrame {
+    /// The display name of the sender, see [`validate_display_name`].
+    pub name: String,
+    pub protocol_version: u16,
+    /// The segment size, ack window and number of streams the sender would like to use.
+    pub transfer_config: Option<TransferConfig>,
+    /// How the sender would like to compress segments. Only sent along with a transfer config.
+    pub compression: CompressionMode,
+    /// What the sender supports. Only sent along with a transfer config.
+    pub capabilities: Capabilities,
+}
+
+impl Frame for HandshakeRequestFrame {
+    fn frame_type(&self) -> u16 {
+        frame_types::HANDSHAKE_REQUEST
+    }
+
+    fn frame_types() -> Vec<u16> {
+        vec![frame_types::HANDSHAKE_REQUEST]
+    }
+
+    fn try_parse(frame_type: u16, buf: Vec<u8>) -> FrameParsingResult<Self> {
+        if frame_type != frame_types::HANDSHAKE_REQUEST {
+            return FrameParsingResult::Skip(buf);
+        }
+
+        if buf.len() < 4 {
+            return FrameParsingResult::Err(Box::new(io::Error::from(
+                io::ErrorKind::UnexpectedEof,
+            )));
+        }
+        let size = LittleEndian::read_u32(&buf) as usize;
+        if buf.len() - 4 < size {
+            return FrameParsingResult::Err(Box::new(io::Error::from(
+                io::ErrorKind::UnexpectedEof,
+            )));
+        }
+        let name = String::from_utf8_lossy(&buf[4..(4 + size)]);
+        let protocol_version = read_protocol_version(&buf[(4 + size)..]);
+        let transfer_config = read_transfer_config(&buf[(4 + size)..]);
+        let compression = read_compression(&buf[(4 + size)..]);
+        let capabilities = read_capabilities(&buf[(4 + size)..], protocol_version);
+        FrameParsingResult::Ok(Self {
+            name: name.into_owned(),
+            protocol_version,
+            transfer_config,
+            compression,
+            capabilities,
+        })
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        let mut size_buf = [0u8; 4];
+        LittleEndian::write_u32(&mut size_buf, self.name.len() as u32);
+
+        let mut protocol_version_buf = [0u8; 2];
+        LittleEndian::write_u16(&mut protocol_version_buf, self.protocol_version);
+
+        let mut buf = Vec::<u8>::with_capacity(23 + self.name.len());
+        buf.extend(size_buf);
+        buf.extend(self.name.as_bytes());
+        buf.extend(protocol_version_buf);
+        write_transfer_config(&mut buf, self.transfer_config, self.protocol_version);
+        write_compression(
+            &mut buf,
+            self.compression,
+            self.transfer_config,
+            self.protocol_version,
+        );
+        write_capabilities(
+            &mut buf,
+            self.capabilities,
+            self.transfer_config,
+            self.protocol_version,
+        );
+
+        buf
+    }
+}
+
+#[derive(Debug)]
+pub struct HandshakeResponseFrame {
+    pub protocol_version: u16,
+    /// The segment size, ack window and number of streams the receiver agreed to.
+    pub transfer_config: Option<TransferConfig>,
+    /// The compression the receiver agreed to. Only sent along with a transfer config.
+    pub compression: CompressionMode,
+    /// What both peers support, and may be used in the session. Only sent along with a transfer
+    /// config.
+    pub capabilities: Capabilities,
+}
+
+impl Frame for HandshakeResponseFrame {
+    fn frame_type(&self) -> u16 {
+        frame_types::HANDSHAKE_RESPONSE
+    }
+
+    fn frame_types() -> Vec<u16> {
+        vec![frame_types::HANDSHAKE_RESPONSE]
+    }
+
+    fn try_parse(frame_type: u16, buf: Vec<u8>) -> FrameParsingResult<Self> {
+        if frame_type != frame_types::HANDSHAKE_RESPONSE {
+            return FrameParsingResult::Skip(buf);
+        }
+
+        let protocol_version = read_protocol_version(&buf);
+        FrameParsingResult::Ok(HandshakeResponseFrame {
+            protocol_version,
+            transfer_config: read_transfer_config(&buf),
+            compression: read_compression(&buf),
+            capabilities: read_capabilities(&buf, protocol_version),
+        })
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        let mut protocol_version_buf = [0u8; 2];
+        LittleEndian::write_u16(&mut protocol_version_buf, self.protocol_version);
+
+        let mut buf = protocol_version_buf.to_vec();
+        write_transfer_config(&mut buf, self.transfer_config, self.protocol_version);
+        write_compression(
+            &mut buf,
+            self.compression,
+            self.transfer_config,
+            self.protocol_version,
+        );
+        write_capabilities(
+            &mut buf,
+            self.capabilities,
+            self.transfer_config,
+            self.protocol_version,
+        );
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        validate_display_name, Capabilities, DisplayNameError, HandshakeRequestFrame,
+        MAX_DISPLAY_NAME_LEN,
+    };
+    use crate::{frame_types, Frame, FrameParsingResult};
+
+    #[test]
+    fn display_names_are_validated() {
+        assert_eq!(validate_display_name("  Anna's 📱 ").unwrap(), "Anna's 📱");
+        assert_eq!(validate_display_name(" \t"), Err(DisplayNameError::Empty));
+        assert_eq!(
+            validate_display_name("work\nlaptop"),
+            Err(DisplayNameError::ControlCharacter)
+        );
+
+        let longest = "🦀".repeat(MAX_DISPLAY_NAME_LEN);
+        assert!(validate_display_name(&longest).is_ok());
+        assert_eq!(
+            validate_display_name(&format!("{}a", longest)),
+            Err(DisplayNameError::TooLong)
+        );
+    }
+
+    #[test]
+    fn truncated_handshakes_are_rejected() {
+        let name_len_too_large = [0xff, 0xff, 0xff, 0xff, b'a', b'b'].to_vec();
+        let name_cut_short = [5, 0, 0, 0, b'a', b'b'].to_vec();
+        for buf in [Vec::new(), vec![5, 0], name_cut_short, name_len_too_large] {
+            let result = HandshakeRequestFrame::try_parse(frame_types::HANDSHAKE_REQUEST, buf);
+            assert!(matches!(result, FrameParsingResult::Err(_)));
+        }
+    }
+
+    #[test]
+    fn older_peers_support_what_their_version_brought() {
+        assert_eq!(Capabilities::implied_by(8), Capabilities::empty());
+        let capabilities = Capabilities::implied_by(10);
+        assert!(capabilities.contains(Capabilities::COMPRESSION));
+        assert!(capabilities.contains(Capabilities::PARALLEL_STREAMS));
+        assert!(!capabilities.contains(Capabilities::PAIRING));
+        assert_eq!(Capabilities::implied_by(11), Capabilities::all());
+
+        // Bits without a meaning yet are dropped.
+        let capabilities = Capabilities::from_bits(0xff).intersection(Capabilities::PAIRING);
+        assert_eq!(capabilities, Capabilities::PAIRING);
+        assert_eq!(Capabilities::from_bits(0xff), Capabilities::all());
+    }
+}
@@ -0,0 +1,58 @@
+//! Frames keeping an idle session alive, e.g. through NAT devices dropping quiet connections.
+
+use crate::frame_types;
+use crate::{Frame, FrameParsingResult};
+
+/// The first protocol version supporting keepalive pings.
+pub const KEEPALIVE_PROTOCOL_VERSION: u16 = 7;
+
+/// Sent periodically by both peers, the other one answers with a [`PongFrame`].
+#[derive(Debug)]
+pub struct PingFrame;
+
+impl Frame for PingFrame {
+    fn frame_type(&self) -> u16 {
+        frame_types::PING
+    }
+
+    fn frame_types() -> Vec<u16> {
+        vec![frame_types::PING]
+    }
+
+    fn try_parse(frame_type: u16, buf: Vec<u8>) -> FrameParsingResult<Self> {
+        if frame_type != frame_types::PING {
+            return FrameParsingResult::Skip(buf);
+        }
+
+        FrameParsingResult::Ok(PingFrame)
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        Vec::new()
+    }
+}
+
+#[derive(Debug)]
+pub struct PongFrame;
+
+impl Frame for PongFrame {
+    fn frame_type(&self) -> u16 {
+        frame_types::PONG
+    }
+
+    fn frame_types() -> Vec<u16> {
+        vec![frame_types::PONG]
+    }
+
+    fn try_parse(frame_type: u16, buf: Vec<u8>) -> FrameParsingResult<Self> {
+        if frame_type != frame_types::PONG {
+            return FrameParsingResult::Skip(buf);
+        }
+
+        FrameParsingResult::Ok(PongFrame)
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        Vec::new()
+    }
+}
@@ -0,0 +1,118 @@
+//! The icedrop wire protocol: frame definitions and their encoding, without any IO.
+//!
+//! Everything here works on byte buffers only, so the same frame logic can be shared by the async
+//! endpoint in `icedrop-core`, other runtimes and external tools such as wire analyzers.
+
+pub mod auth;
+pub mod benchmark;
+pub mod browse;
+pub mod codec;
+pub mod compression;
+pub mod file_transfer;
+pub mod frame_types;
+pub mod handshake;
+pub mod keepalive;
+pub mod pull;
+pub mod relay;
+mod selector;
+pub mod session;
+pub mod text;
+pub mod transfer;
+
+#[cfg(test)]
+mod test_vectors;
+
+use std::error::Error;
+use std::fmt::Debug;
+
+/// The newest protocol version this implementation speaks.
+///
+/// Version 1 is the original protocol with a 6-byte frame header. Version 2 introduces the
+/// extended header carrying [`FrameFlags`] and a stream id. Version 3 keeps that header and adds
+/// segment checksums, retransmission requests and whole-file verification, see
+/// [`transfer::VerificationMode`]. Version 4 allows sending several files in one session, each
+/// announced by a [`file_transfer::FileTransferBeginFrame`]. Version 5 follows that frame with the
+/// file's size and modification time in a [`file_transfer::FileTransferMetadataFrame`]. Version 6
+/// adds network benchmarks, see [`benchmark::BenchmarkFrame`]. Version 7 adds keepalive pings, see
+/// [`keepalive::PingFrame`]. Version 8 negotiates the segment size and ack window during the
+/// handshake, see [`transfer::TransferConfig`]. Version 9 adds the number of parallel streams to
+/// it and sends large files in stripes over several connections, see
+/// [`file_transfer::FileTransferStripeFrame`]. Version 10 negotiates compression of file segments,
+/// see [`compression::CompressionMode`]. Version 11 lets receivers require senders to pair with
+/// them first, see [`auth::AuthChallengeFrame`]. Version 12 has peers tell which optional features
+/// they support in the handshake, see [`handshake::Capabilities`]. Version 13 has receivers tell
+/// why they turn a file down, see [`file_transfer::TransferRejectedFrame`]. Version 14 lets senders
+/// send short text instead of files, see [`text::TextMessageFrame`].
+pub const PROTOCOL_VERSION: u16 = 14;
+
+/// Picks the protocol version both sides can speak.
+pub fn negotiate_protocol_version(peer_version: u16) -> u16 {
+    peer_version.clamp(1, PROTOCOL_VERSION)
+}
+
+/// Per-frame flag bits carried in the extended (version 2) frame header.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FrameFlags(u8);
+
+impl FrameFlags {
+    /// The payload is encrypted.
+    pub const ENCRYPTED: FrameFlags = FrameFlags(0x02);
+    /// The payload is prefixed with header extensions.
+    pub const EXTENSION: FrameFlags = FrameFlags(0x04);
+
+    /// All bits with an assigned meaning, the rest are reserved and must be sent as zero. Bit
+    /// 0x01 is one of them: compressed file data is sent as a frame type of its own instead, see
+    /// [`frame_types::FILE_TRANSFER_COMPRESSED_DATA`].
+    const KNOWN: u8 = 0x06;
+
+    pub fn empty() -> Self {
+        Self(0)
+    }
+
+    pub fn from_bits(bits: u8) -> Self {
+        Self(bits & Self::KNOWN)
+    }
+
+    pub fn bits(&self) -> u8 {
+        self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn contains(&self, other: FrameFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn insert(&mut self, other: FrameFlags) {
+        self.0 |= other.0;
+    }
+}
+
+pub enum FrameParsingResult<F> {
+    Ok(F),
+    Skip(Vec<u8>),
+    Err(Box<dyn Error + Send>),
+}
+
+impl<F> FrameParsingResult<F> {
+    #[doc(hidden)]
+    pub fn unwrap_buf(self) -> Vec<u8> {
+        match self {
+            Self::Skip(val) => val,
+            _ => panic!("called `FrameParsingResult::unwrap_buf()` on a non-skip value"),
+        }
+    }
+}
+
+pub trait Frame: Debug + Send + Sized {
+    fn frame_type(&self) -> u16;
+
+    /// All frame types [`Frame::try_parse`] may accept, used to route incoming frames to handlers.
+    fn frame_types() -> Vec<u16>;
+
+    fn try_parse(frame_type: u16, buf: Vec<u8>) -> FrameParsingResult<Self>;
+
+    fn to_bytes(self) -> Vec<u8>;
+}
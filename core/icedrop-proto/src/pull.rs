@@ -0,0 +1,48 @@
+//! Asking a peer for a file instead of waiting to be sent one.
+//!
+//! A receiver sharing folders also serves their files to peers that ask for them: the peer
+//! connects and starts with a [`PullRequestFrame`] naming the file, see [`crate::browse`] for
+//! finding out which there are. It then introduces itself with a handshake request like a sender,
+//! and pairs if the peer asked requires it, which lets it in with the handshake response or turns
+//! it down. The roles are then reversed, the peer that was asked goes on as the sender with
+//! another handshake and the usual file transfer frames, and the one asking receives. The frame
+//! is sent before the handshake, so it always uses the version 1 header.
+
+use crate::frame_types;
+use crate::{Frame, FrameParsingResult};
+
+use std::io;
+
+/// The first frame sent by a peer pulling a file, naming the file it wants by its path below the
+/// shared roots, with `/` between the names, like `photos/beach.jpg`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PullRequestFrame {
+    pub path: String,
+}
+
+impl Frame for PullRequestFrame {
+    fn frame_type(&self) -> u16 {
+        frame_types::PULL_REQUEST
+    }
+
+    fn frame_types() -> Vec<u16> {
+        vec![frame_types::PULL_REQUEST]
+    }
+
+    fn try_parse(frame_type: u16, buf: Vec<u8>) -> FrameParsingResult<Self> {
+        if frame_type != frame_types::PULL_REQUEST {
+            return FrameParsingResult::Skip(buf);
+        }
+
+        match String::from_utf8(buf) {
+            Ok(path) => FrameParsingResult::Ok(PullRequestFrame { path }),
+            Err(err) => {
+                FrameParsingResult::Err(Box::new(io::Error::new(io::ErrorKind::InvalidData, err)))
+            }
+        }
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        self.path.into_bytes()
+    }
+}
@@ -0,0 +1,88 @@
+//! Frames setting up a session through a relay, for peers that can't connect to each other
+//! directly, e.g. because both are behind NAT.
+//!
+//! The sender and the receiver both connect out to the relay and start with a
+//! [`RelayConnectFrame`] carrying the same token, agreed on beforehand. Once both have arrived,
+//! the relay passes everything between them through unchanged, so they go on with the handshake
+//! as if they had connected to each other. The frame is sent before the handshake, so it always
+//! uses the version 1 header.
+
+use crate::frame_types;
+use crate::{Frame, FrameParsingResult};
+
+use std::convert::TryInto;
+use std::io;
+
+/// Pairs up the two connections of a session at the relay, made up at random for every session.
+pub type RelayToken = [u8; 16];
+
+/// Which end of the session a connection to the relay is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RelayRole {
+    Sender,
+    Receiver,
+}
+
+impl RelayRole {
+    /// The role of the connection this one is piped to.
+    pub fn peer(&self) -> Self {
+        match self {
+            Self::Sender => Self::Receiver,
+            Self::Receiver => Self::Sender,
+        }
+    }
+}
+
+/// The first frame sent to a relay, asking it to pipe the connection to the other end of the
+/// session `token` belongs to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelayConnectFrame {
+    pub role: RelayRole,
+    pub token: RelayToken,
+}
+
+impl Frame for RelayConnectFrame {
+    fn frame_type(&self) -> u16 {
+        frame_types::RELAY_CONNECT
+    }
+
+    fn frame_types() -> Vec<u16> {
+        vec![frame_types::RELAY_CONNECT]
+    }
+
+    fn try_parse(frame_type: u16, buf: Vec<u8>) -> FrameParsingResult<Self> {
+        if frame_type != frame_types::RELAY_CONNECT {
+            return FrameParsingResult::Skip(buf);
+        }
+        if buf.len() < 17 {
+            return FrameParsingResult::Err(Box::new(io::Error::from(
+                io::ErrorKind::UnexpectedEof,
+            )));
+        }
+
+        let role = match buf[0] {
+            0 => RelayRole::Sender,
+            1 => RelayRole::Receiver,
+            _ => {
+                return FrameParsingResult::Err(Box::new(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "unknown relay role",
+                )));
+            }
+        };
+        FrameParsingResult::Ok(RelayConnectFrame {
+            role,
+            token: buf[1..17].try_into().unwrap(),
+        })
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(17);
+        buf.push(match self.role {
+            RelayRole::Sender => 0,
+            RelayRole::Receiver => 1,
+        });
+        buf.extend(self.token);
+        buf
+    }
+}
@@ -0,0 +1,56 @@
+/// Defines an enum frame accepting any of the given frame types, trying them in order.
+///
+/// The member frame types must be in scope where the macro is invoked.
+#[macro_export]
+macro_rules! def_frame_selector {
+    ($name:ident, $($frame_ty:ident),+) => {
+        // Variants are named after the frame types they wrap, which all end in `Frame`.
+        #[allow(clippy::enum_variant_names)]
+        #[derive(Debug)]
+        pub enum $name {
+            $(
+                $frame_ty($frame_ty),
+            )+
+        }
+
+        impl $crate::Frame for $name {
+            fn frame_type(&self) -> u16 {
+                match self {
+                    $(
+                        $name::$frame_ty(frame) => $crate::Frame::frame_type(frame),
+                    )+
+                }
+            }
+
+            fn frame_types() -> Vec<u16> {
+                let mut frame_types = Vec::new();
+                $(
+                    frame_types.extend(<$frame_ty as $crate::Frame>::frame_types());
+                )+
+                frame_types
+            }
+
+            fn try_parse(frame_type: u16, buf: Vec<u8>) -> $crate::FrameParsingResult<Self> {
+                $(
+                    let result = <$frame_ty as $crate::Frame>::try_parse(frame_type, buf);
+                    if let $crate::FrameParsingResult::Ok(frame) = result {
+                        return $crate::FrameParsingResult::Ok($name::$frame_ty(frame));
+                    } else if let $crate::FrameParsingResult::Err(err) = result {
+                        return $crate::FrameParsingResult::Err(err);
+                    }
+                    let buf = result.unwrap_buf();
+                )+
+
+                return $crate::FrameParsingResult::Skip(buf);
+            }
+
+            fn to_bytes(self) -> Vec<u8> {
+                match self {
+                    $(
+                        $name::$frame_ty(frame) => $crate::Frame::to_bytes(frame),
+                    )+
+                }
+            }
+        }
+    };
+}
@@ -0,0 +1,27 @@
+use crate::frame_types;
+use crate::{Frame, FrameParsingResult};
+
+#[derive(Debug)]
+pub struct EndSessionFrame;
+
+impl Frame for EndSessionFrame {
+    fn frame_type(&self) -> u16 {
+        frame_types::END_SESSION
+    }
+
+    fn frame_types() -> Vec<u16> {
+        vec![frame_types::END_SESSION]
+    }
+
+    fn try_parse(frame_type: u16, buf: Vec<u8>) -> FrameParsingResult<Self> {
+        if frame_type != frame_types::END_SESSION {
+            return FrameParsingResult::Skip(buf);
+        }
+
+        FrameParsingResult::Ok(EndSessionFrame)
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        Vec::new()
+    }
+}
@@ -0,0 +1,548 @@
+//! Canonical wire encodings for every frame type.
+//!
+//! Each vector is a complete frame as it appears on the wire (header followed by the payload) and
+//! is stored under `test-vectors/` in the crate root, grouped by the protocol version whose header
+//! layout it uses, so that other implementations of the protocol can check their encoders and
+//! decoders against the same bytes.
+
+use std::io;
+
+use crate::auth::{AuthChallengeFrame, AuthCredential, AuthRequestFrame, PairedFrame};
+use crate::benchmark::BenchmarkFrame;
+use crate::browse::{BrowseRequestFrame, DirectoryEntry, DirectoryListingFrame};
+use crate::codec::{FrameHeader, FrameWithHeader};
+use crate::compression::CompressionMode;
+use crate::file_transfer::{
+    FileTransferAckFrame, FileTransferBeginFrame, FileTransferCompleteFrame, FileTransferDataFrame,
+    FileTransferErrorFrame, FileTransferMetadataFrame, FileTransferRetransmitFrame,
+    FileTransferSlowDownFrame, FileTransferStripeFrame, FileTransferVerifyFrame, RejectionReason,
+    TransferRejectedFrame,
+};
+use crate::frame_types;
+use crate::handshake::{Capabilities, HandshakeRequestFrame, HandshakeResponseFrame};
+use crate::keepalive::{PingFrame, PongFrame};
+use crate::pull::PullRequestFrame;
+use crate::relay::{RelayConnectFrame, RelayRole};
+use crate::session::EndSessionFrame;
+use crate::text::TextMessageFrame;
+use crate::transfer::TransferConfig;
+use crate::{Frame, FrameParsingResult};
+
+macro_rules! vector {
+    ($name:literal) => {
+        include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/test-vectors/", $name))
+    };
+}
+
+fn encode<F>(frame: F, protocol_version: u16) -> Vec<u8>
+where
+    F: Frame,
+{
+    FrameWithHeader { frame }.to_bytes(protocol_version)
+}
+
+fn decode<F>(buf: &[u8], protocol_version: u16) -> F
+where
+    F: Frame,
+{
+    let header_size = FrameHeader::size(protocol_version);
+    let header = FrameHeader::parse(protocol_version, &buf[..header_size]);
+    assert!(header.flags.is_empty(), "unexpected frame flags");
+    assert_eq!(
+        header.frame_len as usize,
+        buf.len() - header_size,
+        "frame length mismatch"
+    );
+
+    match F::try_parse(header.frame_type, buf[header_size..].to_vec()) {
+        FrameParsingResult::Ok(frame) => frame,
+        FrameParsingResult::Skip(_) => panic!("frame type {} was skipped", header.frame_type),
+        FrameParsingResult::Err(err) => {
+            panic!("frame type {} failed: {}", header.frame_type, err)
+        }
+    }
+}
+
+/// Checks that `frame` encodes to exactly `vector`, and that decoding `vector` and encoding it
+/// again gives back the same bytes.
+fn assert_round_trip<F>(frame: F, vector: &[u8], protocol_version: u16)
+where
+    F: Frame,
+{
+    assert_eq!(encode(frame, protocol_version), vector, "encoding mismatch");
+    assert_eq!(
+        encode(decode::<F>(vector, protocol_version), protocol_version),
+        vector,
+        "round trip mismatch"
+    );
+}
+
+#[test]
+fn handshake_request() {
+    let frame = HandshakeRequestFrame {
+        name: "icedrop".to_owned(),
+        protocol_version: 2,
+        transfer_config: None,
+        compression: CompressionMode::None,
+        capabilities: Capabilities::implied_by(2),
+    };
+    assert_round_trip(frame, vector!("v1/handshake_request.bin"), 1);
+
+    let frame: HandshakeRequestFrame = decode(vector!("v1/handshake_request.bin"), 1);
+    assert_eq!(frame.name, "icedrop");
+    assert_eq!(frame.protocol_version, 2);
+}
+
+#[test]
+fn handshake_request_legacy() {
+    let frame: HandshakeRequestFrame = decode(vector!("v1/handshake_request_legacy.bin"), 1);
+    assert_eq!(frame.name, "icedrop");
+    assert_eq!(frame.protocol_version, 1);
+}
+
+#[test]
+fn handshake_response() {
+    let frame = HandshakeResponseFrame {
+        protocol_version: 2,
+        transfer_config: None,
+        compression: CompressionMode::None,
+        capabilities: Capabilities::implied_by(2),
+    };
+    assert_round_trip(frame, vector!("v1/handshake_response.bin"), 1);
+}
+
+#[test]
+fn handshake_transfer_config() {
+    let frame = HandshakeRequestFrame {
+        name: "icedrop".to_owned(),
+        protocol_version: 8,
+        transfer_config: Some(TransferConfig {
+            segment_size: 4096,
+            window_size: 16,
+            parallel_streams: 1,
+        }),
+        compression: CompressionMode::None,
+        capabilities: Capabilities::implied_by(8),
+    };
+    assert_round_trip(frame, vector!("v1/handshake_request_config.bin"), 1);
+
+    let frame = HandshakeResponseFrame {
+        protocol_version: 8,
+        transfer_config: Some(TransferConfig {
+            segment_size: 4096,
+            window_size: 8,
+            parallel_streams: 1,
+        }),
+        compression: CompressionMode::None,
+        capabilities: Capabilities::implied_by(8),
+    };
+    assert_round_trip(frame, vector!("v1/handshake_response_config.bin"), 1);
+
+    // Older peers don't send a config.
+    let frame: HandshakeRequestFrame = decode(vector!("v1/handshake_request.bin"), 1);
+    assert!(frame.transfer_config.is_none());
+}
+
+#[test]
+fn handshake_parallel_streams() {
+    let frame = HandshakeRequestFrame {
+        name: "icedrop".to_owned(),
+        protocol_version: 9,
+        transfer_config: Some(TransferConfig {
+            segment_size: 4096,
+            window_size: 16,
+            parallel_streams: 4,
+        }),
+        compression: CompressionMode::None,
+        capabilities: Capabilities::implied_by(9),
+    };
+    assert_round_trip(frame, vector!("v1/handshake_request_streams.bin"), 1);
+
+    // Version 8 peers only take a single stream.
+    let frame: HandshakeRequestFrame = decode(vector!("v1/handshake_request_config.bin"), 1);
+    assert_eq!(frame.transfer_config.unwrap().parallel_streams, 1);
+}
+
+#[test]
+fn handshake_compression() {
+    let frame = HandshakeRequestFrame {
+        name: "icedrop".to_owned(),
+        protocol_version: 10,
+        transfer_config: Some(TransferConfig {
+            segment_size: 4096,
+            window_size: 16,
+            parallel_streams: 4,
+        }),
+        compression: CompressionMode::Lz4,
+        capabilities: Capabilities::implied_by(10),
+    };
+    assert_round_trip(frame, vector!("v1/handshake_request_compression.bin"), 1);
+
+    // Version 9 peers don't compress.
+    let frame: HandshakeRequestFrame = decode(vector!("v1/handshake_request_streams.bin"), 1);
+    assert_eq!(frame.compression, CompressionMode::None);
+}
+
+#[test]
+fn handshake_capabilities() {
+    let config = TransferConfig {
+        segment_size: 4096,
+        window_size: 16,
+        parallel_streams: 4,
+    };
+    let frame = HandshakeRequestFrame {
+        name: "icedrop".to_owned(),
+        protocol_version: 12,
+        transfer_config: Some(config),
+        compression: CompressionMode::Lz4,
+        capabilities: Capabilities::all(),
+    };
+    assert_round_trip(frame, vector!("v1/handshake_request_capabilities.bin"), 1);
+
+    let frame = HandshakeResponseFrame {
+        protocol_version: 12,
+        transfer_config: Some(config),
+        compression: CompressionMode::None,
+        capabilities: Capabilities::PARALLEL_STREAMS,
+    };
+    assert_round_trip(frame, vector!("v1/handshake_response_capabilities.bin"), 1);
+
+    // Version 10 peers support what came up to it.
+    let frame: HandshakeRequestFrame = decode(vector!("v1/handshake_request_compression.bin"), 1);
+    assert_eq!(frame.capabilities, Capabilities::implied_by(10));
+}
+
+#[test]
+fn handshake_response_legacy() {
+    let frame: HandshakeResponseFrame = decode(vector!("v1/handshake_response_legacy.bin"), 1);
+    assert_eq!(frame.protocol_version, 1);
+}
+
+#[test]
+fn auth() {
+    let new_frame = || AuthChallengeFrame {
+        receiver_id: core::array::from_fn(|i| i as u8),
+        nonce: core::array::from_fn(|i| i as u8 + 16),
+        code_required: true,
+    };
+    assert_round_trip(new_frame(), vector!("v1/auth_challenge.bin"), 1);
+
+    let new_frame = |credential| AuthRequestFrame {
+        device_id: [0xaa; 16],
+        credential,
+    };
+    let proof = AuthCredential::Proof(core::array::from_fn(|i| i as u8));
+    assert_round_trip(
+        new_frame(AuthCredential::None),
+        vector!("v1/auth_request.bin"),
+        1,
+    );
+    assert_round_trip(
+        new_frame(AuthCredential::Code(42917)),
+        vector!("v1/auth_request_code.bin"),
+        1,
+    );
+    assert_round_trip(new_frame(proof), vector!("v1/auth_request_proof.bin"), 1);
+
+    let frame = PairedFrame {
+        key: core::array::from_fn(|i| i as u8),
+    };
+    assert_round_trip(frame, vector!("v1/paired.bin"), 1);
+}
+
+#[test]
+fn file_transfer_data() {
+    let new_frame = || FileTransferDataFrame {
+        segment_idx: 3,
+        chunk_size: 5,
+        data: vec![1, 2, 3, 4, 5],
+        checksum: None,
+        compression: CompressionMode::None,
+    };
+    assert_round_trip(new_frame(), vector!("v1/file_transfer_data.bin"), 1);
+    assert_round_trip(new_frame(), vector!("v2/file_transfer_data.bin"), 2);
+
+    let frame: FileTransferDataFrame = decode(vector!("v2/file_transfer_data.bin"), 2);
+    assert_eq!(frame.segment_idx, 3);
+    assert_eq!(frame.data, [1, 2, 3, 4, 5]);
+}
+
+#[test]
+fn file_transfer_data_compressed() {
+    let new_frame = || FileTransferDataFrame {
+        segment_idx: 3,
+        chunk_size: 100,
+        data: b"icedrop ".repeat(12).into_iter().chain(*b"done").collect(),
+        checksum: None,
+        compression: CompressionMode::Lz4,
+    };
+    assert_round_trip(new_frame(), vector!("v2/file_transfer_data_lz4.bin"), 2);
+
+    let frame: FileTransferDataFrame = decode(vector!("v2/file_transfer_data_lz4.bin"), 2);
+    assert_eq!(frame.data, new_frame().data);
+
+    // Segments that don't compress are sent as they are.
+    let frame: FileTransferDataFrame = decode(vector!("v2/file_transfer_data_stored.bin"), 2);
+    assert_eq!(frame.compression, CompressionMode::None);
+    assert_eq!(frame.data, [1, 2, 3, 4, 5]);
+}
+
+#[test]
+fn file_transfer_data_eof() {
+    let new_frame = || FileTransferDataFrame {
+        segment_idx: 4,
+        chunk_size: 0,
+        data: Vec::new(),
+        checksum: None,
+        compression: CompressionMode::None,
+    };
+    assert_round_trip(new_frame(), vector!("v1/file_transfer_data_eof.bin"), 1);
+    assert_round_trip(new_frame(), vector!("v2/file_transfer_data_eof.bin"), 2);
+}
+
+#[test]
+fn file_transfer_data_checksum() {
+    let new_frame = || FileTransferDataFrame {
+        segment_idx: 3,
+        chunk_size: 5,
+        data: vec![1, 2, 3, 4, 5],
+        checksum: Some(0x470b99f4),
+        compression: CompressionMode::None,
+    };
+    assert_round_trip(
+        new_frame(),
+        vector!("v2/file_transfer_data_checksum.bin"),
+        2,
+    );
+
+    let frame: FileTransferDataFrame = decode(vector!("v2/file_transfer_data_checksum.bin"), 2);
+    assert!(frame.verify_checksum());
+
+    // Peers that don't verify segments still read the data.
+    let frame: FileTransferDataFrame = decode(vector!("v2/file_transfer_data.bin"), 2);
+    assert_eq!(frame.checksum, None);
+}
+
+#[test]
+fn file_transfer_data_malformed() {
+    let error = |frame_type, buf| match FileTransferDataFrame::try_parse(frame_type, buf) {
+        FrameParsingResult::Err(err) => err.to_string(),
+        _ => panic!("malformed data frame was read"),
+    };
+    let eof = io::Error::from(io::ErrorKind::UnexpectedEof).to_string();
+
+    // Too short for the segment index and size.
+    assert_eq!(error(frame_types::FILE_TRANSFER_DATA, vec![3, 0, 0]), eof);
+    assert_eq!(
+        error(frame_types::FILE_TRANSFER_COMPRESSED_DATA, vec![3, 0, 0]),
+        eof
+    );
+
+    // Shorter than the segment size it claims, the missing data isn't made up.
+    let buf = vec![3, 0, 0, 0, 4, 0, 0, 0, 1, 2];
+    assert_eq!(error(frame_types::FILE_TRANSFER_DATA, buf), eof);
+
+    // A segment size beyond the limit isn't allocated.
+    let mut buf = vec![3, 0, 0, 0, 0xff, 0xff, 0xff, 0xff];
+    assert_eq!(
+        error(frame_types::FILE_TRANSFER_DATA, buf.clone()),
+        "segment too large"
+    );
+    buf.extend([1, 0, 0, 0, 0]);
+    assert_eq!(
+        error(frame_types::FILE_TRANSFER_COMPRESSED_DATA, buf),
+        "segment too large"
+    );
+}
+
+#[test]
+fn file_transfer_ack() {
+    let new_frame = || FileTransferAckFrame { segment_idx: 8 };
+    assert_round_trip(new_frame(), vector!("v1/file_transfer_ack.bin"), 1);
+    assert_round_trip(new_frame(), vector!("v2/file_transfer_ack.bin"), 2);
+
+    let frame: FileTransferAckFrame = decode(vector!("v2/file_transfer_ack.bin"), 2);
+    assert_eq!(frame.segment_idx, 8);
+
+    let truncated = FileTransferAckFrame::try_parse(frame_types::FILE_TRANSFER_ACK, vec![8, 0]);
+    assert!(matches!(truncated, FrameParsingResult::Err(_)));
+}
+
+#[test]
+fn file_transfer_slow_down() {
+    let new_frame = || FileTransferSlowDownFrame {
+        write_latency_ms: 250,
+    };
+    assert_round_trip(new_frame(), vector!("v1/file_transfer_slow_down.bin"), 1);
+    assert_round_trip(new_frame(), vector!("v2/file_transfer_slow_down.bin"), 2);
+}
+
+#[test]
+fn file_transfer_error() {
+    let new_frame = || FileTransferErrorFrame {
+        retryable: true,
+        message: "disk full".to_owned(),
+    };
+    assert_round_trip(new_frame(), vector!("v1/file_transfer_error.bin"), 1);
+    assert_round_trip(new_frame(), vector!("v2/file_transfer_error.bin"), 2);
+
+    let frame: FileTransferErrorFrame = decode(vector!("v2/file_transfer_error.bin"), 2);
+    assert!(frame.retryable);
+    assert_eq!(frame.message, "disk full");
+}
+
+#[test]
+fn transfer_rejected() {
+    let frame = TransferRejectedFrame {
+        reason: RejectionReason::QuotaExceeded,
+        message: "quota used up".to_owned(),
+    };
+    assert_round_trip(frame, vector!("v2/transfer_rejected.bin"), 2);
+
+    // Reasons added later are read as a plain decline.
+    let mut vector = vector!("v2/transfer_rejected.bin").to_vec();
+    vector[FrameHeader::size(2)] = 200;
+    let frame: TransferRejectedFrame = decode(&vector, 2);
+    assert_eq!(frame.reason, RejectionReason::Declined);
+    assert_eq!(frame.message, "quota used up");
+}
+
+#[test]
+fn file_transfer_retransmit() {
+    let new_frame = || FileTransferRetransmitFrame { segment_idx: 5 };
+    assert_round_trip(new_frame(), vector!("v2/file_transfer_retransmit.bin"), 2);
+}
+
+#[test]
+fn file_transfer_verify() {
+    let new_frame = || FileTransferVerifyFrame {
+        sha256: core::array::from_fn(|i| i as u8),
+    };
+    assert_round_trip(new_frame(), vector!("v2/file_transfer_verify.bin"), 2);
+}
+
+#[test]
+fn file_transfer_begin() {
+    let new_frame = || FileTransferBeginFrame {
+        transfer_id: 1,
+        file_name: "photo.jpg".to_owned(),
+    };
+    assert_round_trip(new_frame(), vector!("v2/file_transfer_begin.bin"), 2);
+
+    let frame: FileTransferBeginFrame = decode(vector!("v2/file_transfer_begin.bin"), 2);
+    assert_eq!(frame.transfer_id, 1);
+    assert_eq!(frame.file_name, "photo.jpg");
+}
+
+#[test]
+fn file_transfer_complete() {
+    let new_frame = || FileTransferCompleteFrame { transfer_id: 1 };
+    assert_round_trip(new_frame(), vector!("v2/file_transfer_complete.bin"), 2);
+}
+
+#[test]
+fn file_transfer_metadata() {
+    let new_frame = || FileTransferMetadataFrame {
+        transfer_id: 1,
+        file_size: 4096,
+        modified: 1700724864,
+    };
+    assert_round_trip(new_frame(), vector!("v2/file_transfer_metadata.bin"), 2);
+}
+
+#[test]
+fn file_transfer_stripe() {
+    let new_frame = || FileTransferStripeFrame {
+        transfer_id: 1,
+        group_id: 0x0123456789abcdef,
+        stripe_idx: 2,
+        stripe_count: 4,
+        file_size: 10000,
+    };
+    assert_round_trip(new_frame(), vector!("v2/file_transfer_stripe.bin"), 2);
+}
+
+#[test]
+fn benchmark() {
+    let new_frame = || BenchmarkFrame {
+        seq: 7,
+        data: vec![0; 4],
+    };
+    assert_round_trip(new_frame(), vector!("v2/benchmark.bin"), 2);
+
+    let echo = BenchmarkFrame {
+        seq: 7,
+        data: Vec::new(),
+    };
+    assert_round_trip(echo, vector!("v2/benchmark_echo.bin"), 2);
+}
+
+#[test]
+fn keepalive() {
+    assert_round_trip(PingFrame, vector!("v2/ping.bin"), 2);
+    assert_round_trip(PongFrame, vector!("v2/pong.bin"), 2);
+}
+
+#[test]
+fn end_session() {
+    assert_round_trip(EndSessionFrame, vector!("v1/end_session.bin"), 1);
+    assert_round_trip(EndSessionFrame, vector!("v2/end_session.bin"), 2);
+}
+
+#[test]
+fn text_message() {
+    let frame = TextMessageFrame {
+        text: "hello from icedrop".to_owned(),
+    };
+    assert_round_trip(frame, vector!("v2/text_message.bin"), 2);
+}
+
+#[test]
+fn relay_connect() {
+    let frame = RelayConnectFrame {
+        role: RelayRole::Receiver,
+        token: core::array::from_fn(|i| i as u8),
+    };
+    assert_round_trip(frame, vector!("v1/relay_connect.bin"), 1);
+}
+
+#[test]
+fn pull_request() {
+    let frame = PullRequestFrame {
+        path: "photo.jpg".to_owned(),
+    };
+    assert_round_trip(frame, vector!("v1/pull_request.bin"), 1);
+}
+
+#[test]
+fn browse() {
+    let request = BrowseRequestFrame {
+        path: "photos/2024".to_owned(),
+    };
+    assert_round_trip(request, vector!("v1/browse_request.bin"), 1);
+
+    let listing = DirectoryListingFrame {
+        entries: vec![
+            DirectoryEntry {
+                name: "2024".to_owned(),
+                size: 0,
+                modified: 1700724864,
+                is_dir: true,
+            },
+            DirectoryEntry {
+                name: "beach.jpg".to_owned(),
+                size: 4096,
+                modified: 1700724864,
+                is_dir: false,
+            },
+        ],
+    };
+    assert_round_trip(listing, vector!("v1/directory_listing.bin"), 1);
+}
+
+#[test]
+fn foreign_frame_types_are_skipped() {
+    let payload = vector!("v1/file_transfer_ack.bin")[6..].to_vec();
+    let result = HandshakeRequestFrame::try_parse(4, payload);
+    assert!(matches!(result, FrameParsingResult::Skip(_)));
+}
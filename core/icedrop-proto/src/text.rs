@@ -0,0 +1,54 @@
+//! Short text sent instead of files, e.g. what's on the clipboard.
+
+use crate::frame_types;
+use crate::{Frame, FrameParsingResult};
+
+use std::io;
+
+/// The first protocol version supporting text messages.
+pub const TEXT_PROTOCOL_VERSION: u16 = 14;
+
+/// The most bytes of text a message may carry.
+pub const MAX_TEXT_LEN: usize = 64 * 1024;
+
+/// Text sent by the sender right after the handshake instead of files, which ends the session.
+/// The receiver answers with an [`EndSessionFrame`](crate::session::EndSessionFrame) once it has
+/// taken the text, or turns it down with a
+/// [`TransferRejectedFrame`](crate::file_transfer::TransferRejectedFrame).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextMessageFrame {
+    pub text: String,
+}
+
+impl Frame for TextMessageFrame {
+    fn frame_type(&self) -> u16 {
+        frame_types::TEXT_MESSAGE
+    }
+
+    fn frame_types() -> Vec<u16> {
+        vec![frame_types::TEXT_MESSAGE]
+    }
+
+    fn try_parse(frame_type: u16, buf: Vec<u8>) -> FrameParsingResult<Self> {
+        if frame_type != frame_types::TEXT_MESSAGE {
+            return FrameParsingResult::Skip(buf);
+        }
+        if buf.len() > MAX_TEXT_LEN {
+            return FrameParsingResult::Err(Box::new(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "text message too long",
+            )));
+        }
+
+        match String::from_utf8(buf) {
+            Ok(text) => FrameParsingResult::Ok(TextMessageFrame { text }),
+            Err(err) => {
+                FrameParsingResult::Err(Box::new(io::Error::new(io::ErrorKind::InvalidData, err)))
+            }
+        }
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        self.text.into_bytes()
+    }
+}
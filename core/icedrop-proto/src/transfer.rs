@@ -0,0 +1,1629 @@
+//! IO-free state machines for both ends of a file transfer.
+//!
+//! The sessions only check that frames arrive in a valid order and decide which frames to send in
+//! return. Reading and writing the file and the connection is up to the caller, which feeds every
+//! frame it receives in and sends the frames it's handed back.
+
+use crate::compression::CompressionMode;
+use crate::file_transfer::{
+    FileTransferAckFrame, FileTransferBeginFrame, FileTransferCompleteFrame, FileTransferDataFrame,
+    FileTransferErrorFrame, FileTransferMetadataFrame, FileTransferRetransmitFrame,
+    FileTransferStripeFrame, FileTransferVerifyFrame,
+};
+use crate::handshake::{
+    validate_display_name, Capabilities, HandshakeRequestFrame, HandshakeResponseFrame,
+};
+use crate::negotiate_protocol_version;
+use crate::session::EndSessionFrame;
+use crate::text::{TextMessageFrame, TEXT_PROTOCOL_VERSION};
+
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt::Display;
+use std::ops::Range;
+
+use sha2::{Digest, Sha256};
+
+/// Size of the file segments carried by data frames, only the last one may be shorter.
+pub const SEGMENT_SIZE: usize = 1024 * 512;
+
+/// By default the receiver acks once every this many segments.
+pub const ACK_WINDOW: u32 = 8;
+
+/// The sender keeps at most this many ack windows worth of segments unacked, so it can go on
+/// sending while the ack for the previous window is on its way.
+pub const IN_FLIGHT_WINDOWS: u32 = 2;
+
+/// The largest segment size peers can agree on.
+pub const MAX_SEGMENT_SIZE: usize = 1024 * 1024 * 16;
+
+/// The most connections a file can be sent over at once.
+pub const MAX_PARALLEL_STREAMS: u32 = 16;
+
+/// How many times in a row the receiver asks for the same corrupted segment before giving up.
+pub const MAX_SEGMENT_RETRANSMITS: u32 = 3;
+
+/// The first protocol version supporting segment checksums and whole-file verification.
+const VERIFICATION_PROTOCOL_VERSION: u16 = 3;
+
+/// The first protocol version supporting several files per session.
+const MULTI_FILE_PROTOCOL_VERSION: u16 = 4;
+
+/// The first protocol version sending the size and modification time of each file.
+const METADATA_PROTOCOL_VERSION: u16 = 5;
+
+/// The first protocol version negotiating the segment size and ack window during the handshake.
+pub const TRANSFER_CONFIG_PROTOCOL_VERSION: u16 = 8;
+
+/// The first protocol version able to send a file in stripes over several connections.
+pub const PARALLEL_STREAMS_PROTOCOL_VERSION: u16 = 9;
+
+/// The first protocol version negotiating compression of file segments.
+pub const COMPRESSION_PROTOCOL_VERSION: u16 = 10;
+
+/// Longest file name, in bytes, the receiver accepts.
+pub const MAX_FILE_NAME_LEN: usize = 255;
+
+/// How a transfer is checked for corruption. Only takes effect with peers speaking protocol
+/// version 3, transfers with older peers are not verified.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum VerificationMode {
+    /// Rely on the transport alone.
+    None,
+    /// Send a CRC32 with every segment, the receiver asks for corrupted segments again.
+    #[default]
+    Segments,
+    /// Checksum segments and also compare the SHA-256 digest of the whole file at the end.
+    Full,
+}
+
+/// How a file is cut into segments and how often the receiver acks them. Each side sets its own,
+/// the handshake settles on the smaller value of each.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransferConfig {
+    /// Size of the file segments carried by data frames, up to [`MAX_SEGMENT_SIZE`].
+    pub segment_size: usize,
+    /// The receiver acks once every this many segments.
+    pub window_size: u32,
+    /// How many connections large files are split across, up to [`MAX_PARALLEL_STREAMS`]. Each
+    /// connection carries one stripe of the file, see [`stripe_range`]. Both sides default to a
+    /// single connection, so receivers have to opt in as well.
+    pub parallel_streams: u32,
+}
+
+impl TransferConfig {
+    /// The config both sides can live with.
+    pub fn negotiate(self, peer: TransferConfig) -> Self {
+        Self {
+            segment_size: self.segment_size.min(peer.segment_size).max(1),
+            window_size: self.window_size.min(peer.window_size).max(1),
+            parallel_streams: self.parallel_streams.min(peer.parallel_streams).max(1),
+        }
+    }
+
+    /// Panics unless the segment size is between 1 and [`MAX_SEGMENT_SIZE`], the window isn't
+    /// empty and there are between 1 and [`MAX_PARALLEL_STREAMS`] streams.
+    pub fn assert_valid(&self) {
+        assert!(
+            (1..=MAX_SEGMENT_SIZE).contains(&self.segment_size),
+            "the segment size must be between 1 and {} bytes",
+            MAX_SEGMENT_SIZE
+        );
+        assert!(self.window_size > 0, "the ack window must not be empty");
+        assert!(
+            (1..=MAX_PARALLEL_STREAMS).contains(&self.parallel_streams),
+            "the number of parallel streams must be between 1 and {}",
+            MAX_PARALLEL_STREAMS
+        );
+    }
+}
+
+impl Default for TransferConfig {
+    fn default() -> Self {
+        Self {
+            segment_size: SEGMENT_SIZE,
+            window_size: ACK_WINDOW,
+            parallel_streams: 1,
+        }
+    }
+}
+
+/// The bytes of a `file_size` bytes long file carried by stripe `stripe_idx` of `stripe_count`.
+/// Stripes are contiguous and as even as possible, the first ones are a byte longer if the size
+/// doesn't divide evenly.
+pub fn stripe_range(file_size: u64, stripe_idx: u32, stripe_count: u32) -> Range<u64> {
+    assert!(stripe_idx < stripe_count, "no such stripe");
+    let (idx, count) = (stripe_idx as u64, stripe_count as u64);
+    let (len, rest) = (file_size / count, file_size % count);
+    let start = idx * len + idx.min(rest);
+    let end = start + len + u64::from(idx < rest);
+    start..end
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionState {
+    /// Waiting for the handshake to complete.
+    Handshaking,
+    /// File segments are being sent.
+    Streaming,
+    /// The sender has sent the end of the file and waits for the receiver to close the session.
+    Finalizing,
+    /// A file has been transferred, the sender may begin the next one or end the session.
+    Idle,
+    /// The whole file has been transferred.
+    Done,
+    /// The transfer has been aborted.
+    Failed,
+}
+
+#[derive(Debug)]
+pub struct SessionError {
+    message: String,
+}
+
+impl SessionError {
+    fn new(message: &str) -> Self {
+        Self {
+            message: message.to_owned(),
+        }
+    }
+}
+
+impl Display for SessionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.message.as_str())
+    }
+}
+
+impl Error for SessionError {}
+
+fn expect_state(
+    state: SessionState,
+    expected: &[SessionState],
+    what: &str,
+) -> Result<(), SessionError> {
+    if expected.contains(&state) {
+        Ok(())
+    } else {
+        let msg = format!("Unexpected {} in state {:?}", what, state);
+        Err(SessionError::new(msg.as_str()))
+    }
+}
+
+/// The sending end of a transfer.
+#[derive(Debug)]
+pub struct SenderSession {
+    state: SessionState,
+    protocol_version: u16,
+    segments_sent: u32,
+    segments_acked: u32,
+    bytes_sent: u64,
+    bytes_acked: u64,
+    /// File offsets the segments sent but not acked yet end at.
+    unacked_segment_ends: VecDeque<u64>,
+    verification: VerificationMode,
+    transfer_config: TransferConfig,
+    compression: CompressionMode,
+    capabilities: Capabilities,
+    file_hasher: Sha256,
+    /// How much of the file has been fed to `file_hasher`, segments sent again are not hashed
+    /// twice.
+    hashed_len: u64,
+    /// Number of files begun so far, the id of the current file is one less.
+    files_begun: u32,
+    file_name: String,
+}
+
+impl SenderSession {
+    pub fn new() -> Self {
+        Self {
+            state: SessionState::Handshaking,
+            protocol_version: 1,
+            segments_sent: 0,
+            segments_acked: 0,
+            bytes_sent: 0,
+            bytes_acked: 0,
+            unacked_segment_ends: VecDeque::new(),
+            verification: VerificationMode::default(),
+            transfer_config: TransferConfig::default(),
+            compression: CompressionMode::default(),
+            capabilities: Capabilities::empty(),
+            file_hasher: Sha256::new(),
+            hashed_len: 0,
+            files_begun: 0,
+            file_name: String::new(),
+        }
+    }
+
+    /// Sets how the transfer is verified, must be called before the handshake.
+    pub fn set_verification(&mut self, mode: VerificationMode) {
+        self.verification = mode;
+    }
+
+    /// The verification in effect, [`VerificationMode::None`] if the peer doesn't support it.
+    pub fn verification(&self) -> VerificationMode {
+        self.verification
+    }
+
+    /// Sets the segment size and ack window to ask the receiver for, must be called before the
+    /// handshake.
+    pub fn set_transfer_config(&mut self, config: TransferConfig) {
+        config.assert_valid();
+        self.transfer_config = config;
+    }
+
+    /// The config in effect once the handshake is done. Receivers older than protocol version 8
+    /// take segments of any size, so the sender keeps its own segment size then, but they ack
+    /// every [`ACK_WINDOW`] segments and only take a single stream.
+    pub fn transfer_config(&self) -> TransferConfig {
+        self.transfer_config
+    }
+
+    /// Sets how segments are compressed on the wire, must be called before the handshake.
+    pub fn set_compression(&mut self, mode: CompressionMode) {
+        self.compression = mode;
+    }
+
+    /// The compression the receiver agreed to, [`CompressionMode::None`] if it doesn't support
+    /// it.
+    pub fn compression(&self) -> CompressionMode {
+        self.compression
+    }
+
+    /// What both peers support, known once the handshake is done.
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    pub fn state(&self) -> SessionState {
+        self.state
+    }
+
+    pub fn protocol_version(&self) -> u16 {
+        self.protocol_version
+    }
+
+    pub fn segments_sent(&self) -> u32 {
+        self.segments_sent
+    }
+
+    pub fn segments_acked(&self) -> u32 {
+        self.segments_acked
+    }
+
+    /// The id of the file being sent.
+    pub fn transfer_id(&self) -> u32 {
+        self.files_begun.saturating_sub(1)
+    }
+
+    /// The name of the file being sent, empty until a file has been begun.
+    pub fn file_name(&self) -> &str {
+        &self.file_name
+    }
+
+    /// Bytes of the file handed out in data frames so far.
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent
+    }
+
+    /// Bytes of the file the receiver has confirmed to have written.
+    pub fn bytes_acked(&self) -> u64 {
+        self.bytes_acked
+    }
+
+    /// Segments sent but not acked yet.
+    pub fn segments_in_flight(&self) -> u32 {
+        self.unacked_segment_ends.len() as u32
+    }
+
+    /// Whether another segment may be sent, or the sender should wait for acks first so a slow
+    /// receiver doesn't have to buffer segments without end. At most [`IN_FLIGHT_WINDOWS`] ack
+    /// windows are kept in flight.
+    pub fn window_open(&self) -> bool {
+        let limit = self
+            .transfer_config
+            .window_size
+            .saturating_mul(IN_FLIGHT_WINDOWS);
+        self.segments_in_flight() < limit
+    }
+
+    /// Completes the handshake and returns the protocol version to switch to.
+    pub fn handle_handshake_response(
+        &mut self,
+        frame: HandshakeResponseFrame,
+    ) -> Result<u16, SessionError> {
+        expect_state(
+            self.state,
+            &[SessionState::Handshaking],
+            "handshake response",
+        )?;
+
+        self.protocol_version = negotiate_protocol_version(frame.protocol_version);
+        self.capabilities = Capabilities::all().intersection(frame.capabilities);
+        if self.protocol_version < VERIFICATION_PROTOCOL_VERSION {
+            self.verification = VerificationMode::None;
+        }
+        match frame.transfer_config {
+            Some(config) if self.protocol_version >= TRANSFER_CONFIG_PROTOCOL_VERSION => {
+                self.transfer_config = self.transfer_config.negotiate(config);
+            }
+            _ => {
+                self.transfer_config.window_size = ACK_WINDOW;
+                self.transfer_config.parallel_streams = 1;
+            }
+        }
+        if !self.capabilities.contains(Capabilities::PARALLEL_STREAMS) {
+            self.transfer_config.parallel_streams = 1;
+        }
+        if !self.capabilities.contains(Capabilities::COMPRESSION) || frame.transfer_config.is_none()
+        {
+            self.compression = CompressionMode::None;
+        } else {
+            self.compression = frame.compression;
+        }
+        self.state = SessionState::Streaming;
+        Ok(self.protocol_version)
+    }
+
+    /// Starts sending the file called `file_name`, right after the handshake or once the previous
+    /// file has been completed. Returns the frame announcing it to the receiver, or `None` if the
+    /// receiver only takes a single, unnamed file per session.
+    pub fn begin_file(
+        &mut self,
+        file_name: &str,
+    ) -> Result<Option<FileTransferBeginFrame>, SessionError> {
+        let expected = [SessionState::Streaming, SessionState::Idle];
+        expect_state(self.state, &expected, "file")?;
+        if self.state == SessionState::Streaming && (self.segments_sent > 0 || self.files_begun > 0)
+        {
+            return Err(SessionError::new("Previous file has not been completed"));
+        }
+
+        self.files_begun += 1;
+        self.file_name = file_name.to_owned();
+        if self.protocol_version < MULTI_FILE_PROTOCOL_VERSION {
+            if self.files_begun > 1 {
+                return Err(SessionError::new("Peer only takes one file per session"));
+            }
+            return Ok(None);
+        }
+
+        self.segments_sent = 0;
+        self.segments_acked = 0;
+        self.bytes_sent = 0;
+        self.bytes_acked = 0;
+        self.unacked_segment_ends.clear();
+        self.file_hasher = Sha256::new();
+        self.hashed_len = 0;
+        self.state = SessionState::Streaming;
+        Ok(Some(FileTransferBeginFrame {
+            transfer_id: self.transfer_id(),
+            file_name: self.file_name.clone(),
+        }))
+    }
+
+    /// Announces that the file begun next is stripe `stripe_idx` of a `file_size` bytes long file
+    /// split into `stripe_count` stripes, the others being sent over connections of their own.
+    /// `group_id` tells the receiver which stripes belong together, and must be the same for all
+    /// of them. Returns the frame to send right before the one of [`SenderSession::begin_file`].
+    pub fn begin_stripe(
+        &mut self,
+        group_id: u64,
+        stripe_idx: u32,
+        stripe_count: u32,
+        file_size: u64,
+    ) -> Result<FileTransferStripeFrame, SessionError> {
+        let expected = [SessionState::Streaming, SessionState::Idle];
+        expect_state(self.state, &expected, "stripe")?;
+        if self.state == SessionState::Streaming && (self.segments_sent > 0 || self.files_begun > 0)
+        {
+            return Err(SessionError::new("Previous file has not been completed"));
+        }
+        if self.protocol_version < PARALLEL_STREAMS_PROTOCOL_VERSION
+            || stripe_count > self.transfer_config.parallel_streams
+            || stripe_idx >= stripe_count
+        {
+            let msg = format!(
+                "Unexpected stripe {} of {} ({} streams agreed)",
+                stripe_idx, stripe_count, self.transfer_config.parallel_streams
+            );
+            return Err(SessionError::new(msg.as_str()));
+        }
+
+        Ok(FileTransferStripeFrame {
+            transfer_id: self.files_begun,
+            group_id,
+            stripe_idx,
+            stripe_count,
+            file_size,
+        })
+    }
+
+    /// Describes the file just begun with [`SenderSession::begin_file`], before its first segment
+    /// is sent. Returns `None` if the receiver doesn't take file metadata.
+    pub fn describe_file(
+        &self,
+        file_size: u64,
+        modified: u64,
+    ) -> Result<Option<FileTransferMetadataFrame>, SessionError> {
+        expect_state(self.state, &[SessionState::Streaming], "file metadata")?;
+        if self.files_begun == 0 || self.segments_sent > 0 {
+            return Err(SessionError::new(
+                "File metadata must follow the file's begin",
+            ));
+        }
+
+        if self.protocol_version < METADATA_PROTOCOL_VERSION {
+            return Ok(None);
+        }
+        Ok(Some(FileTransferMetadataFrame {
+            transfer_id: self.transfer_id(),
+            file_size,
+            modified,
+        }))
+    }
+
+    /// Wraps the next chunk of the file, starting at [`SenderSession::bytes_sent`], into a data
+    /// frame. An empty chunk marks the end of the file.
+    pub fn next_segment(&mut self, data: Vec<u8>) -> Result<FileTransferDataFrame, SessionError> {
+        expect_state(self.state, &[SessionState::Streaming], "file segment")?;
+
+        let segment_end = self.bytes_sent + data.len() as u64;
+        if self.verification == VerificationMode::Full && segment_end > self.hashed_len {
+            let unhashed_start = (self.hashed_len - self.bytes_sent) as usize;
+            self.file_hasher.update(&data[unhashed_start..]);
+            self.hashed_len = segment_end;
+        }
+        let checksum = match self.verification {
+            VerificationMode::None => None,
+            _ => Some(crc32fast::hash(&data)),
+        };
+
+        let frame = FileTransferDataFrame {
+            segment_idx: self.segments_sent,
+            chunk_size: data.len() as u32,
+            data,
+            checksum,
+            compression: self.compression,
+        };
+        self.segments_sent += 1;
+        self.bytes_sent += frame.chunk_size as u64;
+        self.unacked_segment_ends.push_back(self.bytes_sent);
+        if frame.chunk_size == 0 {
+            self.state = SessionState::Finalizing;
+        }
+        Ok(frame)
+    }
+
+    /// Returns the number of segments the receiver has written so far.
+    pub fn handle_ack(&mut self, frame: FileTransferAckFrame) -> Result<u32, SessionError> {
+        let expected = [SessionState::Streaming, SessionState::Finalizing];
+        expect_state(self.state, &expected, "ack")?;
+
+        if frame.segment_idx < self.segments_acked || frame.segment_idx > self.segments_sent {
+            let msg = format!(
+                "Unexpected ack of {} segments ({} acked, {} sent)",
+                frame.segment_idx, self.segments_acked, self.segments_sent
+            );
+            return Err(SessionError::new(msg.as_str()));
+        }
+        for _ in self.segments_acked..frame.segment_idx {
+            self.bytes_acked = self.unacked_segment_ends.pop_front().unwrap();
+        }
+        self.segments_acked = frame.segment_idx;
+        Ok(self.segments_acked)
+    }
+
+    /// The digest of the whole file, to be sent right before the end of the file with
+    /// [`VerificationMode::Full`].
+    pub fn file_digest(&self) -> Option<FileTransferVerifyFrame> {
+        if self.verification != VerificationMode::Full {
+            return None;
+        }
+        Some(FileTransferVerifyFrame {
+            sha256: self.file_hasher.clone().finalize().into(),
+        })
+    }
+
+    /// Goes back to the segment the receiver asks for again, and returns the file offset to
+    /// continue reading from. Every segment after it is sent again as well.
+    pub fn handle_retransmit(
+        &mut self,
+        frame: FileTransferRetransmitFrame,
+    ) -> Result<u64, SessionError> {
+        let expected = [SessionState::Streaming, SessionState::Finalizing];
+        expect_state(self.state, &expected, "retransmission request")?;
+
+        if self.verification == VerificationMode::None
+            || frame.segment_idx < self.segments_acked
+            || frame.segment_idx >= self.segments_sent
+        {
+            let msg = format!(
+                "Unexpected retransmission request for segment {} ({} acked, {} sent)",
+                frame.segment_idx, self.segments_acked, self.segments_sent
+            );
+            return Err(SessionError::new(msg.as_str()));
+        }
+        let still_sent = (frame.segment_idx - self.segments_acked) as usize;
+        self.unacked_segment_ends.truncate(still_sent);
+        self.segments_sent = frame.segment_idx;
+        self.bytes_sent = match self.unacked_segment_ends.back() {
+            Some(&segment_end) => segment_end,
+            None => self.bytes_acked,
+        };
+        self.state = SessionState::Streaming;
+        Ok(self.bytes_sent)
+    }
+
+    /// Handles the receiver confirming it has written the whole file, and returns its id.
+    pub fn handle_file_complete(
+        &mut self,
+        frame: FileTransferCompleteFrame,
+    ) -> Result<u32, SessionError> {
+        expect_state(self.state, &[SessionState::Finalizing], "file completion")?;
+
+        if self.files_begun == 0 || frame.transfer_id != self.transfer_id() {
+            let msg = format!(
+                "Unexpected completion of file {}, sending {}",
+                frame.transfer_id,
+                self.transfer_id()
+            );
+            return Err(SessionError::new(msg.as_str()));
+        }
+        self.state = SessionState::Idle;
+        Ok(frame.transfer_id)
+    }
+
+    /// Handles the receiver closing the session once it got the whole file.
+    pub fn handle_end_session(&mut self, _frame: EndSessionFrame) -> Result<(), SessionError> {
+        expect_state(self.state, &[SessionState::Finalizing], "end of session")?;
+
+        self.state = SessionState::Done;
+        Ok(())
+    }
+
+    /// Closes the session after the last of several files, returns the frame to send.
+    pub fn end_session(&mut self) -> Result<EndSessionFrame, SessionError> {
+        expect_state(self.state, &[SessionState::Idle], "end of session")?;
+
+        self.state = SessionState::Done;
+        Ok(EndSessionFrame)
+    }
+
+    /// Aborts the transfer, e.g. after the receiver sent a
+    /// [`FileTransferErrorFrame`](crate::file_transfer::FileTransferErrorFrame).
+    pub fn fail(&mut self) {
+        self.state = SessionState::Failed;
+    }
+}
+
+impl Default for SenderSession {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What the receiver has to do with a data frame.
+#[derive(Debug)]
+pub enum ReceiverAction {
+    /// Write the data to the file, then report it with [`ReceiverSession::segment_written`].
+    Write(Vec<u8>),
+    /// The whole file has been received. Flush it and send the frame to close the session.
+    Finish(EndSessionFrame),
+    /// One of several files has been received. Flush it and send the frame, the sender then
+    /// begins the next file or ends the session.
+    FinishFile(FileTransferCompleteFrame),
+    /// The segment is corrupted, send the frame to get it again.
+    Retransmit(FileTransferRetransmitFrame),
+    /// The segment was sent before the sender got a retransmission request and comes again, drop
+    /// it.
+    Discard,
+    /// The file doesn't match the sender's digest. Send the frame to abort the transfer.
+    Fail(FileTransferErrorFrame),
+}
+
+/// The receiving end of a transfer.
+#[derive(Debug)]
+pub struct ReceiverSession {
+    state: SessionState,
+    protocol_version: u16,
+    peer_name: String,
+    segments_received: u32,
+    segments_written: u32,
+    transfer_config: TransferConfig,
+    compression: CompressionMode,
+    capabilities: Capabilities,
+    /// The corrupted segment asked for again, and how many times in a row it has been.
+    awaiting_retransmit: Option<u32>,
+    retransmits: u32,
+    file_hasher: Sha256,
+    expected_digest: Option<[u8; 32]>,
+    /// The id of the file being received, if the sender announced it.
+    transfer_id: Option<u32>,
+    bytes_received: u64,
+    /// The size the sender described the file with, checked once it has been received.
+    expected_size: Option<u64>,
+    /// Set if the file being received is only a stripe of a larger one.
+    stripe: Option<FileTransferStripeFrame>,
+}
+
+impl ReceiverSession {
+    pub fn new() -> Self {
+        Self {
+            state: SessionState::Handshaking,
+            protocol_version: 1,
+            peer_name: String::new(),
+            segments_received: 0,
+            segments_written: 0,
+            transfer_config: TransferConfig::default(),
+            compression: CompressionMode::default(),
+            capabilities: Capabilities::empty(),
+            awaiting_retransmit: None,
+            retransmits: 0,
+            file_hasher: Sha256::new(),
+            expected_digest: None,
+            transfer_id: None,
+            bytes_received: 0,
+            expected_size: None,
+            stripe: None,
+        }
+    }
+
+    /// Sets after how many written segments an ack is sent ([`ACK_WINDOW`] by default).
+    pub fn set_ack_window(&mut self, ack_window: u32) {
+        assert!(ack_window > 0, "the ack window must not be empty");
+        self.transfer_config.window_size = ack_window;
+    }
+
+    /// Sets the largest segment size and ack window to agree to, must be called before the
+    /// handshake.
+    pub fn set_transfer_config(&mut self, config: TransferConfig) {
+        config.assert_valid();
+        self.transfer_config = config;
+    }
+
+    /// The config in effect once the handshake is done.
+    pub fn transfer_config(&self) -> TransferConfig {
+        self.transfer_config
+    }
+
+    pub fn state(&self) -> SessionState {
+        self.state
+    }
+
+    pub fn protocol_version(&self) -> u16 {
+        self.protocol_version
+    }
+
+    /// The compression the sender asked for and segments may come in with. Receivers decompress
+    /// every [`CompressionMode`], so they agree to whichever the sender picks.
+    pub fn compression(&self) -> CompressionMode {
+        self.compression
+    }
+
+    /// What both peers support, known once the handshake is done.
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    /// The sender's display name, empty until the handshake is done.
+    pub fn peer_name(&self) -> &str {
+        &self.peer_name
+    }
+
+    pub fn segments_written(&self) -> u32 {
+        self.segments_written
+    }
+
+    /// Bytes of the file being received taken in so far.
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received
+    }
+
+    /// The size the sender described the file being received with, if it did.
+    pub fn expected_size(&self) -> Option<u64> {
+        self.expected_size
+    }
+
+    /// The stripe of a larger file the file being received is, if it is one. Its data goes at
+    /// the start of its [`stripe_range`].
+    pub fn stripe(&self) -> Option<&FileTransferStripeFrame> {
+        self.stripe.as_ref()
+    }
+
+    /// The SHA-256 digest the sender gave for the file being received, only sent with full
+    /// verification. A file received completely has been checked against it.
+    pub fn expected_digest(&self) -> Option<[u8; 32]> {
+        self.expected_digest
+    }
+
+    /// Returns the response to send. Its `protocol_version` is the one to switch to once it has
+    /// been sent.
+    pub fn handle_handshake_request(
+        &mut self,
+        frame: HandshakeRequestFrame,
+    ) -> Result<HandshakeResponseFrame, SessionError> {
+        expect_state(
+            self.state,
+            &[SessionState::Handshaking],
+            "handshake request",
+        )?;
+
+        self.peer_name = validate_display_name(&frame.name).map_err(|err| {
+            let msg = format!("Invalid peer name: {}", err);
+            SessionError::new(msg.as_str())
+        })?;
+        self.protocol_version = negotiate_protocol_version(frame.protocol_version);
+        self.capabilities = Capabilities::all().intersection(frame.capabilities);
+        let transfer_config = frame
+            .transfer_config
+            .filter(|_| self.protocol_version >= TRANSFER_CONFIG_PROTOCOL_VERSION)
+            .map(|config| {
+                self.transfer_config = self.transfer_config.negotiate(config);
+                self.transfer_config
+            });
+        if !self.capabilities.contains(Capabilities::PARALLEL_STREAMS) {
+            self.transfer_config.parallel_streams = 1;
+        }
+        let transfer_config = transfer_config.map(|_| self.transfer_config);
+        if transfer_config.is_none() {
+            self.transfer_config.parallel_streams = 1;
+        } else if self.capabilities.contains(Capabilities::COMPRESSION) {
+            self.compression = frame.compression;
+        }
+        self.state = SessionState::Streaming;
+        Ok(HandshakeResponseFrame {
+            protocol_version: self.protocol_version,
+            transfer_config,
+            compression: self.compression,
+            capabilities: self.capabilities,
+        })
+    }
+
+    pub fn handle_data(
+        &mut self,
+        frame: FileTransferDataFrame,
+    ) -> Result<ReceiverAction, SessionError> {
+        expect_state(self.state, &[SessionState::Streaming], "file segment")?;
+
+        if let Some(segment_idx) = self.awaiting_retransmit {
+            if frame.segment_idx != segment_idx {
+                return Ok(ReceiverAction::Discard);
+            }
+        }
+        if frame.segment_idx != self.segments_received {
+            let msg = format!(
+                "Unexpected segment {}, expected {}",
+                frame.segment_idx, self.segments_received
+            );
+            return Err(SessionError::new(msg.as_str()));
+        }
+
+        if !frame.verify_checksum() {
+            self.retransmits += 1;
+            if self.retransmits > MAX_SEGMENT_RETRANSMITS {
+                let msg = format!(
+                    "Segment {} corrupted {} times in a row",
+                    frame.segment_idx, self.retransmits
+                );
+                return Err(SessionError::new(msg.as_str()));
+            }
+            self.awaiting_retransmit = Some(frame.segment_idx);
+            return Ok(ReceiverAction::Retransmit(FileTransferRetransmitFrame {
+                segment_idx: frame.segment_idx,
+            }));
+        }
+        self.awaiting_retransmit = None;
+        self.retransmits = 0;
+        self.segments_received += 1;
+
+        if frame.chunk_size == 0 {
+            if self
+                .expected_size
+                .is_some_and(|expected| expected != self.bytes_received)
+            {
+                self.state = SessionState::Failed;
+                return Ok(ReceiverAction::Fail(FileTransferErrorFrame {
+                    retryable: true,
+                    message: "file size mismatch".to_owned(),
+                }));
+            }
+            let digest: [u8; 32] = self.file_hasher.clone().finalize().into();
+            if self
+                .expected_digest
+                .is_some_and(|expected| expected != digest)
+            {
+                self.state = SessionState::Failed;
+                return Ok(ReceiverAction::Fail(FileTransferErrorFrame {
+                    retryable: true,
+                    message: "file checksum mismatch".to_owned(),
+                }));
+            }
+            if let Some(transfer_id) = self.transfer_id {
+                self.state = SessionState::Idle;
+                return Ok(ReceiverAction::FinishFile(FileTransferCompleteFrame {
+                    transfer_id,
+                }));
+            }
+            self.state = SessionState::Done;
+            return Ok(ReceiverAction::Finish(EndSessionFrame));
+        }
+        let bytes_received = self.bytes_received + frame.data.len() as u64;
+        if self
+            .expected_size
+            .is_some_and(|expected| bytes_received > expected)
+        {
+            self.state = SessionState::Failed;
+            return Ok(ReceiverAction::Fail(FileTransferErrorFrame {
+                retryable: false,
+                message: "more data than the file size".to_owned(),
+            }));
+        }
+        if self.protocol_version >= VERIFICATION_PROTOCOL_VERSION {
+            self.file_hasher.update(&frame.data);
+        }
+        self.bytes_received = bytes_received;
+        Ok(ReceiverAction::Write(frame.data))
+    }
+
+    /// Starts receiving the next file, and returns its name once it has been checked to be safe to
+    /// create in the receiver's directory.
+    pub fn handle_begin(&mut self, frame: FileTransferBeginFrame) -> Result<String, SessionError> {
+        let expected = [SessionState::Streaming, SessionState::Idle];
+        expect_state(self.state, &expected, "file")?;
+        if self.state == SessionState::Streaming
+            && (self.segments_received > 0 || self.transfer_id.is_some())
+        {
+            return Err(SessionError::new("Previous file has not been completed"));
+        }
+
+        let expected_id = self.transfer_id.map_or(0, |transfer_id| transfer_id + 1);
+        if frame.transfer_id != expected_id {
+            let msg = format!(
+                "Unexpected file {}, expected {}",
+                frame.transfer_id, expected_id
+            );
+            return Err(SessionError::new(msg.as_str()));
+        }
+        let file_name = validate_file_name(&frame.file_name)?;
+
+        // A stripe is announced right before its file, anything else is left from earlier files.
+        self.stripe = self
+            .stripe
+            .take()
+            .filter(|stripe| stripe.transfer_id == frame.transfer_id);
+        self.transfer_id = Some(frame.transfer_id);
+        self.segments_received = 0;
+        self.segments_written = 0;
+        self.awaiting_retransmit = None;
+        self.retransmits = 0;
+        self.file_hasher = Sha256::new();
+        self.expected_digest = None;
+        self.bytes_received = 0;
+        self.expected_size = None;
+        self.state = SessionState::Streaming;
+        Ok(file_name)
+    }
+
+    /// Takes note of the next file being a stripe of a larger one, see
+    /// [`SenderSession::begin_stripe`].
+    pub fn handle_stripe(&mut self, frame: FileTransferStripeFrame) -> Result<(), SessionError> {
+        let expected = [SessionState::Streaming, SessionState::Idle];
+        expect_state(self.state, &expected, "stripe")?;
+        if self.state == SessionState::Streaming
+            && (self.segments_received > 0 || self.transfer_id.is_some())
+        {
+            return Err(SessionError::new("Previous file has not been completed"));
+        }
+
+        let expected_id = self.transfer_id.map_or(0, |transfer_id| transfer_id + 1);
+        if self.protocol_version < PARALLEL_STREAMS_PROTOCOL_VERSION
+            || frame.transfer_id != expected_id
+            || frame.stripe_count > self.transfer_config.parallel_streams
+            || frame.stripe_idx >= frame.stripe_count
+        {
+            let msg = format!(
+                "Unexpected stripe {} of {} for file {}",
+                frame.stripe_idx, frame.stripe_count, frame.transfer_id
+            );
+            return Err(SessionError::new(msg.as_str()));
+        }
+
+        self.stripe = Some(frame);
+        Ok(())
+    }
+
+    /// Takes note of the size the file being received is checked against once it has been
+    /// received. The modification time is left to the caller to restore.
+    pub fn handle_metadata(
+        &mut self,
+        frame: FileTransferMetadataFrame,
+    ) -> Result<(), SessionError> {
+        expect_state(self.state, &[SessionState::Streaming], "file metadata")?;
+        if self.segments_received > 0 || self.transfer_id != Some(frame.transfer_id) {
+            let msg = format!("Unexpected metadata for file {}", frame.transfer_id);
+            return Err(SessionError::new(msg.as_str()));
+        }
+        if let Some(stripe) = &self.stripe {
+            let range = stripe_range(stripe.file_size, stripe.stripe_idx, stripe.stripe_count);
+            if frame.file_size != range.end - range.start {
+                let msg = format!(
+                    "Stripe {} of file {} is {} bytes, expected {}",
+                    stripe.stripe_idx,
+                    frame.transfer_id,
+                    frame.file_size,
+                    range.end - range.start
+                );
+                return Err(SessionError::new(msg.as_str()));
+            }
+        }
+
+        self.expected_size = Some(frame.file_size);
+        Ok(())
+    }
+
+    /// Handles the sender closing the session after the last of several files.
+    pub fn handle_end_session(&mut self, _frame: EndSessionFrame) -> Result<(), SessionError> {
+        expect_state(self.state, &[SessionState::Idle], "end of session")?;
+
+        self.state = SessionState::Done;
+        Ok(())
+    }
+
+    /// Takes a text message the sender sent right after the handshake instead of files, and
+    /// returns the text. The session is over with it.
+    pub fn handle_text(&mut self, frame: TextMessageFrame) -> Result<String, SessionError> {
+        expect_state(self.state, &[SessionState::Streaming], "text message")?;
+        if self.protocol_version < TEXT_PROTOCOL_VERSION
+            || self.transfer_id.is_some()
+            || self.segments_received > 0
+        {
+            return Err(SessionError::new("Unexpected text message"));
+        }
+
+        self.state = SessionState::Done;
+        Ok(frame.text)
+    }
+
+    /// Takes note of the digest the whole file is checked against once it has been received.
+    pub fn handle_verify(&mut self, frame: FileTransferVerifyFrame) -> Result<(), SessionError> {
+        expect_state(self.state, &[SessionState::Streaming], "file digest")?;
+
+        self.expected_digest = Some(frame.sha256);
+        Ok(())
+    }
+
+    /// Returns the ack to send, if any, after the last segment has been written to the file.
+    pub fn segment_written(&mut self) -> Option<FileTransferAckFrame> {
+        self.segments_written += 1;
+        if !self
+            .segments_written
+            .is_multiple_of(self.transfer_config.window_size)
+        {
+            return None;
+        }
+        Some(FileTransferAckFrame {
+            segment_idx: self.segments_written,
+        })
+    }
+
+    /// Aborts the transfer, e.g. because the file could not be written.
+    pub fn fail(&mut self) {
+        self.state = SessionState::Failed;
+    }
+}
+
+impl Default for ReceiverSession {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Only plain names are accepted, so a sender can't place files outside the receiver's directory.
+fn validate_file_name(file_name: &str) -> Result<String, SessionError> {
+    let problem = if file_name.is_empty() {
+        Some("is empty")
+    } else if file_name == "." || file_name == ".." {
+        Some("names a directory")
+    } else if file_name.len() > MAX_FILE_NAME_LEN {
+        Some("is too long")
+    } else if file_name.contains(['/', '\\']) {
+        Some("contains a path separator")
+    } else if file_name.contains(':') {
+        Some("contains a drive or stream separator")
+    } else if is_reserved_on_windows(file_name) {
+        Some("is reserved on Windows")
+    } else if file_name.chars().any(char::is_control) {
+        Some("contains control characters")
+    } else {
+        None
+    };
+
+    match problem {
+        Some(problem) => {
+            let msg = format!("File name {:?} {}", file_name, problem);
+            Err(SessionError::new(msg.as_str()))
+        }
+        None => Ok(file_name.to_owned()),
+    }
+}
+
+/// Windows maps these names to devices no matter the extension, so `nul.txt` can't be a file.
+fn is_reserved_on_windows(file_name: &str) -> bool {
+    let stem = file_name.split('.').next().unwrap_or_default();
+    let stem = stem.trim_end_matches(' ').to_ascii_uppercase();
+    match stem.as_str() {
+        "CON" | "PRN" | "AUX" | "NUL" => true,
+        _ => {
+            let port = stem
+                .strip_prefix("COM")
+                .or_else(|| stem.strip_prefix("LPT"));
+            matches!(port.map(str::as_bytes), Some([b'1'..=b'9']))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        stripe_range, ReceiverAction, ReceiverSession, SenderSession, SessionState, TransferConfig,
+        VerificationMode, ACK_WINDOW,
+    };
+    use crate::compression::CompressionMode;
+    use crate::file_transfer::{FileTransferAckFrame, FileTransferBeginFrame};
+    use crate::handshake::{Capabilities, HandshakeRequestFrame, HandshakeResponseFrame};
+    use crate::text::TextMessageFrame;
+    use crate::PROTOCOL_VERSION;
+
+    use std::collections::VecDeque;
+
+    use proptest::prelude::*;
+
+    fn handshake(sender: &mut SenderSession, receiver: &mut ReceiverSession) {
+        let request = HandshakeRequestFrame {
+            name: "test".to_owned(),
+            protocol_version: PROTOCOL_VERSION,
+            transfer_config: Some(sender.transfer_config()),
+            compression: sender.compression(),
+            capabilities: Capabilities::all(),
+        };
+        let response = receiver.handle_handshake_request(request).unwrap();
+        let protocol_version = sender.handle_handshake_response(response).unwrap();
+        assert_eq!(protocol_version, PROTOCOL_VERSION);
+        assert_eq!(receiver.peer_name(), "test");
+    }
+
+    #[test]
+    fn transfer_runs_to_completion() {
+        let mut sender = SenderSession::new();
+        let mut receiver = ReceiverSession::new();
+        handshake(&mut sender, &mut receiver);
+
+        let mut received = Vec::new();
+        for chunk in (0..20u8).map(|i| vec![i; 3]).chain([Vec::new()]) {
+            let frame = sender.next_segment(chunk).unwrap();
+            match receiver.handle_data(frame).unwrap() {
+                ReceiverAction::Write(data) => {
+                    received.extend(data);
+                    if let Some(ack) = receiver.segment_written() {
+                        assert_eq!(ack.segment_idx % ACK_WINDOW, 0);
+                        sender.handle_ack(ack).unwrap();
+                    }
+                }
+                ReceiverAction::Finish(frame) => sender.handle_end_session(frame).unwrap(),
+                action => panic!("unexpected {:?}", action),
+            }
+        }
+
+        assert_eq!(received.len(), 60);
+        assert_eq!(sender.segments_acked(), 16);
+        assert_eq!(sender.bytes_acked(), 48);
+        assert_eq!(sender.state(), SessionState::Done);
+        assert_eq!(receiver.state(), SessionState::Done);
+    }
+
+    #[test]
+    fn frames_before_handshake_are_rejected() {
+        let mut sender = SenderSession::new();
+        assert!(sender.next_segment(vec![1, 2, 3]).is_err());
+        assert!(sender
+            .handle_ack(FileTransferAckFrame { segment_idx: 0 })
+            .is_err());
+    }
+
+    #[test]
+    fn text_is_only_taken_instead_of_files() {
+        let text = || TextMessageFrame {
+            text: "hello".to_owned(),
+        };
+        let mut receiver = ReceiverSession::new();
+        assert!(receiver.handle_text(text()).is_err());
+
+        let mut sender = SenderSession::new();
+        handshake(&mut sender, &mut receiver);
+        assert_eq!(receiver.handle_text(text()).unwrap(), "hello");
+        assert_eq!(receiver.state(), SessionState::Done);
+        assert!(receiver.handle_text(text()).is_err());
+
+        let mut receiver = ReceiverSession::new();
+        handshake(&mut SenderSession::new(), &mut receiver);
+        let begin = FileTransferBeginFrame {
+            transfer_id: 0,
+            file_name: "file".to_owned(),
+        };
+        receiver.handle_begin(begin).unwrap();
+        assert!(receiver.handle_text(text()).is_err());
+    }
+
+    #[test]
+    fn out_of_order_segments_are_rejected() {
+        let mut sender = SenderSession::new();
+        let mut receiver = ReceiverSession::new();
+        handshake(&mut sender, &mut receiver);
+
+        let _skipped = sender.next_segment(vec![1]).unwrap();
+        let frame = sender.next_segment(vec![2]).unwrap();
+        assert!(receiver.handle_data(frame).is_err());
+    }
+
+    #[test]
+    fn corrupted_segments_are_sent_again() {
+        let mut sender = SenderSession::new();
+        let mut receiver = ReceiverSession::new();
+        handshake(&mut sender, &mut receiver);
+
+        let first = sender.next_segment(vec![1; 3]).unwrap();
+        let mut corrupted = sender.next_segment(vec![2; 3]).unwrap();
+        corrupted.data[0] = 0;
+        let in_flight = sender.next_segment(vec![3; 3]).unwrap();
+
+        assert!(matches!(
+            receiver.handle_data(first).unwrap(),
+            ReceiverAction::Write(_)
+        ));
+        let request = match receiver.handle_data(corrupted).unwrap() {
+            ReceiverAction::Retransmit(request) => request,
+            action => panic!("unexpected {:?}", action),
+        };
+        assert_eq!(request.segment_idx, 1);
+        assert!(matches!(
+            receiver.handle_data(in_flight).unwrap(),
+            ReceiverAction::Discard
+        ));
+
+        // The sender goes back to the corrupted segment and continues from there.
+        assert_eq!(sender.handle_retransmit(request).unwrap(), 3);
+        assert_eq!(sender.segments_sent(), 1);
+        let resent = sender.next_segment(vec![2; 3]).unwrap();
+        assert_eq!(resent.segment_idx, 1);
+        match receiver.handle_data(resent).unwrap() {
+            ReceiverAction::Write(data) => assert_eq!(data, [2; 3]),
+            action => panic!("unexpected {:?}", action),
+        }
+    }
+
+    #[test]
+    fn file_digest_mismatch_fails_transfer() {
+        let mut sender = SenderSession::new();
+        sender.set_verification(VerificationMode::Full);
+        let mut receiver = ReceiverSession::new();
+        handshake(&mut sender, &mut receiver);
+
+        // Corrupt a segment in a way its checksum can't catch.
+        let mut frame = sender.next_segment(vec![1; 3]).unwrap();
+        frame.data[0] = 0;
+        frame.checksum = None;
+        assert!(matches!(
+            receiver.handle_data(frame).unwrap(),
+            ReceiverAction::Write(_)
+        ));
+
+        let eof = sender.next_segment(Vec::new()).unwrap();
+        receiver
+            .handle_verify(sender.file_digest().unwrap())
+            .unwrap();
+        match receiver.handle_data(eof).unwrap() {
+            ReceiverAction::Fail(frame) => assert!(frame.retryable),
+            action => panic!("unexpected {:?}", action),
+        }
+        assert_eq!(receiver.state(), SessionState::Failed);
+    }
+
+    #[test]
+    fn truncated_file_fails_transfer() {
+        let mut sender = SenderSession::new();
+        let mut receiver = ReceiverSession::new();
+        handshake(&mut sender, &mut receiver);
+
+        let begin = sender.begin_file("a.txt").unwrap().unwrap();
+        receiver.handle_begin(begin).unwrap();
+        let metadata = sender.describe_file(4, 1700724864).unwrap().unwrap();
+        assert_eq!(metadata.transfer_id, 0);
+        receiver.handle_metadata(metadata).unwrap();
+
+        for chunk in [vec![1; 3], Vec::new()] {
+            let frame = sender.next_segment(chunk).unwrap();
+            match receiver.handle_data(frame).unwrap() {
+                ReceiverAction::Write(_) => {}
+                ReceiverAction::Fail(frame) => assert_eq!(frame.message, "file size mismatch"),
+                action => panic!("unexpected {:?}", action),
+            }
+        }
+        assert_eq!(receiver.state(), SessionState::Failed);
+        assert!(sender.describe_file(4, 0).is_err());
+    }
+
+    #[test]
+    fn data_past_the_file_size_fails_transfer() {
+        let mut sender = SenderSession::new();
+        let mut receiver = ReceiverSession::new();
+        handshake(&mut sender, &mut receiver);
+
+        let begin = sender.begin_file("a.txt").unwrap().unwrap();
+        receiver.handle_begin(begin).unwrap();
+        let metadata = sender.describe_file(4, 1700724864).unwrap().unwrap();
+        receiver.handle_metadata(metadata).unwrap();
+
+        let frame = sender.next_segment(vec![1; 3]).unwrap();
+        assert!(matches!(
+            receiver.handle_data(frame).unwrap(),
+            ReceiverAction::Write(_)
+        ));
+        let frame = sender.next_segment(vec![1; 2]).unwrap();
+        match receiver.handle_data(frame).unwrap() {
+            ReceiverAction::Fail(frame) => assert!(!frame.retryable),
+            action => panic!("unexpected {:?}", action),
+        }
+        assert_eq!(receiver.state(), SessionState::Failed);
+        assert_eq!(receiver.bytes_received(), 3);
+    }
+
+    #[test]
+    fn transfer_config_is_negotiated() {
+        let mut sender = SenderSession::new();
+        sender.set_transfer_config(TransferConfig {
+            segment_size: 4096,
+            window_size: 32,
+            parallel_streams: 4,
+        });
+        let mut receiver = ReceiverSession::new();
+        handshake(&mut sender, &mut receiver);
+
+        let agreed = TransferConfig {
+            segment_size: 4096,
+            window_size: ACK_WINDOW,
+            parallel_streams: 1,
+        };
+        assert_eq!(sender.transfer_config(), agreed);
+        assert_eq!(receiver.transfer_config(), agreed);
+
+        // Receivers that don't negotiate leave the sender's config alone.
+        let mut sender = SenderSession::new();
+        sender.set_transfer_config(agreed);
+        let response = HandshakeResponseFrame {
+            protocol_version: 7,
+            transfer_config: Some(TransferConfig::default()),
+            compression: CompressionMode::None,
+            capabilities: Capabilities::implied_by(7),
+        };
+        sender.handle_handshake_response(response).unwrap();
+        assert_eq!(sender.transfer_config(), agreed);
+    }
+
+    #[test]
+    fn stripes_split_files_evenly() {
+        let ranges: Vec<_> = (0..3).map(|idx| stripe_range(10, idx, 3)).collect();
+        assert_eq!(ranges, [0..4, 4..7, 7..10]);
+        assert_eq!(stripe_range(2, 3, 4), 2..2);
+    }
+
+    #[test]
+    fn stripes_are_announced_before_their_file() {
+        let config = TransferConfig {
+            parallel_streams: 4,
+            ..TransferConfig::default()
+        };
+        let mut sender = SenderSession::new();
+        sender.set_transfer_config(config);
+        let mut receiver = ReceiverSession::new();
+        receiver.set_transfer_config(TransferConfig {
+            parallel_streams: 2,
+            ..config
+        });
+        handshake(&mut sender, &mut receiver);
+        assert_eq!(sender.transfer_config().parallel_streams, 2);
+        assert!(sender.begin_stripe(7, 0, 3, 10).is_err());
+
+        let stripe = sender.begin_stripe(7, 1, 2, 10).unwrap();
+        receiver.handle_stripe(stripe.clone()).unwrap();
+        let begin = sender.begin_file("photo.jpg").unwrap().unwrap();
+        receiver.handle_begin(begin).unwrap();
+        assert_eq!(receiver.stripe(), Some(&stripe));
+
+        // The second stripe of 10 bytes is the last 5 of them.
+        let metadata = sender.describe_file(6, 0).unwrap().unwrap();
+        assert!(receiver.handle_metadata(metadata).is_err());
+        let metadata = sender.describe_file(5, 0).unwrap().unwrap();
+        receiver.handle_metadata(metadata).unwrap();
+    }
+
+    #[test]
+    fn sending_pauses_while_the_window_is_full() {
+        let mut sender = SenderSession::new();
+        sender.set_transfer_config(TransferConfig {
+            segment_size: 4,
+            window_size: 2,
+            ..TransferConfig::default()
+        });
+        let mut receiver = ReceiverSession::new();
+        handshake(&mut sender, &mut receiver);
+
+        for _ in 0..4 {
+            assert!(sender.window_open());
+            sender.next_segment(vec![1; 4]).unwrap();
+        }
+        assert!(!sender.window_open());
+        assert_eq!(sender.segments_in_flight(), 4);
+
+        sender
+            .handle_ack(FileTransferAckFrame { segment_idx: 2 })
+            .unwrap();
+        assert!(sender.window_open());
+        assert_eq!(sender.segments_in_flight(), 2);
+    }
+
+    #[test]
+    fn verification_is_off_with_older_peers() {
+        let mut sender = SenderSession::new();
+        sender.set_verification(VerificationMode::Full);
+        let response = HandshakeResponseFrame {
+            protocol_version: 2,
+            transfer_config: None,
+            compression: CompressionMode::None,
+            capabilities: Capabilities::implied_by(2),
+        };
+        sender.handle_handshake_response(response).unwrap();
+
+        assert_eq!(sender.verification(), VerificationMode::None);
+        assert!(sender.next_segment(vec![1]).unwrap().checksum.is_none());
+        assert!(sender.file_digest().is_none());
+    }
+
+    #[test]
+    fn compression_is_agreed_in_the_handshake() {
+        let mut sender = SenderSession::new();
+        let mut receiver = ReceiverSession::new();
+        sender.set_compression(CompressionMode::Zstd);
+        handshake(&mut sender, &mut receiver);
+        assert_eq!(sender.compression(), CompressionMode::Zstd);
+        assert_eq!(receiver.compression(), CompressionMode::Zstd);
+
+        sender.begin_file("notes.txt").unwrap();
+        let frame = sender.next_segment(vec![b'a'; 1024]).unwrap();
+        assert_eq!(frame.compression, CompressionMode::Zstd);
+
+        // Receivers older than version 10 don't decompress anything.
+        let mut sender = SenderSession::new();
+        sender.set_compression(CompressionMode::Lz4);
+        let response = HandshakeResponseFrame {
+            protocol_version: 9,
+            transfer_config: Some(TransferConfig::default()),
+            compression: CompressionMode::Lz4,
+            capabilities: Capabilities::implied_by(9),
+        };
+        sender.handle_handshake_response(response).unwrap();
+        assert_eq!(sender.compression(), CompressionMode::None);
+
+        // Neither do newer ones leaving compression out of their capabilities.
+        let mut sender = SenderSession::new();
+        let mut receiver = ReceiverSession::new();
+        sender.set_compression(CompressionMode::Lz4);
+        let request = HandshakeRequestFrame {
+            name: "test".to_owned(),
+            protocol_version: PROTOCOL_VERSION,
+            transfer_config: Some(sender.transfer_config()),
+            compression: sender.compression(),
+            capabilities: Capabilities::PARALLEL_STREAMS,
+        };
+        let response = receiver.handle_handshake_request(request).unwrap();
+        assert_eq!(response.capabilities, Capabilities::PARALLEL_STREAMS);
+        assert_eq!(receiver.compression(), CompressionMode::None);
+        sender.handle_handshake_response(response).unwrap();
+        assert_eq!(sender.compression(), CompressionMode::None);
+    }
+
+    #[test]
+    fn several_files_are_sent_in_sequence() {
+        let mut sender = SenderSession::new();
+        let mut receiver = ReceiverSession::new();
+        handshake(&mut sender, &mut receiver);
+
+        for (transfer_id, &file_name) in ["a.txt", "b.txt"].iter().enumerate() {
+            let begin = sender.begin_file(file_name).unwrap().unwrap();
+            assert_eq!(begin.transfer_id, transfer_id as u32);
+            assert_eq!(receiver.handle_begin(begin).unwrap(), file_name);
+
+            for chunk in [vec![1; 3], Vec::new()] {
+                let frame = sender.next_segment(chunk).unwrap();
+                match receiver.handle_data(frame).unwrap() {
+                    ReceiverAction::Write(_) => assert!(receiver.segment_written().is_none()),
+                    ReceiverAction::FinishFile(frame) => {
+                        assert_eq!(
+                            sender.handle_file_complete(frame).unwrap(),
+                            transfer_id as u32
+                        )
+                    }
+                    action => panic!("unexpected {:?}", action),
+                }
+            }
+            assert_eq!(sender.bytes_sent(), 3);
+            assert_eq!(sender.state(), SessionState::Idle);
+            assert_eq!(receiver.state(), SessionState::Idle);
+        }
+
+        receiver
+            .handle_end_session(sender.end_session().unwrap())
+            .unwrap();
+        assert_eq!(sender.state(), SessionState::Done);
+        assert_eq!(receiver.state(), SessionState::Done);
+    }
+
+    #[test]
+    fn file_names_outside_the_directory_are_rejected() {
+        let file_names = [
+            "",
+            "..",
+            "../etc/passwd",
+            "dir\\file",
+            "a\u{7}b",
+            "C:file",
+            "file.txt:stream",
+            "CON",
+            "nul.txt",
+            "Aux.tar.gz",
+            "com1",
+            "LPT9.log",
+            "prn .txt",
+        ];
+        for file_name in file_names {
+            let mut sender = SenderSession::new();
+            let mut receiver = ReceiverSession::new();
+            handshake(&mut sender, &mut receiver);
+
+            let frame = FileTransferBeginFrame {
+                transfer_id: 0,
+                file_name: file_name.to_owned(),
+            };
+            assert!(receiver.handle_begin(frame).is_err(), "{:?}", file_name);
+        }
+
+        for file_name in [
+            "console.log",
+            "com10",
+            "lpt0",
+            "nullable.txt",
+            "auxiliary",
+            "ab€",
+        ] {
+            let mut sender = SenderSession::new();
+            let mut receiver = ReceiverSession::new();
+            handshake(&mut sender, &mut receiver);
+
+            let frame = FileTransferBeginFrame {
+                transfer_id: 0,
+                file_name: file_name.to_owned(),
+            };
+            assert!(receiver.handle_begin(frame).is_ok(), "{:?}", file_name);
+        }
+    }
+
+    /// Transfers `file` between a sender and a receiver connected by an in-memory link.
+    ///
+    /// Each entry of `schedule` (repeated as needed) lets either the sender put its next segment
+    /// on the link (`true`) or the receiver take one off (`false`), so segments pile up in flight
+    /// in varying amounts. Acks are delivered right away. The link is cut once `disconnect_after`
+    /// segments have been delivered. Returns the bytes the receiver wrote.
+    fn run_transfer(
+        sender: &mut SenderSession,
+        receiver: &mut ReceiverSession,
+        file: &[u8],
+        chunk_size: usize,
+        schedule: &[bool],
+        disconnect_after: Option<usize>,
+    ) -> Vec<u8> {
+        handshake(sender, receiver);
+
+        let mut chunks = file.chunks(chunk_size).map(<[u8]>::to_vec);
+        let mut eof_sent = false;
+        let mut link = VecDeque::new();
+        let mut delivered = 0;
+        let mut written = Vec::new();
+        for &send in schedule.iter().cycle() {
+            if receiver.state() != SessionState::Streaming || Some(delivered) == disconnect_after {
+                break;
+            }
+
+            if (send || link.is_empty()) && !eof_sent {
+                let chunk = chunks.next().unwrap_or_default();
+                eof_sent = chunk.is_empty();
+                link.push_back(sender.next_segment(chunk).unwrap());
+                continue;
+            }
+
+            let frame = link.pop_front().unwrap();
+            delivered += 1;
+            match receiver.handle_data(frame).unwrap() {
+                ReceiverAction::Write(data) => {
+                    written.extend(data);
+                    if let Some(ack) = receiver.segment_written() {
+                        sender.handle_ack(ack).unwrap();
+                    }
+                }
+                ReceiverAction::Finish(frame) => sender.handle_end_session(frame).unwrap(),
+                action => panic!("unexpected {:?}", action),
+            }
+        }
+        written
+    }
+
+    proptest! {
+        #[test]
+        fn received_bytes_match_sent_bytes(
+            file in proptest::collection::vec(any::<u8>(), 0..8192),
+            chunk_size in 1..2048usize,
+            ack_window in 1..16u32,
+            schedule in proptest::collection::vec(any::<bool>(), 1..32),
+        ) {
+            let mut sender = SenderSession::new();
+            let mut receiver = ReceiverSession::new();
+            sender.set_transfer_config(TransferConfig {
+                window_size: ack_window,
+                ..TransferConfig::default()
+            });
+            receiver.set_ack_window(ack_window);
+            let written =
+                run_transfer(&mut sender, &mut receiver, &file, chunk_size, &schedule, None);
+
+            prop_assert_eq!(sender.bytes_sent(), file.len() as u64);
+            prop_assert_eq!(written, file);
+            prop_assert_eq!(sender.state(), SessionState::Done);
+            prop_assert_eq!(receiver.state(), SessionState::Done);
+            let segments = receiver.segments_written();
+            prop_assert_eq!(sender.segments_acked(), segments - segments % ack_window);
+        }
+
+        #[test]
+        fn disconnects_leave_a_consistent_prefix(
+            file in proptest::collection::vec(any::<u8>(), 1..8192),
+            chunk_size in 1..2048usize,
+            ack_window in 1..16u32,
+            schedule in proptest::collection::vec(any::<bool>(), 1..32),
+            disconnect_after in 0..64usize,
+        ) {
+            let mut sender = SenderSession::new();
+            let mut receiver = ReceiverSession::new();
+            sender.set_transfer_config(TransferConfig {
+                window_size: ack_window,
+                ..TransferConfig::default()
+            });
+            receiver.set_ack_window(ack_window);
+            let written = run_transfer(
+                &mut sender,
+                &mut receiver,
+                &file,
+                chunk_size,
+                &schedule,
+                Some(disconnect_after),
+            );
+
+            // Whatever made it across is exactly the start of the file, and nothing the receiver
+            // hasn't written has been acked.
+            prop_assert!(file.starts_with(&written));
+            prop_assert!(sender.segments_acked() <= receiver.segments_written());
+            prop_assert!(sender.bytes_acked() <= written.len() as u64);
+            if receiver.state() == SessionState::Done {
+                prop_assert_eq!(written, file);
+            }
+        }
+    }
+}
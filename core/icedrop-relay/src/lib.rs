@@ -0,0 +1,256 @@
+//! A relay for peers that can't connect to each other directly, e.g. because both are behind NAT.
+//!
+//! Senders and receivers connect out to the relay and name the session they belong to in a
+//! [`RelayConnectFrame`]. Once both ends of a session have arrived, the relay pipes their
+//! connections together. It never looks at what is sent through, so sessions secured end to end
+//! stay that way.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use icedrop_proto::codec::FrameHeader;
+use icedrop_proto::frame_types;
+use icedrop_proto::relay::{RelayConnectFrame, RelayRole, RelayToken};
+use icedrop_proto::{Frame, FrameParsingResult};
+use log::{info, warn};
+use tokio::io::AsyncReadExt;
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::oneshot;
+
+/// The port relays listen on unless told otherwise.
+pub const DEFAULT_RELAY_PORT: u16 = 7370;
+
+/// How long one end of a session waits for the other one unless told otherwise.
+pub const DEFAULT_PEER_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// How long a new connection has to send its connect frame.
+const CONNECT_FRAME_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Connect frames are a lot shorter, anything longer isn't one.
+const MAX_CONNECT_FRAME_LEN: u32 = 64;
+
+/// The end of a session waiting for the other one, which is handed over through `stream_tx`.
+struct WaitingPeer {
+    role: RelayRole,
+    stream_tx: oneshot::Sender<TcpStream>,
+}
+
+type WaitingPeers = Arc<Mutex<HashMap<RelayToken, WaitingPeer>>>;
+
+pub struct Relay {
+    listener: TcpListener,
+    waiting: WaitingPeers,
+    peer_timeout: Duration,
+}
+
+impl Relay {
+    /// Starts listening on `addr`.
+    pub async fn bind<A>(addr: A) -> io::Result<Self>
+    where
+        A: ToSocketAddrs,
+    {
+        Ok(Self {
+            listener: TcpListener::bind(addr).await?,
+            waiting: WaitingPeers::default(),
+            peer_timeout: DEFAULT_PEER_TIMEOUT,
+        })
+    }
+
+    /// The address the relay listens on, e.g. to find out the port picked when binding to port 0.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Sets how long one end of a session waits for the other one before it's disconnected.
+    pub fn set_peer_timeout(&mut self, timeout: Duration) {
+        self.peer_timeout = timeout;
+    }
+
+    /// Pipes sessions until the task is cancelled.
+    pub async fn run(&mut self) {
+        loop {
+            match self.listener.accept().await {
+                Ok((stream, addr)) => {
+                    tokio::spawn(Self::serve(
+                        stream,
+                        addr,
+                        Arc::clone(&self.waiting),
+                        self.peer_timeout,
+                    ));
+                }
+                Err(err) => warn!("could not accept new connection: {}", err),
+            }
+        }
+    }
+
+    async fn serve(
+        mut stream: TcpStream,
+        addr: SocketAddr,
+        waiting: WaitingPeers,
+        peer_timeout: Duration,
+    ) {
+        let frame = match tokio::time::timeout(
+            CONNECT_FRAME_TIMEOUT,
+            read_connect_frame(&mut stream),
+        )
+        .await
+        {
+            Ok(Ok(frame)) => frame,
+            Ok(Err(err)) => {
+                warn!("{} did not connect properly: {}", addr, err);
+                return;
+            }
+            Err(_) => {
+                warn!("{} did not send a connect frame in time", addr);
+                return;
+            }
+        };
+
+        let stream_rx = {
+            let mut waiting = waiting.lock().unwrap();
+            match waiting.remove(&frame.token) {
+                Some(peer) if !peer.stream_tx.is_closed() && peer.role == frame.role.peer() => {
+                    if peer.stream_tx.send(stream).is_err() {
+                        warn!("the peer of {} went away", addr);
+                    }
+                    return;
+                }
+                Some(peer) if !peer.stream_tx.is_closed() => {
+                    warn!("{} joined a session as {:?} twice", addr, frame.role);
+                    waiting.insert(frame.token, peer);
+                    return;
+                }
+                _ => {
+                    let (stream_tx, stream_rx) = oneshot::channel();
+                    let peer = WaitingPeer {
+                        role: frame.role,
+                        stream_tx,
+                    };
+                    waiting.insert(frame.token, peer);
+                    stream_rx
+                }
+            }
+        };
+
+        info!("{} is waiting for its peer as {:?}", addr, frame.role);
+        let mut peer_stream = match tokio::time::timeout(peer_timeout, stream_rx).await {
+            Ok(Ok(peer_stream)) => peer_stream,
+            _ => {
+                info!("the peer of {} did not show up", addr);
+                // Also drops whoever else has stopped waiting.
+                waiting
+                    .lock()
+                    .unwrap()
+                    .retain(|_, peer| !peer.stream_tx.is_closed());
+                return;
+            }
+        };
+        match tokio::io::copy_bidirectional(&mut stream, &mut peer_stream).await {
+            Ok((to_peer, from_peer)) => info!(
+                "session of {} is over, {} bytes sent and {} bytes received",
+                addr, to_peer, from_peer
+            ),
+            Err(err) => info!("session of {} failed: {}", addr, err),
+        }
+    }
+}
+
+/// Reads the frame every connection to the relay starts with, and nothing after it.
+async fn read_connect_frame(stream: &mut TcpStream) -> io::Result<RelayConnectFrame> {
+    let mut header_buf = [0u8; 6];
+    stream.read_exact(&mut header_buf).await?;
+    let header = FrameHeader::parse(1, &header_buf);
+    if header.frame_type != frame_types::RELAY_CONNECT || header.frame_len > MAX_CONNECT_FRAME_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "expected a relay connect frame",
+        ));
+    }
+
+    let mut payload = vec![0; header.frame_len as usize];
+    stream.read_exact(&mut payload).await?;
+    match RelayConnectFrame::try_parse(header.frame_type, payload) {
+        FrameParsingResult::Ok(frame) => Ok(frame),
+        FrameParsingResult::Err(err) => {
+            Err(io::Error::new(io::ErrorKind::InvalidData, err.to_string()))
+        }
+        FrameParsingResult::Skip(_) => unreachable!("the frame type was checked"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Relay;
+
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use icedrop_proto::codec::FrameWithHeader;
+    use icedrop_proto::relay::{RelayConnectFrame, RelayRole, RelayToken};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio::runtime::Runtime;
+
+    async fn join(addr: SocketAddr, role: RelayRole, token: RelayToken) -> TcpStream {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let frame = RelayConnectFrame { role, token };
+        let buf = FrameWithHeader { frame }.to_bytes(1);
+        stream.write_all(&buf).await.unwrap();
+        stream
+    }
+
+    #[test]
+    fn sessions_are_piped_by_token() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let mut relay = Relay::bind("127.0.0.1:0").await.unwrap();
+            let addr = relay.local_addr().unwrap();
+            let relay_task = tokio::spawn(async move { relay.run().await });
+
+            let mut receiver = join(addr, RelayRole::Receiver, [1; 16]).await;
+            let mut other_receiver = join(addr, RelayRole::Receiver, [2; 16]).await;
+            // The sender may start talking before the relay paired it up.
+            let mut sender = join(addr, RelayRole::Sender, [1; 16]).await;
+            sender.write_all(b"hello").await.unwrap();
+
+            let mut buf = [0; 5];
+            receiver.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+            receiver.write_all(b"hi").await.unwrap();
+            sender.read_exact(&mut buf[..2]).await.unwrap();
+            assert_eq!(&buf[..2], b"hi");
+
+            let read =
+                tokio::time::timeout(Duration::from_millis(100), other_receiver.read(&mut buf))
+                    .await;
+            assert!(read.is_err(), "a session with another token got data");
+            relay_task.abort();
+        });
+    }
+
+    #[test]
+    fn peers_not_showing_up_are_given_up_on() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let mut relay = Relay::bind("127.0.0.1:0").await.unwrap();
+            relay.set_peer_timeout(Duration::from_millis(50));
+            let addr = relay.local_addr().unwrap();
+            let relay_task = tokio::spawn(async move { relay.run().await });
+
+            let mut receiver = join(addr, RelayRole::Receiver, [1; 16]).await;
+            let mut buf = [0; 1];
+            assert_eq!(receiver.read(&mut buf).await.unwrap(), 0);
+
+            // Nobody is paired with a peer that gave up.
+            let mut receiver = join(addr, RelayRole::Receiver, [1; 16]).await;
+            let mut sender = join(addr, RelayRole::Sender, [1; 16]).await;
+            sender.write_all(b"x").await.unwrap();
+            receiver.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"x");
+            relay_task.abort();
+        });
+    }
+}
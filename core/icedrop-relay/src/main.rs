@@ -0,0 +1,64 @@
+//! The `icedrop-relay` server: pipes sessions between senders and receivers that can't connect to
+//! each other directly.
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::process::ExitCode;
+use std::time::Duration;
+
+use clap::{ArgAction, Parser};
+use icedrop_relay::{Relay, DEFAULT_PEER_TIMEOUT, DEFAULT_RELAY_PORT};
+use log::LevelFilter;
+
+/// Relays icedrop sessions between peers that can't connect to each other directly.
+#[derive(Parser)]
+#[command(name = "icedrop-relay", version)]
+struct Cli {
+    /// The port connections are accepted on.
+    #[arg(short, long, default_value_t = DEFAULT_RELAY_PORT)]
+    port: u16,
+    /// How long one end of a session waits for the other one, in seconds.
+    #[arg(long, default_value_t = DEFAULT_PEER_TIMEOUT.as_secs())]
+    peer_timeout: u64,
+    /// Logs more, up to twice.
+    #[arg(short, long, action = ArgAction::Count)]
+    verbose: u8,
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let level = match cli.verbose {
+        0 => LevelFilter::Info,
+        1 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    env_logger::Builder::new()
+        .filter_level(level)
+        .parse_default_env()
+        .init();
+
+    let bind_addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, cli.port));
+    let mut relay = match Relay::bind(bind_addr).await {
+        Ok(relay) => relay,
+        Err(err) => {
+            eprintln!("could not listen on port {}: {}", cli.port, err);
+            return ExitCode::FAILURE;
+        }
+    };
+    relay.set_peer_timeout(Duration::from_secs(cli.peer_timeout));
+    println!("relaying on port {}", cli.port);
+    relay.run().await;
+    ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Cli;
+
+    use clap::CommandFactory;
+
+    #[test]
+    fn cli_is_well_formed() {
+        Cli::command().debug_assert();
+    }
+}
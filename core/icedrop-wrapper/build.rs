@@ -13,6 +13,8 @@ fn main() {
         .with_language(cbindgen::Language::C)
         .with_style(cbindgen::Style::Both)
         .with_include_guard("ICEDROP_H")
+        .with_define("target_os", "windows", "_WIN32")
+        .rename_item("IcedropTransfer", "icedrop_transfer_t")
         .with_autogen_warning("//\n// THIS IS A GENERATED FILE, DO NOT EDIT!!\n//")
         .generate()
         .expect("Unable to generate bindings")
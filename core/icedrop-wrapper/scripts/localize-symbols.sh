@@ -0,0 +1,39 @@
+#!/bin/sh
+#
+# Turns every global symbol of the wrapper staticlib into a local one, except for the C API listed
+# in `exported_symbols.txt`. Without this, the Rust std and dependency symbols bundled in the
+# archive can clash with other static libraries linked into the same app.
+#
+# Usage: localize-symbols.sh <libicedrop_wrapper.a> <output.a>
+#
+# Works with ELF (Linux, Android) and Mach-O (iOS, macOS) archives. LD, OBJCOPY and AR can be
+# overridden to point at a cross toolchain, e.g. the ones shipped with the Android NDK.
+
+set -eu
+
+if [ $# -ne 2 ]; then
+    echo "usage: $0 <libicedrop_wrapper.a> <output.a>" >&2
+    exit 1
+fi
+
+input=$(cd "$(dirname "$1")" && pwd)/$(basename "$1")
+output=$2
+symbols_file=$(cd "$(dirname "$0")/.." && pwd)/exported_symbols.txt
+
+work_dir=$(mktemp -d)
+trap 'rm -rf "$work_dir"' EXIT
+
+# Partially link the whole archive into a single object, so that references between its members
+# are resolved before the internal symbols become local.
+if [ "$(uname)" = "Darwin" ]; then
+    # Mach-O symbol names carry a leading underscore.
+    sed 's/^/_/' "$symbols_file" > "$work_dir/exported_symbols.txt"
+    ${LD:-ld} -r -all_load -exported_symbols_list "$work_dir/exported_symbols.txt" \
+        "$input" -o "$work_dir/icedrop.o"
+else
+    ${LD:-ld} -r --whole-archive "$input" -o "$work_dir/icedrop.o"
+    ${OBJCOPY:-objcopy} --wildcard --keep-global-symbols="$symbols_file" "$work_dir/icedrop.o"
+fi
+
+rm -f "$output"
+${AR:-ar} rcs "$output" "$work_dir/icedrop.o"
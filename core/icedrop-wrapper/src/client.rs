@@ -1,54 +1,135 @@
 use std::ffi::c_void;
 use std::fs::File as StdFile;
-use std::os::unix::prelude::FromRawFd;
-use std::path::Path;
+use std::net::SocketAddr;
+#[cfg(not(target_os = "windows"))]
+use std::os::unix::io::FromRawFd;
+#[cfg(target_os = "windows")]
+use std::os::windows::io::FromRawHandle;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
 
+use async_trait::async_trait;
 use tokio::fs::File;
 use tokio::runtime;
+use tokio::select;
 use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::Notify;
 
-use icedrop_core::Client;
+use icedrop_core::{
+    Client, Decision, Error, HistoryStore, PairingRequest, PairingStore, PeerIdentity, PeerRole,
+    ReceiveEvent, Server, ServerDelegate, TransferControl, TransferOffer,
+};
 
-pub trait ClientRequest {
-    fn execute(self: Box<Self>, client: &mut IcedropClient);
+use crate::{
+    error_code, set_last_error, IcedropBatchProgress, IcedropStatus, IcedropTransferProgress,
+    IcedropTransferSummary, ICEDROP_ERROR_NONE,
+};
+
+/// How many requests may wait for the thread running the client.
+const REQUEST_QUEUE_CAPACITY: usize = 100;
+
+pub trait ClientRequest: Send {
+    fn execute(self: Box<Self>, settings: &mut ClientSettings);
 }
 
+/// Shared by every thread holding a reference to the client, which only ever hands requests over
+/// to the thread running it.
 pub struct IcedropClient {
-    req_rx: Receiver<Box<dyn ClientRequest>>,
+    /// Taken by the thread running the client, and put back once it stops.
+    req_rx: Mutex<Option<Receiver<Box<dyn ClientRequest>>>>,
     req_tx: Sender<Box<dyn ClientRequest>>,
+    stop: Notify,
 }
 
 impl IcedropClient {
     pub fn new() -> Self {
-        let (tx, rx) = channel(100);
+        let (tx, rx) = channel(REQUEST_QUEUE_CAPACITY);
         Self {
-            req_rx: rx,
+            req_rx: Mutex::new(Some(rx)),
             req_tx: tx,
+            stop: Notify::new(),
         }
     }
 
-    pub fn run_in_current_thread(&mut self) {
+    /// Executes the requests handed over until [`stop`](Self::stop) is called. Returns right
+    /// away if the client is run by another thread already.
+    pub fn run_in_current_thread(&self) {
+        let mut req_rx = match self.req_rx.lock().unwrap().take() {
+            Some(req_rx) => req_rx,
+            None => {
+                println!("the client is running already");
+                return;
+            }
+        };
         let rt = runtime::Builder::new_current_thread()
             .thread_name("icedrop-client")
             .enable_all()
             .build()
             .unwrap();
 
+        let mut settings = ClientSettings::default();
         rt.block_on(async {
             loop {
-                let req = self.req_rx.recv().await.unwrap();
-                req.execute(self);
+                select! {
+                    req = req_rx.recv() => match req {
+                        Some(req) => req.execute(&mut settings),
+                        None => break,
+                    },
+                    _ = self.stop.notified() => break,
+                }
             }
         });
+        *self.req_rx.lock().unwrap() = Some(req_rx);
+    }
+
+    /// Makes the thread running the client return, or the next one to run it if none does.
+    pub fn stop(&self) {
+        self.stop.notify_one();
     }
 
-    pub fn send_request<R>(&self, req: R)
+    /// Queues `req` for the thread running the client without waiting, so it may be called from
+    /// any thread, callbacks included. Fails with [`IcedropStatus::Busy`] while the queue is full,
+    /// dropping `req`.
+    pub fn send_request<R>(&self, req: R) -> Result<(), IcedropStatus>
     where
         R: ClientRequest + 'static,
     {
-        let tx = self.req_tx.clone();
-        tx.blocking_send(Box::new(req)).ok().unwrap(); // TODO: this may fail.
+        self.try_send_request(req).map_err(|_| IcedropStatus::Busy)
     }
+
+    /// Like [`send_request`](Self::send_request), but hands `req` back while the queue is full.
+    pub fn try_send_request<R>(&self, req: R) -> Result<(), R>
+    where
+        R: ClientRequest + 'static,
+    {
+        match self.req_tx.try_reserve() {
+            Ok(permit) => {
+                permit.send(Box::new(req));
+                Ok(())
+            }
+            Err(_) => {
+                set_last_error(format_args!(
+                    "the client has {} requests queued already, is it running?",
+                    REQUEST_QUEUE_CAPACITY
+                ));
+                Err(req)
+            }
+        }
+    }
+}
+
+/// What requests set for the ones executed after them, owned by the thread running the client.
+#[derive(Default)]
+pub struct ClientSettings {
+    /// Bytes per second transfers started from now on are capped at.
+    rate_limit: Option<u64>,
+    /// How transfers started from now on pair with other devices.
+    pairing: Option<Pairing>,
+    /// Where transfers started from now on are recorded.
+    history: Option<Arc<HistoryStore>>,
+    /// Where receivers started from now on hand the text they're sent.
+    text: Option<TextReceiving>,
 }
 
 pub struct UserInfoPtr(pub *mut c_void);
@@ -61,69 +142,542 @@ impl Clone for UserInfoPtr {
 
 unsafe impl Send for UserInfoPtr {}
 
-pub struct SendFileRequest {
-    pub remote_addr: String,
+// The pointer is only ever handed back to the callbacks given along with it. Those are called
+// from the client's thread, but also from the thread the pairing prompt blocks on and from
+// threads calling the wrapper, so C has to make `user_info` safe to share between them, as
+// documented on `icedrop_client_new`.
+unsafe impl Sync for UserInfoPtr {}
+
+/// Releases the `user_info` the wrapper was handed ownership of once dropped, after the
+/// callbacks given along with it are done with.
+pub struct UserInfoRelease {
+    user_info: UserInfoPtr,
+    release: unsafe extern "C" fn(*mut c_void),
+}
+
+impl UserInfoRelease {
+    pub fn new(user_info: *mut c_void, release: unsafe extern "C" fn(*mut c_void)) -> Self {
+        Self {
+            user_info: UserInfoPtr(user_info),
+            release,
+        }
+    }
+}
+
+impl Drop for UserInfoRelease {
+    fn drop(&mut self) {
+        unsafe { (self.release)(self.user_info.0) };
+    }
+}
+
+type SegmentSentCallbackFn = Box<dyn Fn(*mut c_void, u32, usize) + Send>;
+type ProgressCallbackFn = Box<dyn Fn(*mut c_void, u32, IcedropTransferProgress) + Send>;
+type BatchProgressCallbackFn = Box<dyn Fn(*mut c_void, IcedropBatchProgress) + Send + Sync>;
+
+/// A file to send.
+pub struct QueuedFile {
     pub file: StdFile,
-    pub user_info: UserInfoPtr,
-    pub segment_sent_callback: Option<Box<dyn Fn(*mut c_void, u32, usize) + Send>>,
-    pub completed_callback: Option<Box<dyn Fn(*mut c_void) + Send>>,
+    /// The name the receiver stores the file under, a default one if not set.
+    pub file_name: Option<String>,
+    /// Whether the file is a descriptor or handle the caller passed in, which is theirs again if
+    /// the request can't be made, see [`SendFileRequest::give_back_files`].
+    pub from_caller: bool,
 }
 
-impl SendFileRequest {
-    pub fn new<A, F>(remote_addr: A, local_file_path: F) -> Result<Self, std::io::Error>
+impl QueuedFile {
+    /// Opens the file at `local_file_path`, the receiver stores it under the same name.
+    pub fn open<F>(local_file_path: F) -> Result<Self, std::io::Error>
     where
-        A: Into<String>,
         F: AsRef<Path>,
     {
+        let local_file_path = local_file_path.as_ref();
         let file = StdFile::open(local_file_path)?;
-        Ok(SendFileRequest {
-            remote_addr: remote_addr.into(),
+        Ok(QueuedFile {
             file,
-            user_info: UserInfoPtr(std::ptr::null_mut()),
-            segment_sent_callback: None,
-            completed_callback: None,
+            file_name: local_file_path
+                .file_name()
+                .map(|file_name| file_name.to_string_lossy().into_owned()),
+            from_caller: false,
         })
     }
 
+    fn from_caller(file: StdFile) -> Self {
+        QueuedFile {
+            file,
+            file_name: None,
+            from_caller: true,
+        }
+    }
+}
+
+pub struct SendFileRequest {
+    pub remote_addr: String,
+    /// The files sent over the connection, in order.
+    pub files: Vec<QueuedFile>,
+    pub user_info: UserInfoPtr,
+    pub segment_sent_callback: Option<SegmentSentCallbackFn>,
+    /// Gets the progress of the file being sent along with its position in `files`.
+    pub progress_callback: Option<ProgressCallbackFn>,
+    pub batch_progress_callback: Option<BatchProgressCallbackFn>,
+    pub completed_callback: Option<Box<dyn Fn(*mut c_void, IcedropTransferSummary) + Send>>,
+    /// Releases `user_info` once the transfer is over.
+    pub release_user_info: Option<UserInfoRelease>,
+    /// Pauses, resumes or cancels the transfer once it's started, even before it's connected.
+    pub control: TransferControl,
+}
+
+impl SendFileRequest {
+    #[cfg(not(target_os = "windows"))]
     pub fn with_fd<A>(remote_addr: A, file_fd: i32) -> Self
     where
         A: Into<String>,
     {
         let file = unsafe { StdFile::from_raw_fd(file_fd) };
+        Self::with_files(remote_addr, vec![QueuedFile::from_caller(file)])
+    }
+
+    #[cfg(target_os = "windows")]
+    pub fn with_handle<A>(remote_addr: A, file_handle: *mut c_void) -> Self
+    where
+        A: Into<String>,
+    {
+        let file = unsafe { StdFile::from_raw_handle(file_handle) };
+        Self::with_files(remote_addr, vec![QueuedFile::from_caller(file)])
+    }
+
+    pub fn with_files<A>(remote_addr: A, files: Vec<QueuedFile>) -> Self
+    where
+        A: Into<String>,
+    {
         SendFileRequest {
             remote_addr: remote_addr.into(),
-            file,
+            files,
             user_info: UserInfoPtr(std::ptr::null_mut()),
             segment_sent_callback: None,
+            progress_callback: None,
+            batch_progress_callback: None,
             completed_callback: None,
+            release_user_info: None,
+            control: TransferControl::new(),
+        }
+    }
+
+    /// Leaves the files the caller passed in as a descriptor or handle open for it to close, as
+    /// the request couldn't be made. The other files are closed.
+    pub fn give_back_files(self) {
+        for queued in self.files {
+            if queued.from_caller {
+                std::mem::forget(queued.file);
+            }
+        }
+    }
+}
+
+/// Tells how far sending all files of a request has come from the progress of the one being
+/// sent.
+struct BatchTally {
+    /// The sizes of the files, 0 for those that couldn't be read.
+    file_sizes: Vec<u64>,
+    files_completed: AtomicU32,
+}
+
+impl BatchTally {
+    fn new(files: &[QueuedFile]) -> Self {
+        let file_sizes = files
+            .iter()
+            .map(|queued| queued.file.metadata().map_or(0, |metadata| metadata.len()))
+            .collect();
+        BatchTally {
+            file_sizes,
+            files_completed: AtomicU32::new(0),
+        }
+    }
+
+    /// The position of the file being sent. The receiver confirms every file before the next
+    /// one is sent, so it's the number of files completed.
+    fn current_file(&self) -> u32 {
+        self.files_completed.load(Ordering::SeqCst)
+    }
+
+    fn file_completed(&self, transfer_id: u32) {
+        self.files_completed
+            .fetch_max(transfer_id + 1, Ordering::SeqCst);
+    }
+
+    /// The progress over all files, with `bytes_confirmed` of the file being sent.
+    fn progress(&self, bytes_confirmed: u64) -> IcedropBatchProgress {
+        let file_count = self.file_sizes.len();
+        let files_completed = (self.current_file() as usize).min(file_count);
+        let mut bytes_completed: u64 = self.file_sizes[..files_completed].iter().sum();
+        if files_completed < file_count {
+            bytes_completed += bytes_confirmed;
+        }
+        let total_bytes = self.file_sizes.iter().sum();
+        IcedropBatchProgress {
+            files_completed: files_completed as u32,
+            file_count: file_count as u32,
+            bytes_confirmed: bytes_completed,
+            total_bytes,
+            percentage: match total_bytes {
+                0 => files_completed as f64 * 100.0 / file_count.max(1) as f64,
+                _ => bytes_completed as f64 * 100.0 / total_bytes as f64,
+            },
         }
     }
 }
 
 impl ClientRequest for SendFileRequest {
-    fn execute(self: Box<Self>, _client: &mut IcedropClient) {
+    fn execute(self: Box<Self>, settings: &mut ClientSettings) {
+        let rate_limit = settings.rate_limit;
+        let pairing = settings.pairing.clone();
+        let history = settings.history.clone();
         runtime::Handle::current().spawn(async move {
-            let client = Client::connect(self.remote_addr).await;
-            if client.is_err() {
-                // TODO: add error handling.
-                return;
+            // Dropped last, once none of the callbacks can be called anymore.
+            let _release_user_info = self.release_user_info;
+            let connected = if self.control.is_cancelled() {
+                Err(Error::Cancelled)
+            } else {
+                Client::connect(self.remote_addr).await.map_err(Error::from)
+            };
+            let mut client = match connected {
+                Ok(client) => client,
+                Err(err) => {
+                    println!("{}", err);
+                    set_last_error(&err);
+                    if let Some(cb) = self.completed_callback {
+                        let summary = IcedropTransferSummary::failed(err);
+                        cb(self.user_info.0, summary);
+                    }
+                    return;
+                }
+            };
+            client.set_rate_limit(rate_limit);
+            client.set_control(self.control);
+            if let Some(history) = history {
+                client.set_history(history);
+            }
+            if let Some(pairing) = pairing {
+                let prompt_callback = pairing.prompt_callback;
+                let user_info = pairing.user_info;
+                client.set_pairing(pairing.store, move || {
+                    let cb = prompt_callback.as_ref()?;
+                    cb(user_info.0)
+                });
+            }
+            let batch = Arc::new(BatchTally::new(&self.files));
+            for queued in self.files {
+                let file = File::from_std(queued.file);
+                match &queued.file_name {
+                    Some(file_name) => client.queue_file(file_name, file),
+                    None => client.set_file(file),
+                }
             }
-
-            let mut client = client.unwrap();
-            client.set_file(File::from_std(self.file));
             if let Some(cb) = self.segment_sent_callback {
                 let user_info = self.user_info.clone();
                 client.set_segment_sent_callback(move |segment_idx, bytes_sent| {
-                    cb.call((user_info.0, segment_idx, bytes_sent));
+                    cb(user_info.0, segment_idx, bytes_sent);
+                });
+            }
+            if self.progress_callback.is_some() || self.batch_progress_callback.is_some() {
+                let progress_callback = self.progress_callback;
+                let batch_progress_callback = self.batch_progress_callback.map(Arc::new);
+                let user_info = self.user_info.clone();
+                let batch_clone = Arc::clone(&batch);
+                let batch_progress_callback_clone = batch_progress_callback.clone();
+                client.set_progress_callback(move |progress| {
+                    let batch = &batch_clone;
+                    if let Some(cb) = &progress_callback {
+                        let progress = IcedropTransferProgress::from(progress);
+                        cb(user_info.0, batch.current_file(), progress);
+                    }
+                    if let Some(cb) = &batch_progress_callback_clone {
+                        cb(user_info.0, batch.progress(progress.bytes_confirmed));
+                    }
+                });
+                let user_info = self.user_info.clone();
+                client.set_file_completed_callback(move |transfer_id, _| {
+                    batch.file_completed(transfer_id);
+                    if let Some(cb) = &batch_progress_callback {
+                        cb(user_info.0, batch.progress(0));
+                    }
                 });
             }
+            let summary = client.run().await;
+            if let Some(err) = &summary.error {
+                set_last_error(err);
+            }
             if let Some(cb) = self.completed_callback {
+                cb(self.user_info.0, IcedropTransferSummary::from(summary));
+            }
+        });
+    }
+}
+
+type OfferCallbackFn = Box<dyn Fn(*mut c_void, &str, SocketAddr) -> bool + Send + Sync>;
+type ReceiveProgressCallbackFn = Box<dyn Fn(*mut c_void, u64, u64) + Send + Sync>;
+type ReceivedCallbackFn = Box<dyn Fn(*mut c_void, &Path, u64) + Send + Sync>;
+
+pub struct StartReceiverRequest {
+    pub bind_addr: String,
+    /// The directory received files are written to, it must exist.
+    pub dest_dir: PathBuf,
+    pub user_info: UserInfoPtr,
+    /// Decides whether to receive from a sender, given its display name and address.
+    pub offer_callback: Option<OfferCallbackFn>,
+    /// Given the bytes of the file being received so far, and its size or 0 if unknown.
+    pub progress_callback: Option<ReceiveProgressCallbackFn>,
+    /// Given the path a file has been received to, and its size.
+    pub received_callback: Option<ReceivedCallbackFn>,
+}
+
+impl StartReceiverRequest {
+    pub fn new<A, P>(bind_addr: A, dest_dir: P) -> Self
+    where
+        A: Into<String>,
+        P: AsRef<Path>,
+    {
+        StartReceiverRequest {
+            bind_addr: bind_addr.into(),
+            dest_dir: dest_dir.as_ref().to_owned(),
+            user_info: UserInfoPtr(std::ptr::null_mut()),
+            offer_callback: None,
+            progress_callback: None,
+            received_callback: None,
+        }
+    }
+}
+
+impl ClientRequest for StartReceiverRequest {
+    fn execute(self: Box<Self>, settings: &mut ClientSettings) {
+        let rate_limit = settings.rate_limit;
+        let pairing = settings.pairing.clone();
+        let history = settings.history.clone();
+        let text = settings.text.clone();
+        runtime::Handle::current().spawn(async move {
+            let mut builder = Server::builder()
+                .dest_dir(&self.dest_dir)
+                .rate_limit(rate_limit);
+            if let Some(history) = history {
+                builder = builder.history(history);
+            }
+            if let Some(text) = text {
+                builder = builder.delegate(text);
+            }
+            if let Some(pairing) = pairing {
+                if let Some(cb) = pairing.code_callback {
+                    let user_info = pairing.user_info;
+                    builder = builder.pairing(pairing.store, move |request| {
+                        cb(user_info.0, &request);
+                    });
+                }
+            }
+            if let Some(cb) = self.offer_callback {
                 let user_info = self.user_info.clone();
-                client.set_completed_callback(move || {
-                    cb.call((user_info.0,));
+                builder = builder.handshake_callback(move |identity| {
+                    let accepted = cb(user_info.0, identity.name.as_str(), identity.addr);
+                    async move {
+                        if accepted {
+                            Ok(PeerRole::Full)
+                        } else {
+                            Err("the receiver declined the transfer".to_owned())
+                        }
+                    }
                 });
             }
-            client.run().await;
+            if self.progress_callback.is_some() || self.received_callback.is_some() {
+                let user_info = self.user_info.clone();
+                let progress_callback = self.progress_callback;
+                let received_callback = self.received_callback;
+                builder = builder.event_callback(move |event| match event {
+                    ReceiveEvent::Progress {
+                        bytes_received,
+                        file_size,
+                    } => {
+                        if let Some(cb) = &progress_callback {
+                            cb(user_info.0, bytes_received, file_size.unwrap_or(0));
+                        }
+                    }
+                    ReceiveEvent::FileReceived { path, bytes } => {
+                        if let Some(cb) = &received_callback {
+                            cb(user_info.0, path.as_path(), bytes);
+                        }
+                    }
+                });
+            }
+            match builder.bind(self.bind_addr.as_str()).await {
+                Ok(mut server) => server.run().await,
+                Err(err) => println!("could not receive on {}: {}", self.bind_addr, err),
+            }
+        });
+    }
+}
+
+/// Sends a short text instead of a file.
+pub struct SendTextRequest {
+    pub remote_addr: String,
+    pub text: String,
+    pub user_info: UserInfoPtr,
+    /// Gets `ICEDROP_ERROR_NONE` once the receiver took the text, or why it didn't.
+    pub completed_callback: Option<Box<dyn Fn(*mut c_void, u32) + Send>>,
+}
+
+impl ClientRequest for SendTextRequest {
+    fn execute(self: Box<Self>, settings: &mut ClientSettings) {
+        let pairing = settings.pairing.clone();
+        runtime::Handle::current().spawn(async move {
+            let result = match Client::connect(self.remote_addr).await {
+                Ok(mut client) => {
+                    if let Some(pairing) = pairing {
+                        let prompt_callback = pairing.prompt_callback;
+                        let user_info = pairing.user_info;
+                        client.set_pairing(pairing.store, move || {
+                            let cb = prompt_callback.as_ref()?;
+                            cb(user_info.0)
+                        });
+                    }
+                    client.send_text(&self.text).await
+                }
+                Err(err) => Err(Error::from(err)),
+            };
+            if let Err(err) = &result {
+                println!("{}", err);
+                set_last_error(err);
+            }
+            if let Some(cb) = self.completed_callback {
+                let error = result.as_ref().err();
+                cb(
+                    self.user_info.0,
+                    error.map_or(ICEDROP_ERROR_NONE, error_code),
+                );
+            }
+        });
+    }
+}
+
+type TextCallbackFn = Arc<dyn Fn(*mut c_void, &PeerIdentity, &str) + Send + Sync>;
+
+/// Where receivers hand the text they're sent, see [`SetTextCallbackRequest`].
+#[derive(Clone)]
+pub struct TextReceiving {
+    user_info: UserInfoPtr,
+    text_callback: TextCallbackFn,
+}
+
+#[async_trait]
+impl ServerDelegate for TextReceiving {
+    async fn should_accept(&self, _offer: TransferOffer) -> Decision {
+        Decision::Accept
+    }
+
+    async fn text_received(&self, sender: PeerIdentity, text: String) -> Result<(), String> {
+        (self.text_callback)(self.user_info.0, &sender, &text);
+        Ok(())
+    }
+}
+
+/// Has the receivers started after it take text, handing it to `text_callback`. Without the
+/// callback they turn text down again.
+pub struct SetTextCallbackRequest {
+    pub user_info: UserInfoPtr,
+    pub text_callback: Option<TextCallbackFn>,
+}
+
+impl ClientRequest for SetTextCallbackRequest {
+    fn execute(self: Box<Self>, settings: &mut ClientSettings) {
+        let user_info = self.user_info;
+        settings.text = self.text_callback.map(|text_callback| TextReceiving {
+            user_info,
+            text_callback,
         });
     }
 }
+
+/// Caps the bandwidth of the transfers started after it.
+pub struct SetRateLimitRequest {
+    pub bytes_per_sec: Option<u64>,
+}
+
+impl ClientRequest for SetRateLimitRequest {
+    fn execute(self: Box<Self>, settings: &mut ClientSettings) {
+        settings.rate_limit = self.bytes_per_sec;
+    }
+}
+
+type PairingPromptCallbackFn = Arc<dyn Fn(*mut c_void) -> Option<String> + Send + Sync>;
+type PairingCodeCallbackFn = Arc<dyn Fn(*mut c_void, &PairingRequest) + Send + Sync>;
+
+/// How transfers pair with other devices, see [`SetPairingRequest`].
+#[derive(Clone)]
+pub struct Pairing {
+    store: Arc<PairingStore>,
+    user_info: UserInfoPtr,
+    prompt_callback: Option<PairingPromptCallbackFn>,
+    code_callback: Option<PairingCodeCallbackFn>,
+}
+
+/// Pairs the transfers started after it with other devices, keeping the keys at `store_path`.
+pub struct SetPairingRequest {
+    pub store_path: PathBuf,
+    pub user_info: UserInfoPtr,
+    /// Asks the user for the code a receiver shows, `None` to give up.
+    pub prompt_callback: Option<PairingPromptCallbackFn>,
+    /// Shows the user the code for a sender asking to pair. Receivers only require pairing with
+    /// it set.
+    pub code_callback: Option<PairingCodeCallbackFn>,
+}
+
+impl SetPairingRequest {
+    pub fn new<P>(store_path: P) -> Self
+    where
+        P: AsRef<Path>,
+    {
+        SetPairingRequest {
+            store_path: store_path.as_ref().to_owned(),
+            user_info: UserInfoPtr(std::ptr::null_mut()),
+            prompt_callback: None,
+            code_callback: None,
+        }
+    }
+}
+
+impl ClientRequest for SetPairingRequest {
+    fn execute(self: Box<Self>, settings: &mut ClientSettings) {
+        settings.pairing = match PairingStore::open(&self.store_path) {
+            Ok(store) => Some(Pairing {
+                store: Arc::new(store),
+                user_info: self.user_info,
+                prompt_callback: self.prompt_callback,
+                code_callback: self.code_callback,
+            }),
+            Err(err) => {
+                println!(
+                    "could not open the pairings at {}: {}",
+                    self.store_path.display(),
+                    err
+                );
+                None
+            }
+        };
+    }
+}
+
+/// Records the transfers started after it in the history at `store_path`.
+pub struct SetHistoryRequest {
+    pub store_path: PathBuf,
+}
+
+impl ClientRequest for SetHistoryRequest {
+    fn execute(self: Box<Self>, settings: &mut ClientSettings) {
+        settings.history = match HistoryStore::open(&self.store_path) {
+            Ok(store) => Some(Arc::new(store)),
+            Err(err) => {
+                println!(
+                    "could not open the history at {}: {}",
+                    self.store_path.display(),
+                    err
+                );
+                None
+            }
+        };
+    }
+}
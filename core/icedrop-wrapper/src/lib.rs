@@ -1,4 +1,3 @@
-#![feature(fn_traits)]
 #![allow(dead_code)]
 
 mod client;
@@ -6,123 +5,1119 @@ mod client;
 #[cfg(test)]
 mod tests;
 
-use std::ffi::{c_void, CStr};
-use std::mem::forget;
+use std::cell::RefCell;
+use std::ffi::{c_void, CStr, CString};
+use std::fmt::Display;
+use std::io;
 use std::os::raw::c_char;
+#[cfg(not(target_os = "windows"))]
+use std::os::unix::ffi::OsStrExt;
+#[cfg(target_os = "windows")]
+use std::os::windows::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-use client::{IcedropClient, SendFileRequest, UserInfoPtr};
+use client::{
+    ClientRequest, IcedropClient, QueuedFile, SendFileRequest, SendTextRequest, SetHistoryRequest,
+    SetPairingRequest, SetRateLimitRequest, SetTextCallbackRequest, StartReceiverRequest,
+    UserInfoPtr, UserInfoRelease,
+};
+use icedrop_core::{
+    Error, HistoryEntry, HistoryStore, TransferControl, TransferDirection, TransferProgress,
+    TransferStatus, TransferSummary,
+};
 
-/// Creates and returns a new [`IcedropClient`] instance. Must be destroyed
-/// via [`icedrop_client_destroy`] function after usage.
+/// Error codes in [`IcedropTransferSummary::error_code`], one for each kind of
+/// `icedrop_core::Error`, with the most common I/O errors told apart.
+pub const ICEDROP_ERROR_NONE: u32 = 0;
+pub const ICEDROP_ERROR_IO: u32 = 1;
+pub const ICEDROP_ERROR_PROTOCOL: u32 = 2;
+pub const ICEDROP_ERROR_HANDSHAKE: u32 = 3;
+pub const ICEDROP_ERROR_CANCELLED: u32 = 4;
+pub const ICEDROP_ERROR_TIMEOUT: u32 = 5;
+pub const ICEDROP_ERROR_REJECTED: u32 = 6;
+/// The file to send doesn't exist.
+pub const ICEDROP_ERROR_NOT_FOUND: u32 = 7;
+/// Nothing was listening at the receiver's address.
+pub const ICEDROP_ERROR_CONNECTION_REFUSED: u32 = 8;
+
+/// The error code reported for `err`.
+fn error_code(err: &Error) -> u32 {
+    match err {
+        Error::Io(err) => match err.kind() {
+            io::ErrorKind::NotFound => ICEDROP_ERROR_NOT_FOUND,
+            io::ErrorKind::ConnectionRefused => ICEDROP_ERROR_CONNECTION_REFUSED,
+            _ => ICEDROP_ERROR_IO,
+        },
+        Error::Protocol(_) => ICEDROP_ERROR_PROTOCOL,
+        Error::Handshake(_) => ICEDROP_ERROR_HANDSHAKE,
+        Error::Cancelled => ICEDROP_ERROR_CANCELLED,
+        Error::Timeout(_) => ICEDROP_ERROR_TIMEOUT,
+        Error::Rejected { .. } => ICEDROP_ERROR_REJECTED,
+    }
+}
+
+/// What the `icedrop_client_*` functions that start something return. On failure,
+/// [`icedrop_last_error_message`] tells what went wrong.
+/// cbindgen:prefix-with-name
+/// cbindgen:rename-all=ScreamingSnakeCase
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IcedropStatus {
+    Ok = 0,
+    /// A pointer was NULL, or a string wasn't valid UTF-8.
+    InvalidArgument = 1,
+    /// The file to send doesn't exist.
+    NotFound = 2,
+    /// The file to send couldn't be opened for another reason.
+    Io = 3,
+    /// The client has too many requests waiting already, e.g. as no thread runs it. Nothing has
+    /// been started, the call may be tried again later.
+    Busy = 4,
+}
+
+impl From<&io::Error> for IcedropStatus {
+    fn from(err: &io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::NotFound => Self::NotFound,
+            _ => Self::Io,
+        }
+    }
+}
+
+thread_local! {
+    /// The message of the last error on this thread, see [`icedrop_last_error_message`].
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Remembers `err` as the last error on this thread.
+pub(crate) fn set_last_error<E>(err: E)
+where
+    E: Display,
+{
+    let message = CString::new(err.to_string().replace('\0', "")).unwrap();
+    LAST_ERROR.with(|last_error| *last_error.borrow_mut() = Some(message));
+}
+
+/// The message of the last error on the calling thread, NULL if there was none. It's set when a
+/// function returns a status other than `ICEDROP_STATUS_OK` or `false`, and before a completion
+/// callback is called for a failed transfer, which may read it from within the callback. The
+/// string is owned by the wrapper and valid until the next error on the same thread.
+#[no_mangle]
+pub extern "C" fn icedrop_last_error_message() -> *const c_char {
+    LAST_ERROR.with(|last_error| {
+        last_error
+            .borrow()
+            .as_ref()
+            .map_or(std::ptr::null(), |message| message.as_ptr())
+    })
+}
+
+/// Turns what `f` returns into the status of a call.
+fn status_of<F>(f: F) -> IcedropStatus
+where
+    F: FnOnce() -> Result<(), IcedropStatus>,
+{
+    match f() {
+        Ok(()) => IcedropStatus::Ok,
+        Err(status) => status,
+    }
+}
+
+/// Fails with [`IcedropStatus::InvalidArgument`] if the argument `name` is NULL.
+fn non_null<T>(ptr: *const T, name: &str) -> Result<(), IcedropStatus> {
+    if ptr.is_null() {
+        set_last_error(format_args!("`{}` is NULL", name));
+        return Err(IcedropStatus::InvalidArgument);
+    }
+    Ok(())
+}
+
+/// The UTF-8 string `ptr` points to, the argument `name`.
+unsafe fn str_from_c<'a>(ptr: *const c_char, name: &str) -> Result<&'a str, IcedropStatus> {
+    non_null(ptr, name)?;
+    CStr::from_ptr(ptr).to_str().map_err(|_| {
+        set_last_error(format_args!("`{}` isn't valid UTF-8", name));
+        IcedropStatus::InvalidArgument
+    })
+}
+
+/// Summary of a transfer, handed to the completion callback once it's over.
+#[repr(C)]
+pub struct IcedropTransferSummary {
+    /// Whether the receiver got the whole file.
+    pub success: bool,
+    /// Bytes the receiver confirmed to have written.
+    pub bytes: u64,
+    pub duration_ms: u64,
+    /// Bytes per second over the whole transfer.
+    pub average_speed: f64,
+    /// Highest rate, in bytes per second, the receiver confirmed data at.
+    pub peak_speed: f64,
+    /// Segments the receiver asked for again.
+    pub retransmits: u32,
+    /// Whether `sha256` holds the digest of the file, only computed with full verification.
+    pub has_sha256: bool,
+    pub sha256: [u8; 32],
+    /// Why the transfer failed, one of the `ICEDROP_ERROR_*` codes.
+    pub error_code: u32,
+}
+
+impl IcedropTransferSummary {
+    /// The summary of a transfer that failed with `error` before anything was sent.
+    pub(crate) fn failed(error: Error) -> Self {
+        Self::from(TransferSummary {
+            success: false,
+            files: Vec::new(),
+            bytes: 0,
+            duration: Default::default(),
+            average_speed: 0.0,
+            peak_speed: 0.0,
+            sha256: None,
+            retransmits: 0,
+            error: Some(error),
+        })
+    }
+}
+
+impl From<TransferSummary> for IcedropTransferSummary {
+    fn from(summary: TransferSummary) -> Self {
+        Self {
+            success: summary.success,
+            bytes: summary.bytes,
+            duration_ms: summary.duration.as_millis() as u64,
+            average_speed: summary.average_speed,
+            peak_speed: summary.peak_speed,
+            retransmits: summary.retransmits,
+            has_sha256: summary.sha256.is_some(),
+            sha256: summary.sha256.unwrap_or_default(),
+            error_code: summary
+                .error
+                .as_ref()
+                .map_or(ICEDROP_ERROR_NONE, error_code),
+        }
+    }
+}
+
+/// Progress of the file being sent, handed to the progress callback whenever it changes.
+#[repr(C)]
+pub struct IcedropTransferProgress {
+    pub bytes_sent: u64,
+    /// Bytes the receiver confirmed to have written, what progress shown to users should use.
+    pub bytes_confirmed: u64,
+    /// Whether `total_bytes` and `percentage` are known, they aren't if the file size couldn't be
+    /// read.
+    pub has_total_bytes: bool,
+    pub total_bytes: u64,
+    /// The share of the file the receiver confirmed, from 0 to 100.
+    pub percentage: f64,
+    /// Bytes per second the receiver confirmed since the file started.
+    pub throughput: f64,
+    /// Whether `eta_ms` is known yet.
+    pub has_eta: bool,
+    /// Time left until the receiver has the whole file at the current throughput.
+    pub eta_ms: u64,
+}
+
+impl From<TransferProgress> for IcedropTransferProgress {
+    fn from(progress: TransferProgress) -> Self {
+        Self {
+            bytes_sent: progress.bytes_sent,
+            bytes_confirmed: progress.bytes_confirmed,
+            has_total_bytes: progress.total_bytes.is_some(),
+            total_bytes: progress.total_bytes.unwrap_or_default(),
+            percentage: progress.percentage().unwrap_or_default(),
+            throughput: progress.throughput,
+            has_eta: progress.eta.is_some(),
+            eta_ms: progress.eta.map_or(0, |eta| eta.as_millis() as u64),
+        }
+    }
+}
+
+/// Progress over all files sent by [`icedrop_client_send_files`], handed to its batch progress
+/// callback whenever it changes.
+#[repr(C)]
+pub struct IcedropBatchProgress {
+    /// Files the receiver confirmed to have written.
+    pub files_completed: u32,
+    pub file_count: u32,
+    /// Bytes the receiver confirmed to have written, over all files.
+    pub bytes_confirmed: u64,
+    /// The size of all files, those whose size couldn't be read counting as empty.
+    pub total_bytes: u64,
+    /// The share of all files the receiver confirmed, from 0 to 100.
+    pub percentage: f64,
+}
+
+/// A transfer recorded in the history, handed to the callback of [`icedrop_history_read`]. The
+/// strings are only valid until the callback returns.
+#[repr(C)]
+pub struct IcedropHistoryEntry {
+    /// When the transfer was over, in milliseconds since the Unix epoch.
+    pub finished_at_ms: u64,
+    /// Whether the file was received rather than sent.
+    pub received: bool,
+    /// The address of the other end.
+    pub peer: *const c_char,
+    pub file_name: *const c_char,
+    /// Bytes of the file that made it, its size if the transfer succeeded.
+    pub size: u64,
+    pub duration_ms: u64,
+    pub success: bool,
+    /// Why the transfer failed, NULL if it succeeded.
+    pub error: *const c_char,
+    /// Whether `sha256` holds the digest of the file, only computed with full verification.
+    pub has_sha256: bool,
+    pub sha256: [u8; 32],
+}
+
+/// Hands `entry` over to `callback` as an [`IcedropHistoryEntry`].
+unsafe fn with_history_entry<F>(entry: &HistoryEntry, callback: F)
+where
+    F: FnOnce(&IcedropHistoryEntry),
+{
+    let finished_at = entry
+        .finished_at
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    let peer = CString::new(entry.peer.as_str()).unwrap_or_default();
+    let file_name = CString::new(entry.file_name.as_str()).unwrap_or_default();
+    let error = match &entry.status {
+        TransferStatus::Completed => None,
+        TransferStatus::Failed(error) => Some(CString::new(error.as_str()).unwrap_or_default()),
+    };
+    callback(&IcedropHistoryEntry {
+        finished_at_ms: finished_at.as_millis() as u64,
+        received: entry.direction == TransferDirection::Received,
+        peer: peer.as_ptr(),
+        file_name: file_name.as_ptr(),
+        size: entry.size,
+        duration_ms: entry.duration.as_millis() as u64,
+        success: error.is_none(),
+        error: error
+            .as_ref()
+            .map_or(std::ptr::null(), |error| error.as_ptr()),
+        has_sha256: entry.sha256.is_some(),
+        sha256: entry.sha256.unwrap_or_default(),
+    });
+}
+
+/// A character of the file paths passed through the C API, which are NUL-terminated. Paths are
+/// UTF-8 strings, except on Windows where they're UTF-16 ones, `wchar_t` strings, as Win32 uses.
+#[cfg(not(target_os = "windows"))]
+pub type IcedropPathChar = c_char;
+
+/// A character of the file paths passed through the C API, which are NUL-terminated. Paths are
+/// UTF-8 strings, except on Windows where they're UTF-16 ones, `wchar_t` strings, as Win32 uses.
+#[cfg(target_os = "windows")]
+pub type IcedropPathChar = u16;
+
+/// The path `ptr` points to.
+#[cfg(not(target_os = "windows"))]
+unsafe fn path_from_c(ptr: *const IcedropPathChar) -> PathBuf {
+    use std::ffi::OsStr;
+
+    PathBuf::from(OsStr::from_bytes(CStr::from_ptr(ptr).to_bytes()))
+}
+
+/// The path `ptr` points to.
+#[cfg(target_os = "windows")]
+unsafe fn path_from_c(ptr: *const IcedropPathChar) -> PathBuf {
+    use std::ffi::OsString;
+
+    let mut len = 0;
+    while *ptr.add(len) != 0 {
+        len += 1;
+    }
+    PathBuf::from(OsString::from_wide(std::slice::from_raw_parts(ptr, len)))
+}
+
+/// `path` as a NUL-terminated string to hand over to C.
+#[cfg(not(target_os = "windows"))]
+fn path_to_c(path: &Path) -> Vec<IcedropPathChar> {
+    let bytes = path.as_os_str().as_bytes().iter();
+    bytes.map(|&byte| byte as c_char).chain(Some(0)).collect()
+}
+
+/// `path` as a NUL-terminated string to hand over to C.
+#[cfg(target_os = "windows")]
+fn path_to_c(path: &Path) -> Vec<IcedropPathChar> {
+    path.as_os_str().encode_wide().chain(Some(0)).collect()
+}
+
+/// The size of the buffer the pairing prompt callback writes the code into.
+const PAIRING_CODE_BUF_LEN: usize = 32;
+
+type CompletedCallbackFn =
+    unsafe extern "C" fn(*mut c_void, *const IcedropTransferSummary) -> c_void;
+
+/// Creates and returns a new [`IcedropClient`] instance, holding a reference to it. Must be
+/// released via [`icedrop_client_release`] after usage.
+///
+/// Clients are reference-counted and safe to use from several threads at the same time. Every
+/// function taking a client must be called with a reference the caller holds, see
+/// [`icedrop_client_retain`].
+///
+/// Callbacks are called from the thread running the client, see
+/// [`icedrop_client_run_in_current_thread`], except for:
+/// - the pairing prompt callback, called from a thread of its own while other callbacks may run,
+///   see [`icedrop_client_set_pairing`],
+/// - completion and release callbacks of calls failing right away, called from the calling
+///   thread before the call returns.
+///
+/// A `user_info` given along with callbacks has to be safe to use from every thread they may be
+/// called from.
 #[no_mangle]
 pub extern "C" fn icedrop_client_new() -> *mut c_void {
-    let client = Box::new(IcedropClient::new());
-    let client_ptr = Box::leak(client) as *mut IcedropClient;
-    unsafe { std::mem::transmute(client_ptr) }
+    Arc::into_raw(Arc::new(IcedropClient::new())) as *mut c_void
+}
+
+/// Takes another reference to `client`, e.g. for another thread to hold, and returns it.
+///
+/// # Safety
+///
+/// `client` must be NULL or a client the caller holds a reference to.
+#[no_mangle]
+pub unsafe extern "C" fn icedrop_client_retain(client: *mut c_void) -> *mut c_void {
+    if !client.is_null() {
+        Arc::increment_strong_count(client as *const IcedropClient);
+    }
+    client
 }
 
-/// Destroys the given [`IcedropClient`] instance. Must called when the client is not running, it's
-/// always recommended to call this function in the same thread that runs the client.
+/// Releases a reference to `client`, destroying it once none is left. Does nothing if `client`
+/// is NULL.
+///
+/// # Safety
+///
+/// `client` must be NULL or a client the caller holds a reference to. The caller gives that
+/// reference up and must not use it afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn icedrop_client_release(client: *mut c_void) {
+    if !client.is_null() {
+        drop(Arc::from_raw(client as *const IcedropClient));
+    }
+}
+
+/// Same as [`icedrop_client_release`], kept for existing callers.
+///
+/// # Safety
+///
+/// Same as for [`icedrop_client_release`].
 #[no_mangle]
-pub extern "C" fn icedrop_client_destroy(client: *mut c_void) {
-    let client_ptr = client as *mut IcedropClient;
-    let client = unsafe { Box::from_raw(client_ptr) };
-    drop(client);
+pub unsafe extern "C" fn icedrop_client_destroy(client: *mut c_void) {
+    icedrop_client_release(client);
+}
+
+/// The client `client` points to, borrowed from the reference the caller holds.
+unsafe fn client_ref<'a>(client: *mut c_void) -> &'a IcedropClient {
+    &*(client as *const IcedropClient)
 }
 
 /// Runs the client's main loop in caller thread. This function will block until the client is asked
-/// to stop via [`icedrop_client_stop`] function.
+/// to stop via [`icedrop_client_stop`] function, and returns right away if the client is running in
+/// another thread already.
+///
+/// # Safety
+///
+/// `client` must be a client the caller holds a reference to, it isn't checked for NULL.
 #[no_mangle]
-pub extern "C" fn icedrop_client_run_in_current_thread(client: *mut c_void) {
-    let client_ptr = client as *mut IcedropClient;
-    let mut client = unsafe { Box::from_raw(client_ptr) };
-    client.run_in_current_thread();
-    forget(client);
+pub unsafe extern "C" fn icedrop_client_run_in_current_thread(client: *mut c_void) {
+    client_ref(client).run_in_current_thread();
 }
 
-/// Forces the client to stop running.
+/// Forces the client to stop running, transfers still going on are given up. The client may be
+/// run again afterwards.
 ///
 /// Note that the client can still run before this function returns.
+///
+/// # Safety
+///
+/// `client` must be a client the caller holds a reference to, it isn't checked for NULL.
+#[no_mangle]
+pub unsafe extern "C" fn icedrop_client_stop(client: *mut c_void) {
+    client_ref(client).stop();
+}
+
+/// Caps the bytes per second each transfer started afterwards sends and receives, both files
+/// sent and files received. 0 removes the limit.
+///
+/// # Safety
+///
+/// `client` must be NULL or a client the caller holds a reference to.
+#[no_mangle]
+pub unsafe extern "C" fn icedrop_client_set_rate_limit(
+    client: *mut c_void,
+    bytes_per_sec: u64,
+) -> IcedropStatus {
+    status_of(|| unsafe {
+        non_null(client, "client")?;
+        let bytes_per_sec = Some(bytes_per_sec).filter(|&bytes_per_sec| bytes_per_sec > 0);
+        send_request(client, SetRateLimitRequest { bytes_per_sec })
+    })
+}
+
+/// Hands `req` over to `client`, failing with `ICEDROP_STATUS_BUSY` if it has too many requests
+/// waiting already. Requests are only dropped then, never waited on, so the `icedrop_client_*`
+/// functions may be called from within callbacks too.
+unsafe fn send_request<R>(client: *mut c_void, req: R) -> Result<(), IcedropStatus>
+where
+    R: ClientRequest + 'static,
+{
+    client_ref(client).send_request(req)
+}
+
+/// Makes the transfers started afterwards pair with other devices, keeping this device's id and
+/// the keys of the devices paired with it in the file at `store_path`, created if it doesn't
+/// exist.
+///
+/// Sending to a receiver that requires pairing for the first time calls `prompt_callback` for
+/// the code the receiver shows, which it writes as a NUL-terminated string into the buffer of
+/// the given size, returning `false` to give up instead. It's called from a thread of its own and
+/// may block until the user has typed the code in, while `code_callback` may be called with the
+/// same `user_info` from the thread running the client. Receivers started afterwards only require
+/// pairing with `code_callback` set, it gets the display name, address and code of a sender
+/// asking to pair, the code to show the user.
+///
+/// # Safety
+///
+/// `client` must be NULL or a client the caller holds a reference to, and `store_path` NULL or a
+/// NUL-terminated path. The callbacks must be safe to call with `user_info` from the threads named
+/// above.
 #[no_mangle]
-pub extern "C" fn icedrop_client_stop(client: *mut c_void) {}
+pub unsafe extern "C" fn icedrop_client_set_pairing(
+    client: *mut c_void,
+    store_path: *const IcedropPathChar,
+    user_info: *mut c_void,
+    prompt_callback: Option<unsafe extern "C" fn(*mut c_void, *mut c_char, usize) -> bool>,
+    code_callback: Option<
+        unsafe extern "C" fn(*mut c_void, *const c_char, *const c_char, *const c_char) -> c_void,
+    >,
+) -> IcedropStatus {
+    status_of(|| unsafe {
+        non_null(client, "client")?;
+        non_null(store_path, "store_path")?;
+        let store_path = path_from_c(store_path);
+
+        let mut set_pairing_req = SetPairingRequest::new(store_path);
+
+        set_pairing_req.user_info = UserInfoPtr(user_info);
+        if let Some(prompt_callback) = prompt_callback {
+            set_pairing_req.prompt_callback = Some(Arc::new(move |arg_0| {
+                let mut buf = [0 as c_char; PAIRING_CODE_BUF_LEN];
+                if !prompt_callback(arg_0, buf.as_mut_ptr(), buf.len()) {
+                    return None;
+                }
+                buf[PAIRING_CODE_BUF_LEN - 1] = 0;
+                let code = CStr::from_ptr(buf.as_ptr());
+                Some(code.to_string_lossy().into_owned())
+            }));
+        }
+        if let Some(code_callback) = code_callback {
+            set_pairing_req.code_callback = Some(Arc::new(move |arg_0, arg_1| {
+                let name = CString::new(arg_1.name.as_str()).unwrap_or_default();
+                let addr = CString::new(arg_1.addr.to_string()).unwrap();
+                let code = CString::new(arg_1.code.as_str()).unwrap();
+                code_callback(arg_0, name.as_ptr(), addr.as_ptr(), code.as_ptr());
+            }));
+        }
+
+        send_request(client, set_pairing_req)
+    })
+}
+
+type SegmentSentCallbackFn = unsafe extern "C" fn(*mut c_void, u32, usize) -> c_void;
+
+type ProgressCallbackFn =
+    unsafe extern "C" fn(*mut c_void, *const IcedropTransferProgress) -> c_void;
+
+/// Opens the file at `local_file_path` to send it. If it can't be, the completion callback is
+/// called with why, and so is the release callback if any.
+unsafe fn open_file(
+    local_file_path: *const IcedropPathChar,
+    user_info: *mut c_void,
+    completed_callback: Option<CompletedCallbackFn>,
+) -> Result<QueuedFile, IcedropStatus> {
+    non_null(local_file_path, "local_file_path")?;
+    let local_file_path = path_from_c(local_file_path);
+
+    QueuedFile::open(&local_file_path).map_err(|err| {
+        let status = IcedropStatus::from(&err);
+        set_last_error(format_args!(
+            "could not open {}: {}",
+            local_file_path.display(),
+            err
+        ));
+        if let Some(completed_callback) = completed_callback {
+            completed_callback(user_info, &IcedropTransferSummary::failed(err.into()));
+        }
+        status
+    })
+}
+
+/// Hands `send_file_req` over to `client` with the callbacks given to the C API.
+unsafe fn send_file(
+    client: *mut c_void,
+    mut send_file_req: SendFileRequest,
+    user_info: *mut c_void,
+    segment_sent_callback: Option<SegmentSentCallbackFn>,
+    progress_callback: Option<ProgressCallbackFn>,
+    completed_callback: Option<CompletedCallbackFn>,
+    transfer: *mut *mut IcedropTransfer,
+) -> Result<(), IcedropStatus> {
+    send_file_req.user_info = UserInfoPtr(user_info);
+    if let Some(segment_sent_callback) = segment_sent_callback {
+        send_file_req.segment_sent_callback = Some(Box::new(move |arg_0, arg_1, arg_2| {
+            segment_sent_callback(arg_0, arg_1, arg_2);
+        }));
+    }
+    if let Some(progress_callback) = progress_callback {
+        send_file_req.progress_callback = Some(Box::new(move |arg_0, _, arg_2| {
+            progress_callback(arg_0, &arg_2);
+        }));
+    }
+    if let Some(completed_callback) = completed_callback {
+        send_file_req.completed_callback = Some(Box::new(move |arg_0, arg_1| {
+            completed_callback(arg_0, &arg_1);
+        }));
+    }
+
+    let control = send_file_req.control.clone();
+    if let Err(send_file_req) = client_ref(client).try_send_request(send_file_req) {
+        send_file_req.give_back_files();
+        return Err(IcedropStatus::Busy);
+    }
+    if !transfer.is_null() {
+        *transfer = Box::into_raw(Box::new(IcedropTransfer { control }));
+    }
+    Ok(())
+}
 
 /// Initiate an send file request.
+///
+/// Fails with `ICEDROP_STATUS_NOT_FOUND` or `ICEDROP_STATUS_IO` if the file can't be opened, after
+/// calling `completed_callback` with the same error, and with `ICEDROP_STATUS_BUSY` without
+/// calling it if the client has too many requests waiting. Any failure once the request was made,
+/// e.g. `ICEDROP_ERROR_CONNECTION_REFUSED`, only goes to `completed_callback`.
+///
+/// Unless `transfer` is NULL, it's set to a handle to pause, resume or cancel the transfer if the
+/// call succeeds, see [`IcedropTransfer`]. The other `icedrop_client_send_file*` functions take
+/// it too.
+///
+/// # Safety
+///
+/// `client` must be NULL or a client the caller holds a reference to. `remote_addr` and
+/// `local_file_path` must be NULL or NUL-terminated strings, and `transfer` NULL or valid to write
+/// a handle to.
 #[no_mangle]
-pub extern "C" fn icedrop_client_send_file(
+pub unsafe extern "C" fn icedrop_client_send_file(
     client: *mut c_void,
     remote_addr: *const c_char,
-    local_file_path: *const c_char,
+    local_file_path: *const IcedropPathChar,
     user_info: *mut c_void,
     segment_sent_callback: Option<unsafe extern "C" fn(*mut c_void, u32, usize) -> c_void>,
-    completed_callback: Option<unsafe extern "C" fn(*mut c_void, bool) -> c_void>,
-) {
-    let client_ptr = client as *mut IcedropClient;
-    let client = unsafe { Box::from_raw(client_ptr) };
+    progress_callback: Option<
+        unsafe extern "C" fn(*mut c_void, *const IcedropTransferProgress) -> c_void,
+    >,
+    completed_callback: Option<
+        unsafe extern "C" fn(*mut c_void, *const IcedropTransferSummary) -> c_void,
+    >,
+    transfer: *mut *mut IcedropTransfer,
+) -> IcedropStatus {
+    status_of(|| unsafe {
+        non_null(client, "client")?;
+        let remote_addr = str_from_c(remote_addr, "remote_addr")?;
+        let file = open_file(local_file_path, user_info, completed_callback)?;
+        let send_file_req = SendFileRequest::with_files(remote_addr, vec![file]);
 
-    unsafe {
-        let remote_addr = CStr::from_ptr(remote_addr).to_str().unwrap();
-        let local_file_path = CStr::from_ptr(local_file_path).to_str().unwrap();
+        send_file(
+            client,
+            send_file_req,
+            user_info,
+            segment_sent_callback,
+            progress_callback,
+            completed_callback,
+            transfer,
+        )
+    })
+}
 
-        let maybe_send_file_req = SendFileRequest::new(remote_addr, local_file_path);
-        if maybe_send_file_req.is_err() {
-            if let Some(completed_callback) = completed_callback {
-                completed_callback(user_info, false);
-            }
-            return;
-        }
+/// Like [`icedrop_client_send_file`], but hands the wrapper ownership of `user_info`, e.g. a
+/// retained Swift or Kotlin object the callbacks use. `release_callback` is called with it exactly
+/// once, after the last of the other callbacks, whether the transfer succeeded or not, and even
+/// if the call fails.
+///
+/// # Safety
+///
+/// Same as for [`icedrop_client_send_file`].
+#[no_mangle]
+pub unsafe extern "C" fn icedrop_client_send_file_ex(
+    client: *mut c_void,
+    remote_addr: *const c_char,
+    local_file_path: *const IcedropPathChar,
+    user_info: *mut c_void,
+    release_callback: Option<unsafe extern "C" fn(*mut c_void)>,
+    segment_sent_callback: Option<unsafe extern "C" fn(*mut c_void, u32, usize) -> c_void>,
+    progress_callback: Option<
+        unsafe extern "C" fn(*mut c_void, *const IcedropTransferProgress) -> c_void,
+    >,
+    completed_callback: Option<
+        unsafe extern "C" fn(*mut c_void, *const IcedropTransferSummary) -> c_void,
+    >,
+    transfer: *mut *mut IcedropTransfer,
+) -> IcedropStatus {
+    let release_user_info =
+        release_callback.map(|release_callback| UserInfoRelease::new(user_info, release_callback));
+
+    status_of(|| unsafe {
+        non_null(client, "client")?;
+        let remote_addr = str_from_c(remote_addr, "remote_addr")?;
+        let file = open_file(local_file_path, user_info, completed_callback)?;
+        let mut send_file_req = SendFileRequest::with_files(remote_addr, vec![file]);
+        send_file_req.release_user_info = release_user_info;
 
-        let mut send_file_req = maybe_send_file_req.unwrap();
+        send_file(
+            client,
+            send_file_req,
+            user_info,
+            segment_sent_callback,
+            progress_callback,
+            completed_callback,
+            transfer,
+        )
+    })
+}
 
-        send_file_req.user_info = UserInfoPtr(user_info);
-        if let Some(segment_sent_callback) = segment_sent_callback {
-            send_file_req.segment_sent_callback = Some(Box::new(move |arg_0, arg_1, arg_2| {
-                segment_sent_callback(arg_0, arg_1, arg_2);
+/// Sends the `count` files at `paths` to `remote_addr` one after another over a single
+/// connection, each stored under its own name.
+///
+/// `file_progress_callback` gets the progress of the file being sent along with its position in
+/// `paths`, and `batch_progress_callback` the progress over all files, also once the receiver
+/// confirmed a file. `completed_callback` is called once, after the last file or the first
+/// failure, and `transfer` works as for [`icedrop_client_send_file`]. Fails without sending any
+/// file if one of them can't be opened.
+///
+/// # Safety
+///
+/// `client` must be NULL or a client the caller holds a reference to, and `remote_addr` NULL or a
+/// NUL-terminated string. Unless it's NULL, `paths` must point to `count` NUL-terminated paths,
+/// none of them NULL. `transfer` must be NULL or valid to write a handle to.
+#[no_mangle]
+pub unsafe extern "C" fn icedrop_client_send_files(
+    client: *mut c_void,
+    remote_addr: *const c_char,
+    paths: *const *const IcedropPathChar,
+    count: usize,
+    user_info: *mut c_void,
+    file_progress_callback: Option<
+        unsafe extern "C" fn(*mut c_void, u32, *const IcedropTransferProgress) -> c_void,
+    >,
+    batch_progress_callback: Option<
+        unsafe extern "C" fn(*mut c_void, *const IcedropBatchProgress) -> c_void,
+    >,
+    completed_callback: Option<
+        unsafe extern "C" fn(*mut c_void, *const IcedropTransferSummary) -> c_void,
+    >,
+    transfer: *mut *mut IcedropTransfer,
+) -> IcedropStatus {
+    status_of(|| unsafe {
+        non_null(client, "client")?;
+        let remote_addr = str_from_c(remote_addr, "remote_addr")?;
+        non_null(paths, "paths")?;
+        if count == 0 {
+            set_last_error("`count` is 0");
+            return Err(IcedropStatus::InvalidArgument);
+        }
+        let files = std::slice::from_raw_parts(paths, count)
+            .iter()
+            .map(|&path| open_file(path, user_info, completed_callback))
+            .collect::<Result<_, _>>()?;
+
+        let mut send_file_req = SendFileRequest::with_files(remote_addr, files);
+        if let Some(file_progress_callback) = file_progress_callback {
+            send_file_req.progress_callback = Some(Box::new(move |arg_0, arg_1, arg_2| {
+                file_progress_callback(arg_0, arg_1, &arg_2);
             }));
         }
-        if let Some(completed_callback) = completed_callback {
-            send_file_req.completed_callback = Some(Box::new(move |arg_0| {
-                completed_callback(arg_0, true);
+        if let Some(batch_progress_callback) = batch_progress_callback {
+            send_file_req.batch_progress_callback = Some(Box::new(move |arg_0, arg_1| {
+                batch_progress_callback(arg_0, &arg_1);
             }));
         }
 
-        client.send_request(send_file_req);
-    }
-
-    forget(client);
+        send_file(
+            client,
+            send_file_req,
+            user_info,
+            None,
+            None,
+            completed_callback,
+            transfer,
+        )
+    })
 }
 
-/// Initiate an send file request with an opened file descriptor.
+/// Initiate an send file request with an opened file descriptor. The client takes ownership of
+/// `file_fd` and closes it once done with, unless the call fails, e.g. with
+/// `ICEDROP_STATUS_BUSY`, which leaves it open for the caller.
+///
+/// # Safety
+///
+/// `client` must be NULL or a client the caller holds a reference to, and `remote_addr` NULL or a
+/// NUL-terminated string. `file_fd` must be an open file descriptor, which nothing else may
+/// close while the client owns it. `transfer` must be NULL or valid to write a handle to.
+#[cfg(not(target_os = "windows"))]
 #[no_mangle]
-pub extern "C" fn icedrop_client_send_file_with_fd(
+pub unsafe extern "C" fn icedrop_client_send_file_with_fd(
     client: *mut c_void,
     remote_addr: *const c_char,
     file_fd: i32,
     user_info: *mut c_void,
     segment_sent_callback: Option<unsafe extern "C" fn(*mut c_void, u32, usize) -> c_void>,
-    completed_callback: Option<unsafe extern "C" fn(*mut c_void, bool) -> c_void>,
-) {
-    let client_ptr = client as *mut IcedropClient;
-    let client = unsafe { Box::from_raw(client_ptr) };
+    progress_callback: Option<
+        unsafe extern "C" fn(*mut c_void, *const IcedropTransferProgress) -> c_void,
+    >,
+    completed_callback: Option<
+        unsafe extern "C" fn(*mut c_void, *const IcedropTransferSummary) -> c_void,
+    >,
+    transfer: *mut *mut IcedropTransfer,
+) -> IcedropStatus {
+    status_of(|| unsafe {
+        non_null(client, "client")?;
+        let remote_addr = str_from_c(remote_addr, "remote_addr")?;
 
-    unsafe {
-        let remote_addr = CStr::from_ptr(remote_addr).to_str().unwrap();
+        send_file(
+            client,
+            SendFileRequest::with_fd(remote_addr, file_fd),
+            user_info,
+            segment_sent_callback,
+            progress_callback,
+            completed_callback,
+            transfer,
+        )
+    })
+}
+
+/// Initiate an send file request with an opened file `HANDLE`, which the client takes ownership
+/// of and closes once done with, unless the call fails, e.g. with `ICEDROP_STATUS_BUSY`, which
+/// leaves it open for the caller.
+///
+/// # Safety
+///
+/// `client` must be NULL or a client the caller holds a reference to, and `remote_addr` NULL or a
+/// NUL-terminated string. `file_handle` must be an open file `HANDLE`, which nothing else may
+/// close while the client owns it. `transfer` must be NULL or valid to write a handle to.
+#[cfg(target_os = "windows")]
+#[no_mangle]
+pub unsafe extern "C" fn icedrop_client_send_file_with_handle(
+    client: *mut c_void,
+    remote_addr: *const c_char,
+    file_handle: *mut c_void,
+    user_info: *mut c_void,
+    segment_sent_callback: Option<unsafe extern "C" fn(*mut c_void, u32, usize) -> c_void>,
+    progress_callback: Option<
+        unsafe extern "C" fn(*mut c_void, *const IcedropTransferProgress) -> c_void,
+    >,
+    completed_callback: Option<
+        unsafe extern "C" fn(*mut c_void, *const IcedropTransferSummary) -> c_void,
+    >,
+    transfer: *mut *mut IcedropTransfer,
+) -> IcedropStatus {
+    status_of(|| unsafe {
+        non_null(client, "client")?;
+        let remote_addr = str_from_c(remote_addr, "remote_addr")?;
 
-        let mut send_file_req = SendFileRequest::with_fd(remote_addr, file_fd);
+        send_file(
+            client,
+            SendFileRequest::with_handle(remote_addr, file_handle),
+            user_info,
+            segment_sent_callback,
+            progress_callback,
+            completed_callback,
+            transfer,
+        )
+    })
+}
 
-        send_file_req.user_info = UserInfoPtr(user_info);
-        if let Some(segment_sent_callback) = segment_sent_callback {
-            send_file_req.segment_sent_callback = Some(Box::new(move |arg_0, arg_1, arg_2| {
-                segment_sent_callback(arg_0, arg_1, arg_2);
+/// Sends `text`, e.g. what's on the clipboard, to `remote_addr` instead of a file. The receiver
+/// only takes it if it has a text callback, see [`icedrop_client_set_text_callback`].
+///
+/// `completed_callback` gets `ICEDROP_ERROR_NONE` once the receiver took the text, or one of the
+/// other `ICEDROP_ERROR_*` codes, e.g. `ICEDROP_ERROR_REJECTED` if it turned the text down.
+/// Text longer than 64 KiB fails with `ICEDROP_ERROR_PROTOCOL`.
+///
+/// # Safety
+///
+/// `client` must be NULL or a client the caller holds a reference to. `remote_addr` and `text` must
+/// be NULL or NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn icedrop_client_send_text(
+    client: *mut c_void,
+    remote_addr: *const c_char,
+    text: *const c_char,
+    user_info: *mut c_void,
+    completed_callback: Option<unsafe extern "C" fn(*mut c_void, u32) -> c_void>,
+) -> IcedropStatus {
+    status_of(|| unsafe {
+        non_null(client, "client")?;
+        let remote_addr = str_from_c(remote_addr, "remote_addr")?;
+        let text = str_from_c(text, "text")?;
+
+        let mut send_text_req = SendTextRequest {
+            remote_addr: remote_addr.to_owned(),
+            text: text.to_owned(),
+            user_info: UserInfoPtr(user_info),
+            completed_callback: None,
+        };
+        if let Some(completed_callback) = completed_callback {
+            send_text_req.completed_callback = Some(Box::new(move |arg_0, arg_1| {
+                completed_callback(arg_0, arg_1);
             }));
         }
-        if let Some(completed_callback) = completed_callback {
-            send_file_req.completed_callback = Some(Box::new(move |arg_0| {
-                completed_callback(arg_0, true);
+
+        send_request(client, send_text_req)
+    })
+}
+
+/// A transfer one of the `icedrop_client_send_file*` functions started, to pause, resume or
+/// cancel it from any thread. Must be destroyed via [`icedrop_transfer_destroy`] after usage,
+/// which leaves the transfer running.
+pub struct IcedropTransfer {
+    control: TransferControl,
+}
+
+/// The control of the transfer `transfer` points to.
+unsafe fn transfer_control<'a>(
+    transfer: *const IcedropTransfer,
+) -> Result<&'a TransferControl, IcedropStatus> {
+    non_null(transfer, "transfer")?;
+    Ok(&(*transfer).control)
+}
+
+/// Stops sending file data until [`icedrop_transfer_resume`] is called. The connection is kept
+/// alive meanwhile, so a transfer may stay paused for as long as needed.
+///
+/// # Safety
+///
+/// `transfer` must be NULL or a handle that hasn't been destroyed.
+#[no_mangle]
+pub unsafe extern "C" fn icedrop_transfer_pause(transfer: *const IcedropTransfer) -> IcedropStatus {
+    status_of(|| unsafe {
+        transfer_control(transfer)?.pause();
+        Ok(())
+    })
+}
+
+/// Goes on sending after [`icedrop_transfer_pause`].
+///
+/// # Safety
+///
+/// Same as for [`icedrop_transfer_pause`].
+#[no_mangle]
+pub unsafe extern "C" fn icedrop_transfer_resume(
+    transfer: *const IcedropTransfer,
+) -> IcedropStatus {
+    status_of(|| unsafe {
+        transfer_control(transfer)?.resume();
+        Ok(())
+    })
+}
+
+/// Gives up on the transfer, paused or not. The completion callback is then called with
+/// `ICEDROP_ERROR_CANCELLED`, unless the transfer was over already.
+///
+/// # Safety
+///
+/// Same as for [`icedrop_transfer_pause`].
+#[no_mangle]
+pub unsafe extern "C" fn icedrop_transfer_cancel(
+    transfer: *const IcedropTransfer,
+) -> IcedropStatus {
+    status_of(|| unsafe {
+        transfer_control(transfer)?.cancel();
+        Ok(())
+    })
+}
+
+/// Destroys the given [`IcedropTransfer`] handle, does nothing if it's NULL.
+///
+/// # Safety
+///
+/// `transfer` must be NULL or a handle that hasn't been destroyed, and mustn't be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn icedrop_transfer_destroy(transfer: *mut IcedropTransfer) {
+    if !transfer.is_null() {
+        drop(Box::from_raw(transfer));
+    }
+}
+
+/// Starts receiving files on `bind_addr`, like `0.0.0.0:8080`, into the existing directory
+/// `dest_dir`.
+///
+/// Each sender is first offered to `offer_callback` with its display name and address, and turned
+/// away unless it returns `true`. Without the callback every sender is accepted.
+/// `progress_callback` gets the bytes of the file being received so far and its size, 0 if
+/// unknown. `received_callback` gets the path a file has been written to and its size once it
+/// has been received.
+///
+/// # Safety
+///
+/// `client` must be NULL or a client the caller holds a reference to. `bind_addr` and `dest_dir`
+/// must be NULL or NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn icedrop_client_start_receiver(
+    client: *mut c_void,
+    bind_addr: *const c_char,
+    dest_dir: *const IcedropPathChar,
+    user_info: *mut c_void,
+    offer_callback: Option<unsafe extern "C" fn(*mut c_void, *const c_char, *const c_char) -> bool>,
+    progress_callback: Option<unsafe extern "C" fn(*mut c_void, u64, u64) -> c_void>,
+    received_callback: Option<
+        unsafe extern "C" fn(*mut c_void, *const IcedropPathChar, u64) -> c_void,
+    >,
+) -> IcedropStatus {
+    status_of(|| unsafe {
+        non_null(client, "client")?;
+        let bind_addr = str_from_c(bind_addr, "bind_addr")?;
+        non_null(dest_dir, "dest_dir")?;
+        let dest_dir = path_from_c(dest_dir);
+
+        let mut start_receiver_req = StartReceiverRequest::new(bind_addr, dest_dir);
+
+        start_receiver_req.user_info = UserInfoPtr(user_info);
+        if let Some(offer_callback) = offer_callback {
+            start_receiver_req.offer_callback = Some(Box::new(move |arg_0, arg_1, arg_2| {
+                let name = CString::new(arg_1).unwrap_or_default();
+                let addr = CString::new(arg_2.to_string()).unwrap();
+                offer_callback(arg_0, name.as_ptr(), addr.as_ptr())
             }));
         }
+        if let Some(progress_callback) = progress_callback {
+            start_receiver_req.progress_callback = Some(Box::new(move |arg_0, arg_1, arg_2| {
+                progress_callback(arg_0, arg_1, arg_2);
+            }));
+        }
+        if let Some(received_callback) = received_callback {
+            start_receiver_req.received_callback = Some(Box::new(move |arg_0, arg_1, arg_2| {
+                let path = path_to_c(arg_1);
+                received_callback(arg_0, path.as_ptr(), arg_2);
+            }));
+        }
+
+        send_request(client, start_receiver_req)
+    })
+}
+
+/// Has the receivers started afterwards take text senders send instead of files, see
+/// [`icedrop_client_send_text`]. `text_callback` gets the display name and address of the
+/// sender along with the text, all only valid until it returns. Setting it to NULL has receivers
+/// started afterwards turn text down again, which is what they do by default.
+///
+/// # Safety
+///
+/// `client` must be NULL or a client the caller holds a reference to.
+#[no_mangle]
+pub unsafe extern "C" fn icedrop_client_set_text_callback(
+    client: *mut c_void,
+    user_info: *mut c_void,
+    text_callback: Option<
+        unsafe extern "C" fn(*mut c_void, *const c_char, *const c_char, *const c_char) -> c_void,
+    >,
+) -> IcedropStatus {
+    status_of(|| unsafe {
+        non_null(client, "client")?;
+
+        let mut set_text_callback_req = SetTextCallbackRequest {
+            user_info: UserInfoPtr(user_info),
+            text_callback: None,
+        };
+        if let Some(text_callback) = text_callback {
+            set_text_callback_req.text_callback = Some(Arc::new(move |arg_0, arg_1, arg_2| {
+                let name = CString::new(arg_1.name.as_str()).unwrap_or_default();
+                let addr = CString::new(arg_1.addr.to_string()).unwrap();
+                let text = CString::new(arg_2).unwrap_or_default();
+                text_callback(arg_0, name.as_ptr(), addr.as_ptr(), text.as_ptr());
+            }));
+        }
+
+        send_request(client, set_text_callback_req)
+    })
+}
+
+/// Records the transfers started afterwards, both files sent and files received, in the history
+/// kept at `store_path`, created if it doesn't exist. Read it with [`icedrop_history_read`].
+///
+/// # Safety
+///
+/// `client` must be NULL or a client the caller holds a reference to, and `store_path` NULL or a
+/// NUL-terminated path.
+#[no_mangle]
+pub unsafe extern "C" fn icedrop_client_set_history(
+    client: *mut c_void,
+    store_path: *const IcedropPathChar,
+) -> IcedropStatus {
+    status_of(|| unsafe {
+        non_null(client, "client")?;
+        non_null(store_path, "store_path")?;
 
-        client.send_request(send_file_req);
+        send_request(
+            client,
+            SetHistoryRequest {
+                store_path: path_from_c(store_path),
+            },
+        )
+    })
+}
+
+/// Calls `entry_callback` with each of the last `max_entries` transfers recorded in the history
+/// kept at `store_path`, most recent first, e.g. to show a list of recent transfers. 0 lists all
+/// of them. Returns `false` if the history can't be read.
+///
+/// Unlike the `icedrop_client_*` functions it doesn't need a client, and calls `entry_callback`
+/// from the caller's thread before returning.
+///
+/// # Safety
+///
+/// `store_path` must be NULL or a NUL-terminated path.
+#[no_mangle]
+pub unsafe extern "C" fn icedrop_history_read(
+    store_path: *const IcedropPathChar,
+    max_entries: usize,
+    user_info: *mut c_void,
+    entry_callback: Option<unsafe extern "C" fn(*mut c_void, *const IcedropHistoryEntry) -> c_void>,
+) -> bool {
+    if non_null(store_path, "store_path").is_err() {
+        return false;
+    }
+    unsafe {
+        let store_path = path_from_c(store_path);
+        let max_entries = if max_entries == 0 {
+            usize::MAX
+        } else {
+            max_entries
+        };
+        let entries =
+            match HistoryStore::open(&store_path).and_then(|store| store.recent(max_entries)) {
+                Ok(entries) => entries,
+                Err(err) => {
+                    let message = format!(
+                        "could not read the history at {}: {}",
+                        store_path.display(),
+                        err
+                    );
+                    println!("{}", message);
+                    set_last_error(message);
+                    return false;
+                }
+            };
+        if let Some(entry_callback) = entry_callback {
+            for entry in &entries {
+                with_history_entry(entry, |entry| {
+                    entry_callback(user_info, entry);
+                });
+            }
+        }
     }
 
-    forget(client);
+    true
 }
@@ -1,6 +1,10 @@
 use std::ffi::CString;
 
-use super::{icedrop_client_new, icedrop_client_run_in_current_thread, icedrop_client_send_file};
+use std::path::Path;
+
+use super::{
+    icedrop_client_new, icedrop_client_run_in_current_thread, icedrop_client_send_file, path_to_c,
+};
 
 #[derive(Clone, Copy)]
 struct AnySendable<T>(T);
@@ -13,16 +17,20 @@ fn simple_test() {
 
     std::thread::spawn(move || {
         let remote_addr = CString::new("127.0.0.1:8080").unwrap();
-        let local_file_path = CString::new("/Users/cyandev/Downloads/Docker.dmg").unwrap();
-        icedrop_client_send_file(
-            client.0,
-            remote_addr.as_ptr(),
-            local_file_path.as_ptr(),
-            std::ptr::null_mut(),
-            None,
-            None,
-        );
+        let local_file_path = path_to_c(Path::new("/Users/cyandev/Downloads/Docker.dmg"));
+        unsafe {
+            icedrop_client_send_file(
+                client.0,
+                remote_addr.as_ptr(),
+                local_file_path.as_ptr(),
+                std::ptr::null_mut(),
+                None,
+                None,
+                None,
+                std::ptr::null_mut(),
+            )
+        };
     });
 
-    icedrop_client_run_in_current_thread(client.0);
+    unsafe { icedrop_client_run_in_current_thread(client.0) };
 }
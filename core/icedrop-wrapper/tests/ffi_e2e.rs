@@ -0,0 +1,1273 @@
+//! End-to-end tests that drive the wrapper purely through its exported C ABI.
+//!
+//! The cdylib is loaded at runtime via `libloading` and the symbols are typed the same way the
+//! generated `icedrop.h` declares them, so any drift between the Rust signatures, cbindgen output
+//! and what C callers see shows up here instead of in a native app.
+
+use std::ffi::{c_void, CStr, CString};
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::raw::c_char;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use libloading::{library_filename, Library, Symbol};
+
+/// Mirrors `IcedropPathChar` in `icedrop.h`.
+#[cfg(not(target_os = "windows"))]
+type PathChar = c_char;
+#[cfg(target_os = "windows")]
+type PathChar = u16;
+
+/// Mirrors `IcedropStatus` in `icedrop.h`.
+type Status = i32;
+const STATUS_OK: Status = 0;
+const STATUS_INVALID_ARGUMENT: Status = 1;
+const STATUS_NOT_FOUND: Status = 2;
+const STATUS_BUSY: Status = 4;
+
+const ERROR_CANCELLED: u32 = 4;
+const ERROR_REJECTED: u32 = 6;
+const ERROR_NOT_FOUND: u32 = 7;
+const ERROR_CONNECTION_REFUSED: u32 = 8;
+
+type ClientNewFn = unsafe extern "C" fn() -> *mut c_void;
+type LastErrorMessageFn = unsafe extern "C" fn() -> *const c_char;
+type ClientRunFn = unsafe extern "C" fn(*mut c_void);
+type ClientRetainFn = unsafe extern "C" fn(*mut c_void) -> *mut c_void;
+type ClientSetRateLimitFn = unsafe extern "C" fn(*mut c_void, u64) -> Status;
+type SegmentSentCallback = unsafe extern "C" fn(*mut c_void, u32, usize);
+type ProgressCallback = unsafe extern "C" fn(*mut c_void, *const TransferProgress);
+type CompletedCallback = unsafe extern "C" fn(*mut c_void, *const TransferSummary);
+type ClientSendFileFn = unsafe extern "C" fn(
+    *mut c_void,
+    *const c_char,
+    *const PathChar,
+    *mut c_void,
+    Option<SegmentSentCallback>,
+    Option<ProgressCallback>,
+    Option<CompletedCallback>,
+    *mut *mut c_void,
+) -> Status;
+#[cfg(not(target_os = "windows"))]
+type ClientSendFileWithFdFn = unsafe extern "C" fn(
+    *mut c_void,
+    *const c_char,
+    i32,
+    *mut c_void,
+    Option<SegmentSentCallback>,
+    Option<ProgressCallback>,
+    Option<CompletedCallback>,
+    *mut *mut c_void,
+) -> Status;
+type FileProgressCallback = unsafe extern "C" fn(*mut c_void, u32, *const TransferProgress);
+type BatchProgressCallback = unsafe extern "C" fn(*mut c_void, *const BatchProgress);
+type ClientSendFilesFn = unsafe extern "C" fn(
+    *mut c_void,
+    *const c_char,
+    *const *const PathChar,
+    usize,
+    *mut c_void,
+    Option<FileProgressCallback>,
+    Option<BatchProgressCallback>,
+    Option<CompletedCallback>,
+    *mut *mut c_void,
+) -> Status;
+type ReleaseCallback = unsafe extern "C" fn(*mut c_void);
+type ClientSendFileExFn = unsafe extern "C" fn(
+    *mut c_void,
+    *const c_char,
+    *const PathChar,
+    *mut c_void,
+    Option<ReleaseCallback>,
+    Option<SegmentSentCallback>,
+    Option<ProgressCallback>,
+    Option<CompletedCallback>,
+    *mut *mut c_void,
+) -> Status;
+type TransferFn = unsafe extern "C" fn(*mut c_void) -> Status;
+type TransferDestroyFn = unsafe extern "C" fn(*mut c_void);
+type OfferCallback = unsafe extern "C" fn(*mut c_void, *const c_char, *const c_char) -> bool;
+type ReceiveProgressCallback = unsafe extern "C" fn(*mut c_void, u64, u64);
+type ReceivedCallback = unsafe extern "C" fn(*mut c_void, *const PathChar, u64);
+type ClientStartReceiverFn = unsafe extern "C" fn(
+    *mut c_void,
+    *const c_char,
+    *const PathChar,
+    *mut c_void,
+    Option<OfferCallback>,
+    Option<ReceiveProgressCallback>,
+    Option<ReceivedCallback>,
+) -> Status;
+type TextCompletedCallback = unsafe extern "C" fn(*mut c_void, u32);
+type ClientSendTextFn = unsafe extern "C" fn(
+    *mut c_void,
+    *const c_char,
+    *const c_char,
+    *mut c_void,
+    Option<TextCompletedCallback>,
+) -> Status;
+type TextCallback = unsafe extern "C" fn(*mut c_void, *const c_char, *const c_char, *const c_char);
+type ClientSetTextCallbackFn =
+    unsafe extern "C" fn(*mut c_void, *mut c_void, Option<TextCallback>) -> Status;
+type HistoryEntryCallback = unsafe extern "C" fn(*mut c_void, *const HistoryEntry);
+type HistoryReadFn =
+    unsafe extern "C" fn(*const PathChar, usize, *mut c_void, Option<HistoryEntryCallback>) -> bool;
+
+const EXPORTED_SYMBOLS: &[&str] = &[
+    "icedrop_client_new",
+    "icedrop_client_destroy",
+    "icedrop_client_retain",
+    "icedrop_client_release",
+    "icedrop_client_run_in_current_thread",
+    "icedrop_client_stop",
+    "icedrop_client_send_file",
+    "icedrop_client_send_file_ex",
+    "icedrop_client_send_files",
+    #[cfg(not(target_os = "windows"))]
+    "icedrop_client_send_file_with_fd",
+    #[cfg(target_os = "windows")]
+    "icedrop_client_send_file_with_handle",
+    "icedrop_client_send_text",
+    "icedrop_client_start_receiver",
+    "icedrop_client_set_text_callback",
+    "icedrop_client_set_rate_limit",
+    "icedrop_client_set_pairing",
+    "icedrop_client_set_history",
+    "icedrop_history_read",
+    "icedrop_last_error_message",
+    "icedrop_transfer_pause",
+    "icedrop_transfer_resume",
+    "icedrop_transfer_cancel",
+    "icedrop_transfer_destroy",
+];
+
+const SEGMENT_SIZE: usize = 1024 * 512;
+
+/// Mirrors `IcedropTransferProgress` in `icedrop.h`.
+#[repr(C)]
+struct TransferProgress {
+    bytes_sent: u64,
+    bytes_confirmed: u64,
+    has_total_bytes: bool,
+    total_bytes: u64,
+    percentage: f64,
+    throughput: f64,
+    has_eta: bool,
+    eta_ms: u64,
+}
+
+/// Mirrors `IcedropTransferSummary` in `icedrop.h`.
+#[repr(C)]
+struct TransferSummary {
+    success: bool,
+    bytes: u64,
+    duration_ms: u64,
+    average_speed: f64,
+    peak_speed: f64,
+    retransmits: u32,
+    has_sha256: bool,
+    sha256: [u8; 32],
+    error_code: u32,
+}
+
+/// Mirrors `IcedropBatchProgress` in `icedrop.h`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct BatchProgress {
+    files_completed: u32,
+    file_count: u32,
+    bytes_confirmed: u64,
+    total_bytes: u64,
+    percentage: f64,
+}
+
+/// Mirrors `IcedropHistoryEntry` in `icedrop.h`.
+#[repr(C)]
+struct HistoryEntry {
+    finished_at_ms: u64,
+    received: bool,
+    peer: *const c_char,
+    file_name: *const c_char,
+    size: u64,
+    duration_ms: u64,
+    success: bool,
+    error: *const c_char,
+    has_sha256: bool,
+    sha256: [u8; 32],
+}
+
+/// What the callbacks report, shared with them through the user info pointer.
+#[derive(Default)]
+struct Reported {
+    bytes_acked: AtomicUsize,
+    /// The highest percentage reported by the progress callback.
+    percentage: Mutex<f64>,
+    /// Bytes in the completion summary, plus one so zero means not completed yet.
+    bytes_completed: AtomicU64,
+}
+
+/// What the receiving callbacks report.
+#[derive(Default)]
+struct Received {
+    offered_name: Mutex<String>,
+    bytes_received: AtomicU64,
+    /// The path and size of the received file, once it has been received.
+    file: Mutex<Option<(PathBuf, u64)>>,
+}
+
+/// What the callbacks of `icedrop_client_send_files` report.
+#[derive(Default)]
+struct BatchReported {
+    /// The positions of the files progress was reported for, in order.
+    file_indexes: Mutex<Vec<u32>>,
+    batch_progress: Mutex<Vec<BatchProgress>>,
+    /// The error code of the completion summary, plus one so zero means not completed yet.
+    error_code: AtomicU64,
+}
+
+/// What the text callbacks report.
+#[derive(Default)]
+struct TextReported {
+    /// The sender's display name and the text, for each text received.
+    received: Mutex<Vec<(String, String)>>,
+    /// The error codes sending reported, plus one so zero means not completed yet.
+    error_codes: Mutex<Vec<u64>>,
+}
+
+/// Owned by the wrapper once handed over to `icedrop_client_send_file_ex`, like a retained
+/// object of a Swift or Kotlin binding. Logs the callbacks it gets.
+struct Retained {
+    calls: std::sync::Arc<Mutex<Vec<String>>>,
+    last_error_message: LastErrorMessageFn,
+}
+
+#[derive(Clone, Copy)]
+struct AnySendable<T>(T);
+
+unsafe impl<T> Send for AnySendable<T> {}
+
+/// `path` as the NUL-terminated string the C API takes.
+fn c_path(path: &Path) -> Vec<PathChar> {
+    #[cfg(not(target_os = "windows"))]
+    let chars = {
+        use std::os::unix::ffi::OsStrExt;
+        path.as_os_str()
+            .as_bytes()
+            .iter()
+            .map(|&byte| byte as c_char)
+    };
+    #[cfg(target_os = "windows")]
+    let chars = {
+        use std::os::windows::ffi::OsStrExt;
+        path.as_os_str().encode_wide()
+    };
+    chars.chain(Some(0)).collect()
+}
+
+/// The path the C API hands over at `ptr`.
+unsafe fn path_from_c(ptr: *const PathChar) -> PathBuf {
+    let mut len = 0;
+    while *ptr.add(len) != 0 {
+        len += 1;
+    }
+    let chars = std::slice::from_raw_parts(ptr, len);
+    #[cfg(not(target_os = "windows"))]
+    let path = {
+        use std::os::unix::ffi::OsStrExt;
+        let bytes: Vec<u8> = chars.iter().map(|&char| char as u8).collect();
+        std::ffi::OsStr::from_bytes(&bytes).to_owned()
+    };
+    #[cfg(target_os = "windows")]
+    let path = {
+        use std::os::windows::ffi::OsStringExt;
+        std::ffi::OsString::from_wide(chars)
+    };
+    PathBuf::from(path)
+}
+
+/// Returns the directory cargo places the wrapper artifacts (cdylib and `icedrop.h`) in.
+fn artifacts_dir() -> PathBuf {
+    // The test binary lives in `target/<profile>/deps`.
+    let mut dir = std::env::current_exe().unwrap();
+    dir.pop();
+    if dir.ends_with("deps") {
+        dir.pop();
+    }
+    dir
+}
+
+fn load_wrapper() -> &'static Library {
+    let lib_path = artifacts_dir().join(library_filename("icedrop_wrapper"));
+    let lib = unsafe { Library::new(&lib_path) }
+        .unwrap_or_else(|err| panic!("could not load {:?}: {}", lib_path, err));
+
+    // The client thread never returns, so the library must never be unloaded.
+    Box::leak(Box::new(lib))
+}
+
+fn read_frame(stream: &mut TcpStream) -> (u16, Vec<u8>) {
+    let mut header = [0u8; 6];
+    stream.read_exact(&mut header).unwrap();
+    let frame_type = u16::from_le_bytes([header[0], header[1]]);
+    let frame_len = u32::from_le_bytes([header[2], header[3], header[4], header[5]]) as usize;
+
+    let mut payload = vec![0u8; frame_len];
+    stream.read_exact(&mut payload).unwrap();
+    (frame_type, payload)
+}
+
+fn write_frame(stream: &mut TcpStream, frame_type: u16, payload: &[u8]) {
+    let mut buf = Vec::with_capacity(6 + payload.len());
+    buf.extend(frame_type.to_le_bytes());
+    buf.extend((payload.len() as u32).to_le_bytes());
+    buf.extend(payload);
+    stream.write_all(&buf).unwrap();
+}
+
+/// A minimal receiver speaking the wire protocol directly, so the test doesn't depend on any
+/// Rust-side API of the receiving end.
+fn run_receiver(listener: TcpListener) -> Vec<u8> {
+    let (mut stream, _) = listener.accept().unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(30)))
+        .unwrap();
+
+    let mut received = Vec::new();
+    let mut windowed_recv_segments = 0;
+    loop {
+        let (frame_type, payload) = read_frame(&mut stream);
+        match frame_type {
+            1 => write_frame(&mut stream, 2, &[]),
+            3 => {
+                let segment_idx =
+                    u32::from_le_bytes([payload[0], payload[1], payload[2], payload[3]]);
+                let chunk_size =
+                    u32::from_le_bytes([payload[4], payload[5], payload[6], payload[7]]) as usize;
+                if chunk_size == 0 {
+                    write_frame(&mut stream, 99, &[]);
+                    return received;
+                }
+                received.extend(&payload[8..(8 + chunk_size)]);
+
+                windowed_recv_segments += 1;
+                if windowed_recv_segments >= 8 {
+                    windowed_recv_segments = 0;
+                    write_frame(&mut stream, 4, &(segment_idx + 1).to_le_bytes());
+                }
+            }
+            _ => panic!("unexpected frame type from the wrapper: {}", frame_type),
+        }
+    }
+}
+
+unsafe extern "C" fn on_segment_sent(user_info: *mut c_void, _segment_idx: u32, bytes_sent: usize) {
+    let reported = &*(user_info as *const Reported);
+    reported.bytes_acked.store(bytes_sent, Ordering::SeqCst);
+}
+
+unsafe extern "C" fn on_progress(user_info: *mut c_void, progress: *const TransferProgress) {
+    let reported = &*(user_info as *const Reported);
+    let progress = &*progress;
+    assert!(progress.has_total_bytes);
+    assert!(progress.bytes_confirmed <= progress.total_bytes);
+    let mut percentage = reported.percentage.lock().unwrap();
+    *percentage = percentage.max(progress.percentage);
+}
+
+unsafe extern "C" fn on_completed(user_info: *mut c_void, summary: *const TransferSummary) {
+    let reported = &*(user_info as *const Reported);
+    let summary = &*summary;
+    assert!(summary.success);
+    assert_eq!(summary.error_code, 0);
+    assert!(!summary.has_sha256);
+    reported
+        .bytes_completed
+        .store(summary.bytes + 1, Ordering::SeqCst);
+}
+
+unsafe extern "C" fn on_retained_completed(
+    user_info: *mut c_void,
+    summary: *const TransferSummary,
+) {
+    let retained = &*(user_info as *const Retained);
+    assert!(!(*summary).success);
+    let message = CStr::from_ptr((retained.last_error_message)());
+    retained.calls.lock().unwrap().push(format!(
+        "completed with {}: {}",
+        (*summary).error_code,
+        message.to_str().unwrap().lines().next().unwrap()
+    ));
+}
+
+unsafe extern "C" fn on_error_code(user_info: *mut c_void, summary: *const TransferSummary) {
+    let error_code = &*(user_info as *const AtomicU64);
+    error_code.store((*summary).error_code as u64 + 1, Ordering::SeqCst);
+}
+
+unsafe extern "C" fn on_file_progress(
+    user_info: *mut c_void,
+    file_idx: u32,
+    _: *const TransferProgress,
+) {
+    let reported = &*(user_info as *const BatchReported);
+    let mut file_indexes = reported.file_indexes.lock().unwrap();
+    if file_indexes.last() != Some(&file_idx) {
+        file_indexes.push(file_idx);
+    }
+}
+
+unsafe extern "C" fn on_batch_progress(user_info: *mut c_void, progress: *const BatchProgress) {
+    let reported = &*(user_info as *const BatchReported);
+    reported.batch_progress.lock().unwrap().push(*progress);
+}
+
+unsafe extern "C" fn on_batch_completed(user_info: *mut c_void, summary: *const TransferSummary) {
+    let reported = &*(user_info as *const BatchReported);
+    reported
+        .error_code
+        .store((*summary).error_code as u64 + 1, Ordering::SeqCst);
+}
+
+unsafe extern "C" fn on_release(user_info: *mut c_void) {
+    let retained = Box::from_raw(user_info as *mut Retained);
+    retained.calls.lock().unwrap().push("released".to_owned());
+}
+
+unsafe extern "C" fn on_offer(
+    user_info: *mut c_void,
+    name: *const c_char,
+    _: *const c_char,
+) -> bool {
+    let received = &*(user_info as *const Received);
+    *received.offered_name.lock().unwrap() = CStr::from_ptr(name).to_str().unwrap().to_owned();
+    true
+}
+
+unsafe extern "C" fn on_receive_progress(user_info: *mut c_void, bytes_received: u64, _: u64) {
+    let received = &*(user_info as *const Received);
+    received
+        .bytes_received
+        .store(bytes_received, Ordering::SeqCst);
+}
+
+unsafe extern "C" fn on_received(user_info: *mut c_void, path: *const PathChar, bytes: u64) {
+    let received = &*(user_info as *const Received);
+    let path = path_from_c(path);
+    *received.file.lock().unwrap() = Some((path, bytes));
+}
+
+unsafe extern "C" fn on_text(
+    user_info: *mut c_void,
+    name: *const c_char,
+    _: *const c_char,
+    text: *const c_char,
+) {
+    let reported = &*(user_info as *const TextReported);
+    let name = CStr::from_ptr(name).to_str().unwrap().to_owned();
+    let text = CStr::from_ptr(text).to_str().unwrap().to_owned();
+    reported.received.lock().unwrap().push((name, text));
+}
+
+unsafe extern "C" fn on_text_sent(user_info: *mut c_void, error_code: u32) {
+    let reported = &*(user_info as *const TextReported);
+    reported
+        .error_codes
+        .lock()
+        .unwrap()
+        .push(error_code as u64 + 1);
+}
+
+unsafe extern "C" fn on_history_entry(user_info: *mut c_void, entry: *const HistoryEntry) {
+    let entries = &*(user_info as *const Mutex<Vec<(String, bool, Option<String>)>>);
+    let entry = &*entry;
+    let file_name = CStr::from_ptr(entry.file_name).to_str().unwrap().to_owned();
+    let error =
+        (!entry.error.is_null()).then(|| CStr::from_ptr(entry.error).to_str().unwrap().to_owned());
+    entries
+        .lock()
+        .unwrap()
+        .push((file_name, entry.received, error));
+}
+
+#[test]
+fn header_declares_exported_symbols() {
+    let header_path = artifacts_dir().join("icedrop.h");
+    let header = fs::read_to_string(&header_path)
+        .unwrap_or_else(|err| panic!("could not read {:?}: {}", header_path, err));
+
+    let lib = load_wrapper();
+    for symbol in EXPORTED_SYMBOLS {
+        assert!(
+            header.contains(&format!("{}(", symbol)),
+            "`{}` is missing from icedrop.h",
+            symbol
+        );
+        assert!(header.contains("IcedropTransferSummary"));
+        assert!(header.contains("IcedropTransferProgress"));
+        assert!(header.contains("IcedropHistoryEntry"));
+        assert!(header.contains("IcedropPathChar"));
+        assert!(header.contains("icedrop_transfer_t"));
+        unsafe {
+            lib.get::<*const c_void>(symbol.as_bytes())
+                .unwrap_or_else(|err| panic!("`{}` is not exported: {}", symbol, err));
+        }
+    }
+}
+
+#[test]
+fn clients_are_shared_between_threads() {
+    let lib = load_wrapper();
+    let (client_new, client_run, client_stop, client_retain, client_release) = unsafe {
+        let client_new: Symbol<ClientNewFn> = lib.get(b"icedrop_client_new").unwrap();
+        let client_run: Symbol<ClientRunFn> =
+            lib.get(b"icedrop_client_run_in_current_thread").unwrap();
+        let client_retain: Symbol<ClientRetainFn> = lib.get(b"icedrop_client_retain").unwrap();
+        let client_stop: Symbol<ClientRunFn> = lib.get(b"icedrop_client_stop").unwrap();
+        let client_release: Symbol<ClientRunFn> = lib.get(b"icedrop_client_release").unwrap();
+        (
+            *client_new,
+            *client_run,
+            *client_stop,
+            *client_retain,
+            *client_release,
+        )
+    };
+    let client_set_rate_limit: ClientSetRateLimitFn =
+        unsafe { *lib.get(b"icedrop_client_set_rate_limit").unwrap() };
+
+    // Only one of the threads gets to run the client, the other one returns right away.
+    let client = AnySendable(unsafe { client_new() });
+    let mut runners: Vec<_> = (0..2)
+        .map(|_| {
+            let running = AnySendable(unsafe { client_retain(client.0) });
+            thread::spawn(move || unsafe {
+                client_run(running.0);
+                client_release(running.0);
+            })
+        })
+        .collect();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while runners.iter().all(|runner| !runner.is_finished()) {
+        assert!(Instant::now() < deadline, "both threads run the client");
+        thread::sleep(Duration::from_millis(10));
+    }
+    runners.retain(|runner| !runner.is_finished());
+    assert_eq!(runners.len(), 1);
+
+    let callers: Vec<_> = (0..8)
+        .map(|i| {
+            let caller = AnySendable(unsafe { client_retain(client.0) });
+            thread::spawn(move || unsafe {
+                for bytes_per_sec in 0..100 {
+                    // Callers outpacing the client are told to try again.
+                    while client_set_rate_limit(caller.0, i * 100 + bytes_per_sec) == STATUS_BUSY {
+                        thread::sleep(Duration::from_millis(1));
+                    }
+                }
+                client_release(caller.0);
+            })
+        })
+        .collect();
+    for caller in callers {
+        caller.join().unwrap();
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    unsafe { client_stop(client.0) };
+    while !runners[0].is_finished() {
+        assert!(Instant::now() < deadline, "the client did not stop");
+        thread::sleep(Duration::from_millis(10));
+    }
+    unsafe { client_release(client.0) };
+}
+
+#[test]
+fn requests_fail_while_the_queue_is_full() {
+    let lib = load_wrapper();
+    let (client_new, client_release, client_set_rate_limit, last_error_message) = unsafe {
+        let client_new: Symbol<ClientNewFn> = lib.get(b"icedrop_client_new").unwrap();
+        let client_release: Symbol<ClientRunFn> = lib.get(b"icedrop_client_release").unwrap();
+        let client_set_rate_limit: Symbol<ClientSetRateLimitFn> =
+            lib.get(b"icedrop_client_set_rate_limit").unwrap();
+        let last_error_message: Symbol<LastErrorMessageFn> =
+            lib.get(b"icedrop_last_error_message").unwrap();
+        (
+            *client_new,
+            *client_release,
+            *client_set_rate_limit,
+            *last_error_message,
+        )
+    };
+
+    // Nothing runs the client, so its requests pile up instead of blocking the caller.
+    let client = unsafe { client_new() };
+    let statuses: Vec<_> = (0..101)
+        .map(|bytes_per_sec| unsafe { client_set_rate_limit(client, bytes_per_sec) })
+        .collect();
+    assert!(statuses[..100].iter().all(|&status| status == STATUS_OK));
+    assert_eq!(statuses[100], STATUS_BUSY);
+    let message = unsafe { CStr::from_ptr(last_error_message()) };
+    assert!(
+        message.to_str().unwrap().contains("queued"),
+        "{:?}",
+        message
+    );
+    assert_eq!(
+        unsafe { client_set_rate_limit(std::ptr::null_mut(), 0) },
+        STATUS_INVALID_ARGUMENT
+    );
+    unsafe { client_release(client) };
+}
+
+#[cfg(not(target_os = "windows"))]
+#[test]
+fn busy_send_file_with_fd_leaves_the_fd_open() {
+    use std::os::unix::io::{FromRawFd, IntoRawFd};
+
+    let lib = load_wrapper();
+    let (client_new, client_release, client_set_rate_limit, client_send_file_with_fd) = unsafe {
+        let client_new: Symbol<ClientNewFn> = lib.get(b"icedrop_client_new").unwrap();
+        let client_release: Symbol<ClientRunFn> = lib.get(b"icedrop_client_release").unwrap();
+        let client_set_rate_limit: Symbol<ClientSetRateLimitFn> =
+            lib.get(b"icedrop_client_set_rate_limit").unwrap();
+        let client_send_file_with_fd: Symbol<ClientSendFileWithFdFn> =
+            lib.get(b"icedrop_client_send_file_with_fd").unwrap();
+        (
+            *client_new,
+            *client_release,
+            *client_set_rate_limit,
+            *client_send_file_with_fd,
+        )
+    };
+
+    let path = std::env::temp_dir().join(format!("icedrop-ffi-busy-fd-{}", std::process::id()));
+    fs::write(&path, b"still mine").unwrap();
+    let fd = fs::File::open(&path).unwrap().into_raw_fd();
+
+    let client = unsafe { client_new() };
+    for bytes_per_sec in 0..100 {
+        assert_eq!(
+            unsafe { client_set_rate_limit(client, bytes_per_sec) },
+            STATUS_OK
+        );
+    }
+    let remote_addr = CString::new("127.0.0.1:1").unwrap();
+    let status = unsafe {
+        client_send_file_with_fd(
+            client,
+            remote_addr.as_ptr(),
+            fd,
+            std::ptr::null_mut(),
+            None,
+            None,
+            None,
+            std::ptr::null_mut(),
+        )
+    };
+    assert_eq!(status, STATUS_BUSY);
+
+    // The fd is still the caller's to read from and close.
+    let mut file = unsafe { fs::File::from_raw_fd(fd) };
+    let mut contents = Vec::new();
+    file.read_to_end(&mut contents).unwrap();
+    assert_eq!(contents, b"still mine");
+    unsafe { client_release(client) };
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn send_file_over_localhost() {
+    let lib = load_wrapper();
+    let (client_new, client_run, client_send_file) = unsafe {
+        let client_new: Symbol<ClientNewFn> = lib.get(b"icedrop_client_new").unwrap();
+        let client_run: Symbol<ClientRunFn> =
+            lib.get(b"icedrop_client_run_in_current_thread").unwrap();
+        let client_send_file: Symbol<ClientSendFileFn> =
+            lib.get(b"icedrop_client_send_file").unwrap();
+        (*client_new, *client_run, *client_send_file)
+    };
+
+    // Ten segments, so the receiver acks at least once and the progress callback fires.
+    let content: Vec<u8> = (0..(SEGMENT_SIZE * 10 + 1234))
+        .map(|i| (i % 251) as u8)
+        .collect();
+    let file_path = std::env::temp_dir().join(format!("icedrop-ffi-e2e-{}", std::process::id()));
+    fs::write(&file_path, &content).unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let remote_addr = CString::new(listener.local_addr().unwrap().to_string()).unwrap();
+    let receiver = thread::spawn(move || run_receiver(listener));
+
+    let reported: &'static Reported = Box::leak(Box::default());
+    let local_file_path = c_path(&file_path);
+    let client = AnySendable(unsafe { client_new() });
+    unsafe {
+        client_send_file(
+            client.0,
+            remote_addr.as_ptr(),
+            local_file_path.as_ptr(),
+            reported as *const Reported as *mut c_void,
+            Some(on_segment_sent),
+            Some(on_progress),
+            Some(on_completed),
+            std::ptr::null_mut(),
+        );
+    }
+
+    // The client loop never returns, so it's left running until the test process exits.
+    thread::spawn(move || unsafe { client_run(client.0) });
+
+    let received = receiver.join().unwrap();
+    fs::remove_file(&file_path).unwrap();
+    assert_eq!(received.len(), content.len());
+    assert!(
+        received == content,
+        "received content differs from the sent file"
+    );
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while reported.bytes_completed.load(Ordering::SeqCst) == 0 && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(
+        reported.bytes_acked.load(Ordering::SeqCst),
+        SEGMENT_SIZE * 8
+    );
+    assert_eq!(
+        reported.bytes_completed.load(Ordering::SeqCst),
+        content.len() as u64 + 1
+    );
+    let expected_percentage = (SEGMENT_SIZE * 8) as f64 * 100.0 / content.len() as f64;
+    assert_eq!(*reported.percentage.lock().unwrap(), expected_percentage);
+}
+
+#[test]
+fn send_file_ex_releases_user_info_once() {
+    let lib = load_wrapper();
+    let (client_new, client_run, client_send_file_ex, last_error_message) = unsafe {
+        let client_new: Symbol<ClientNewFn> = lib.get(b"icedrop_client_new").unwrap();
+        let client_run: Symbol<ClientRunFn> =
+            lib.get(b"icedrop_client_run_in_current_thread").unwrap();
+        let client_send_file_ex: Symbol<ClientSendFileExFn> =
+            lib.get(b"icedrop_client_send_file_ex").unwrap();
+        let last_error_message: Symbol<LastErrorMessageFn> =
+            lib.get(b"icedrop_last_error_message").unwrap();
+        (
+            *client_new,
+            *client_run,
+            *client_send_file_ex,
+            *last_error_message,
+        )
+    };
+    let client = AnySendable(unsafe { client_new() });
+    thread::spawn(move || unsafe { client_run(client.0) });
+
+    // Nothing listens on the port once the listener is dropped.
+    let remote_addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let remote_addr = CString::new(remote_addr.to_string()).unwrap();
+    let file_path = std::env::temp_dir().join(format!("icedrop-ffi-ex-{}", std::process::id()));
+    fs::write(&file_path, b"some data").unwrap();
+
+    // Failing right away, as the file doesn't exist, and failing once the client got to it.
+    let missing_path = file_path.with_extension("missing");
+    for (path, expected_status, expected_completion) in [
+        (
+            &missing_path,
+            STATUS_NOT_FOUND,
+            format!(
+                "completed with {}: could not open {}",
+                ERROR_NOT_FOUND,
+                missing_path.display()
+            ),
+        ),
+        (
+            &file_path,
+            STATUS_OK,
+            format!(
+                "completed with {}: could not connect to any of 1 address(es)",
+                ERROR_CONNECTION_REFUSED
+            ),
+        ),
+    ] {
+        let calls = std::sync::Arc::new(Mutex::new(Vec::new()));
+        let retained = Box::into_raw(Box::new(Retained {
+            calls: calls.clone(),
+            last_error_message,
+        }));
+        let status = unsafe {
+            client_send_file_ex(
+                client.0,
+                remote_addr.as_ptr(),
+                c_path(path).as_ptr(),
+                retained as *mut c_void,
+                Some(on_release),
+                None,
+                None,
+                Some(on_retained_completed),
+                std::ptr::null_mut(),
+            )
+        };
+        assert_eq!(status, expected_status);
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while calls.lock().unwrap().len() < 2 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        thread::sleep(Duration::from_millis(50));
+        let calls = calls.lock().unwrap();
+        assert!(calls[0].starts_with(&expected_completion), "{:?}", calls);
+        assert_eq!(calls[1..], ["released"]);
+    }
+    fs::remove_file(&file_path).unwrap();
+}
+
+#[test]
+fn transfer_can_be_paused_and_cancelled() {
+    let lib = load_wrapper();
+    let (client_new, client_run, client_send_file, transfer_pause, transfer_cancel) = unsafe {
+        let client_new: Symbol<ClientNewFn> = lib.get(b"icedrop_client_new").unwrap();
+        let client_run: Symbol<ClientRunFn> =
+            lib.get(b"icedrop_client_run_in_current_thread").unwrap();
+        let client_send_file: Symbol<ClientSendFileFn> =
+            lib.get(b"icedrop_client_send_file").unwrap();
+        let transfer_pause: Symbol<TransferFn> = lib.get(b"icedrop_transfer_pause").unwrap();
+        let transfer_cancel: Symbol<TransferFn> = lib.get(b"icedrop_transfer_cancel").unwrap();
+        (
+            *client_new,
+            *client_run,
+            *client_send_file,
+            *transfer_pause,
+            *transfer_cancel,
+        )
+    };
+    let transfer_destroy: TransferDestroyFn =
+        unsafe { *lib.get(b"icedrop_transfer_destroy").unwrap() };
+
+    let file_path = std::env::temp_dir().join(format!("icedrop-ffi-cancel-{}", std::process::id()));
+    fs::write(&file_path, vec![7u8; SEGMENT_SIZE * 4]).unwrap();
+
+    // Counts the data frames until the wrapper hangs up.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let remote_addr = CString::new(listener.local_addr().unwrap().to_string()).unwrap();
+    let receiver = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(30)))
+            .unwrap();
+        let mut data_frames = 0;
+        let mut header = [0u8; 6];
+        while stream.read_exact(&mut header).is_ok() {
+            let frame_type = u16::from_le_bytes([header[0], header[1]]);
+            let frame_len = u32::from_le_bytes([header[2], header[3], header[4], header[5]]);
+            let mut payload = vec![0u8; frame_len as usize];
+            stream.read_exact(&mut payload).unwrap();
+            match frame_type {
+                1 => write_frame(&mut stream, 2, &[]),
+                3 => data_frames += 1,
+                _ => {}
+            }
+        }
+        data_frames
+    });
+
+    let error_code: &'static AtomicU64 = Box::leak(Box::default());
+    let local_file_path = c_path(&file_path);
+    let client = AnySendable(unsafe { client_new() });
+    let mut transfer = std::ptr::null_mut();
+    let status = unsafe {
+        client_send_file(
+            client.0,
+            remote_addr.as_ptr(),
+            local_file_path.as_ptr(),
+            error_code as *const AtomicU64 as *mut c_void,
+            None,
+            None,
+            Some(on_error_code),
+            &mut transfer,
+        )
+    };
+    assert_eq!(status, STATUS_OK);
+    assert!(!transfer.is_null());
+    assert_eq!(unsafe { transfer_pause(transfer) }, STATUS_OK);
+    thread::spawn(move || unsafe { client_run(client.0) });
+
+    // Paused before it started, nothing is sent until it's cancelled.
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(error_code.load(Ordering::SeqCst), 0);
+    assert_eq!(unsafe { transfer_cancel(transfer) }, STATUS_OK);
+    unsafe { transfer_destroy(transfer) };
+    assert_eq!(receiver.join().unwrap(), 0);
+    fs::remove_file(&file_path).unwrap();
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while error_code.load(Ordering::SeqCst) == 0 && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(
+        error_code.load(Ordering::SeqCst),
+        ERROR_CANCELLED as u64 + 1
+    );
+    assert_eq!(
+        unsafe { transfer_cancel(std::ptr::null_mut()) },
+        STATUS_INVALID_ARGUMENT
+    );
+}
+
+#[test]
+fn receive_file_over_localhost() {
+    let lib = load_wrapper();
+    let (client_new, client_run, client_send_file, client_start_receiver) = unsafe {
+        let client_new: Symbol<ClientNewFn> = lib.get(b"icedrop_client_new").unwrap();
+        let client_run: Symbol<ClientRunFn> =
+            lib.get(b"icedrop_client_run_in_current_thread").unwrap();
+        let client_send_file: Symbol<ClientSendFileFn> =
+            lib.get(b"icedrop_client_send_file").unwrap();
+        let client_start_receiver: Symbol<ClientStartReceiverFn> =
+            lib.get(b"icedrop_client_start_receiver").unwrap();
+        (
+            *client_new,
+            *client_run,
+            *client_send_file,
+            *client_start_receiver,
+        )
+    };
+
+    let dir = std::env::temp_dir().join(format!("icedrop-ffi-recv-{}", std::process::id()));
+    fs::create_dir_all(dir.join("out")).unwrap();
+    let content: Vec<u8> = (0..(SEGMENT_SIZE * 3)).map(|i| (i % 241) as u8).collect();
+    fs::write(dir.join("sent"), &content).unwrap();
+
+    // Find a free port for the receiver to bind.
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let bind_addr = CString::new(addr.to_string()).unwrap();
+    let dest_dir = c_path(&dir.join("out"));
+    let received: &'static Received = Box::leak(Box::default());
+    let client = AnySendable(unsafe { client_new() });
+    unsafe {
+        client_start_receiver(
+            client.0,
+            bind_addr.as_ptr(),
+            dest_dir.as_ptr(),
+            received as *const Received as *mut c_void,
+            Some(on_offer),
+            Some(on_receive_progress),
+            Some(on_received),
+        );
+    }
+    thread::spawn(move || unsafe { client_run(client.0) });
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while TcpStream::connect(addr).is_err() {
+        assert!(Instant::now() < deadline, "the receiver did not start");
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let sender = AnySendable(unsafe { client_new() });
+    let local_file_path = c_path(&dir.join("sent"));
+    unsafe {
+        client_send_file(
+            sender.0,
+            bind_addr.as_ptr(),
+            local_file_path.as_ptr(),
+            std::ptr::null_mut(),
+            None,
+            None,
+            None,
+            std::ptr::null_mut(),
+        );
+    }
+    thread::spawn(move || unsafe { client_run(sender.0) });
+
+    let deadline = Instant::now() + Duration::from_secs(30);
+    let (path, bytes) = loop {
+        if let Some(file) = received.file.lock().unwrap().clone() {
+            break file;
+        }
+        assert!(Instant::now() < deadline, "the file was not received");
+        thread::sleep(Duration::from_millis(10));
+    };
+    assert_eq!(path, dir.join("out").join("sent"));
+    assert_eq!(bytes, content.len() as u64);
+    assert_eq!(*received.offered_name.lock().unwrap(), "icedrop");
+    assert_eq!(
+        received.bytes_received.load(Ordering::SeqCst),
+        content.len() as u64
+    );
+    assert!(fs::read(&path).unwrap() == content);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn history_lists_recent_transfers() {
+    use icedrop_core::{HistoryStore, TransferDirection, TransferStatus};
+
+    let lib = load_wrapper();
+    let history_read: HistoryReadFn = unsafe { *lib.get(b"icedrop_history_read").unwrap() };
+
+    let path = std::env::temp_dir().join(format!("icedrop-ffi-history-{}", std::process::id()));
+    fs::remove_file(&path).ok();
+    let store = HistoryStore::open(&path).unwrap();
+    for (file_name, direction, status) in [
+        ("a", TransferDirection::Sent, TransferStatus::Completed),
+        ("b", TransferDirection::Received, TransferStatus::Completed),
+        (
+            "c",
+            TransferDirection::Sent,
+            TransferStatus::Failed("refused".to_owned()),
+        ),
+    ] {
+        let entry = icedrop_core::HistoryEntry {
+            finished_at: std::time::SystemTime::now(),
+            direction,
+            peer: "127.0.0.1:7360".to_owned(),
+            file_name: file_name.to_owned(),
+            size: 1,
+            duration: Duration::from_millis(1),
+            status,
+            sha256: None,
+        };
+        store.record(&entry).unwrap();
+    }
+
+    let entries: Mutex<Vec<(String, bool, Option<String>)>> = Mutex::default();
+    let store_path = c_path(&path);
+    let read = unsafe {
+        history_read(
+            store_path.as_ptr(),
+            2,
+            &entries as *const _ as *mut c_void,
+            Some(on_history_entry),
+        )
+    };
+    fs::remove_file(&path).unwrap();
+    assert!(read);
+    assert_eq!(
+        entries.into_inner().unwrap(),
+        [
+            ("c".to_owned(), false, Some("refused".to_owned())),
+            ("b".to_owned(), true, None),
+        ]
+    );
+}
+
+#[test]
+fn send_files_over_one_connection() {
+    let lib = load_wrapper();
+    let (client_new, client_run, client_send_files, client_start_receiver) = unsafe {
+        let client_new: Symbol<ClientNewFn> = lib.get(b"icedrop_client_new").unwrap();
+        let client_run: Symbol<ClientRunFn> =
+            lib.get(b"icedrop_client_run_in_current_thread").unwrap();
+        let client_send_files: Symbol<ClientSendFilesFn> =
+            lib.get(b"icedrop_client_send_files").unwrap();
+        let client_start_receiver: Symbol<ClientStartReceiverFn> =
+            lib.get(b"icedrop_client_start_receiver").unwrap();
+        (
+            *client_new,
+            *client_run,
+            *client_send_files,
+            *client_start_receiver,
+        )
+    };
+
+    let dir = std::env::temp_dir().join(format!("icedrop-ffi-batch-{}", std::process::id()));
+    fs::create_dir_all(dir.join("out")).unwrap();
+    let contents: Vec<Vec<u8>> = (0..3)
+        .map(|i| vec![i as u8; SEGMENT_SIZE + i * 1000])
+        .collect();
+    let total_bytes = contents.iter().map(Vec::len).sum::<usize>() as u64;
+    for (i, content) in contents.iter().enumerate() {
+        fs::write(dir.join(format!("sent-{}", i)), content).unwrap();
+    }
+
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let bind_addr = CString::new(addr.to_string()).unwrap();
+    let dest_dir = c_path(&dir.join("out"));
+    let receiver = AnySendable(unsafe { client_new() });
+    unsafe {
+        client_start_receiver(
+            receiver.0,
+            bind_addr.as_ptr(),
+            dest_dir.as_ptr(),
+            std::ptr::null_mut(),
+            None,
+            None,
+            None,
+        );
+    }
+    thread::spawn(move || unsafe { client_run(receiver.0) });
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while TcpStream::connect(addr).is_err() {
+        assert!(Instant::now() < deadline, "the receiver did not start");
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let paths: Vec<Vec<PathChar>> = (0..contents.len())
+        .map(|i| c_path(&dir.join(format!("sent-{}", i))))
+        .collect();
+    let path_ptrs: Vec<*const PathChar> = paths.iter().map(|path| path.as_ptr()).collect();
+    let reported: &'static BatchReported = Box::leak(Box::default());
+    let sender = AnySendable(unsafe { client_new() });
+    let status = unsafe {
+        client_send_files(
+            sender.0,
+            bind_addr.as_ptr(),
+            path_ptrs.as_ptr(),
+            path_ptrs.len(),
+            reported as *const BatchReported as *mut c_void,
+            Some(on_file_progress),
+            Some(on_batch_progress),
+            Some(on_batch_completed),
+            std::ptr::null_mut(),
+        )
+    };
+    assert_eq!(status, STATUS_OK);
+    thread::spawn(move || unsafe { client_run(sender.0) });
+
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while reported.error_code.load(Ordering::SeqCst) == 0 {
+        assert!(Instant::now() < deadline, "the files were not sent");
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(reported.error_code.load(Ordering::SeqCst), 1);
+    for (i, content) in contents.iter().enumerate() {
+        let received = fs::read(dir.join("out").join(format!("sent-{}", i))).unwrap();
+        assert!(received == *content, "file {} differs from the sent one", i);
+    }
+    assert_eq!(*reported.file_indexes.lock().unwrap(), [0, 1, 2]);
+    let batch_progress = reported.batch_progress.lock().unwrap();
+    assert!(batch_progress
+        .windows(2)
+        .all(|pair| pair[0].bytes_confirmed <= pair[1].bytes_confirmed));
+    assert_eq!(
+        batch_progress.last(),
+        Some(&BatchProgress {
+            files_completed: 3,
+            file_count: 3,
+            bytes_confirmed: total_bytes,
+            total_bytes,
+            percentage: 100.0,
+        })
+    );
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn send_text_over_localhost() {
+    let lib = load_wrapper();
+    let (client_new, client_run, start_receiver, set_text_callback, send_text) = unsafe {
+        let client_new: Symbol<ClientNewFn> = lib.get(b"icedrop_client_new").unwrap();
+        let client_run: Symbol<ClientRunFn> =
+            lib.get(b"icedrop_client_run_in_current_thread").unwrap();
+        let client_start_receiver: Symbol<ClientStartReceiverFn> =
+            lib.get(b"icedrop_client_start_receiver").unwrap();
+        let client_set_text_callback: Symbol<ClientSetTextCallbackFn> =
+            lib.get(b"icedrop_client_set_text_callback").unwrap();
+        let client_send_text: Symbol<ClientSendTextFn> =
+            lib.get(b"icedrop_client_send_text").unwrap();
+        (
+            *client_new,
+            *client_run,
+            *client_start_receiver,
+            *client_set_text_callback,
+            *client_send_text,
+        )
+    };
+
+    let dir = std::env::temp_dir().join(format!("icedrop-ffi-text-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let dest_dir = c_path(&dir);
+    let reported: &'static TextReported = Box::leak(Box::default());
+    let user_info = reported as *const TextReported as *mut c_void;
+
+    // The first receiver takes text, the one started after the callback was unset doesn't.
+    let bind_addrs: Vec<CString> = (0..2)
+        .map(|_| {
+            let addr = TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap();
+            CString::new(addr.to_string()).unwrap()
+        })
+        .collect();
+    let receiver = AnySendable(unsafe { client_new() });
+    unsafe {
+        let status = set_text_callback(receiver.0, user_info, Some(on_text));
+        assert_eq!(status, STATUS_OK);
+        for (i, bind_addr) in bind_addrs.iter().enumerate() {
+            if i == 1 {
+                set_text_callback(receiver.0, std::ptr::null_mut(), None);
+            }
+            start_receiver(
+                receiver.0,
+                bind_addr.as_ptr(),
+                dest_dir.as_ptr(),
+                std::ptr::null_mut(),
+                None,
+                None,
+                None,
+            );
+        }
+    }
+    thread::spawn(move || unsafe { client_run(receiver.0) });
+    let deadline = Instant::now() + Duration::from_secs(5);
+    for bind_addr in &bind_addrs {
+        while TcpStream::connect(bind_addr.to_str().unwrap()).is_err() {
+            assert!(Instant::now() < deadline, "the receiver did not start");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    let text = CString::new("hello from icedrop").unwrap();
+    let sender = AnySendable(unsafe { client_new() });
+    for bind_addr in &bind_addrs {
+        let status = unsafe {
+            send_text(
+                sender.0,
+                bind_addr.as_ptr(),
+                text.as_ptr(),
+                user_info,
+                Some(on_text_sent),
+            )
+        };
+        assert_eq!(status, STATUS_OK);
+    }
+    let status = unsafe {
+        send_text(
+            sender.0,
+            bind_addrs[0].as_ptr(),
+            std::ptr::null(),
+            user_info,
+            Some(on_text_sent),
+        )
+    };
+    assert_eq!(status, STATUS_INVALID_ARGUMENT);
+    thread::spawn(move || unsafe { client_run(sender.0) });
+
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while reported.error_codes.lock().unwrap().len() < 2 {
+        assert!(Instant::now() < deadline, "the text was not sent");
+        thread::sleep(Duration::from_millis(10));
+    }
+    let mut error_codes = reported.error_codes.lock().unwrap().clone();
+    error_codes.sort_unstable();
+    assert_eq!(error_codes, [1, ERROR_REJECTED as u64 + 1]);
+    assert_eq!(
+        *reported.received.lock().unwrap(),
+        [("icedrop".to_owned(), "hello from icedrop".to_owned())]
+    );
+    fs::remove_dir_all(dir).unwrap();
+}